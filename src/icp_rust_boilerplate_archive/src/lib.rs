@@ -0,0 +1,127 @@
+//! Archive for transactions migrated out of the wallet canister. Only the
+//! wallet canister given at install time can append; anyone can read.
+
+#[macro_use]
+extern crate serde;
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const MAX_TRANSACTIONS_PER_PAGE: u64 = 500;
+
+// Same Candid shape as the wallet's `Transaction`
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Transaction {
+    id: u64,
+    from_user_id: u64,
+    to_user_id: u64,
+    amount: u64,
+    created_at: u64,
+    memo: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchiveConfig {
+    wallet_canister: Option<Principal>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ArchiveInitArgs {
+    wallet_canister: Principal,
+}
+
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ArchiveConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static CONFIG: RefCell<Cell<ArchiveConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+            ArchiveConfig { wallet_canister: None },
+        )
+        .expect("Cannot create the archive config")
+    );
+
+    static TRANSACTION_STORAGE: RefCell<StableBTreeMap<u64, Transaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+}
+
+#[ic_cdk::init]
+fn init(args: ArchiveInitArgs) {
+    CONFIG
+        .with(|config| {
+            config.borrow_mut().set(ArchiveConfig {
+                wallet_canister: Some(args.wallet_canister),
+            })
+        })
+        .expect("Cannot store the archive config");
+}
+
+/// Stores a batch of transactions and returns how many the archive holds.
+/// Appending a transaction again overwrites it, so a batch whose reply was
+/// lost can be retried safely.
+#[ic_cdk::update]
+fn append_transactions(transactions: Vec<Transaction>) -> Result<u64, String> {
+    let wallet_canister = CONFIG.with(|config| config.borrow().get().wallet_canister);
+    if wallet_canister != Some(ic_cdk::caller()) {
+        return Err("Only the wallet canister can append transactions".to_string());
+    }
+
+    TRANSACTION_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for transaction in transactions {
+            storage.insert(transaction.id, transaction);
+        }
+        Ok(storage.len())
+    })
+}
+
+#[ic_cdk::query]
+fn get_transaction(tx_id: u64) -> Option<Transaction> {
+    TRANSACTION_STORAGE.with(|storage| storage.borrow().get(&tx_id))
+}
+
+/// Returns up to `limit` transactions with an id of at least `start`.
+#[ic_cdk::query]
+fn get_transactions(start: u64, limit: u64) -> Vec<Transaction> {
+    TRANSACTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(start..)
+            .take(limit.min(MAX_TRANSACTIONS_PER_PAGE) as usize)
+            .map(|(_, transaction)| transaction)
+            .collect()
+    })
+}
+
+ic_cdk::export_candid!();
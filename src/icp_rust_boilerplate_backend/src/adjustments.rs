@@ -0,0 +1,257 @@
+//! Manual balance adjustments under dual control. One admin proposes a
+//! credit or debit with a written justification, and a second, different
+//! admin approves it before the ledger applies it against the treasury. The
+//! proposal keeps who did what and links to the journal entry it posted.
+//! Adjustments stay available while transfers are paused, so balances can
+//! be corrected before resuming after a reconciliation break.
+
+use crate::backup::ensure_writable;
+use crate::events::{self, EventKind};
+use crate::hardening::{self, TextKind};
+use crate::ledger::{self, EntryKind, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, notify_admins, NotificationKind};
+use crate::{
+    alerts, auth, current_time, ensure_admin, next_id, perf, token, Memory, WalletError,
+    MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const PROPOSAL_TTL_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MIN_REASON_LENGTH: usize = 10;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum AdjustmentStatus {
+    Pending,
+    Approved {
+        by: Principal,
+        at: u64,
+        journal_entry_id: u64,
+    },
+    Rejected {
+        by: Principal,
+        at: u64,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Adjustment {
+    id: u64,
+    user_id: u64,
+    // Positive credits the user, negative debits them
+    delta: i64,
+    reason: String,
+    proposed_by: Principal,
+    proposed_at: u64,
+    // Pending proposals can no longer be approved after this
+    expires_at: u64,
+    status: AdjustmentStatus,
+}
+
+impl Storable for Adjustment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static ADJUSTMENT_STORAGE: RefCell<StableBTreeMap<u64, Adjustment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map(
+        "adjustments.adjustment_storage",
+        84,
+        &ADJUSTMENT_STORAGE,
+    )]
+}
+
+fn save_adjustment(adjustment: &Adjustment) {
+    ADJUSTMENT_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(adjustment.id, adjustment.clone())
+    });
+}
+
+// The adjustment, if it is still waiting for a second admin
+fn pending_adjustment(adjustment_id: u64) -> Result<Adjustment, WalletError> {
+    let adjustment = ADJUSTMENT_STORAGE
+        .with(|storage| storage.borrow().get(&adjustment_id))
+        .ok_or(WalletError::not_found("adjustment", adjustment_id))?;
+    if adjustment.status != AdjustmentStatus::Pending {
+        return Err(WalletError::InvalidState {
+            reason: format!("Adjustment {} was already decided", adjustment_id),
+        });
+    }
+    if current_time() >= adjustment.expires_at {
+        return Err(WalletError::InvalidState {
+            reason: format!("Adjustment {} expired; propose it again", adjustment_id),
+        });
+    }
+    Ok(adjustment)
+}
+
+fn describe(delta: i64) -> String {
+    let amount = token::format_amount(delta.unsigned_abs());
+    if delta > 0 {
+        format!("credit of {}", amount)
+    } else {
+        format!("debit of {}", amount)
+    }
+}
+
+/// Proposes crediting (positive `delta`) or debiting (negative) `user_id`.
+/// Another admin must approve it.
+#[ic_cdk::update]
+fn propose_adjustment(user_id: u64, delta: i64, reason: String) -> Result<Adjustment, WalletError> {
+    perf::instrument("propose_adjustment", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        if delta == 0 {
+            return Err(WalletError::invalid("delta", "must not be 0"));
+        }
+        token::validate_amount("delta", delta.unsigned_abs())?;
+        hardening::check_args(|fields| {
+            fields.text("reason", &reason, TextKind::Reason);
+            if reason.trim().chars().count() < MIN_REASON_LENGTH {
+                fields.reject(
+                    "reason",
+                    &format!("must be at least {} characters", MIN_REASON_LENGTH),
+                );
+            }
+        })?;
+        let reason = reason.trim().to_string();
+
+        let now = current_time();
+        let adjustment = Adjustment {
+            id: next_id(),
+            user_id,
+            delta,
+            reason,
+            proposed_by: auth::caller(),
+            proposed_at: now,
+            expires_at: now + PROPOSAL_TTL_NANOS,
+            status: AdjustmentStatus::Pending,
+        };
+        save_adjustment(&adjustment);
+        notify_admins(format!(
+            "Adjustment {} proposes a {} for user {} and needs a second approval: {}",
+            adjustment.id,
+            describe(delta),
+            user_id,
+            adjustment.reason
+        ));
+        Ok(adjustment)
+    })
+}
+
+/// Applies a pending adjustment. The approver must be a different admin
+/// from the one who proposed it.
+#[ic_cdk::update]
+fn approve_adjustment(adjustment_id: u64) -> Result<Adjustment, WalletError> {
+    perf::instrument("approve_adjustment", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let mut adjustment = pending_adjustment(adjustment_id)?;
+        let approver = auth::caller();
+        if approver == adjustment.proposed_by {
+            return Err(WalletError::Unauthorized {
+                reason: "an adjustment must be approved by a second admin".to_string(),
+            });
+        }
+
+        let kind = EntryKind::ManualAdjustment { adjustment_id };
+        let user = ledger::user(adjustment.user_id);
+        let amount = adjustment.delta.unsigned_abs();
+        let entry = if adjustment.delta > 0 {
+            ledger::transfer(kind, LedgerAccount::Treasury, user, amount)?
+        } else {
+            ledger::transfer(kind, user, LedgerAccount::Treasury, amount)?
+        };
+        let now = current_time();
+        adjustment.status = AdjustmentStatus::Approved {
+            by: approver,
+            at: now,
+            journal_entry_id: entry.id(),
+        };
+        save_adjustment(&adjustment);
+
+        let balance = ledger::user_balance(adjustment.user_id);
+        alerts::check_balance(adjustment.user_id, balance);
+        events::record(EventKind::BalanceAdjusted {
+            adjustment_id,
+            user_id: adjustment.user_id,
+            delta: adjustment.delta,
+        });
+        notify(
+            adjustment.user_id,
+            NotificationKind::Adjustment,
+            format!(
+                "An administrator applied a {} to your balance: {}",
+                describe(adjustment.delta),
+                adjustment.reason
+            ),
+        );
+        Ok(adjustment)
+    })
+}
+
+/// Rejects a pending adjustment; its proposer can use this to withdraw it.
+#[ic_cdk::update]
+fn reject_adjustment(adjustment_id: u64) -> Result<Adjustment, WalletError> {
+    perf::instrument("reject_adjustment", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let mut adjustment = pending_adjustment(adjustment_id)?;
+        adjustment.status = AdjustmentStatus::Rejected {
+            by: auth::caller(),
+            at: current_time(),
+        };
+        save_adjustment(&adjustment);
+        Ok(adjustment)
+    })
+}
+
+/// Every adjustment, newest first, optionally only those still pending.
+#[ic_cdk::query]
+fn list_adjustments(pending_only: bool) -> Result<Vec<Adjustment>, WalletError> {
+    perf::instrument("list_adjustments", || {
+        ensure_admin()?;
+
+        let now = current_time();
+        let mut adjustments: Vec<Adjustment> = ADJUSTMENT_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, adjustment)| adjustment)
+                .filter(|adjustment| {
+                    !pending_only
+                        || (adjustment.status == AdjustmentStatus::Pending
+                            && now < adjustment.expires_at)
+                })
+                .collect()
+        });
+        adjustments.reverse();
+        Ok(adjustments)
+    })
+}
@@ -0,0 +1,215 @@
+use crate::auth::ensure_owner;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::events::{self, EventKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::perf;
+use crate::{current_time, next_id, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// By default a user is alerted at most once an hour
+const DEFAULT_ALERT_COOLDOWN_SECONDS: u64 = 60 * 60;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BalanceAlertConfig {
+    threshold: u64,
+    cooldown_seconds: u64,
+    last_triggered_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Alert {
+    id: u64,
+    user_id: u64,
+    threshold: u64,
+    balance: u64,
+    created_at: u64,
+    acknowledged: bool,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct BalanceAlertPayload {
+    user_id: u64,
+    threshold: u64,
+    cooldown_seconds: Option<u64>,
+}
+
+impl Storable for BalanceAlertConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Alert {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static ALERT_CONFIG_STORAGE: RefCell<StableBTreeMap<u64, BalanceAlertConfig, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static ALERT_STORAGE: RefCell<StableBTreeMap<u64, Alert, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("alerts.alert_config_storage", 23, &ALERT_CONFIG_STORAGE),
+        manifest::map("alerts.alert_storage", 24, &ALERT_STORAGE),
+    ]
+}
+
+/// Called after every debit of `user_id`. Raises an alert when the balance
+/// ends up below the user's threshold, unless one was raised within the
+/// cooldown.
+pub(crate) fn check_balance(user_id: u64, balance: u64) {
+    let Some(mut config) = ALERT_CONFIG_STORAGE.with(|configs| configs.borrow().get(&user_id))
+    else {
+        return;
+    };
+    if balance >= config.threshold {
+        return;
+    }
+    let now = current_time();
+    let cooldown = config.cooldown_seconds.saturating_mul(NANOS_PER_SECOND);
+    if let Some(last_triggered_at) = config.last_triggered_at {
+        if now < last_triggered_at.saturating_add(cooldown) {
+            return;
+        }
+    }
+
+    let id = next_id();
+    let alert = Alert {
+        id,
+        user_id,
+        threshold: config.threshold,
+        balance,
+        created_at: now,
+        acknowledged: false,
+    };
+    ALERT_STORAGE.with(|alerts| alerts.borrow_mut().insert(id, alert));
+    events::record(EventKind::LowBalanceAlert {
+        alert_id: id,
+        user_id,
+        balance,
+    });
+    notify(
+        user_id,
+        NotificationKind::LowBalance,
+        format!(
+            "Balance dropped to {}, below the alert threshold of {}",
+            balance, config.threshold
+        ),
+    );
+
+    config.last_triggered_at = Some(now);
+    ALERT_CONFIG_STORAGE.with(|configs| configs.borrow_mut().insert(user_id, config));
+}
+
+pub(crate) fn unacknowledged_alerts(user_id: u64) -> Vec<Alert> {
+    ALERT_STORAGE.with(|alerts| {
+        alerts
+            .borrow()
+            .iter()
+            .map(|(_, alert)| alert)
+            .filter(|alert| alert.user_id == user_id && !alert.acknowledged)
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn set_balance_alert(payload: BalanceAlertPayload) -> Result<BalanceAlertConfig, WalletError> {
+    perf::instrument("set_balance_alert", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id)) {
+            return Err(WalletError::not_found("user", payload.user_id));
+        }
+        ensure_owner(payload.user_id)?;
+        if payload.threshold == 0 {
+            return Err(WalletError::invalid("threshold", "must be greater than 0"));
+        }
+
+        let previous = ALERT_CONFIG_STORAGE.with(|configs| configs.borrow().get(&payload.user_id));
+        let config = BalanceAlertConfig {
+            threshold: payload.threshold,
+            cooldown_seconds: payload
+                .cooldown_seconds
+                .unwrap_or(DEFAULT_ALERT_COOLDOWN_SECONDS),
+            last_triggered_at: previous.and_then(|config| config.last_triggered_at),
+        };
+        ALERT_CONFIG_STORAGE
+            .with(|configs| configs.borrow_mut().insert(payload.user_id, config.clone()));
+        Ok(config)
+    })
+}
+
+#[ic_cdk::update]
+fn remove_balance_alert(user_id: u64) -> Result<(), WalletError> {
+    perf::instrument("remove_balance_alert", || {
+        ensure_writable()?;
+
+        ensure_owner(user_id)?;
+        ALERT_CONFIG_STORAGE
+            .with(|configs| configs.borrow_mut().remove(&user_id))
+            .map(|_| ())
+            .ok_or(WalletError::not_found("balance alert", user_id))
+    })
+}
+
+#[ic_cdk::query]
+fn get_alerts(user_id: u64) -> Result<Vec<Alert>, WalletError> {
+    perf::instrument("get_alerts", || {
+        ensure_not_restoring()?;
+
+        ensure_owner(user_id)?;
+        Ok(ALERT_STORAGE.with(|alerts| {
+            alerts
+                .borrow()
+                .iter()
+                .map(|(_, alert)| alert)
+                .filter(|alert| alert.user_id == user_id)
+                .collect()
+        }))
+    })
+}
+
+#[ic_cdk::update]
+fn acknowledge_alert(alert_id: u64) -> Result<Alert, WalletError> {
+    perf::instrument("acknowledge_alert", || {
+        ensure_writable()?;
+
+        let mut alert = ALERT_STORAGE
+            .with(|alerts| alerts.borrow().get(&alert_id))
+            .ok_or(WalletError::not_found("alert", alert_id))?;
+        ensure_owner(alert.user_id)?;
+        alert.acknowledged = true;
+        ALERT_STORAGE.with(|alerts| alerts.borrow_mut().insert(alert_id, alert.clone()));
+        Ok(alert)
+    })
+}
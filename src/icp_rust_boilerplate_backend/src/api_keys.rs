@@ -0,0 +1,389 @@
+//! API keys for server-side integrations. A key is created by a principal
+//! and acts for it: a backend presenting the key to `call_with_key` runs one
+//! of the methods its scopes allow, with ownership and admin checks applied
+//! to the key's creator. Only the SHA-256 of a key's secret is stored, so the
+//! secret is shown once, when the key is created or rotated.
+
+use crate::auth::{self, user_of};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::{
+    cache, current_time, ensure_admin, events, next_id, overview, perf, receipts, reconciliation,
+    sha256_hex, v2_get_transaction_history, v2_get_user_balance, v2_get_user_points,
+    v2_send_transaction, v2_validate_transfer, Memory, TransactionPayload, WalletError,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const SECRET_BYTES: usize = 32;
+const SECRET_PREFIX: &str = "wk_";
+const MAX_KEYS_PER_OWNER: usize = 20;
+const MAX_EXPIRY_DAYS: u64 = 365;
+
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum ApiKeyScope {
+    // Balances, history, overviews and events
+    ReadOnly,
+    // Sending transfers
+    Pay,
+    // Controller reads and reconciliation; keys of controllers only
+    Admin,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ApiKey {
+    id: u64,
+    owner: Principal,
+    // Account of the owner when the key was created
+    user_id: Option<u64>,
+    label: String,
+    scopes: Vec<ApiKeyScope>,
+    created_at: u64,
+    expires_at: Option<u64>,
+    rotated_at: Option<u64>,
+    revoked_at: Option<u64>,
+    last_used_at: Option<u64>,
+    use_count: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CreatedApiKey {
+    key: ApiKey,
+    secret: String,
+}
+
+impl Storable for ApiKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static API_KEYS: RefCell<StableBTreeMap<u64, ApiKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76)))
+    ));
+
+    // Hex SHA-256 of the current secret to key id
+    static API_KEY_HASHES: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("api_keys.api_keys", 76, &API_KEYS),
+        manifest::map("api_keys.api_key_hashes", 77, &API_KEY_HASHES),
+    ]
+}
+
+impl ApiKey {
+    fn is_live(&self, now: u64) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+// Scope a method needs, for the methods keys can call
+fn required_scope(method: &str) -> Option<ApiKeyScope> {
+    match method {
+        "v2_get_user_balance"
+        | "v2_get_user_points"
+        | "v2_get_transaction_history"
+        | "v2_validate_transfer"
+        | "get_transaction_detail"
+        | "get_wallet_overview"
+        | "get_events_since" => Some(ApiKeyScope::ReadOnly),
+        "v2_send_transaction" => Some(ApiKeyScope::Pay),
+        "get_metrics"
+        | "get_performance_stats"
+        | "get_last_reconciliation"
+        | "run_reconciliation_now" => Some(ApiKeyScope::Admin),
+        _ => None,
+    }
+}
+
+fn invalid_args(error: candid::Error) -> WalletError {
+    WalletError::invalid("args", &error.to_string())
+}
+
+fn encode_reply<T: candid::CandidType>(reply: T) -> Result<Vec<u8>, WalletError> {
+    Encode!(&reply).map_err(|error| WalletError::Internal {
+        reason: format!("Cannot encode the reply: {}", error),
+    })
+}
+
+// Runs `method` with candid-encoded `args` and returns its encoded reply
+fn dispatch(method: &str, args: &[u8]) -> Result<Vec<u8>, WalletError> {
+    match method {
+        "v2_get_user_balance" => {
+            let user_id = Decode!(args, u64).map_err(invalid_args)?;
+            encode_reply(v2_get_user_balance(user_id))
+        }
+        "v2_get_user_points" => {
+            let user_id = Decode!(args, u64).map_err(invalid_args)?;
+            encode_reply(v2_get_user_points(user_id))
+        }
+        "v2_get_transaction_history" => {
+            let user_id = Decode!(args, u64).map_err(invalid_args)?;
+            encode_reply(v2_get_transaction_history(user_id))
+        }
+        "v2_validate_transfer" => {
+            let payload = Decode!(args, TransactionPayload).map_err(invalid_args)?;
+            encode_reply(v2_validate_transfer(payload))
+        }
+        "get_transaction_detail" => {
+            let tx_id = Decode!(args, u64).map_err(invalid_args)?;
+            encode_reply(receipts::get_transaction_detail(tx_id))
+        }
+        "get_wallet_overview" => {
+            let user_id = Decode!(args, u64).map_err(invalid_args)?;
+            encode_reply(overview::get_wallet_overview(user_id))
+        }
+        "get_events_since" => {
+            let (since, limit) = Decode!(args, u64, u64).map_err(invalid_args)?;
+            encode_reply(events::get_events_since(since, limit))
+        }
+        "v2_send_transaction" => {
+            let payload = Decode!(args, TransactionPayload).map_err(invalid_args)?;
+            encode_reply(v2_send_transaction(payload))
+        }
+        "get_metrics" => encode_reply(cache::get_metrics()),
+        "get_performance_stats" => encode_reply(perf::get_performance_stats()),
+        "get_last_reconciliation" => encode_reply(reconciliation::get_last_reconciliation()),
+        "run_reconciliation_now" => encode_reply(reconciliation::run_reconciliation_now()),
+        _ => Err(WalletError::invalid(
+            "method",
+            "cannot be called with an API key",
+        )),
+    }
+}
+
+fn get_key(key_id: u64) -> Option<ApiKey> {
+    API_KEYS.with(|keys| keys.borrow().get(&key_id))
+}
+
+fn save_key(key: &ApiKey) {
+    API_KEYS.with(|keys| keys.borrow_mut().insert(key.id, key.clone()));
+}
+
+// The key, if the caller created it
+fn caller_key(key_id: u64) -> Result<ApiKey, WalletError> {
+    get_key(key_id)
+        .filter(|key| key.owner == auth::caller())
+        .ok_or(WalletError::not_found("API key", key_id))
+}
+
+async fn new_secret() -> Result<String, WalletError> {
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(_, message)| WalletError::Internal {
+            reason: format!("Cannot generate an API key: {}", message),
+        })?;
+    let hex: String = random[..SECRET_BYTES]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(format!("{}{}", SECRET_PREFIX, hex))
+}
+
+fn validate_scopes(scopes: &mut Vec<ApiKeyScope>, user_id: Option<u64>) -> Result<(), WalletError> {
+    scopes.sort();
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(WalletError::invalid("scopes", "must not be empty"));
+    }
+    if scopes.contains(&ApiKeyScope::Admin) {
+        ensure_admin()?;
+    }
+    if user_id.is_none() && scopes.iter().any(|scope| *scope != ApiKeyScope::Admin) {
+        return Err(WalletError::Unauthorized {
+            reason: "caller does not own an account".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Creates a key for the caller and returns its secret, which cannot be
+/// read again. Keys without `expires_in_days` never expire.
+#[ic_cdk::update]
+async fn create_api_key(
+    label: String,
+    scopes: Vec<ApiKeyScope>,
+    expires_in_days: Option<u64>,
+) -> Result<CreatedApiKey, WalletError> {
+    perf::instrument_async("create_api_key", async move {
+        ensure_writable()?;
+
+        let owner = auth::caller();
+        let user_id = user_of(owner);
+        let mut scopes = scopes;
+        validate_scopes(&mut scopes, user_id)?;
+        hardening::check_args(|fields| fields.required_text("label", &label, TextKind::Alias))?;
+        let label = label.trim().to_string();
+        if expires_in_days.is_some_and(|days| days == 0 || days > MAX_EXPIRY_DAYS) {
+            return Err(WalletError::invalid(
+                "expires_in_days",
+                &format!("must be between 1 and {}", MAX_EXPIRY_DAYS),
+            ));
+        }
+        let now = current_time();
+        let live_keys = API_KEYS.with(|keys| {
+            keys.borrow()
+                .iter()
+                .filter(|(_, key)| key.owner == owner && key.is_live(now))
+                .count()
+        });
+        if live_keys >= MAX_KEYS_PER_OWNER {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "At most {} keys can be active; revoke one first",
+                    MAX_KEYS_PER_OWNER
+                ),
+            });
+        }
+
+        let secret = new_secret().await?;
+        ensure_not_restoring()?;
+        let now = current_time();
+        let key = ApiKey {
+            id: next_id(),
+            owner,
+            user_id,
+            label,
+            scopes,
+            created_at: now,
+            expires_at: expires_in_days.map(|days| now + days * NANOS_PER_DAY),
+            rotated_at: None,
+            revoked_at: None,
+            last_used_at: None,
+            use_count: 0,
+        };
+        save_key(&key);
+        API_KEY_HASHES.with(|hashes| {
+            hashes
+                .borrow_mut()
+                .insert(sha256_hex(secret.as_bytes()), key.id)
+        });
+        Ok(CreatedApiKey { key, secret })
+    })
+    .await
+}
+
+/// Replaces the secret of a key; the previous secret stops working at once.
+#[ic_cdk::update]
+async fn rotate_api_key(key_id: u64) -> Result<CreatedApiKey, WalletError> {
+    perf::instrument_async("rotate_api_key", async move {
+        ensure_writable()?;
+
+        if !caller_key(key_id)?.is_live(current_time()) {
+            return Err(WalletError::InvalidState {
+                reason: format!("API key {} is revoked or expired", key_id),
+            });
+        }
+        let secret = new_secret().await?;
+        ensure_not_restoring()?;
+
+        // Read again: the key may have been revoked while waiting
+        let mut key = caller_key(key_id)?;
+        if !key.is_live(current_time()) {
+            return Err(WalletError::InvalidState {
+                reason: format!("API key {} is revoked or expired", key_id),
+            });
+        }
+        API_KEY_HASHES.with(|hashes| {
+            let mut hashes = hashes.borrow_mut();
+            let previous: Vec<String> = hashes
+                .iter()
+                .filter(|(_, id)| *id == key_id)
+                .map(|(hash, _)| hash)
+                .collect();
+            for hash in previous {
+                hashes.remove(&hash);
+            }
+            hashes.insert(sha256_hex(secret.as_bytes()), key_id);
+        });
+        key.rotated_at = Some(current_time());
+        save_key(&key);
+        Ok(CreatedApiKey { key, secret })
+    })
+    .await
+}
+
+#[ic_cdk::update]
+fn revoke_api_key(key_id: u64) -> Result<ApiKey, WalletError> {
+    perf::instrument("revoke_api_key", || {
+        ensure_writable()?;
+
+        let mut key = caller_key(key_id)?;
+        if key.revoked_at.is_none() {
+            key.revoked_at = Some(current_time());
+            save_key(&key);
+        }
+        Ok(key)
+    })
+}
+
+/// Keys the caller created, revoked and expired ones included.
+#[ic_cdk::query]
+fn list_api_keys() -> Result<Vec<ApiKey>, WalletError> {
+    perf::instrument("list_api_keys", || {
+        ensure_not_restoring()?;
+
+        let owner = auth::caller();
+        Ok(API_KEYS.with(|keys| {
+            keys.borrow()
+                .iter()
+                .filter(|(_, key)| key.owner == owner)
+                .map(|(_, key)| key)
+                .collect()
+        }))
+    })
+}
+
+/// Runs `method` with the candid-encoded `args` on behalf of the key's
+/// owner and returns the method's candid-encoded reply.
+#[ic_cdk::update]
+fn call_with_key(key: String, method: String, args: Vec<u8>) -> Result<Vec<u8>, WalletError> {
+    perf::instrument("call_with_key", || {
+        ensure_writable()?;
+
+        let now = current_time();
+        let mut api_key = API_KEY_HASHES
+            .with(|hashes| hashes.borrow().get(&sha256_hex(key.as_bytes())))
+            .and_then(get_key)
+            .filter(|api_key| api_key.is_live(now))
+            .ok_or(WalletError::Unauthorized {
+                reason: "invalid API key".to_string(),
+            })?;
+        let scope = required_scope(&method).ok_or(WalletError::invalid(
+            "method",
+            "cannot be called with an API key",
+        ))?;
+        if !api_key.scopes.contains(&scope) {
+            return Err(WalletError::Unauthorized {
+                reason: format!("API key {} lacks the {:?} scope", api_key.id, scope),
+            });
+        }
+
+        api_key.last_used_at = Some(now);
+        api_key.use_count += 1;
+        save_key(&api_key);
+        auth::act_as(api_key.owner, || dispatch(&method, &args))
+    })
+}
@@ -0,0 +1,247 @@
+//! Archiving of old transactions, modeled on the ICP ledger's archive
+//! canisters. Once more than `max_local_transactions` are stored here, a
+//! timer moves the oldest ones in batches to the configured archive canister,
+//! and `get_transaction` follows lookups of archived ids there.
+
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::notifications::notify_admins;
+use crate::perf;
+use crate::{ensure_admin, Memory, Transaction, WalletError, MEMORY_MANAGER, TRANSACTION_STORAGE};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::{call, CallResult};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const DEFAULT_MAX_LOCAL_TRANSACTIONS: u64 = 100_000;
+const DEFAULT_ARCHIVE_BATCH_SIZE: u64 = 500;
+// Keeps a batch well below the 2MB inter-canister message limit
+const MAX_ARCHIVE_BATCH_SIZE: u64 = 2_000;
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchiveState {
+    archive_canister: Option<Principal>,
+    max_local_transactions: u64,
+    batch_size: u64,
+    // Highest transaction id moved to the archive; every older id is there too
+    archived_up_to: Option<u64>,
+    archived_count: u64,
+}
+
+impl Default for ArchiveState {
+    fn default() -> Self {
+        ArchiveState {
+            archive_canister: None,
+            max_local_transactions: DEFAULT_MAX_LOCAL_TRANSACTIONS,
+            batch_size: DEFAULT_ARCHIVE_BATCH_SIZE,
+            archived_up_to: None,
+            archived_count: 0,
+        }
+    }
+}
+
+impl Storable for ArchiveState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ArchiveConfigPayload {
+    archive_canister: Option<Principal>,
+    max_local_transactions: Option<u64>,
+    batch_size: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ArchiveStatus {
+    archive_canister: Option<Principal>,
+    max_local_transactions: u64,
+    batch_size: u64,
+    local_transactions: u64,
+    archived_count: u64,
+    archived_up_to: Option<u64>,
+    batch_in_flight: bool,
+}
+
+thread_local! {
+    static ARCHIVE_STATE: RefCell<Cell<ArchiveState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+            ArchiveState::default(),
+        )
+        .expect("Cannot create the archive state cell")
+    );
+
+    // Set while a batch is being appended, so batches never overlap
+    static BATCH_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell("archive.archive_state", 33, &ARCHIVE_STATE)]
+}
+
+fn archive_state() -> ArchiveState {
+    ARCHIVE_STATE.with(|state| state.borrow().get().clone())
+}
+
+fn set_archive_state(state: ArchiveState) {
+    ARCHIVE_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("Cannot update the archive state");
+}
+
+pub(crate) fn start_archive_job() {
+    ic_cdk_timers::set_timer_interval(ARCHIVE_INTERVAL, archive_batch);
+}
+
+fn archive_batch() {
+    if ensure_writable().is_err() || BATCH_IN_FLIGHT.with(|flag| *flag.borrow()) {
+        return;
+    }
+    let state = archive_state();
+    let Some(archive_canister) = state.archive_canister else {
+        return;
+    };
+    let local = TRANSACTION_STORAGE.with(|storage| storage.borrow().len());
+    if local <= state.max_local_transactions {
+        return;
+    }
+
+    let count = state.batch_size.min(local - state.max_local_transactions);
+    let batch: Vec<Transaction> = TRANSACTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .take(count as usize)
+            .map(|(_, transaction)| transaction)
+            .collect()
+    });
+    BATCH_IN_FLIGHT.with(|flag| *flag.borrow_mut() = true);
+    ic_cdk::spawn(async move {
+        let result: CallResult<(Result<u64, String>,)> =
+            call(archive_canister, "append_transactions", (batch.clone(),)).await;
+        BATCH_IN_FLIGHT.with(|flag| *flag.borrow_mut() = false);
+        let failure = match result {
+            Ok((Ok(_),)) => None,
+            Ok((Err(reason),)) => Some(reason),
+            Err((_, message)) => Some(message),
+        };
+        if let Some(reason) = failure {
+            notify_admins(format!("Archiving transactions failed: {}", reason));
+            return;
+        }
+        // A restore started meanwhile replaces the transactions, which must
+        // then be left alone; the archive merely holds extra copies
+        if ensure_writable().is_err() {
+            return;
+        }
+        TRANSACTION_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            for transaction in &batch {
+                storage.remove(&transaction.id.0);
+            }
+        });
+        let mut state = archive_state();
+        state.archived_up_to = batch.last().map(|transaction| transaction.id.0);
+        state.archived_count += batch.len() as u64;
+        set_archive_state(state);
+    });
+}
+
+#[ic_cdk::update]
+fn set_archive_config(payload: ArchiveConfigPayload) -> Result<(), WalletError> {
+    perf::instrument("set_archive_config", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let mut state = archive_state();
+        if state.archived_up_to.is_some() && payload.archive_canister != state.archive_canister {
+            return Err(WalletError::InvalidState {
+                reason: "The archive canister cannot change once transactions were archived"
+                    .to_string(),
+            });
+        }
+        if let Some(max_local_transactions) = payload.max_local_transactions {
+            if max_local_transactions == 0 {
+                return Err(WalletError::invalid(
+                    "max_local_transactions",
+                    "must be greater than 0",
+                ));
+            }
+            state.max_local_transactions = max_local_transactions;
+        }
+        if let Some(batch_size) = payload.batch_size {
+            if batch_size == 0 || batch_size > MAX_ARCHIVE_BATCH_SIZE {
+                return Err(WalletError::invalid(
+                    "batch_size",
+                    &format!("must be between 1 and {}", MAX_ARCHIVE_BATCH_SIZE),
+                ));
+            }
+            state.batch_size = batch_size;
+        }
+        state.archive_canister = payload.archive_canister;
+        set_archive_state(state);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_archive_status() -> Result<ArchiveStatus, WalletError> {
+    perf::instrument("get_archive_status", || {
+        ensure_admin()?;
+
+        let state = archive_state();
+        Ok(ArchiveStatus {
+            archive_canister: state.archive_canister,
+            max_local_transactions: state.max_local_transactions,
+            batch_size: state.batch_size,
+            local_transactions: TRANSACTION_STORAGE.with(|storage| storage.borrow().len()),
+            archived_count: state.archived_count,
+            archived_up_to: state.archived_up_to,
+            batch_in_flight: BATCH_IN_FLIGHT.with(|flag| *flag.borrow()),
+        })
+    })
+}
+
+/// Looks a transaction up locally, or in the archive canister if it was
+/// already archived.
+#[ic_cdk::query(composite = true)]
+async fn get_transaction(tx_id: u64) -> Result<Transaction, WalletError> {
+    perf::instrument_async("get_transaction", async move {
+        ensure_not_restoring()?;
+
+        if let Some(transaction) = TRANSACTION_STORAGE.with(|storage| storage.borrow().get(&tx_id))
+        {
+            return Ok(transaction);
+        }
+        let state = archive_state();
+        let archive_canister = match (state.archive_canister, state.archived_up_to) {
+            (Some(archive_canister), Some(archived_up_to)) if tx_id <= archived_up_to => {
+                archive_canister
+            }
+            _ => return Err(WalletError::not_found("transaction", tx_id)),
+        };
+
+        let result: CallResult<(Option<Transaction>,)> =
+            call(archive_canister, "get_transaction", (tx_id,)).await;
+        match result {
+            Ok((Some(transaction),)) => Ok(transaction),
+            Ok((None,)) => Err(WalletError::not_found("transaction", tx_id)),
+            Err((_, message)) => Err(WalletError::Internal {
+                reason: format!("Cannot reach the archive canister: {}", message),
+            }),
+        }
+    })
+    .await
+}
@@ -0,0 +1,156 @@
+use crate::manifest::{self, Storage};
+use crate::{clear_map, Memory, WalletError, MEMORY_MANAGER};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct StorablePrincipal(pub(crate) Principal);
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StorablePrincipal(Principal::from_slice(bytes.as_ref()))
+    }
+
+    // Principals are at most 29 bytes long
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29,
+        is_fixed_size: false,
+    };
+}
+
+thread_local! {
+    static USER_OWNERS: RefCell<StableBTreeMap<u64, StorablePrincipal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    static OWNER_INDEX: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    // Set while `call_with_key` runs a method on behalf of a key's owner
+    static ACTING_PRINCIPAL: std::cell::Cell<Option<Principal>> =
+        const { std::cell::Cell::new(None) };
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("auth.user_owners", 4, &USER_OWNERS),
+        manifest::map("auth.owner_index", 5, &OWNER_INDEX),
+    ]
+}
+
+/// The principal ownership and admin checks apply to: the caller, or the
+/// owner of the API key the call presented. Test builds can inject it with
+/// `set_mock_caller`.
+pub(crate) fn caller() -> Principal {
+    let acting = ACTING_PRINCIPAL.with(|acting| acting.get());
+    #[cfg(feature = "test-hooks")]
+    let acting = acting.or_else(crate::test_hooks::mock_caller);
+    acting.unwrap_or_else(ic_cdk::caller)
+}
+
+// Puts back the acting principal it replaced when dropped, including when
+// the method it wraps panics
+struct RestoreActing(Option<Principal>);
+
+impl Drop for RestoreActing {
+    fn drop(&mut self) {
+        ACTING_PRINCIPAL.with(|acting| acting.set(self.0));
+    }
+}
+
+/// Runs `f` with the checks applying to `principal` instead of the caller.
+pub(crate) fn act_as<T>(principal: Principal, f: impl FnOnce() -> T) -> T {
+    let _restore = RestoreActing(ACTING_PRINCIPAL.with(|acting| acting.replace(Some(principal))));
+    f()
+}
+
+/// Checks that `principal` may open a new account: it must be authenticated
+/// and must not own one already.
+pub(crate) fn ensure_can_own_account(principal: Principal) -> Result<(), WalletError> {
+    if principal == Principal::anonymous() {
+        return Err(WalletError::Unauthorized {
+            reason: "anonymous principals cannot own an account".to_string(),
+        });
+    }
+    if OWNER_INDEX.with(|index| index.borrow().contains_key(&StorablePrincipal(principal))) {
+        return Err(WalletError::AlreadyExists {
+            entity: "user".to_string(),
+            field: "owner".to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn bind_owner(user_id: u64, principal: Principal) {
+    USER_OWNERS.with(|owners| {
+        owners
+            .borrow_mut()
+            .insert(user_id, StorablePrincipal(principal))
+    });
+    OWNER_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert(StorablePrincipal(principal), user_id)
+    });
+}
+
+/// Moves `user_id` to a new owner principal, releasing the previous one.
+pub(crate) fn rotate_owner(user_id: u64, principal: Principal) {
+    if let Some(previous) = owner_of(user_id) {
+        OWNER_INDEX.with(|index| index.borrow_mut().remove(&StorablePrincipal(previous)));
+    }
+    bind_owner(user_id, principal);
+}
+
+pub(crate) fn owner_of(user_id: u64) -> Option<Principal> {
+    USER_OWNERS.with(|owners| owners.borrow().get(&user_id).map(|owner| owner.0))
+}
+
+pub(crate) fn user_of(principal: Principal) -> Option<u64> {
+    OWNER_INDEX.with(|index| index.borrow().get(&StorablePrincipal(principal)))
+}
+
+/// Id of the account owned by the caller.
+pub(crate) fn caller_user_id() -> Result<u64, WalletError> {
+    user_of(caller()).ok_or(WalletError::Unauthorized {
+        reason: "caller does not own an account".to_string(),
+    })
+}
+
+/// Rejects the call unless the caller owns `user_id`.
+pub(crate) fn ensure_owner(user_id: u64) -> Result<(), WalletError> {
+    if owner_of(user_id) != Some(caller()) {
+        return Err(WalletError::Unauthorized {
+            reason: format!("caller does not own user {}", user_id),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn export_owners() -> Vec<(u64, Principal)> {
+    USER_OWNERS.with(|owners| {
+        owners
+            .borrow()
+            .iter()
+            .map(|(user_id, owner)| (user_id, owner.0))
+            .collect()
+    })
+}
+
+pub(crate) fn import_owners(entries: Vec<(u64, Principal)>) {
+    USER_OWNERS.with(|owners| clear_map(&mut owners.borrow_mut()));
+    OWNER_INDEX.with(|index| clear_map(&mut index.borrow_mut()));
+    for (user_id, principal) in entries {
+        bind_owner(user_id, principal);
+    }
+}
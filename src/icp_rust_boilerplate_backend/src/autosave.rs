@@ -0,0 +1,360 @@
+//! Autosave plans. A user sets aside a fixed amount from their main balance
+//! into their savings account at a regular interval, and a timer runs every
+//! due plan. A run the available balance cannot cover is skipped, the user
+//! is notified, and the plan carries on with the next interval. Every run,
+//! saved or skipped, is kept in the plan's history.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::ledger::{self, EntryKind, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::{
+    alerts, current_time, devices, holds, next_id, pause, perf, token, Memory, WalletError,
+    MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Plans save at most once a day
+const MIN_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
+const MAX_ACTIVE_PLANS_PER_USER: usize = 10;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AutosavePlan {
+    id: u64,
+    user_id: u64,
+    amount: u64,
+    interval_seconds: u64,
+    active: bool,
+    next_run_at: u64,
+    total_saved: u64,
+    skipped_runs: u64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum AutosaveOutcome {
+    Saved { journal_entry_id: u64 },
+    // The available balance did not cover the amount
+    Skipped { available: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AutosaveRun {
+    id: u64,
+    plan_id: u64,
+    amount: u64,
+    run_at: u64,
+    outcome: AutosaveOutcome,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SavingsSummary {
+    user_id: u64,
+    savings_balance: u64,
+    active_plans: Vec<AutosavePlan>,
+}
+
+impl Storable for AutosavePlan {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AutosaveRun {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static AUTOSAVE_PLANS: RefCell<StableBTreeMap<u64, AutosavePlan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85)))
+    ));
+
+    // Keyed by (plan, run), so a plan's history is one range
+    static AUTOSAVE_RUNS: RefCell<StableBTreeMap<(u64, u64), AutosaveRun, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("autosave.autosave_plans", 85, &AUTOSAVE_PLANS),
+        manifest::map("autosave.autosave_runs", 86, &AUTOSAVE_RUNS),
+    ]
+}
+
+fn save_plan(plan: &AutosavePlan) {
+    AUTOSAVE_PLANS.with(|storage| storage.borrow_mut().insert(plan.id, plan.clone()));
+}
+
+fn get_plan_record(plan_id: u64) -> Result<AutosavePlan, WalletError> {
+    AUTOSAVE_PLANS
+        .with(|storage| storage.borrow().get(&plan_id))
+        .ok_or(WalletError::not_found("autosave plan", plan_id))
+}
+
+fn plans_of(user_id: u64) -> Vec<AutosavePlan> {
+    AUTOSAVE_PLANS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, plan)| plan)
+            .filter(|plan| plan.user_id == user_id)
+            .collect()
+    })
+}
+
+fn run_plan(mut plan: AutosavePlan, now: u64) {
+    let run_id = next_id();
+    let available = holds::available_balance(plan.user_id, ledger::user_balance(plan.user_id));
+    let outcome = if available < plan.amount {
+        Err(available)
+    } else {
+        ledger::transfer(
+            EntryKind::Autosave {
+                plan_id: plan.id,
+                run_id,
+            },
+            ledger::user(plan.user_id),
+            LedgerAccount::Savings {
+                user_id: plan.user_id,
+            },
+            plan.amount,
+        )
+        .map_err(|_| available)
+    };
+    let outcome = match outcome {
+        Ok(entry) => {
+            plan.total_saved = plan.total_saved.saturating_add(plan.amount);
+            alerts::check_balance(plan.user_id, ledger::user_balance(plan.user_id));
+            AutosaveOutcome::Saved {
+                journal_entry_id: entry.id(),
+            }
+        }
+        Err(available) => {
+            plan.skipped_runs += 1;
+            notify(
+                plan.user_id,
+                NotificationKind::Autosave,
+                format!(
+                    "Autosave {} skipped this run: {} needed, {} available",
+                    plan.id,
+                    token::format_amount(plan.amount),
+                    token::format_amount(available)
+                ),
+            );
+            AutosaveOutcome::Skipped { available }
+        }
+    };
+    AUTOSAVE_RUNS.with(|storage| {
+        storage.borrow_mut().insert(
+            (plan.id, run_id),
+            AutosaveRun {
+                id: run_id,
+                plan_id: plan.id,
+                amount: plan.amount,
+                run_at: now,
+                outcome,
+            },
+        )
+    });
+    // A canister that was stopped for a while runs each plan once, not once
+    // for every interval it missed
+    let interval = plan.interval_seconds.saturating_mul(NANOS_PER_SECOND);
+    while plan.next_run_at <= now {
+        plan.next_run_at = plan.next_run_at.saturating_add(interval);
+    }
+    save_plan(&plan);
+}
+
+pub(crate) fn start_autosave_job() {
+    ic_cdk_timers::set_timer_interval(AUTOSAVE_INTERVAL, run_autosaves);
+}
+
+fn run_autosaves() {
+    if ensure_writable().is_err() || pause::ensure_transfers_allowed().is_err() {
+        return;
+    }
+    let now = current_time();
+    let due: Vec<AutosavePlan> = AUTOSAVE_PLANS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, plan)| plan)
+            .filter(|plan| plan.active && plan.next_run_at <= now)
+            .collect()
+    });
+    for plan in due {
+        run_plan(plan, now);
+    }
+}
+
+/// Saves `amount` from the caller's balance every `interval_seconds`,
+/// starting one interval from now.
+#[ic_cdk::update]
+fn create_autosave(amount: u64, interval_seconds: u64) -> Result<AutosavePlan, WalletError> {
+    perf::instrument("create_autosave", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+
+        let user_id = caller_user_id()?;
+        if amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", amount)?;
+        if interval_seconds < MIN_INTERVAL_SECONDS {
+            return Err(WalletError::invalid(
+                "interval_seconds",
+                &format!("must be at least {}", MIN_INTERVAL_SECONDS),
+            ));
+        }
+        let active = plans_of(user_id).iter().filter(|plan| plan.active).count();
+        if active >= MAX_ACTIVE_PLANS_PER_USER {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "At most {} autosave plans can be active at a time",
+                    MAX_ACTIVE_PLANS_PER_USER
+                ),
+            });
+        }
+
+        let now = current_time();
+        let plan = AutosavePlan {
+            id: next_id(),
+            user_id,
+            amount,
+            interval_seconds,
+            active: true,
+            next_run_at: now.saturating_add(interval_seconds.saturating_mul(NANOS_PER_SECOND)),
+            total_saved: 0,
+            skipped_runs: 0,
+            created_at: now,
+        };
+        save_plan(&plan);
+        Ok(plan)
+    })
+}
+
+/// Stops a plan; what it already saved stays in savings.
+#[ic_cdk::update]
+fn cancel_autosave(plan_id: u64) -> Result<AutosavePlan, WalletError> {
+    perf::instrument("cancel_autosave", || {
+        ensure_writable()?;
+
+        let mut plan = get_plan_record(plan_id)?;
+        ensure_owner(plan.user_id)?;
+        if !plan.active {
+            return Err(WalletError::InvalidState {
+                reason: format!("Autosave {} is already cancelled", plan_id),
+            });
+        }
+        plan.active = false;
+        save_plan(&plan);
+        Ok(plan)
+    })
+}
+
+/// Moves `amount` from the caller's savings back to their main balance and
+/// returns what is left in savings.
+#[ic_cdk::update]
+fn withdraw_savings(amount: u64) -> Result<u64, WalletError> {
+    perf::instrument("withdraw_savings", || {
+        ensure_writable()?;
+        pause::ensure_transfers_allowed()?;
+
+        let user_id = caller_user_id()?;
+        devices::record_activity(user_id);
+        if amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        ledger::transfer(
+            EntryKind::SavingsWithdrawal { user_id },
+            LedgerAccount::Savings { user_id },
+            ledger::user(user_id),
+            amount,
+        )?;
+        Ok(ledger::savings_balance(user_id))
+    })
+}
+
+/// The user's savings balance and the plans paying into it.
+#[ic_cdk::query]
+fn get_savings(user_id: u64) -> Result<SavingsSummary, WalletError> {
+    perf::instrument("get_savings", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(SavingsSummary {
+            user_id,
+            savings_balance: ledger::savings_balance(user_id),
+            active_plans: plans_of(user_id)
+                .into_iter()
+                .filter(|plan| plan.active)
+                .collect(),
+        })
+    })
+}
+
+/// Every autosave plan of the user, including cancelled ones.
+#[ic_cdk::query]
+fn list_autosaves(user_id: u64) -> Result<Vec<AutosavePlan>, WalletError> {
+    perf::instrument("list_autosaves", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(plans_of(user_id))
+    })
+}
+
+/// The runs of a plan, newest first.
+#[ic_cdk::query]
+fn get_autosave_history(plan_id: u64) -> Result<Vec<AutosaveRun>, WalletError> {
+    perf::instrument("get_autosave_history", || {
+        ensure_not_restoring()?;
+
+        let plan = get_plan_record(plan_id)?;
+        ensure_owner(plan.user_id)?;
+
+        let mut runs: Vec<AutosaveRun> = AUTOSAVE_RUNS.with(|storage| {
+            storage
+                .borrow()
+                .range((plan_id, 0)..=(plan_id, u64::MAX))
+                .map(|(_, run)| run)
+                .collect()
+        });
+        runs.reverse();
+        Ok(runs)
+    })
+}
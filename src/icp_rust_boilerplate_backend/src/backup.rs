@@ -0,0 +1,362 @@
+use crate::hardening::{self, FieldChecker, Harden};
+use crate::manifest::{self, Storage};
+use crate::{
+    auth, counterparties, directory, ids, leaderboard, pause, perf, points, reconciliation, risk,
+    supply, username,
+};
+use crate::{
+    current_time, ensure_admin, sha256_hex, Memory, PointsTransfer, Transaction, User, WalletError,
+    ID_COUNTER, MEMORY_MANAGER, TRANSACTION_STORAGE, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Bump whenever the layout of `CanisterSnapshot` changes
+const SNAPSHOT_FORMAT_VERSION: u32 = 3;
+// Keep chunks comfortably below the 2MB message limit
+pub(crate) const MAX_CHUNK_SIZE: u64 = 1024 * 1024;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RestoreState {
+    in_progress: bool,
+    started_at: u64,
+    expected_size: u64,
+    expected_checksum: String,
+}
+
+impl Storable for RestoreState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Everything needed to rebuild the canister from scratch
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CanisterSnapshot {
+    format_version: u32,
+    id_counter: u64,
+    // Next id of every namespace in `ids`
+    id_counters: Vec<(String, u64)>,
+    users: Vec<User>,
+    transactions: Vec<Transaction>,
+    owners: Vec<(u64, Principal)>,
+    points_transfers: Vec<PointsTransfer>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BackupManifest {
+    format_version: u32,
+    created_at: u64,
+    total_size: u64,
+    max_chunk_size: u64,
+    checksum: String,
+    user_count: u64,
+    transaction_count: u64,
+    id_counter: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct RestoreChunkPayload {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl Harden for RestoreChunkPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.bytes("data", &self.data, MAX_CHUNK_SIZE as usize);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct RestoreProgress {
+    received_bytes: u64,
+    expected_bytes: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct RestoreSummary {
+    user_count: u64,
+    transaction_count: u64,
+    id_counter: u64,
+}
+
+thread_local! {
+    static RESTORE_STATE: RefCell<Cell<RestoreState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+            RestoreState::default(),
+        )
+        .expect("Cannot create the restore state cell")
+    );
+
+    // The last prepared backup, served chunk by chunk until the next `prepare_backup`
+    static BACKUP_BUFFER: RefCell<Option<(BackupManifest, Vec<u8>)>> = const { RefCell::new(None) };
+
+    // Bytes received so far by `restore_chunk`
+    static RESTORE_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell("backup.restore_state", 3, &RESTORE_STATE)]
+}
+
+/// Rejects normal traffic while a restore is being staged or the canister
+/// is fully paused.
+pub(crate) fn ensure_not_restoring() -> Result<(), WalletError> {
+    if RESTORE_STATE.with(|state| state.borrow().get().in_progress) {
+        return Err(WalletError::RestoreInProgress);
+    }
+    pause::ensure_not_fully_paused()
+}
+
+/// `ensure_not_restoring` for updates and timer jobs that change state,
+/// which read-only mode also rejects.
+pub(crate) fn ensure_writable() -> Result<(), WalletError> {
+    ensure_not_restoring()?;
+    pause::ensure_not_read_only()
+}
+
+fn ensure_restoring() -> Result<(), WalletError> {
+    if !RESTORE_STATE.with(|state| state.borrow().get().in_progress) {
+        return Err(WalletError::InvalidState {
+            reason: "No restore in progress".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn set_restore_state(state: RestoreState) {
+    RESTORE_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("Cannot update the restore state");
+}
+
+fn take_snapshot() -> CanisterSnapshot {
+    CanisterSnapshot {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        id_counter: ID_COUNTER.with(|counter| *counter.borrow().get()),
+        id_counters: ids::export_counters(),
+        users: USER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, user)| user).collect()),
+        transactions: TRANSACTION_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, transaction)| transaction)
+                .collect()
+        }),
+        owners: auth::export_owners(),
+        points_transfers: points::export_points_transfers(),
+    }
+}
+
+fn encode_snapshot(snapshot: &CanisterSnapshot) -> Result<Vec<u8>, WalletError> {
+    Encode!(snapshot).map_err(|e| WalletError::Internal {
+        reason: format!("cannot encode the snapshot: {}", e),
+    })
+}
+
+/// Replaces the state with an encoded snapshot, as `finish_restore` does
+/// once every chunk arrived.
+pub(crate) fn apply_snapshot(bytes: &[u8]) -> Result<RestoreSummary, WalletError> {
+    let snapshot = Decode!(bytes, CanisterSnapshot)
+        .map_err(|e| WalletError::invalid("data", &format!("cannot decode the snapshot: {}", e)))?;
+    let summary = RestoreSummary {
+        user_count: snapshot.users.len() as u64,
+        transaction_count: snapshot.transactions.len() as u64,
+        id_counter: snapshot.id_counter,
+    };
+
+    USER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        storage.clear();
+        for user in snapshot.users {
+            storage.insert(user.id.0, user);
+        }
+    });
+    TRANSACTION_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        storage.clear();
+        for transaction in snapshot.transactions {
+            storage.insert(transaction.id.0, transaction);
+        }
+    });
+    auth::import_owners(snapshot.owners);
+    username::rebuild_index();
+    leaderboard::rebuild_index();
+    directory::rebuild_index();
+    points::import_points_transfers(snapshot.points_transfers);
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(snapshot.id_counter))
+        .expect("Cannot restore ID counter");
+    ids::import_counters(snapshot.id_counters);
+
+    // The restored balances are what reconciliation checks against from now on
+    reconciliation::reseed();
+    supply::reseed_points();
+    counterparties::rebuild_sent_windows();
+    risk::rebuild_aggregates();
+    Ok(summary)
+}
+
+#[ic_cdk::update]
+fn prepare_backup() -> Result<BackupManifest, WalletError> {
+    perf::instrument("prepare_backup", || {
+        ensure_admin()?;
+        ensure_not_restoring()?;
+
+        let snapshot = take_snapshot();
+        let bytes = encode_snapshot(&snapshot)?;
+
+        let manifest = BackupManifest {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            created_at: current_time(),
+            total_size: bytes.len() as u64,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            checksum: sha256_hex(&bytes),
+            user_count: snapshot.users.len() as u64,
+            transaction_count: snapshot.transactions.len() as u64,
+            id_counter: snapshot.id_counter,
+        };
+        BACKUP_BUFFER.with(|buffer| *buffer.borrow_mut() = Some((manifest.clone(), bytes)));
+        Ok(manifest)
+    })
+}
+
+#[ic_cdk::query]
+fn backup_chunk(offset: u64, len: u64) -> Result<Vec<u8>, WalletError> {
+    perf::instrument("backup_chunk", || {
+        ensure_admin()?;
+        if len == 0 || len > MAX_CHUNK_SIZE {
+            return Err(WalletError::invalid(
+                "len",
+                &format!("must be between 1 and {} bytes", MAX_CHUNK_SIZE),
+            ));
+        }
+
+        BACKUP_BUFFER.with(|buffer| {
+            let buffer = buffer.borrow();
+            let (_, bytes) = buffer.as_ref().ok_or(WalletError::InvalidState {
+                reason: "No backup prepared, call 'prepare_backup' first".to_string(),
+            })?;
+            let total = bytes.len() as u64;
+            if offset >= total {
+                return Err(WalletError::invalid(
+                    "offset",
+                    &format!("past the end of the backup ({} bytes)", total),
+                ));
+            }
+            let end = total.min(offset + len);
+            Ok(bytes[offset as usize..end as usize].to_vec())
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn begin_restore(manifest: BackupManifest) -> Result<RestoreProgress, WalletError> {
+    perf::instrument("begin_restore", || {
+        ensure_admin()?;
+        pause::ensure_not_read_only()?;
+        if manifest.format_version != SNAPSHOT_FORMAT_VERSION {
+            return Err(WalletError::invalid(
+                "format_version",
+                &format!(
+                    "unsupported backup format version {}",
+                    manifest.format_version
+                ),
+            ));
+        }
+
+        set_restore_state(RestoreState {
+            in_progress: true,
+            started_at: current_time(),
+            expected_size: manifest.total_size,
+            expected_checksum: manifest.checksum,
+        });
+        RESTORE_BUFFER.with(|buffer| buffer.borrow_mut().clear());
+        Ok(RestoreProgress {
+            received_bytes: 0,
+            expected_bytes: manifest.total_size,
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn restore_chunk(payload: RestoreChunkPayload) -> Result<RestoreProgress, WalletError> {
+    perf::instrument("restore_chunk", || {
+        ensure_admin()?;
+        ensure_restoring()?;
+
+        hardening::check(&payload)?;
+        let expected_size = RESTORE_STATE.with(|state| state.borrow().get().expected_size);
+        RESTORE_BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            // Chunks must arrive in order so a lost or repeated chunk is caught immediately
+            if payload.offset != buffer.len() as u64 {
+                return Err(WalletError::invalid(
+                    "offset",
+                    &format!("expected a chunk at offset {}", buffer.len()),
+                ));
+            }
+            if buffer.len() as u64 + payload.data.len() as u64 > expected_size {
+                return Err(WalletError::invalid(
+                    "data",
+                    "chunk exceeds the size declared in the manifest",
+                ));
+            }
+            buffer.extend_from_slice(&payload.data);
+            Ok(RestoreProgress {
+                received_bytes: buffer.len() as u64,
+                expected_bytes: expected_size,
+            })
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn finish_restore() -> Result<RestoreSummary, WalletError> {
+    perf::instrument("finish_restore", || {
+        ensure_admin()?;
+        pause::ensure_not_read_only()?;
+        ensure_restoring()?;
+
+        let state = RESTORE_STATE.with(|state| state.borrow().get().clone());
+        let bytes = RESTORE_BUFFER.with(|buffer| buffer.borrow().clone());
+        if bytes.len() as u64 != state.expected_size {
+            return Err(WalletError::InvalidState {
+                reason: format!("Received {} of {} bytes", bytes.len(), state.expected_size),
+            });
+        }
+        if sha256_hex(&bytes) != state.expected_checksum {
+            return Err(WalletError::invalid(
+                "checksum",
+                "does not match the received data",
+            ));
+        }
+        let summary = apply_snapshot(&bytes)?;
+
+        RESTORE_BUFFER.with(|buffer| buffer.borrow_mut().clear());
+        set_restore_state(RestoreState::default());
+        Ok(summary)
+    })
+}
+
+#[ic_cdk::update]
+fn abort_restore() -> Result<(), WalletError> {
+    perf::instrument("abort_restore", || {
+        ensure_admin()?;
+        ensure_restoring()?;
+
+        RESTORE_BUFFER.with(|buffer| buffer.borrow_mut().clear());
+        set_restore_state(RestoreState::default());
+        Ok(())
+    })
+}
@@ -0,0 +1,197 @@
+//! Balance history for charts. Shortly after each UTC midnight a timer
+//! records every user's balance as it stood at the end of the previous day,
+//! storing a snapshot only when the balance changed since the last one.
+//! `get_balance_history` carries the snapshots forward over the days
+//! between them and reports one balance per day, week or month, so a
+//! frontend can chart a balance without replaying its transactions.
+//!
+//! History starts on the first snapshot after the account was created, or
+//! after this feature was deployed; the current day uses the live balance.
+
+use crate::auth::ensure_owner;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::{current_time, ledger, perf, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use chrono::DateTime;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+use std::time::Duration;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_RANGE_DAYS: u64 = 3660;
+const MAX_POINTS: usize = 400;
+
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) enum Granularity {
+    Day,
+    // ISO weeks, starting on Monday
+    Week,
+    Month,
+}
+
+impl Granularity {
+    // The period a day belongs to, e.g. "2026-10-14", "2026-W42" or "2026-10"
+    fn period_of(self, day: u64) -> String {
+        let format = match self {
+            Granularity::Day => "%Y-%m-%d",
+            Granularity::Week => "%G-W%V",
+            Granularity::Month => "%Y-%m",
+        };
+        DateTime::from_timestamp((day * NANOS_PER_DAY / 1_000_000_000) as i64, 0)
+            .map(|date| date.format(format).to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct BalancePoint {
+    period: String,
+    // Start of the first day of the period within the requested range
+    period_start: u64,
+    // Balance at the end of the period, or now for the current one
+    balance: u64,
+}
+
+thread_local! {
+    // (user id, day since the epoch) to the balance at the end of that day,
+    // stored only when it changed
+    static BALANCE_SNAPSHOTS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106)))
+    ));
+
+    // Balance in each user's latest snapshot
+    static LAST_SNAPSHOT_BALANCES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107)))
+    ));
+
+    // Day of the latest snapshot run, 0 before the first
+    static LAST_SNAPSHOT_DAY: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108))), 0)
+            .expect("Cannot create the balance snapshot day cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("balance_history.balance_snapshots", 106, &BALANCE_SNAPSHOTS),
+        manifest::map(
+            "balance_history.last_snapshot_balances",
+            107,
+            &LAST_SNAPSHOT_BALANCES,
+        ),
+        manifest::cell("balance_history.last_snapshot_day", 108, &LAST_SNAPSHOT_DAY),
+    ]
+}
+
+pub(crate) fn start_snapshot_job() {
+    ic_cdk_timers::set_timer_interval(SNAPSHOT_CHECK_INTERVAL, take_daily_snapshot);
+}
+
+fn take_daily_snapshot() {
+    if ensure_writable().is_err() {
+        return;
+    }
+    let today = current_time() / NANOS_PER_DAY;
+    let Some(day) = today.checked_sub(1) else {
+        return;
+    };
+    if LAST_SNAPSHOT_DAY.with(|cell| *cell.borrow().get()) >= day {
+        return;
+    }
+    let user_ids: Vec<u64> =
+        USER_STORAGE.with(|storage| storage.borrow().iter().map(|(id, _)| id).collect());
+    for user_id in user_ids {
+        let balance = ledger::user_balance(user_id);
+        let unchanged = LAST_SNAPSHOT_BALANCES
+            .with(|balances| balances.borrow().get(&user_id))
+            .is_some_and(|last| last == balance);
+        if unchanged {
+            continue;
+        }
+        BALANCE_SNAPSHOTS.with(|storage| storage.borrow_mut().insert((user_id, day), balance));
+        LAST_SNAPSHOT_BALANCES.with(|balances| balances.borrow_mut().insert(user_id, balance));
+    }
+    LAST_SNAPSHOT_DAY
+        .with(|cell| cell.borrow_mut().set(day))
+        .expect("Cannot update the balance snapshot day");
+}
+
+/// One balance per period between `from` and `to`, oldest first. Periods
+/// before the user's history starts are left out.
+#[ic_cdk::query]
+fn get_balance_history(
+    user_id: u64,
+    from: u64,
+    to: u64,
+    granularity: Granularity,
+) -> Result<Vec<BalancePoint>, WalletError> {
+    perf::instrument("get_balance_history", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        if from > to {
+            return Err(WalletError::invalid("from", "must not be after `to`"));
+        }
+        let today = current_time() / NANOS_PER_DAY;
+        let from_day = from / NANOS_PER_DAY;
+        let to_day = (to / NANOS_PER_DAY).min(today);
+        if to_day.saturating_sub(from_day) >= MAX_RANGE_DAYS {
+            return Err(WalletError::invalid(
+                "to",
+                &format!("must be less than {} days after `from`", MAX_RANGE_DAYS),
+            ));
+        }
+
+        // Balance changes up to the end of the range, with today's live
+        // balance last
+        let mut changes: Vec<(u64, u64)> = BALANCE_SNAPSHOTS.with(|storage| {
+            storage
+                .borrow()
+                .range((user_id, 0)..=(user_id, to_day))
+                .map(|((_, day), balance)| (day, balance))
+                .collect()
+        });
+        if to_day == today {
+            changes.push((today, ledger::user_balance(user_id)));
+        }
+
+        let mut points: Vec<BalancePoint> = Vec::new();
+        let mut next_change = changes.iter().peekable();
+        let mut balance = None;
+        for day in from_day..=to_day {
+            while let Some((_, changed)) = next_change.next_if(|(at, _)| *at <= day) {
+                balance = Some(*changed);
+            }
+            let Some(balance) = balance else {
+                continue;
+            };
+            let period = granularity.period_of(day);
+            match points.last_mut() {
+                Some(point) if point.period == period => point.balance = balance,
+                _ => points.push(BalancePoint {
+                    period,
+                    period_start: day * NANOS_PER_DAY,
+                    balance,
+                }),
+            }
+            if points.len() > MAX_POINTS {
+                return Err(WalletError::invalid(
+                    "granularity",
+                    &format!(
+                        "the range spans more than {} periods; narrow it or use longer periods",
+                        MAX_POINTS
+                    ),
+                ));
+            }
+        }
+        Ok(points)
+    })
+}
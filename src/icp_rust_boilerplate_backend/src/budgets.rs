@@ -0,0 +1,356 @@
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{current_time, Memory, WalletError, MEMORY_MANAGER, TRANSACTION_STORAGE, USER_STORAGE};
+use candid::{Decode, Encode};
+use chrono::{DateTime, Months, NaiveDate};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Budgets are checked once an hour and a warning is recorded at 90% utilization
+const BUDGET_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const WARNING_THRESHOLD_PERCENT: u64 = 90;
+const MAX_CATEGORY_LEN: usize = 32;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Category {
+    Groceries,
+    Rent,
+    Utilities,
+    Transport,
+    Entertainment,
+    Custom(String),
+}
+
+impl Category {
+    fn label(&self) -> String {
+        match self {
+            Category::Groceries => "Groceries".to_string(),
+            Category::Rent => "Rent".to_string(),
+            Category::Utilities => "Utilities".to_string(),
+            Category::Transport => "Transport".to_string(),
+            Category::Entertainment => "Entertainment".to_string(),
+            Category::Custom(name) => name.clone(),
+        }
+    }
+}
+
+impl Harden for Category {
+    fn harden(&self, fields: &mut FieldChecker) {
+        if let Category::Custom(name) = self {
+            fields.text("category", name, TextKind::Alias);
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Budget {
+    category: Category,
+    monthly_limit: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BudgetWarning {
+    category: Category,
+    month: String,
+    spent: u64,
+    monthly_limit: u64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UserBudgets {
+    budgets: Vec<Budget>,
+    warnings: Vec<BudgetWarning>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct BudgetPayload {
+    category: Category,
+    monthly_limit: u64,
+}
+
+impl Harden for BudgetPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        self.category.harden(fields);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct BudgetStatus {
+    category: Category,
+    monthly_limit: u64,
+    spent: u64,
+    remaining: u64,
+    utilization_percent: u64,
+    // Set once the budget monitor has seen this budget reach the warning threshold
+    warning: Option<BudgetWarning>,
+}
+
+impl Storable for Category {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for UserBudgets {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static TRANSACTION_CATEGORIES: RefCell<StableBTreeMap<u64, Category, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static BUDGET_STORAGE: RefCell<StableBTreeMap<u64, UserBudgets, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map(
+            "budgets.transaction_categories",
+            10,
+            &TRANSACTION_CATEGORIES,
+        ),
+        manifest::map("budgets.budget_storage", 11, &BUDGET_STORAGE),
+    ]
+}
+
+pub(crate) fn validate_category(category: &Category) -> Result<(), WalletError> {
+    if let Category::Custom(name) = category {
+        if name.trim().is_empty() || name.len() > MAX_CATEGORY_LEN {
+            return Err(WalletError::invalid(
+                "category",
+                "custom categories must be 1-32 characters long",
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn record_category(transaction_id: u64, category: Category) {
+    TRANSACTION_CATEGORIES
+        .with(|categories| categories.borrow_mut().insert(transaction_id, category));
+}
+
+pub(crate) fn category_of(transaction_id: u64) -> Option<Category> {
+    TRANSACTION_CATEGORIES.with(|categories| categories.borrow().get(&transaction_id))
+}
+
+pub(crate) fn start_budget_monitor() {
+    ic_cdk_timers::set_timer_interval(BUDGET_CHECK_INTERVAL, check_budgets);
+}
+
+// Parses a "YYYY-MM" month into its [start, end) range in nanoseconds
+pub(crate) fn month_range(month: &str) -> Result<(u64, u64), WalletError> {
+    let invalid_month = || WalletError::invalid("month", "must be formatted as YYYY-MM");
+    let start = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .map_err(|_| invalid_month())?;
+    let end = start
+        .checked_add_months(Months::new(1))
+        .ok_or_else(invalid_month)?;
+    Ok((date_to_nanos(start), date_to_nanos(end)))
+}
+
+fn date_to_nanos(date: NaiveDate) -> u64 {
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_utc().timestamp_nanos_opt())
+        .map_or(0, |nanos| nanos.max(0) as u64)
+}
+
+pub(crate) fn month_of(timestamp: u64) -> String {
+    DateTime::from_timestamp((timestamp / 1_000_000_000) as i64, 0)
+        .map(|date| date.format("%Y-%m").to_string())
+        .unwrap_or_default()
+}
+
+// Total categorized spend of `user_id` within [start, end)
+fn spending_by_category(user_id: u64, start: u64, end: u64) -> BTreeMap<Category, u64> {
+    let mut spent = BTreeMap::new();
+    TRANSACTION_CATEGORIES.with(|categories| {
+        TRANSACTION_STORAGE.with(|transactions| {
+            let transactions = transactions.borrow();
+            for (id, category) in categories.borrow().iter() {
+                let Some(transaction) = transactions.get(&id) else {
+                    continue;
+                };
+                if transaction.from_user_id.0 == user_id
+                    && transaction.created_at >= start
+                    && transaction.created_at < end
+                {
+                    let total: &mut u64 = spent.entry(category).or_default();
+                    *total = total.saturating_add(transaction.amount);
+                }
+            }
+        })
+    });
+    spent
+}
+
+fn utilization_percent(spent: u64, monthly_limit: u64) -> u64 {
+    ((spent as u128 * 100) / monthly_limit as u128).min(u64::MAX as u128) as u64
+}
+
+fn check_budgets() {
+    // Budget warnings are not worth spending cycles on while frozen
+    if ensure_writable().is_err() || ensure_not_frozen().is_err() {
+        return;
+    }
+
+    let now = current_time();
+    let month = month_of(now);
+    let Ok((start, end)) = month_range(&month) else {
+        return;
+    };
+
+    let users: Vec<(u64, UserBudgets)> =
+        BUDGET_STORAGE.with(|storage| storage.borrow().iter().collect());
+    for (user_id, mut user_budgets) in users {
+        let spent = spending_by_category(user_id, start, end);
+        let mut changed = false;
+        for budget in &user_budgets.budgets {
+            let spent = spent.get(&budget.category).copied().unwrap_or(0);
+            let already_warned = user_budgets
+                .warnings
+                .iter()
+                .any(|warning| warning.category == budget.category && warning.month == month);
+            if !already_warned
+                && utilization_percent(spent, budget.monthly_limit) >= WARNING_THRESHOLD_PERCENT
+            {
+                user_budgets.warnings.push(BudgetWarning {
+                    category: budget.category.clone(),
+                    month: month.clone(),
+                    spent,
+                    monthly_limit: budget.monthly_limit,
+                    created_at: now,
+                });
+                changed = true;
+            }
+        }
+        if changed {
+            BUDGET_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, user_budgets));
+        }
+    }
+}
+
+#[ic_cdk::update]
+fn set_budget(payload: BudgetPayload) -> Result<Budget, WalletError> {
+    perf::instrument("set_budget", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+
+        hardening::check(&payload)?;
+        let user_id = caller_user_id()?;
+        validate_category(&payload.category)?;
+        if payload.monthly_limit == 0 {
+            return Err(WalletError::invalid(
+                "monthly_limit",
+                "must be greater than 0",
+            ));
+        }
+
+        let budget = Budget {
+            category: payload.category,
+            monthly_limit: payload.monthly_limit,
+        };
+        BUDGET_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let mut user_budgets = storage.get(&user_id).unwrap_or_default();
+            user_budgets
+                .budgets
+                .retain(|existing| existing.category != budget.category);
+            user_budgets.budgets.push(budget.clone());
+            storage.insert(user_id, user_budgets);
+        });
+        Ok(budget)
+    })
+}
+
+#[ic_cdk::update]
+fn remove_budget(category: Category) -> Result<(), WalletError> {
+    perf::instrument("remove_budget", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        BUDGET_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let mut user_budgets = storage.get(&user_id).unwrap_or_default();
+            let before = user_budgets.budgets.len();
+            user_budgets
+                .budgets
+                .retain(|existing| existing.category != category);
+            if user_budgets.budgets.len() == before {
+                return Err(WalletError::NotFoundByKey {
+                    entity: "budget".to_string(),
+                    key: category.label(),
+                });
+            }
+            storage.insert(user_id, user_budgets);
+            Ok(())
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_budget_status(user_id: u64, month: String) -> Result<Vec<BudgetStatus>, WalletError> {
+    perf::instrument("get_budget_status", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+        let (start, end) = month_range(&month)?;
+
+        let user_budgets = BUDGET_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .unwrap_or_default();
+        let spent = spending_by_category(user_id, start, end);
+        Ok(user_budgets
+            .budgets
+            .iter()
+            .map(|budget| {
+                let spent = spent.get(&budget.category).copied().unwrap_or(0);
+                BudgetStatus {
+                    category: budget.category.clone(),
+                    monthly_limit: budget.monthly_limit,
+                    spent,
+                    remaining: budget.monthly_limit.saturating_sub(spent),
+                    utilization_percent: utilization_percent(spent, budget.monthly_limit),
+                    warning: user_budgets
+                        .warnings
+                        .iter()
+                        .find(|warning| {
+                            warning.category == budget.category && warning.month == month
+                        })
+                        .cloned(),
+                }
+            })
+            .collect())
+    })
+}
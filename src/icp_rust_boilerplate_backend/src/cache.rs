@@ -0,0 +1,209 @@
+//! Heap caches for hot reads. `CachedMap` wraps a stable map and keeps the
+//! most recently used records deserialized in an LRU cache. Writes go to the
+//! stable map and the cache together, so the cache is never stale. The cache
+//! lives on the heap only: it starts empty after an upgrade and fills again
+//! as records are read.
+//!
+//! State changes made by query calls are discarded, so only update calls
+//! warm the cache and move its counters; queries still benefit from what
+//! they find in it.
+
+use crate::perf;
+use crate::retention::{self, PrunedCounts};
+use crate::{ensure_admin, Memory, WalletError, TRANSACTION_STORAGE, USER_STORAGE};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+pub(crate) const USER_CACHE_CAPACITY: usize = 1_000;
+pub(crate) const TRANSACTION_CACHE_CAPACITY: usize = 1_000;
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CacheStats {
+    entries: u64,
+    capacity: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct Metrics {
+    user_count: u64,
+    transaction_count: u64,
+    user_cache: CacheStats,
+    transaction_cache: CacheStats,
+    // Entries freed by the retention job since deployment
+    pruned: PrunedCounts,
+}
+
+struct Lru<K, V> {
+    capacity: usize,
+    // Each entry with the tick it was last used at
+    entries: BTreeMap<K, (V, u64)>,
+    // Tick to key, so the least recently used entry comes first
+    recency: BTreeMap<u64, K>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl<K: Ord + Clone, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            entries: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        if let Some((_, used_at)) = self.entries.get_mut(key) {
+            self.recency.remove(used_at);
+            *used_at = self.tick;
+            self.recency.insert(self.tick, key.clone());
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        let value = self.entries.get(key).map(|(value, _)| value.clone())?;
+        self.touch(key);
+        Some(value)
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        self.invalidate(&key);
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            self.evictions += 1;
+        }
+    }
+
+    fn invalidate(&mut self, key: &K) {
+        if let Some((_, used_at)) = self.entries.remove(key) {
+            self.recency.remove(&used_at);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len() as u64,
+            capacity: self.capacity as u64,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+}
+
+/// A stable map with a write-through LRU cache in front of its point reads.
+/// Iteration reads the stable map directly, so scans do not evict the hot
+/// records.
+pub(crate) struct CachedMap<K, V>
+where
+    K: Storable + Ord + Clone,
+    V: Storable + Clone,
+{
+    map: StableBTreeMap<K, V, Memory>,
+    cache: RefCell<Lru<K, V>>,
+}
+
+impl<K, V> CachedMap<K, V>
+where
+    K: Storable + Ord + Clone,
+    V: Storable + Clone,
+{
+    pub(crate) fn init(memory: Memory, capacity: usize) -> Self {
+        CachedMap {
+            map: StableBTreeMap::init(memory),
+            cache: RefCell::new(Lru::new(capacity)),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<V> {
+        let mut cache = self.cache.borrow_mut();
+        if let Some(value) = cache.get(key) {
+            cache.hits += 1;
+            return Some(value);
+        }
+        cache.misses += 1;
+        let value = self.map.get(key)?;
+        cache.put(key.clone(), value.clone());
+        Some(value)
+    }
+
+    pub(crate) fn contains_key(&self, key: &K) -> bool {
+        self.cache.borrow().entries.contains_key(key) || self.map.contains_key(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.cache.get_mut().put(key.clone(), value.clone());
+        self.map.insert(key, value)
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        self.cache.get_mut().invalidate(key);
+        self.map.remove(key)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.map.iter()
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Removes every record, as a restore does before loading a snapshot.
+    pub(crate) fn clear(&mut self) {
+        let keys: Vec<K> = self.map.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            self.map.remove(&key);
+        }
+        self.cache.get_mut().clear();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_metrics() -> Result<Metrics, WalletError> {
+    perf::instrument("get_metrics", || {
+        ensure_admin()?;
+
+        let (user_count, user_cache) = USER_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            (storage.len(), storage.stats())
+        });
+        let (transaction_count, transaction_cache) = TRANSACTION_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            (storage.len(), storage.stats())
+        });
+        Ok(Metrics {
+            user_count,
+            transaction_count,
+            user_cache,
+            transaction_cache,
+            pruned: retention::total_pruned(),
+        })
+    })
+}
@@ -0,0 +1,358 @@
+//! Promo campaigns. Controllers create promo codes granting bonus points or
+//! balance, capped in total and per user and valid for a time window; users
+//! redeem them with `apply_promo`.
+
+use crate::auth::caller_user_id;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::events::{self, EventKind};
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::supply::{self, Asset};
+use crate::{
+    current_time, ensure_admin, next_id, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use crate::{leaderboard, ledger, pause, perf};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MIN_CODE_LEN: usize = 4;
+const MAX_CODE_LEN: usize = 32;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum PromoBonus {
+    Points(u64),
+    Balance(u64),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Campaign {
+    id: u64,
+    code: String,
+    bonus: PromoBonus,
+    // Total redemptions across all users; `None` is unlimited
+    max_redemptions: Option<u64>,
+    per_user_limit: u64,
+    starts_at: u64,
+    ends_at: u64,
+    active: bool,
+    redemption_count: u64,
+    unique_users: u64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CampaignPayload {
+    code: String,
+    bonus: PromoBonus,
+    max_redemptions: Option<u64>,
+    per_user_limit: u64,
+    starts_at: u64,
+    ends_at: u64,
+}
+
+impl Harden for CampaignPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.text("code", &self.code, TextKind::Alias);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CampaignStats {
+    campaign_id: u64,
+    code: String,
+    redemption_count: u64,
+    unique_users: u64,
+    // `None` when the campaign has no overall cap
+    remaining_redemptions: Option<u64>,
+    total_points_awarded: u64,
+    total_balance_awarded: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct PromoReceipt {
+    campaign_id: u64,
+    user_id: u64,
+    bonus: PromoBonus,
+    new_balance: u64,
+    new_points: u64,
+}
+
+impl Storable for Campaign {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static CAMPAIGN_STORAGE: RefCell<StableBTreeMap<u64, Campaign, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    // Normalized promo code to campaign id
+    static CAMPAIGN_CODE_INDEX: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    // Redemptions per (campaign id, user id)
+    static PROMO_REDEMPTIONS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("campaigns.campaign_storage", 45, &CAMPAIGN_STORAGE),
+        manifest::map("campaigns.campaign_code_index", 46, &CAMPAIGN_CODE_INDEX),
+        manifest::map("campaigns.promo_redemptions", 47, &PROMO_REDEMPTIONS),
+    ]
+}
+
+// Codes are matched case-insensitively
+fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn get_campaign_record(campaign_id: u64) -> Result<Campaign, WalletError> {
+    CAMPAIGN_STORAGE
+        .with(|storage| storage.borrow().get(&campaign_id))
+        .ok_or(WalletError::not_found("campaign", campaign_id))
+}
+
+fn save_campaign(campaign: &Campaign) {
+    CAMPAIGN_STORAGE.with(|storage| storage.borrow_mut().insert(campaign.id, campaign.clone()));
+}
+
+// Credits the bonus and returns the user's new balance and points
+fn grant_bonus(
+    user_id: u64,
+    campaign_id: u64,
+    bonus: PromoBonus,
+) -> Result<(u64, u64), WalletError> {
+    let user = USER_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or(WalletError::not_found("user", user_id))?;
+    match bonus {
+        PromoBonus::Points(points) => {
+            let mut user = user;
+            user.points = user
+                .points
+                .checked_add(points)
+                .ok_or(WalletError::Overflow {
+                    field: "points".to_string(),
+                })?;
+            supply::record_minted(Asset::Points, points as u128);
+            let granted = (user.balance, user.points);
+            USER_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, user));
+            leaderboard::index_points(user_id, granted.1);
+            events::record(EventKind::PointsAwarded { user_id, points });
+            Ok(granted)
+        }
+        PromoBonus::Balance(amount) => {
+            let balance = ledger::deposit(
+                ledger::EntryKind::PromoBonus { campaign_id },
+                user_id,
+                amount,
+            )?;
+            events::record(EventKind::FundsDeposited { user_id, amount });
+            Ok((balance, user.points))
+        }
+    }
+}
+
+#[ic_cdk::update]
+fn create_campaign(payload: CampaignPayload) -> Result<Campaign, WalletError> {
+    perf::instrument("create_campaign", || {
+        ensure_writable()?;
+        ensure_admin()?;
+        ensure_not_frozen()?;
+        hardening::check(&payload)?;
+
+        let code = normalize_code(&payload.code);
+        if code.len() < MIN_CODE_LEN
+            || code.len() > MAX_CODE_LEN
+            || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(WalletError::invalid(
+                "code",
+                &format!(
+                    "must be {} to {} letters, digits or '-'",
+                    MIN_CODE_LEN, MAX_CODE_LEN
+                ),
+            ));
+        }
+        if CAMPAIGN_CODE_INDEX.with(|index| index.borrow().contains_key(&code)) {
+            return Err(WalletError::AlreadyExists {
+                entity: "campaign".to_string(),
+                field: "code".to_string(),
+            });
+        }
+        let bonus_amount = match payload.bonus {
+            PromoBonus::Points(points) => points,
+            PromoBonus::Balance(amount) => amount,
+        };
+        if bonus_amount == 0 {
+            return Err(WalletError::invalid("bonus", "must be greater than 0"));
+        }
+        if payload.max_redemptions == Some(0) {
+            return Err(WalletError::invalid(
+                "max_redemptions",
+                "must be greater than 0",
+            ));
+        }
+        if payload.per_user_limit == 0 {
+            return Err(WalletError::invalid(
+                "per_user_limit",
+                "must be greater than 0",
+            ));
+        }
+        if payload.starts_at >= payload.ends_at {
+            return Err(WalletError::invalid("ends_at", "must be after starts_at"));
+        }
+
+        let campaign = Campaign {
+            id: next_id(),
+            code: code.clone(),
+            bonus: payload.bonus,
+            max_redemptions: payload.max_redemptions,
+            per_user_limit: payload.per_user_limit,
+            starts_at: payload.starts_at,
+            ends_at: payload.ends_at,
+            active: true,
+            redemption_count: 0,
+            unique_users: 0,
+            created_at: current_time(),
+        };
+        save_campaign(&campaign);
+        CAMPAIGN_CODE_INDEX.with(|index| index.borrow_mut().insert(code, campaign.id));
+        Ok(campaign)
+    })
+}
+
+#[ic_cdk::update]
+fn set_campaign_active(campaign_id: u64, active: bool) -> Result<Campaign, WalletError> {
+    perf::instrument("set_campaign_active", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let mut campaign = get_campaign_record(campaign_id)?;
+        campaign.active = active;
+        save_campaign(&campaign);
+        Ok(campaign)
+    })
+}
+
+/// Redeems a promo code for the caller's account. Every limit is checked
+/// before anything is credited.
+#[ic_cdk::update]
+fn apply_promo(code: String) -> Result<PromoReceipt, WalletError> {
+    perf::instrument("apply_promo", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let normalized = normalize_code(&code);
+        let campaign_id = CAMPAIGN_CODE_INDEX
+            .with(|index| index.borrow().get(&normalized))
+            .ok_or(WalletError::NotFoundByKey {
+                entity: "promo code".to_string(),
+                key: normalized,
+            })?;
+        let mut campaign = get_campaign_record(campaign_id)?;
+
+        let now = current_time();
+        if !campaign.active || now < campaign.starts_at || now >= campaign.ends_at {
+            return Err(WalletError::InvalidState {
+                reason: format!("Promo code {} is not valid at this time", campaign.code),
+            });
+        }
+        if matches!(campaign.max_redemptions, Some(max) if campaign.redemption_count >= max) {
+            return Err(WalletError::InvalidState {
+                reason: format!("Promo code {} has been fully redeemed", campaign.code),
+            });
+        }
+        let key = (campaign_id, user_id);
+        let used = PROMO_REDEMPTIONS
+            .with(|redemptions| redemptions.borrow().get(&key))
+            .unwrap_or(0);
+        if used >= campaign.per_user_limit {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "Promo code {} can be used {} time(s) per user",
+                    campaign.code, campaign.per_user_limit
+                ),
+            });
+        }
+        if let PromoBonus::Balance(_) = campaign.bonus {
+            pause::ensure_transfers_allowed()?;
+        }
+
+        let (new_balance, new_points) = grant_bonus(user_id, campaign.id, campaign.bonus)?;
+        PROMO_REDEMPTIONS.with(|redemptions| redemptions.borrow_mut().insert(key, used + 1));
+        campaign.redemption_count += 1;
+        if used == 0 {
+            campaign.unique_users += 1;
+        }
+        save_campaign(&campaign);
+
+        Ok(PromoReceipt {
+            campaign_id,
+            user_id,
+            bonus: campaign.bonus,
+            new_balance,
+            new_points,
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn list_campaigns() -> Result<Vec<Campaign>, WalletError> {
+    perf::instrument("list_campaigns", || {
+        ensure_not_restoring()?;
+        ensure_admin()?;
+
+        Ok(CAMPAIGN_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, campaign)| campaign)
+                .collect()
+        }))
+    })
+}
+
+#[ic_cdk::query]
+fn get_campaign_stats(campaign_id: u64) -> Result<CampaignStats, WalletError> {
+    perf::instrument("get_campaign_stats", || {
+        ensure_not_restoring()?;
+        ensure_admin()?;
+
+        let campaign = get_campaign_record(campaign_id)?;
+        let (total_points_awarded, total_balance_awarded) = match campaign.bonus {
+            PromoBonus::Points(points) => (points.saturating_mul(campaign.redemption_count), 0),
+            PromoBonus::Balance(amount) => (0, amount.saturating_mul(campaign.redemption_count)),
+        };
+        Ok(CampaignStats {
+            campaign_id,
+            code: campaign.code,
+            redemption_count: campaign.redemption_count,
+            unique_users: campaign.unique_users,
+            remaining_redemptions: campaign
+                .max_redemptions
+                .map(|max| max.saturating_sub(campaign.redemption_count)),
+            total_points_awarded,
+            total_balance_awarded,
+        })
+    })
+}
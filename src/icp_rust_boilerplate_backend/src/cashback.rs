@@ -0,0 +1,437 @@
+//! Cashback. The pool is funded by admins, who top it up from the treasury,
+//! and by a share of the transfer fees collected; transfers between wallet
+//! users are free today, so the top-ups are what it pays out. At the end of
+//! every period a timer pays the pool out to the users who sent transfers
+//! in it, pro rata to the amount each sent. Admins choose the share, the
+//! period and which users qualify. Payouts are
+//! `Cashback` ledger entries from the pool to each recipient; rounding
+//! leftovers stay in the pool for the next period.
+
+use crate::auth::ensure_owner;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::ledger::{self, EntryKind, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::supply::{self, Asset};
+use crate::{
+    current_time, ensure_admin, next_id, pause, perf, token, Memory, WalletError, MEMORY_MANAGER,
+    TRANSACTION_STORAGE, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CASHBACK_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_SHARE_BPS: u32 = 10_000;
+const MAX_INTERVAL_DAYS: u32 = 90;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum CashbackTier {
+    // Everyone who sent a transfer in the period
+    AllActive,
+    // Active users with a verified email address or phone number
+    Verified,
+    // Active users who sent at least this much in the period
+    MinVolume { amount: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CashbackPolicy {
+    enabled: bool,
+    // Share of collected fees paid into the pool, in basis points
+    share_bps: u32,
+    interval_days: u32,
+    tier: CashbackTier,
+}
+
+impl Default for CashbackPolicy {
+    fn default() -> Self {
+        CashbackPolicy {
+            enabled: false,
+            share_bps: 0,
+            interval_days: 30,
+            tier: CashbackTier::AllActive,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct CashbackState {
+    policy: CashbackPolicy,
+    // Fees collected when the pool was last topped up
+    fees_accrued_through: u128,
+    // Start of the current period, once the program was enabled
+    period_start: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CashbackDistribution {
+    id: u64,
+    period_start: u64,
+    period_end: u64,
+    pool_amount: u64,
+    distributed: u64,
+    recipients: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CashbackPayout {
+    distribution_id: u64,
+    user_id: u64,
+    amount: u64,
+    // What the user sent in the period
+    volume: u64,
+    paid_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CashbackStatus {
+    policy: CashbackPolicy,
+    pool_balance: u64,
+    next_distribution_at: Option<u64>,
+    last_distribution: Option<CashbackDistribution>,
+}
+
+impl Storable for CashbackState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CashbackDistribution {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CashbackPayout {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static CASHBACK_STATE: RefCell<Cell<CashbackState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93))),
+            CashbackState::default(),
+        )
+        .expect("Cannot create the cashback state cell")
+    );
+
+    static CASHBACK_DISTRIBUTIONS: RefCell<StableBTreeMap<u64, CashbackDistribution, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94)))
+    ));
+
+    // Keyed by (user, distribution), so a user's history is one range
+    static CASHBACK_PAYOUTS: RefCell<StableBTreeMap<(u64, u64), CashbackPayout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::cell("cashback.cashback_state", 93, &CASHBACK_STATE),
+        manifest::map(
+            "cashback.cashback_distributions",
+            94,
+            &CASHBACK_DISTRIBUTIONS,
+        ),
+        manifest::map("cashback.cashback_payouts", 95, &CASHBACK_PAYOUTS),
+    ]
+}
+
+fn state() -> CashbackState {
+    CASHBACK_STATE.with(|cell| cell.borrow().get().clone())
+}
+
+fn save_state(state: CashbackState) {
+    CASHBACK_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("Cannot update the cashback state");
+}
+
+fn pool_balance() -> u64 {
+    ledger::balance_of(LedgerAccount::Cashback).clamp(0, u64::MAX as i128) as u64
+}
+
+fn next_distribution_at(state: &CashbackState) -> Option<u64> {
+    let period = state.policy.interval_days as u64 * NANOS_PER_DAY;
+    state
+        .period_start
+        .filter(|_| state.policy.enabled)
+        .map(|start| start.saturating_add(period))
+}
+
+/// Moves the pool's share of the fees collected since the last top-up from
+/// the fee account into the pool.
+fn accrue(state: &mut CashbackState) {
+    let collected = supply::fees_collected(Asset::Token);
+    let new_fees = collected.saturating_sub(state.fees_accrued_through);
+    state.fees_accrued_through = collected;
+    let share = new_fees * state.policy.share_bps as u128 / MAX_SHARE_BPS as u128;
+    let fees = ledger::balance_of(LedgerAccount::Fees).max(0) as u128;
+    let amount = share.min(fees).min(u64::MAX as u128) as u64;
+    if amount > 0 {
+        ledger::transfer(
+            EntryKind::CashbackAccrual,
+            LedgerAccount::Fees,
+            LedgerAccount::Cashback,
+            amount,
+        )
+        .unwrap_or_else(|error| ic_cdk::trap(&format!("Cannot accrue cashback: {}", error)));
+    }
+}
+
+fn qualifies(tier: CashbackTier, user_id: u64, volume: u64) -> bool {
+    match tier {
+        CashbackTier::AllActive => true,
+        CashbackTier::Verified => USER_STORAGE.with(|storage| {
+            storage.borrow().get(&user_id).is_some_and(|user| {
+                user.email_verified_at.is_some() || user.phone_verified_at.is_some()
+            })
+        }),
+        CashbackTier::MinVolume { amount } => volume >= amount,
+    }
+}
+
+/// What each qualifying user sent between `start` and `end`.
+fn volumes(tier: CashbackTier, start: u64, end: u64) -> BTreeMap<u64, u64> {
+    let mut volumes: BTreeMap<u64, u64> = BTreeMap::new();
+    TRANSACTION_STORAGE.with(|storage| {
+        for (_, tx) in storage.borrow().iter() {
+            if tx.created_at >= start && tx.created_at < end {
+                let volume = volumes.entry(tx.from_user_id.0).or_default();
+                *volume = volume.saturating_add(tx.amount);
+            }
+        }
+    });
+    volumes.retain(|user_id, volume| qualifies(tier, *user_id, *volume));
+    volumes
+}
+
+/// Pays out the pool for the period ending now and starts the next one.
+fn distribute(mut state: CashbackState, now: u64) -> CashbackDistribution {
+    accrue(&mut state);
+    let period_start = state.period_start.unwrap_or(now);
+    let pool_amount = pool_balance();
+    let volumes = volumes(state.policy.tier, period_start, now);
+    let total_volume: u128 = volumes.values().map(|volume| *volume as u128).sum();
+
+    let id = next_id();
+    let mut distributed = 0u64;
+    let mut recipients = 0u64;
+    if pool_amount > 0 && total_volume > 0 {
+        for (user_id, volume) in volumes {
+            let amount = (pool_amount as u128 * volume as u128 / total_volume) as u64;
+            if amount == 0 {
+                continue;
+            }
+            if ledger::transfer(
+                EntryKind::Cashback {
+                    distribution_id: id,
+                },
+                LedgerAccount::Cashback,
+                ledger::user(user_id),
+                amount,
+            )
+            .is_err()
+            {
+                continue;
+            }
+            distributed += amount;
+            recipients += 1;
+            CASHBACK_PAYOUTS.with(|storage| {
+                storage.borrow_mut().insert(
+                    (user_id, id),
+                    CashbackPayout {
+                        distribution_id: id,
+                        user_id,
+                        amount,
+                        volume,
+                        paid_at: now,
+                    },
+                )
+            });
+            notify(
+                user_id,
+                NotificationKind::Cashback,
+                format!("You received {} in cashback", token::format_amount(amount)),
+            );
+        }
+    }
+
+    let distribution = CashbackDistribution {
+        id,
+        period_start,
+        period_end: now,
+        pool_amount,
+        distributed,
+        recipients,
+    };
+    CASHBACK_DISTRIBUTIONS.with(|storage| storage.borrow_mut().insert(id, distribution.clone()));
+    state.period_start = Some(now);
+    save_state(state);
+    distribution
+}
+
+pub(crate) fn start_cashback_job() {
+    ic_cdk_timers::set_timer_interval(CASHBACK_CHECK_INTERVAL, run_cashback);
+}
+
+fn run_cashback() {
+    if ensure_writable().is_err() || pause::ensure_transfers_allowed().is_err() {
+        return;
+    }
+    let state = state();
+    let now = current_time();
+    if next_distribution_at(&state).is_some_and(|at| at <= now) {
+        distribute(state, now);
+    }
+}
+
+#[ic_cdk::query]
+fn get_cashback_status() -> Result<CashbackStatus, WalletError> {
+    perf::instrument("get_cashback_status", || {
+        ensure_not_restoring()?;
+        ensure_admin()?;
+
+        let state = state();
+        Ok(CashbackStatus {
+            next_distribution_at: next_distribution_at(&state),
+            policy: state.policy,
+            pool_balance: pool_balance(),
+            last_distribution: CASHBACK_DISTRIBUTIONS
+                .with(|storage| storage.borrow().last_key_value())
+                .map(|(_, distribution)| distribution),
+        })
+    })
+}
+
+/// Replaces the distribution policy. Enabling the program starts its first
+/// period now; fees collected while it was disabled are not paid in.
+#[ic_cdk::update]
+fn set_cashback_policy(policy: CashbackPolicy) -> Result<(), WalletError> {
+    perf::instrument("set_cashback_policy", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if policy.share_bps > MAX_SHARE_BPS {
+            return Err(WalletError::invalid(
+                "share_bps",
+                &format!("must be at most {}", MAX_SHARE_BPS),
+            ));
+        }
+        if policy.interval_days == 0 || policy.interval_days > MAX_INTERVAL_DAYS {
+            return Err(WalletError::invalid(
+                "interval_days",
+                &format!("must be between 1 and {}", MAX_INTERVAL_DAYS),
+            ));
+        }
+
+        let mut state = state();
+        if state.policy.enabled {
+            // Fees collected so far accrue under the share they were
+            // collected with
+            accrue(&mut state);
+        } else {
+            state.fees_accrued_through = supply::fees_collected(Asset::Token);
+        }
+        state.period_start = match (state.policy.enabled, policy.enabled) {
+            (false, true) => Some(current_time()),
+            (_, false) => None,
+            (true, true) => state.period_start,
+        };
+        state.policy = policy;
+        save_state(state);
+        Ok(())
+    })
+}
+
+/// Moves `amount` from the treasury into the pool, to be paid out at the
+/// end of the current period. Returns the pool balance.
+#[ic_cdk::update]
+fn fund_cashback_pool(amount: u64) -> Result<u64, WalletError> {
+    perf::instrument("fund_cashback_pool", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", amount)?;
+        ledger::transfer(
+            EntryKind::CashbackFunding,
+            LedgerAccount::Treasury,
+            LedgerAccount::Cashback,
+            amount,
+        )?;
+        Ok(pool_balance())
+    })
+}
+
+/// Ends the current period now and pays out the pool.
+#[ic_cdk::update]
+fn run_cashback_distribution_now() -> Result<CashbackDistribution, WalletError> {
+    perf::instrument("run_cashback_distribution_now", || {
+        ensure_writable()?;
+        ensure_admin()?;
+        pause::ensure_transfers_allowed()?;
+
+        let state = state();
+        if !state.policy.enabled {
+            return Err(WalletError::InvalidState {
+                reason: "Cashback is disabled".to_string(),
+            });
+        }
+        Ok(distribute(state, current_time()))
+    })
+}
+
+/// The cashback the user received, newest first.
+#[ic_cdk::query]
+fn get_cashback_history(user_id: u64) -> Result<Vec<CashbackPayout>, WalletError> {
+    perf::instrument("get_cashback_history", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        let mut payouts: Vec<CashbackPayout> = CASHBACK_PAYOUTS.with(|storage| {
+            storage
+                .borrow()
+                .range((user_id, 0)..=(user_id, u64::MAX))
+                .map(|(_, payout)| payout)
+                .collect()
+        });
+        payouts.reverse();
+        Ok(payouts)
+    })
+}
@@ -0,0 +1,546 @@
+//! Counterparty rules. A user can block other users, which stops transfers
+//! between them in both directions, including payment links, payment intents,
+//! subscription charges and holds. In
+//! allowlist-only mode, meant for minors' and corporate accounts, a user can
+//! only send to the counterparties they approved beforehand.
+//!
+//! A user can also cap what they send to a counterparty over a rolling
+//! period. A transfer over the cap fails with `CounterpartyLimitExceeded`;
+//! the user confirms it with `confirm_limit_override` and sends it again,
+//! and the override covers that one transfer. What each user sent each
+//! counterparty is kept per day as transfers execute, so caps keep counting
+//! transfers that were archived or pruned since.
+
+use crate::auth::ensure_owner;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::{
+    clear_map, current_time, devices, perf, Memory, WalletError, MEMORY_MANAGER,
+    TRANSACTION_STORAGE, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_COUNTERPARTIES: usize = 1_000;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_LIMIT_PERIOD_DAYS: u32 = 365;
+// How long a confirmed override waits for the transfer it was confirmed for
+const OVERRIDE_TTL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum CounterpartyStatus {
+    Blocked,
+    Allowed,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct LimitOverride {
+    // Largest transfer the override lets through
+    amount: u64,
+    expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct CounterpartyLimit {
+    max_amount: u64,
+    period_days: u32,
+    pending_override: Option<LimitOverride>,
+}
+
+// What a user sent a counterparty per day, over the longest limit period
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct SentWindow {
+    // (day since the epoch, amount sent that day), oldest first
+    days: Vec<(u64, u64)>,
+}
+
+impl SentWindow {
+    fn add(&mut self, day: u64, amount: u64) {
+        match self.days.binary_search_by_key(&day, |(day, _)| *day) {
+            Ok(index) => self.days[index].1 = self.days[index].1.saturating_add(amount),
+            Err(index) => self.days.insert(index, (day, amount)),
+        }
+    }
+
+    fn sent_since(&self, first_day: u64) -> u64 {
+        self.days
+            .iter()
+            .filter(|(day, _)| *day >= first_day)
+            .fold(0u64, |total, (_, sent)| total.saturating_add(*sent))
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CounterpartyLimitPayload {
+    pub(crate) max_amount: u64,
+    pub(crate) period_days: u32,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CounterpartyLimitStatus {
+    counterparty_id: u64,
+    max_amount: u64,
+    period_days: u32,
+    sent_in_period: u64,
+    // Confirmed and not used yet
+    override_amount: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CounterpartyRules {
+    user_id: u64,
+    allowlist_only: bool,
+    blocked: Vec<u64>,
+    allowed: Vec<u64>,
+    limits: Vec<CounterpartyLimitStatus>,
+}
+
+impl Storable for CounterpartyStatus {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CounterpartyLimit {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for SentWindow {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // (user id, counterparty id) to what the user decided about them
+    static COUNTERPARTIES: RefCell<StableBTreeMap<(u64, u64), CounterpartyStatus, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+
+    // Users that can only send to allowed counterparties
+    static ALLOWLIST_ONLY: RefCell<StableBTreeMap<u64, bool, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73)))
+    ));
+
+    // (user id, counterparty id) to the user's cap on sends to them
+    static COUNTERPARTY_LIMITS: RefCell<StableBTreeMap<(u64, u64), CounterpartyLimit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87)))
+    ));
+
+    // (user id, counterparty id) to what the user sent them per day
+    static SENT_WINDOWS: RefCell<StableBTreeMap<(u64, u64), SentWindow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("counterparties.counterparties", 72, &COUNTERPARTIES),
+        manifest::map("counterparties.allowlist_only", 73, &ALLOWLIST_ONLY),
+        manifest::map(
+            "counterparties.counterparty_limits",
+            87,
+            &COUNTERPARTY_LIMITS,
+        ),
+        manifest::map("counterparties.sent_windows", 113, &SENT_WINDOWS),
+    ]
+}
+
+fn status_of(user_id: u64, counterparty_id: u64) -> Option<CounterpartyStatus> {
+    COUNTERPARTIES.with(|counterparties| counterparties.borrow().get(&(user_id, counterparty_id)))
+}
+
+fn is_allowlist_only(user_id: u64) -> bool {
+    ALLOWLIST_ONLY.with(|users| users.borrow().contains_key(&user_id))
+}
+
+fn limit_of(user_id: u64, counterparty_id: u64) -> Option<CounterpartyLimit> {
+    COUNTERPARTY_LIMITS.with(|limits| limits.borrow().get(&(user_id, counterparty_id)))
+}
+
+fn today() -> u64 {
+    current_time() / NANOS_PER_DAY
+}
+
+// Days before this one fall outside every limit period
+fn first_tracked_day() -> u64 {
+    (today() + 1).saturating_sub(MAX_LIMIT_PERIOD_DAYS as u64)
+}
+
+// Sent from `user_id` to `counterparty_id` within the limit's period: today
+// and the `period_days - 1` days before
+fn sent_in_period(user_id: u64, counterparty_id: u64, limit: &CounterpartyLimit) -> u64 {
+    let first_day = (today() + 1).saturating_sub(limit.period_days as u64);
+    SENT_WINDOWS
+        .with(|windows| windows.borrow().get(&(user_id, counterparty_id)))
+        .map_or(0, |window| window.sent_since(first_day))
+}
+
+fn record_sent(from_user_id: u64, to_user_id: u64, day: u64, amount: u64) {
+    SENT_WINDOWS.with(|windows| {
+        let mut windows = windows.borrow_mut();
+        let key = (from_user_id, to_user_id);
+        let mut window = windows.get(&key).unwrap_or_default();
+        let first_day = first_tracked_day();
+        window.days.retain(|(day, _)| *day >= first_day);
+        window.add(day, amount);
+        windows.insert(key, window);
+    });
+}
+
+/// Recomputes what users sent each counterparty from the stored
+/// transactions, used after a restore replaced them.
+pub(crate) fn rebuild_sent_windows() {
+    SENT_WINDOWS.with(|windows| clear_map(&mut windows.borrow_mut()));
+    let first_day = first_tracked_day();
+    let sends: Vec<(u64, u64, u64, u64)> = TRANSACTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, transaction)| transaction)
+            .filter(|transaction| transaction.created_at / NANOS_PER_DAY >= first_day)
+            .map(|transaction| {
+                (
+                    transaction.from_user_id.0,
+                    transaction.to_user_id.0,
+                    transaction.created_at / NANOS_PER_DAY,
+                    transaction.amount,
+                )
+            })
+            .collect()
+    });
+    for (from_user_id, to_user_id, day, amount) in sends {
+        record_sent(from_user_id, to_user_id, day, amount);
+    }
+}
+
+/// Fills the sent windows of a canister that capped counterparties before
+/// they were kept, from the transactions still in storage.
+pub(crate) fn seed_sent_windows_if_needed() {
+    if SENT_WINDOWS.with(|windows| windows.borrow().is_empty()) {
+        rebuild_sent_windows();
+    }
+}
+
+fn active_override(limit: &CounterpartyLimit) -> Option<LimitOverride> {
+    limit
+        .pending_override
+        .filter(|pending| current_time() < pending.expires_at)
+}
+
+/// Fails if sending `amount` would take `from_user_id` over their cap for
+/// `to_user_id`, unless they confirmed an override that covers it.
+pub(crate) fn ensure_within_limit(
+    from_user_id: u64,
+    to_user_id: u64,
+    amount: u64,
+) -> Result<(), WalletError> {
+    let Some(limit) = limit_of(from_user_id, to_user_id) else {
+        return Ok(());
+    };
+    let sent = sent_in_period(from_user_id, to_user_id, &limit);
+    let over = sent.saturating_add(amount).saturating_sub(limit.max_amount);
+    if over == 0 || active_override(&limit).is_some_and(|pending| pending.amount >= amount) {
+        return Ok(());
+    }
+    Err(WalletError::CounterpartyLimitExceeded {
+        counterparty_id: to_user_id,
+        remaining: limit.max_amount.saturating_sub(sent),
+    })
+}
+
+/// Called for every executed transfer; counts it towards the sender's caps,
+/// and an override is used up by the next transfer to its counterparty.
+pub(crate) fn record_transfer(from_user_id: u64, to_user_id: u64, amount: u64) {
+    record_sent(from_user_id, to_user_id, today(), amount);
+    COUNTERPARTY_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        let key = (from_user_id, to_user_id);
+        if let Some(mut limit) = limits.get(&key) {
+            if limit.pending_override.take().is_some() {
+                limits.insert(key, limit);
+            }
+        }
+    });
+}
+
+fn limit_status(
+    user_id: u64,
+    counterparty_id: u64,
+    limit: &CounterpartyLimit,
+) -> CounterpartyLimitStatus {
+    CounterpartyLimitStatus {
+        counterparty_id,
+        max_amount: limit.max_amount,
+        period_days: limit.period_days,
+        sent_in_period: sent_in_period(user_id, counterparty_id, limit),
+        override_amount: active_override(limit).map(|pending| pending.amount),
+    }
+}
+
+fn ensure_user_owner(user_id: u64) -> Result<(), WalletError> {
+    if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+        return Err(WalletError::not_found("user", user_id));
+    }
+    ensure_owner(user_id)
+}
+
+/// Fails unless the rules of both users let `from_user_id` move funds to
+/// `to_user_id`; `field` names the recipient in the error.
+pub(crate) fn ensure_transfer_permitted(
+    from_user_id: u64,
+    to_user_id: u64,
+    field: &str,
+) -> Result<(), WalletError> {
+    let sender_status = status_of(from_user_id, to_user_id);
+    if sender_status == Some(CounterpartyStatus::Blocked) {
+        return Err(WalletError::invalid(
+            field,
+            "the sender blocked this recipient",
+        ));
+    }
+    if is_allowlist_only(from_user_id) && sender_status != Some(CounterpartyStatus::Allowed) {
+        return Err(WalletError::invalid(
+            field,
+            "the sender can only send to approved counterparties",
+        ));
+    }
+    if status_of(to_user_id, from_user_id) == Some(CounterpartyStatus::Blocked) {
+        return Err(WalletError::invalid(
+            field,
+            "the recipient does not accept transfers from the sender",
+        ));
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_counterparty_rules(user_id: u64) -> Result<CounterpartyRules, WalletError> {
+    perf::instrument("get_counterparty_rules", || {
+        ensure_not_restoring()?;
+        ensure_user_owner(user_id)?;
+
+        let mut rules = CounterpartyRules {
+            user_id,
+            allowlist_only: is_allowlist_only(user_id),
+            blocked: Vec::new(),
+            allowed: Vec::new(),
+            limits: Vec::new(),
+        };
+        COUNTERPARTIES.with(|counterparties| {
+            for ((_, counterparty_id), status) in counterparties
+                .borrow()
+                .range((user_id, 0)..=(user_id, u64::MAX))
+            {
+                match status {
+                    CounterpartyStatus::Blocked => rules.blocked.push(counterparty_id),
+                    CounterpartyStatus::Allowed => rules.allowed.push(counterparty_id),
+                }
+            }
+        });
+        COUNTERPARTY_LIMITS.with(|limits| {
+            for ((_, counterparty_id), limit) in
+                limits.borrow().range((user_id, 0)..=(user_id, u64::MAX))
+            {
+                rules
+                    .limits
+                    .push(limit_status(user_id, counterparty_id, &limit));
+            }
+        });
+        Ok(rules)
+    })
+}
+
+/// Blocks or allows `counterparty_id` for `user_id`, or clears the decision
+/// with `None`.
+#[ic_cdk::update]
+fn set_counterparty_status(
+    user_id: u64,
+    counterparty_id: u64,
+    status: Option<CounterpartyStatus>,
+) -> Result<(), WalletError> {
+    perf::instrument("set_counterparty_status", || {
+        ensure_writable()?;
+        ensure_user_owner(user_id)?;
+
+        let Some(status) = status else {
+            COUNTERPARTIES.with(|counterparties| {
+                counterparties
+                    .borrow_mut()
+                    .remove(&(user_id, counterparty_id))
+            });
+            return Ok(());
+        };
+        if counterparty_id == user_id {
+            return Err(WalletError::invalid(
+                "counterparty_id",
+                "must be a different user",
+            ));
+        }
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&counterparty_id)) {
+            return Err(WalletError::not_found("user", counterparty_id));
+        }
+        COUNTERPARTIES.with(|counterparties| {
+            let mut counterparties = counterparties.borrow_mut();
+            let key = (user_id, counterparty_id);
+            if !counterparties.contains_key(&key)
+                && counterparties
+                    .range((user_id, 0)..=(user_id, u64::MAX))
+                    .count()
+                    >= MAX_COUNTERPARTIES
+            {
+                return Err(WalletError::invalid(
+                    "counterparty_id",
+                    &format!(
+                        "at most {} counterparties can be listed",
+                        MAX_COUNTERPARTIES
+                    ),
+                ));
+            }
+            counterparties.insert(key, status);
+            Ok(())
+        })
+    })
+}
+
+/// Limits `user_id` to sending to allowed counterparties.
+#[ic_cdk::update]
+fn set_allowlist_only(user_id: u64, enabled: bool) -> Result<(), WalletError> {
+    perf::instrument("set_allowlist_only", || {
+        ensure_writable()?;
+        ensure_user_owner(user_id)?;
+
+        ALLOWLIST_ONLY.with(|users| {
+            let mut users = users.borrow_mut();
+            if enabled {
+                users.insert(user_id, true);
+            } else {
+                users.remove(&user_id);
+            }
+        });
+        Ok(())
+    })
+}
+
+/// Caps what `user_id` sends to `counterparty_id` over a rolling period of
+/// `period_days`, or removes the cap with `None`.
+#[ic_cdk::update]
+pub(crate) fn set_counterparty_limit(
+    user_id: u64,
+    counterparty_id: u64,
+    limit: Option<CounterpartyLimitPayload>,
+) -> Result<(), WalletError> {
+    perf::instrument("set_counterparty_limit", || {
+        ensure_writable()?;
+        ensure_user_owner(user_id)?;
+
+        let Some(limit) = limit else {
+            COUNTERPARTY_LIMITS
+                .with(|limits| limits.borrow_mut().remove(&(user_id, counterparty_id)));
+            return Ok(());
+        };
+        if counterparty_id == user_id {
+            return Err(WalletError::invalid(
+                "counterparty_id",
+                "must be a different user",
+            ));
+        }
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&counterparty_id)) {
+            return Err(WalletError::not_found("user", counterparty_id));
+        }
+        if limit.max_amount == 0 {
+            return Err(WalletError::invalid(
+                "max_amount",
+                "must be greater than 0; block the counterparty instead",
+            ));
+        }
+        if limit.period_days == 0 || limit.period_days > MAX_LIMIT_PERIOD_DAYS {
+            return Err(WalletError::invalid(
+                "period_days",
+                &format!("must be between 1 and {}", MAX_LIMIT_PERIOD_DAYS),
+            ));
+        }
+        COUNTERPARTY_LIMITS.with(|limits| {
+            let mut limits = limits.borrow_mut();
+            let key = (user_id, counterparty_id);
+            if !limits.contains_key(&key)
+                && limits.range((user_id, 0)..=(user_id, u64::MAX)).count() >= MAX_COUNTERPARTIES
+            {
+                return Err(WalletError::invalid(
+                    "counterparty_id",
+                    &format!("at most {} limits can be set", MAX_COUNTERPARTIES),
+                ));
+            }
+            limits.insert(
+                key,
+                CounterpartyLimit {
+                    max_amount: limit.max_amount,
+                    period_days: limit.period_days,
+                    pending_override: None,
+                },
+            );
+            Ok(())
+        })
+    })
+}
+
+/// Second step of sending over a cap: lets the next transfer from `user_id`
+/// to `counterparty_id` of up to `amount` go over it, if sent within ten
+/// minutes.
+#[ic_cdk::update]
+fn confirm_limit_override(
+    user_id: u64,
+    counterparty_id: u64,
+    amount: u64,
+) -> Result<CounterpartyLimitStatus, WalletError> {
+    perf::instrument("confirm_limit_override", || {
+        ensure_writable()?;
+        ensure_user_owner(user_id)?;
+
+        let mut limit = limit_of(user_id, counterparty_id).ok_or(WalletError::NotFound {
+            entity: "counterparty limit".to_string(),
+            id: counterparty_id,
+        })?;
+        if amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        devices::record_activity(user_id);
+        limit.pending_override = Some(LimitOverride {
+            amount,
+            expires_at: current_time() + OVERRIDE_TTL_NANOS,
+        });
+        COUNTERPARTY_LIMITS.with(|limits| {
+            limits
+                .borrow_mut()
+                .insert((user_id, counterparty_id), limit)
+        });
+        Ok(limit_status(user_id, counterparty_id, &limit))
+    })
+}
@@ -0,0 +1,362 @@
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::events::{self, EventKind};
+use crate::ledger;
+use crate::manifest::{self, Storage};
+use crate::notifications::notify_admins;
+use crate::perf;
+use crate::{
+    current_time, ensure_admin, next_id, pause, token, Memory, WalletError, MEMORY_MANAGER,
+    USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_cdk::api::call::{msg_cycles_accept128, msg_cycles_available128};
+use ic_cdk::api::canister_balance128;
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+const CYCLES_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Freeze below 1T cycles unless the admins configure otherwise
+const DEFAULT_LOW_CYCLES_THRESHOLD: u128 = 1_000_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CyclesState {
+    monitor_enabled: bool,
+    low_threshold: u128,
+    // Set by the monitor while the balance is below `low_threshold`
+    frozen: bool,
+    last_sample_at: u64,
+    last_sample_balance: u128,
+    burn_per_day: Option<u128>,
+    // Cycles that buy one unit of balance through `deposit_with_cycles`;
+    // `None` turns cycles deposits off
+    deposit_cycles_per_unit: Option<u128>,
+}
+
+impl Default for CyclesState {
+    fn default() -> Self {
+        CyclesState {
+            monitor_enabled: false,
+            low_threshold: DEFAULT_LOW_CYCLES_THRESHOLD,
+            frozen: false,
+            last_sample_at: 0,
+            last_sample_balance: 0,
+            burn_per_day: None,
+            deposit_cycles_per_unit: None,
+        }
+    }
+}
+
+/// A balance top-up paid for with cycles.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CyclesDeposit {
+    id: u64,
+    user_id: u64,
+    cycles: u128,
+    amount: u64,
+    new_balance: u64,
+    created_at: u64,
+}
+
+impl Storable for CyclesDeposit {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CyclesState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static CYCLES_STATE: RefCell<Cell<CyclesState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+            CyclesState::default(),
+        )
+        .expect("Cannot create the cycles state cell")
+    );
+
+    static CYCLES_DEPOSIT_STORAGE: RefCell<StableBTreeMap<u64, CyclesDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+
+    static CYCLES_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::cell("cycles.cycles_state", 25, &CYCLES_STATE),
+        manifest::map("cycles.cycles_deposit_storage", 67, &CYCLES_DEPOSIT_STORAGE),
+    ]
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CyclesStatus {
+    balance: u128,
+    low_threshold: u128,
+    frozen: bool,
+    monitor_enabled: bool,
+    burn_per_day: Option<u128>,
+    projected_days_left: Option<u64>,
+    deposit_cycles_per_unit: Option<u128>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CyclesMonitorPayload {
+    enabled: bool,
+    low_threshold: Option<u128>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct WalletReceiveResult {
+    accepted: u64,
+}
+
+fn cycles_state() -> CyclesState {
+    CYCLES_STATE.with(|state| state.borrow().get().clone())
+}
+
+fn set_cycles_state(state: CyclesState) {
+    CYCLES_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("Cannot update the cycles state");
+}
+
+/// Rejects non-essential calls while the canister is low on cycles, so what
+/// is left goes to transfers and balance queries.
+pub(crate) fn ensure_not_frozen() -> Result<(), WalletError> {
+    if CYCLES_STATE.with(|state| state.borrow().get().frozen) {
+        return Err(WalletError::InvalidState {
+            reason: "The canister is low on cycles and only serves essential calls".to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn start_cycles_monitor() {
+    if !cycles_state().monitor_enabled {
+        return;
+    }
+    let timer = ic_cdk_timers::set_timer_interval(CYCLES_CHECK_INTERVAL, check_cycles);
+    CYCLES_TIMER.with(|slot| *slot.borrow_mut() = Some(timer));
+}
+
+fn stop_cycles_monitor() {
+    if let Some(timer) = CYCLES_TIMER.with(|slot| slot.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+}
+
+fn check_cycles() {
+    let mut state = cycles_state();
+    let now = current_time();
+    let balance = canister_balance128();
+
+    // Only a drop in balance between two samples says anything about burn
+    if state.last_sample_at > 0 && now > state.last_sample_at {
+        if let Some(burned) = state.last_sample_balance.checked_sub(balance) {
+            state.burn_per_day =
+                Some(burned * NANOS_PER_DAY / (now - state.last_sample_at) as u128);
+        }
+    }
+    state.last_sample_at = now;
+    state.last_sample_balance = balance;
+
+    let low = balance < state.low_threshold;
+    if low && !state.frozen {
+        notify_admins(format!(
+            "Cycles balance {} fell below {}; non-essential endpoints are frozen",
+            balance, state.low_threshold
+        ));
+    } else if !low && state.frozen {
+        notify_admins(format!(
+            "Cycles balance recovered to {}; all endpoints are available again",
+            balance
+        ));
+    }
+    state.frozen = low;
+    set_cycles_state(state);
+}
+
+#[ic_cdk::query]
+fn get_cycles_status() -> Result<CyclesStatus, WalletError> {
+    perf::instrument("get_cycles_status", || {
+        ensure_admin()?;
+
+        let state = cycles_state();
+        let balance = canister_balance128();
+        let projected_days_left = state
+            .burn_per_day
+            .filter(|burn| *burn > 0)
+            .map(|burn| (balance / burn).min(u64::MAX as u128) as u64);
+        Ok(CyclesStatus {
+            balance,
+            low_threshold: state.low_threshold,
+            frozen: state.frozen,
+            monitor_enabled: state.monitor_enabled,
+            burn_per_day: state.burn_per_day,
+            projected_days_left,
+            deposit_cycles_per_unit: state.deposit_cycles_per_unit,
+        })
+    })
+}
+
+// Anyone may top the canister up
+#[ic_cdk::update]
+fn wallet_receive() -> WalletReceiveResult {
+    perf::measure("wallet_receive", || {
+        let accepted = msg_cycles_accept128(msg_cycles_available128());
+        WalletReceiveResult {
+            accepted: accepted.min(u64::MAX as u128) as u64,
+        }
+    })
+}
+
+#[ic_cdk::update]
+fn set_cycles_monitor(payload: CyclesMonitorPayload) -> Result<(), WalletError> {
+    perf::instrument("set_cycles_monitor", || {
+        ensure_admin()?;
+        pause::ensure_not_read_only()?;
+
+        let mut state = cycles_state();
+        state.monitor_enabled = payload.enabled;
+        if let Some(low_threshold) = payload.low_threshold {
+            state.low_threshold = low_threshold;
+        }
+        if !payload.enabled {
+            state.frozen = false;
+        }
+        set_cycles_state(state);
+
+        stop_cycles_monitor();
+        if payload.enabled {
+            start_cycles_monitor();
+            check_cycles();
+        }
+        Ok(())
+    })
+}
+
+/// Credits the caller's account for the cycles attached to the call, at the
+/// rate the controllers set. Only whole multiples of the token's smallest
+/// unit are bought; the cycles left over are refunded. Cycles can only be
+/// attached by canisters, so the caller is typically a cycles wallet that
+/// owns the account.
+#[ic_cdk::update]
+fn deposit_with_cycles() -> Result<CyclesDeposit, WalletError> {
+    perf::instrument("deposit_with_cycles", || {
+        ensure_writable()?;
+        pause::ensure_transfers_allowed()?;
+
+        let user_id = caller_user_id()?;
+        let cycles_per_unit =
+            cycles_state()
+                .deposit_cycles_per_unit
+                .ok_or(WalletError::InvalidState {
+                    reason: "Cycles deposits are not enabled".to_string(),
+                })?;
+        let min_unit = token::min_unit() as u128;
+        let available = msg_cycles_available128();
+        let units = (available / cycles_per_unit).min(u64::MAX as u128);
+        let amount = (units - units % min_unit) as u64;
+        if amount == 0 {
+            return Err(WalletError::invalid(
+                "cycles",
+                &format!(
+                    "at least {} cycles must be attached",
+                    cycles_per_unit.saturating_mul(min_unit)
+                ),
+            ));
+        }
+        let id = next_id();
+        let new_balance = ledger::deposit(
+            ledger::EntryKind::CyclesDeposit { deposit_id: id },
+            user_id,
+            amount,
+        )?;
+
+        let cycles = msg_cycles_accept128(amount as u128 * cycles_per_unit);
+        let deposit = CyclesDeposit {
+            id,
+            user_id,
+            cycles,
+            amount,
+            new_balance,
+            created_at: current_time(),
+        };
+        CYCLES_DEPOSIT_STORAGE
+            .with(|storage| storage.borrow_mut().insert(deposit.id, deposit.clone()));
+        events::record(EventKind::FundsDeposited { user_id, amount });
+        Ok(deposit)
+    })
+}
+
+#[ic_cdk::query]
+fn list_cycles_deposits(user_id: u64) -> Result<Vec<CyclesDeposit>, WalletError> {
+    perf::instrument("list_cycles_deposits", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(CYCLES_DEPOSIT_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, deposit)| deposit)
+                .filter(|deposit| deposit.user_id == user_id)
+                .collect()
+        }))
+    })
+}
+
+/// Cycles that buy one unit of balance, or `None` while cycles deposits are
+/// off.
+#[ic_cdk::query]
+fn get_cycles_deposit_rate() -> Option<u128> {
+    perf::measure("get_cycles_deposit_rate", || {
+        cycles_state().deposit_cycles_per_unit
+    })
+}
+
+#[ic_cdk::update]
+fn set_cycles_deposit_rate(cycles_per_unit: Option<u128>) -> Result<(), WalletError> {
+    perf::instrument("set_cycles_deposit_rate", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if cycles_per_unit == Some(0) {
+            return Err(WalletError::invalid(
+                "cycles_per_unit",
+                "must be greater than 0",
+            ));
+        }
+        let mut state = cycles_state();
+        state.deposit_cycles_per_unit = cycles_per_unit;
+        set_cycles_state(state);
+        Ok(())
+    })
+}
@@ -0,0 +1,317 @@
+//! Webhook deliveries. Every call to a merchant's webhook is logged with the
+//! exact arguments it carried and its outcome, so operators can see which
+//! integrations are failing and replay what they missed once they are back
+//! up. Calls wait for the webhook's reply: a webhook canister that is
+//! stopped, missing or traps counts as a failed delivery.
+//!
+//! `replay_delivery` sends one delivery again and `replay_range` the failed
+//! deliveries created in a time window, each to the webhook it was first
+//! sent to and with its original arguments. Delivered and failed calls are
+//! counted per webhook endpoint. The log keeps the latest `MAX_DELIVERIES`
+//! deliveries.
+
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::payment_intents::MerchantWebhook;
+use crate::{current_time, ensure_admin, next_id, perf, Memory, WalletError, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_DELIVERIES: u64 = 5_000;
+// Upper bound of the calls one `replay_range` starts
+const MAX_REPLAY_BATCH: usize = 100;
+// A delivery still pending this long lost its reply, for instance to an
+// upgrade, and can be replayed
+const STALE_PENDING_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum DeliveryStatus {
+    // The call is in flight
+    Pending,
+    Delivered { at: u64 },
+    Failed { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Delivery {
+    id: u64,
+    merchant_id: u64,
+    // The payment intent the webhook was notified of
+    intent_id: u64,
+    webhook: MerchantWebhook,
+    created_at: u64,
+    status: DeliveryStatus,
+    attempts: u32,
+    last_attempt_at: u64,
+}
+
+impl Delivery {
+    fn replayable(&self, now: u64) -> bool {
+        self.status != DeliveryStatus::Pending
+            || now.saturating_sub(self.last_attempt_at) >= STALE_PENDING_NANOS
+    }
+}
+
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct EndpointCounters {
+    delivered: u64,
+    failed: u64,
+    last_delivered_at: Option<u64>,
+    last_failed_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct DeliveryStats {
+    // "<canister>.<method>"
+    endpoint: String,
+    delivered: u64,
+    failed: u64,
+    // Share of completed calls that were delivered, between 0 and 1
+    success_rate: f64,
+    last_delivered_at: Option<u64>,
+    last_failed_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Storable for Delivery {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for EndpointCounters {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static DELIVERIES: RefCell<StableBTreeMap<u64, Delivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110)))
+    ));
+
+    // Candid-encoded arguments of every logged delivery
+    static DELIVERY_ARGS: RefCell<StableBTreeMap<u64, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111)))
+    ));
+
+    static ENDPOINT_COUNTERS: RefCell<StableBTreeMap<String, EndpointCounters, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("deliveries.deliveries", 110, &DELIVERIES),
+        manifest::map("deliveries.delivery_args", 111, &DELIVERY_ARGS),
+        manifest::map("deliveries.endpoint_counters", 112, &ENDPOINT_COUNTERS),
+    ]
+}
+
+fn get_delivery_record(delivery_id: u64) -> Result<Delivery, WalletError> {
+    DELIVERIES
+        .with(|storage| storage.borrow().get(&delivery_id))
+        .ok_or(WalletError::not_found("delivery", delivery_id))
+}
+
+fn save_delivery(delivery: &Delivery) {
+    DELIVERIES.with(|storage| storage.borrow_mut().insert(delivery.id, delivery.clone()));
+}
+
+// Drops the oldest deliveries beyond `MAX_DELIVERIES`
+fn evict_oldest() {
+    while DELIVERIES.with(|storage| storage.borrow().len()) > MAX_DELIVERIES {
+        let Some((oldest_id, _)) = DELIVERIES.with(|storage| storage.borrow().first_key_value())
+        else {
+            return;
+        };
+        DELIVERIES.with(|storage| storage.borrow_mut().remove(&oldest_id));
+        DELIVERY_ARGS.with(|storage| storage.borrow_mut().remove(&oldest_id));
+    }
+}
+
+/// Logs a delivery of `args` to `webhook` for `intent_id` and sends it.
+pub(crate) fn enqueue(merchant_id: u64, intent_id: u64, webhook: MerchantWebhook, args: Vec<u8>) {
+    let now = current_time();
+    let delivery = Delivery {
+        id: next_id(),
+        merchant_id,
+        intent_id,
+        webhook,
+        created_at: now,
+        status: DeliveryStatus::Pending,
+        attempts: 0,
+        last_attempt_at: now,
+    };
+    DELIVERY_ARGS.with(|storage| storage.borrow_mut().insert(delivery.id, args));
+    save_delivery(&delivery);
+    evict_oldest();
+    attempt(delivery);
+}
+
+fn attempt(mut delivery: Delivery) {
+    let Some(args) = DELIVERY_ARGS.with(|storage| storage.borrow().get(&delivery.id)) else {
+        return;
+    };
+    delivery.status = DeliveryStatus::Pending;
+    delivery.attempts += 1;
+    delivery.last_attempt_at = current_time();
+    save_delivery(&delivery);
+    ic_cdk::spawn(async move {
+        let result = delivery.webhook.call(args).await;
+        // The canister turned read-only meanwhile; the delivery stays
+        // pending and becomes replayable once stale
+        if ensure_writable().is_err() {
+            return;
+        }
+        record_outcome(delivery.id, &delivery.webhook.endpoint(), result);
+    });
+}
+
+fn record_outcome(delivery_id: u64, endpoint: &str, result: Result<(), String>) {
+    let now = current_time();
+    ENDPOINT_COUNTERS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut counters = storage.get(&endpoint.to_string()).unwrap_or_default();
+        match &result {
+            Ok(()) => {
+                counters.delivered += 1;
+                counters.last_delivered_at = Some(now);
+            }
+            Err(reason) => {
+                counters.failed += 1;
+                counters.last_failed_at = Some(now);
+                counters.last_error = Some(reason.clone());
+            }
+        }
+        storage.insert(endpoint.to_string(), counters);
+    });
+    // Evicted while the call was in flight
+    let Ok(mut delivery) = get_delivery_record(delivery_id) else {
+        return;
+    };
+    delivery.status = match result {
+        Ok(()) => DeliveryStatus::Delivered { at: now },
+        Err(reason) => DeliveryStatus::Failed { reason },
+    };
+    save_delivery(&delivery);
+}
+
+/// Failed deliveries still in the log, newest first.
+#[ic_cdk::query]
+fn list_failed_deliveries() -> Result<Vec<Delivery>, WalletError> {
+    perf::instrument("list_failed_deliveries", || {
+        ensure_not_restoring()?;
+        ensure_admin()?;
+
+        let mut failed: Vec<Delivery> = DELIVERIES.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, delivery)| delivery)
+                .filter(|delivery| matches!(delivery.status, DeliveryStatus::Failed { .. }))
+                .collect()
+        });
+        failed.reverse();
+        Ok(failed)
+    })
+}
+
+/// Sends a delivery again, whether it failed or was delivered. A pending
+/// delivery can only be replayed once it is stale.
+#[ic_cdk::update]
+fn replay_delivery(delivery_id: u64) -> Result<Delivery, WalletError> {
+    perf::instrument("replay_delivery", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let delivery = get_delivery_record(delivery_id)?;
+        if !delivery.replayable(current_time()) {
+            return Err(WalletError::InvalidState {
+                reason: format!("Delivery {} is still in flight", delivery_id),
+            });
+        }
+        attempt(delivery);
+        get_delivery_record(delivery_id)
+    })
+}
+
+/// Replays the failed deliveries created between `from_ts` and `to_ts`,
+/// oldest first and at most `MAX_REPLAY_BATCH` per call, and returns how
+/// many it started. Calling again picks up the rest.
+#[ic_cdk::update]
+fn replay_range(from_ts: u64, to_ts: u64) -> Result<u64, WalletError> {
+    perf::instrument("replay_range", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if from_ts > to_ts {
+            return Err(WalletError::invalid("from_ts", "must not be after `to_ts`"));
+        }
+        let failed: Vec<Delivery> = DELIVERIES.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, delivery)| delivery)
+                .filter(|delivery| {
+                    (from_ts..=to_ts).contains(&delivery.created_at)
+                        && matches!(delivery.status, DeliveryStatus::Failed { .. })
+                })
+                .take(MAX_REPLAY_BATCH)
+                .collect()
+        });
+        let replayed = failed.len() as u64;
+        for delivery in failed {
+            attempt(delivery);
+        }
+        Ok(replayed)
+    })
+}
+
+/// Delivered and failed calls of every webhook endpoint, most failures
+/// first.
+#[ic_cdk::query]
+fn get_delivery_stats() -> Result<Vec<DeliveryStats>, WalletError> {
+    perf::instrument("get_delivery_stats", || {
+        ensure_not_restoring()?;
+        ensure_admin()?;
+
+        let mut stats: Vec<DeliveryStats> = ENDPOINT_COUNTERS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(endpoint, counters)| DeliveryStats {
+                    endpoint,
+                    delivered: counters.delivered,
+                    failed: counters.failed,
+                    success_rate: counters.delivered as f64
+                        / (counters.delivered + counters.failed).max(1) as f64,
+                    last_delivered_at: counters.last_delivered_at,
+                    last_failed_at: counters.last_failed_at,
+                    last_error: counters.last_error,
+                })
+                .collect()
+        });
+        stats.sort_by_key(|stats| std::cmp::Reverse(stats.failed));
+        Ok(stats)
+    })
+}
@@ -0,0 +1,213 @@
+//! Registry of the principals (devices) that acted on an account. Protected
+//! actions record the caller here; the first action from a principal the
+//! account has not seen before raises a security alert. Owners can revoke a
+//! device, after which it can no longer spend on the account's behalf.
+
+use crate::auth::{self, caller_user_id};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::events::{self, EventKind};
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::perf;
+use crate::{current_time, spenders, Memory, WalletError, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Devices are keyed by account first so one account's devices are adjacent
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct DeviceKey {
+    user_id: u64,
+    principal: Principal,
+}
+
+impl Storable for DeviceKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.user_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.principal.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (user_id, principal) = bytes.split_at(8);
+        DeviceKey {
+            user_id: u64::from_be_bytes(user_id.try_into().unwrap()),
+            principal: Principal::from_slice(principal),
+        }
+    }
+
+    // An 8 byte user id followed by a principal of at most 29 bytes
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 37,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Device {
+    principal: Principal,
+    // Supplied by the client, e.g. "Firefox on Linux"
+    label: Option<String>,
+    first_seen: u64,
+    last_seen: u64,
+    revoked_at: Option<u64>,
+}
+
+impl Storable for Device {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static DEVICE_STORAGE: RefCell<StableBTreeMap<DeviceKey, Device, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map("devices.device_storage", 48, &DEVICE_STORAGE)]
+}
+
+fn devices_of(user_id: u64) -> Vec<Device> {
+    let start = DeviceKey {
+        user_id,
+        principal: Principal::from_slice(&[]),
+    };
+    DEVICE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.user_id == user_id)
+            .map(|(_, device)| device)
+            .collect()
+    })
+}
+
+fn get_device(user_id: u64, principal: Principal) -> Option<Device> {
+    DEVICE_STORAGE.with(|storage| storage.borrow().get(&DeviceKey { user_id, principal }))
+}
+
+fn save_device(user_id: u64, device: Device) {
+    let key = DeviceKey {
+        user_id,
+        principal: device.principal,
+    };
+    DEVICE_STORAGE.with(|storage| storage.borrow_mut().insert(key, device));
+}
+
+/// Records that the caller performed a protected action on `user_id`. The
+/// first action of an unknown principal raises an alert, unless the account
+/// has no devices recorded yet.
+pub(crate) fn record_activity(user_id: u64) {
+    let principal = auth::caller();
+    let now = current_time();
+    if let Some(mut device) = get_device(user_id, principal) {
+        device.last_seen = now;
+        save_device(user_id, device);
+        return;
+    }
+
+    let first_device = devices_of(user_id).is_empty();
+    save_device(
+        user_id,
+        Device {
+            principal,
+            label: None,
+            first_seen: now,
+            last_seen: now,
+            revoked_at: None,
+        },
+    );
+    if !first_device {
+        events::record(EventKind::NewDeviceSeen { user_id, principal });
+        notify(
+            user_id,
+            NotificationKind::Security,
+            format!("A new device ({}) acted on your account", principal),
+        );
+    }
+}
+
+pub(crate) fn is_revoked(user_id: u64, principal: Principal) -> bool {
+    get_device(user_id, principal).is_some_and(|device| device.revoked_at.is_some())
+}
+
+/// Lifts a revocation because the owner explicitly granted the principal
+/// spending rights again.
+pub(crate) fn reinstate(user_id: u64, principal: Principal) {
+    if let Some(mut device) = get_device(user_id, principal) {
+        device.revoked_at = None;
+        save_device(user_id, device);
+    }
+}
+
+/// Labels the caller's device on the account it owns or spends for.
+#[ic_cdk::update]
+fn register_device(user_id: u64, label: String) -> Result<Device, WalletError> {
+    perf::instrument("register_device", || {
+        ensure_writable()?;
+
+        let principal = auth::caller();
+        if auth::owner_of(user_id) != Some(principal) && !spenders::is_spender(user_id, principal) {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller does not own or spend for user {}", user_id),
+            });
+        }
+        hardening::check_args(|fields| fields.required_text("label", &label, TextKind::Alias))?;
+        let label = label.trim().to_string();
+
+        record_activity(user_id);
+        let mut device = get_device(user_id, principal).expect("Device was just recorded");
+        device.label = Some(label);
+        save_device(user_id, device.clone());
+        Ok(device)
+    })
+}
+
+#[ic_cdk::query]
+fn list_my_devices() -> Result<Vec<Device>, WalletError> {
+    perf::instrument("list_my_devices", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        Ok(devices_of(user_id))
+    })
+}
+
+/// Revokes a device of the caller's account and its spending grant. The
+/// owner's own principal cannot be revoked.
+#[ic_cdk::update]
+fn revoke_device(principal: Principal) -> Result<Device, WalletError> {
+    perf::instrument("revoke_device", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        if principal == auth::caller() {
+            return Err(WalletError::invalid(
+                "principal",
+                "the owner's own device cannot be revoked",
+            ));
+        }
+        let mut device = get_device(user_id, principal).ok_or(WalletError::NotFoundByKey {
+            entity: "device".to_string(),
+            key: principal.to_text(),
+        })?;
+        if device.revoked_at.is_none() {
+            device.revoked_at = Some(current_time());
+            save_device(user_id, device.clone());
+        }
+        spenders::remove_grant(user_id, principal);
+        Ok(device)
+    })
+}
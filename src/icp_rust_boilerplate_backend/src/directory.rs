@@ -0,0 +1,196 @@
+//! User directory. `search_users` finds accounts by the start of their
+//! username or display name, so senders can pick a recipient without knowing
+//! its id. Matches come from a sorted index of lowercased names, read as a
+//! range rather than by scanning every account. Only the names a user's
+//! privacy settings show in search are indexed, and none at all once the
+//! owner turns discovery off.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::privacy::{self, Surface};
+use crate::{clear_map, Memory, User, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+const MAX_SEARCH_RESULTS: u32 = 50;
+const MAX_QUERY_LEN: usize = 64;
+
+/// What a search reveals about an account: the fields its privacy settings
+/// show in search.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct PublicProfile {
+    user_id: u64,
+    username: Option<String>,
+    display_name: Option<String>,
+    avatar: Option<String>,
+}
+
+thread_local! {
+    // "<lowercased name>\0<user id>" to user id; the id keeps keys unique
+    // for users sharing a display name
+    static DIRECTORY_INDEX: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+
+    // Users who turned discovery off
+    static HIDDEN_USERS: RefCell<StableBTreeMap<u64, bool, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("directory.directory_index", 68, &DIRECTORY_INDEX),
+        manifest::map("directory.hidden_users", 69, &HIDDEN_USERS),
+    ]
+}
+
+fn display_name(user: &User) -> String {
+    format!("{} {}", user.first_name, user.last_name)
+}
+
+// Lowercased, with control characters dropped so no name can reach into the
+// id part of a key
+fn normalize(text: &str) -> String {
+    text.trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn index_key(name: &str, user: &User) -> String {
+    format!("{}\0{}", normalize(name), user.id)
+}
+
+fn index_keys(user: &User) -> [String; 2] {
+    [
+        index_key(&user.username, user),
+        index_key(&display_name(user), user),
+    ]
+}
+
+fn is_hidden(user_id: u64) -> bool {
+    HIDDEN_USERS.with(|hidden| hidden.borrow().contains_key(&user_id))
+}
+
+/// Lists `user` under the current names it shows in search, unless it
+/// turned discovery off.
+pub(crate) fn index_user(user: &User) {
+    if is_hidden(user.id.0) {
+        return;
+    }
+    let (username_shown, display_name_shown) = privacy::searchable_names(user.id.0);
+    let mut keys = Vec::new();
+    if username_shown {
+        keys.push(index_key(&user.username, user));
+    }
+    if display_name_shown {
+        keys.push(index_key(&display_name(user), user));
+    }
+    DIRECTORY_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.insert(key, user.id.0);
+        }
+    });
+}
+
+/// Removes the entries of `user`'s names; called with the record as it was
+/// before a name changes.
+pub(crate) fn unindex_user(user: &User) {
+    DIRECTORY_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in index_keys(user) {
+            index.remove(&key);
+        }
+    });
+}
+
+/// Re-indexes every stored user, used after upgrades and restores.
+pub(crate) fn rebuild_index() {
+    let users: Vec<User> =
+        USER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, user)| user).collect());
+    DIRECTORY_INDEX.with(|index| clear_map(&mut index.borrow_mut()));
+    for user in &users {
+        index_user(user);
+    }
+}
+
+/// Up to `limit` discoverable users whose username or display name starts
+/// with `query`, ignoring case.
+#[ic_cdk::query]
+fn search_users(query: String, limit: u32) -> Result<Vec<PublicProfile>, WalletError> {
+    perf::instrument("search_users", || {
+        ensure_not_restoring()?;
+        // Only account holders can browse the directory
+        caller_user_id()?;
+
+        let prefix = normalize(&query);
+        if prefix.is_empty() || prefix.chars().count() > MAX_QUERY_LEN {
+            return Err(WalletError::invalid(
+                "query",
+                &format!("must be between 1 and {} characters", MAX_QUERY_LEN),
+            ));
+        }
+        if limit == 0 || limit > MAX_SEARCH_RESULTS {
+            return Err(WalletError::invalid(
+                "limit",
+                &format!("must be between 1 and {}", MAX_SEARCH_RESULTS),
+            ));
+        }
+
+        let mut user_ids = Vec::new();
+        let mut seen = BTreeSet::new();
+        DIRECTORY_INDEX.with(|index| {
+            for (key, user_id) in index.borrow().range(prefix.clone()..) {
+                if !key.starts_with(&prefix) || user_ids.len() == limit as usize {
+                    break;
+                }
+                if seen.insert(user_id) {
+                    user_ids.push(user_id);
+                }
+            }
+        });
+        Ok(user_ids
+            .into_iter()
+            .filter_map(|user_id| USER_STORAGE.with(|storage| storage.borrow().get(&user_id)))
+            .map(|user| {
+                let profile = privacy::visible_profile(&user, Surface::Search);
+                PublicProfile {
+                    user_id: user.id.0,
+                    username: profile.username,
+                    display_name: profile.display_name,
+                    avatar: profile.avatar,
+                }
+            })
+            .collect())
+    })
+}
+
+#[ic_cdk::update]
+fn set_discoverable(user_id: u64, discoverable: bool) -> Result<(), WalletError> {
+    perf::instrument("set_discoverable", || {
+        ensure_writable()?;
+
+        let user = USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("user", user_id))?;
+        ensure_owner(user_id)?;
+
+        if discoverable {
+            HIDDEN_USERS.with(|hidden| hidden.borrow_mut().remove(&user_id));
+            index_user(&user);
+        } else {
+            HIDDEN_USERS.with(|hidden| hidden.borrow_mut().insert(user_id, true));
+            unindex_user(&user);
+        }
+        Ok(())
+    })
+}
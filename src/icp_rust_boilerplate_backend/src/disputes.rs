@@ -0,0 +1,359 @@
+//! Transaction disputes. A participant can flag a transaction for a limited
+//! time after it executed; controllers then review and either uphold it or
+//! refund it, in which case the transfer is reversed automatically.
+
+use crate::auth::caller_user_id;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::events::{self, EventKind};
+use crate::hardening::{self, TextKind};
+use crate::ledger::{self, EntryKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, notify_admins, NotificationKind};
+use crate::supply::{self, Asset};
+use crate::{
+    current_time, ensure_admin, next_id, Memory, Transaction, WalletError, MEMORY_MANAGER,
+    TRANSACTION_STORAGE, USER_STORAGE,
+};
+use crate::{earning, ids, leaderboard, pause, perf, receipts, risk};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const DISPUTE_WINDOW_DAYS: u64 = 30;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum DisputeStatus {
+    Open,
+    UnderReview,
+    ResolvedRefund { refund_tx_id: u64 },
+    ResolvedUpheld,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize)]
+pub(crate) enum DisputeResolution {
+    Refund,
+    Uphold,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Dispute {
+    id: u64,
+    tx_id: u64,
+    claimant_user_id: u64,
+    reason: String,
+    status: DisputeStatus,
+    opened_at: u64,
+    updated_at: u64,
+    resolution_note: Option<String>,
+}
+
+impl Dispute {
+    fn is_resolved(&self) -> bool {
+        matches!(
+            self.status,
+            DisputeStatus::ResolvedRefund { .. } | DisputeStatus::ResolvedUpheld
+        )
+    }
+}
+
+impl Storable for Dispute {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static DISPUTE_STORAGE: RefCell<StableBTreeMap<u64, Dispute, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map(
+        "disputes.dispute_storage",
+        27,
+        &DISPUTE_STORAGE,
+    )]
+}
+
+fn get_transaction(tx_id: u64) -> Result<Transaction, WalletError> {
+    TRANSACTION_STORAGE
+        .with(|storage| storage.borrow().get(&tx_id))
+        .ok_or(WalletError::not_found("transaction", tx_id))
+}
+
+fn get_dispute_record(dispute_id: u64) -> Result<Dispute, WalletError> {
+    DISPUTE_STORAGE
+        .with(|storage| storage.borrow().get(&dispute_id))
+        .ok_or(WalletError::not_found("dispute", dispute_id))
+}
+
+fn save_dispute(dispute: &Dispute) {
+    DISPUTE_STORAGE.with(|storage| storage.borrow_mut().insert(dispute.id, dispute.clone()));
+}
+
+fn notify_participants(tx: &Transaction, message: String) {
+    notify(
+        tx.from_user_id.0,
+        NotificationKind::Dispute,
+        message.clone(),
+    );
+    if tx.to_user_id != tx.from_user_id {
+        notify(tx.to_user_id.0, NotificationKind::Dispute, message);
+    }
+}
+
+/// Moves the amount of `tx` back from its recipient to its sender and takes
+/// back the points the sender earned for it. Fails without side effects if
+/// the recipient no longer holds the amount.
+fn reverse_transaction(tx: &Transaction) -> Result<Transaction, WalletError> {
+    ledger::transfer(
+        EntryKind::Reversal { tx_id: tx.id.0 },
+        ledger::user(tx.to_user_id.0),
+        ledger::user(tx.from_user_id.0),
+        tx.amount,
+    )?;
+    let recipient_balance = ledger::user_balance(tx.to_user_id.0);
+    let sender_balance = ledger::user_balance(tx.from_user_id.0);
+    USER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut sender) = storage.get(&tx.from_user_id.0) {
+            let clawed_back = earning::awarded_for(tx.id.0, tx.amount).min(sender.points);
+            sender.points -= clawed_back;
+            supply::record_burned(Asset::Points, clawed_back as u128);
+            leaderboard::index_points(sender.id.0, sender.points);
+            storage.insert(sender.id.0, sender);
+        }
+    });
+
+    let id = ids::next_transaction_id();
+    let reversal = Transaction {
+        id,
+        from_user_id: tx.to_user_id,
+        to_user_id: tx.from_user_id,
+        amount: tx.amount,
+        created_at: current_time(),
+        memo: Some(format!("Refund of transaction {}", tx.id)),
+    };
+    TRANSACTION_STORAGE.with(|storage| storage.borrow_mut().insert(id.0, reversal.clone()));
+    risk::record_send(&reversal);
+    // The reversal runs from the original recipient back to the sender
+    receipts::record_balances_after(id.0, recipient_balance, sender_balance);
+    events::record(EventKind::TransferExecuted {
+        tx_id: id.0,
+        from_user_id: reversal.from_user_id.0,
+        to_user_id: reversal.to_user_id.0,
+        amount: reversal.amount,
+    });
+    Ok(reversal)
+}
+
+/// The status of the dispute raised on each disputed transaction.
+pub(crate) fn statuses_by_transaction() -> BTreeMap<u64, DisputeStatus> {
+    DISPUTE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, dispute)| (dispute.tx_id, dispute.status))
+            .collect()
+    })
+}
+
+/// Unresolved disputes on transactions `user_id` took part in.
+pub(crate) fn pending_disputes(user_id: u64) -> Vec<Dispute> {
+    DISPUTE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| !dispute.is_resolved())
+            .filter(|dispute| {
+                get_transaction(dispute.tx_id)
+                    .is_ok_and(|tx| tx.from_user_id.0 == user_id || tx.to_user_id.0 == user_id)
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn open_dispute(tx_id: u64, reason: String) -> Result<Dispute, WalletError> {
+    perf::instrument("open_dispute", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let tx = get_transaction(tx_id)?;
+        if user_id != tx.from_user_id.0 && user_id != tx.to_user_id.0 {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller is not a participant of transaction {}", tx_id),
+            });
+        }
+        hardening::check_args(|fields| fields.required_text("reason", &reason, TextKind::Reason))?;
+        let reason = reason.trim().to_string();
+        let now = current_time();
+        if now > tx.created_at + DISPUTE_WINDOW_DAYS * NANOS_PER_DAY {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "Transactions can only be disputed within {} days",
+                    DISPUTE_WINDOW_DAYS
+                ),
+            });
+        }
+        let already_disputed = DISPUTE_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .any(|(_, dispute)| dispute.tx_id == tx_id)
+        });
+        if already_disputed {
+            return Err(WalletError::AlreadyExists {
+                entity: "dispute".to_string(),
+                field: "tx_id".to_string(),
+            });
+        }
+
+        let dispute = Dispute {
+            id: next_id(),
+            tx_id,
+            claimant_user_id: user_id,
+            reason,
+            status: DisputeStatus::Open,
+            opened_at: now,
+            updated_at: now,
+            resolution_note: None,
+        };
+        save_dispute(&dispute);
+        notify_participants(
+            &tx,
+            format!("Dispute {} was opened on transaction {}", dispute.id, tx_id),
+        );
+        notify_admins(format!(
+            "Dispute {} on transaction {} awaits review",
+            dispute.id, tx_id
+        ));
+        Ok(dispute)
+    })
+}
+
+#[ic_cdk::query]
+fn get_dispute(dispute_id: u64) -> Result<Dispute, WalletError> {
+    perf::instrument("get_dispute", || {
+        ensure_not_restoring()?;
+
+        let dispute = get_dispute_record(dispute_id)?;
+        if ensure_admin().is_err() {
+            let tx = get_transaction(dispute.tx_id)?;
+            let user_id = caller_user_id()?;
+            if user_id != tx.from_user_id.0 && user_id != tx.to_user_id.0 {
+                return Err(WalletError::Unauthorized {
+                    reason: format!("caller is not a party to dispute {}", dispute_id),
+                });
+            }
+        }
+        Ok(dispute)
+    })
+}
+
+#[ic_cdk::query]
+fn list_disputes(status: Option<DisputeStatus>) -> Result<Vec<Dispute>, WalletError> {
+    perf::instrument("list_disputes", || {
+        ensure_admin()?;
+
+        Ok(DISPUTE_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, dispute)| dispute)
+                .filter(|dispute| status.is_none() || status == Some(dispute.status))
+                .collect()
+        }))
+    })
+}
+
+#[ic_cdk::update]
+fn review_dispute(dispute_id: u64) -> Result<Dispute, WalletError> {
+    perf::instrument("review_dispute", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let mut dispute = get_dispute_record(dispute_id)?;
+        if dispute.status != DisputeStatus::Open {
+            return Err(WalletError::InvalidState {
+                reason: format!("Dispute {} is not open", dispute_id),
+            });
+        }
+        dispute.status = DisputeStatus::UnderReview;
+        dispute.updated_at = current_time();
+        save_dispute(&dispute);
+        notify(
+            dispute.claimant_user_id,
+            NotificationKind::Dispute,
+            format!("Dispute {} is under review", dispute_id),
+        );
+        Ok(dispute)
+    })
+}
+
+#[ic_cdk::update]
+fn resolve_dispute(
+    dispute_id: u64,
+    resolution: DisputeResolution,
+    note: Option<String>,
+) -> Result<Dispute, WalletError> {
+    perf::instrument("resolve_dispute", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let mut dispute = get_dispute_record(dispute_id)?;
+        if dispute.is_resolved() {
+            return Err(WalletError::InvalidState {
+                reason: format!("Dispute {} is already resolved", dispute_id),
+            });
+        }
+        hardening::check_args(|fields| fields.optional_text("note", &note, TextKind::Reason))?;
+        let tx = get_transaction(dispute.tx_id)?;
+
+        let message = match resolution {
+            DisputeResolution::Refund => {
+                // The dispute stays pending if the refund cannot be executed
+                pause::ensure_transfers_allowed()?;
+                let reversal = reverse_transaction(&tx)?;
+                dispute.status = DisputeStatus::ResolvedRefund {
+                    refund_tx_id: reversal.id.0,
+                };
+                format!(
+                    "Dispute {} was resolved with a refund of {} in transaction {}",
+                    dispute_id, tx.amount, reversal.id
+                )
+            }
+            DisputeResolution::Uphold => {
+                dispute.status = DisputeStatus::ResolvedUpheld;
+                format!(
+                    "Dispute {} was resolved and transaction {} upheld",
+                    dispute_id, tx.id
+                )
+            }
+        };
+        dispute.resolution_note = note;
+        dispute.updated_at = current_time();
+        save_dispute(&dispute);
+        events::record(EventKind::DisputeResolved {
+            dispute_id,
+            from_user_id: tx.from_user_id.0,
+            to_user_id: tx.to_user_id.0,
+            refunded: matches!(resolution, DisputeResolution::Refund),
+        });
+        notify_participants(&tx, message);
+        Ok(dispute)
+    })
+}
@@ -0,0 +1,146 @@
+//! Dust. Admins set a minimum transfer amount, and sends below it are
+//! rejected, so fees and ledger entries are not spent on amounts too small
+//! to matter. Balances below the minimum left in a user's sub-accounts
+//! cannot be sent on their own; `consolidate_dust` sweeps them into the
+//! main balance, where they add up with the rest. Savings is the only
+//! sub-account today; points are not the currency and are never swept.
+//!
+//! `get_transfer_constraints` reports the thresholds so clients can
+//! validate amounts before they submit them.
+
+use crate::auth::ensure_owner;
+use crate::backup::ensure_writable;
+use crate::hardening::MAX_MEMO_LEN;
+use crate::ledger::{self, EntryKind, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::{
+    devices, ensure_admin, pause, perf, token, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+
+// Any amount above zero is accepted until an admin sets a minimum
+const DEFAULT_MIN_TRANSFER_AMOUNT: u64 = 1;
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct TransferConstraints {
+    min_transfer_amount: u64,
+    // Amounts must be a multiple of this many units
+    min_unit: u64,
+    max_memo_length: u32,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SweptDust {
+    account: LedgerAccount,
+    amount: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct DustConsolidation {
+    user_id: u64,
+    swept: Vec<SweptDust>,
+    // Main balance after the sweep
+    balance: u64,
+}
+
+thread_local! {
+    static MIN_TRANSFER_AMOUNT: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92))),
+            DEFAULT_MIN_TRANSFER_AMOUNT,
+        )
+        .expect("Cannot create the minimum transfer amount cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell(
+        "dust.min_transfer_amount",
+        92,
+        &MIN_TRANSFER_AMOUNT,
+    )]
+}
+
+fn min_transfer_amount() -> u64 {
+    MIN_TRANSFER_AMOUNT.with(|cell| *cell.borrow().get())
+}
+
+/// Rejects sends below the minimum transfer amount.
+pub(crate) fn ensure_above_minimum(field: &str, amount: u64) -> Result<(), WalletError> {
+    let minimum = min_transfer_amount();
+    if amount < minimum {
+        return Err(WalletError::invalid(
+            field,
+            &format!("must be at least {}", token::format_amount(minimum)),
+        ));
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_transfer_constraints() -> TransferConstraints {
+    perf::measure("get_transfer_constraints", || TransferConstraints {
+        min_transfer_amount: min_transfer_amount(),
+        min_unit: token::min_unit(),
+        max_memo_length: MAX_MEMO_LEN as u32,
+    })
+}
+
+#[ic_cdk::update]
+fn set_min_transfer_amount(amount: u64) -> Result<(), WalletError> {
+    perf::instrument("set_min_transfer_amount", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", amount)?;
+        MIN_TRANSFER_AMOUNT
+            .with(|cell| cell.borrow_mut().set(amount))
+            .map_err(|_| WalletError::Internal {
+                reason: "cannot update the minimum transfer amount".to_string(),
+            })?;
+        Ok(())
+    })
+}
+
+/// Sweeps every sub-account balance of the user that is below the minimum
+/// transfer amount into their main balance.
+#[ic_cdk::update]
+fn consolidate_dust(user_id: u64) -> Result<DustConsolidation, WalletError> {
+    perf::instrument("consolidate_dust", || {
+        ensure_writable()?;
+        pause::ensure_transfers_allowed()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+        devices::record_activity(user_id);
+
+        let minimum = min_transfer_amount();
+        let mut swept = Vec::new();
+        let savings = ledger::savings_balance(user_id);
+        if savings > 0 && savings < minimum {
+            let account = LedgerAccount::Savings { user_id };
+            ledger::transfer(
+                EntryKind::DustConsolidation { user_id },
+                account,
+                ledger::user(user_id),
+                savings,
+            )?;
+            swept.push(SweptDust {
+                account,
+                amount: savings,
+            });
+        }
+        Ok(DustConsolidation {
+            user_id,
+            swept,
+            balance: ledger::user_balance(user_id),
+        })
+    })
+}
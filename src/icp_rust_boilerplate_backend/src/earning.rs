@@ -0,0 +1,295 @@
+//! Points earning rules. The rate, minimum size, category multipliers,
+//! first-transaction bonus and daily cap are stored in stable memory and can
+//! be changed by the controllers; every executed transfer is evaluated
+//! against the rules active at that moment.
+
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::budgets::{self, Category};
+use crate::hardening;
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{
+    current_time, ensure_admin, Memory, TransactionPayload, WalletError, MEMORY_MANAGER,
+    TRANSACTION_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_MULTIPLIER_PERCENT: u32 = 1_000;
+const MAX_CATEGORY_MULTIPLIERS: usize = 50;
+// Points awarded by transfers made before the rules were configurable
+const LEGACY_UNITS_PER_POINT: u64 = 10;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CategoryMultiplier {
+    category: Category,
+    // 150 earns one and a half times the base points
+    multiplier_percent: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct EarningRules {
+    // Units of the wallet's token that earn one point
+    units_per_point: u64,
+    // Smaller transfers earn nothing
+    min_transaction_amount: u64,
+    category_multipliers: Vec<CategoryMultiplier>,
+    // Extra points for a sender's first transfer
+    first_transaction_bonus: u64,
+    daily_cap: Option<u64>,
+}
+
+impl Default for EarningRules {
+    // Award 1 point for every 10 units of currency
+    fn default() -> Self {
+        EarningRules {
+            units_per_point: LEGACY_UNITS_PER_POINT,
+            min_transaction_amount: 0,
+            category_multipliers: Vec::new(),
+            first_transaction_bonus: 0,
+            daily_cap: None,
+        }
+    }
+}
+
+/// The points a transfer earns, broken down by rule.
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+pub(crate) struct PointsQuote {
+    base_points: u64,
+    category_bonus: u64,
+    first_transaction_bonus: u64,
+    // Points withheld because of the daily cap
+    capped: u64,
+    total: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EarningState {
+    // Day (since the epoch) that `earned_today` refers to
+    day: u64,
+    earned_today: u64,
+    has_transacted: bool,
+}
+
+impl Storable for EarningRules {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for EarningState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static EARNING_RULES: RefCell<Cell<EarningRules, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))),
+            EarningRules::default(),
+        )
+        .expect("Cannot create the earning rules cell")
+    );
+
+    static EARNING_STATE: RefCell<StableBTreeMap<u64, EarningState, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    // Points each transaction earned its sender, so a refund takes back
+    // exactly that many
+    static AWARDED_POINTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::cell("earning.earning_rules", 49, &EARNING_RULES),
+        manifest::map("earning.earning_state", 50, &EARNING_STATE),
+        manifest::map("earning.awarded_points", 51, &AWARDED_POINTS),
+    ]
+}
+
+fn earning_rules() -> EarningRules {
+    EARNING_RULES.with(|rules| rules.borrow().get().clone())
+}
+
+fn earning_state(user_id: u64) -> EarningState {
+    EARNING_STATE
+        .with(|states| states.borrow().get(&user_id))
+        .unwrap_or_else(|| EarningState {
+            // Senders from before the rules existed are not new
+            has_transacted: TRANSACTION_STORAGE.with(|storage| {
+                storage
+                    .borrow()
+                    .iter()
+                    .any(|(_, transaction)| transaction.from_user_id.0 == user_id)
+            }),
+            ..EarningState::default()
+        })
+}
+
+fn quote(user_id: u64, amount: u64, category: Option<&Category>) -> PointsQuote {
+    let rules = earning_rules();
+    if amount < rules.min_transaction_amount {
+        return PointsQuote::default();
+    }
+    let base_points = amount / rules.units_per_point;
+    let multiplier = category
+        .and_then(|category| {
+            rules
+                .category_multipliers
+                .iter()
+                .find(|multiplier| &multiplier.category == category)
+        })
+        .map_or(100, |multiplier| multiplier.multiplier_percent as u64);
+    // Multipliers below 100% reduce the base points
+    let multiplied = (base_points as u128 * multiplier as u128 / 100) as u64;
+    let category_bonus = multiplied.saturating_sub(base_points);
+    let base_points = base_points.min(multiplied);
+
+    let state = earning_state(user_id);
+    let first_transaction_bonus = if state.has_transacted {
+        0
+    } else {
+        rules.first_transaction_bonus
+    };
+    let uncapped = multiplied.saturating_add(first_transaction_bonus);
+    let total = match rules.daily_cap {
+        Some(cap) => {
+            let earned_today = if state.day == current_time() / NANOS_PER_DAY {
+                state.earned_today
+            } else {
+                0
+            };
+            uncapped.min(cap.saturating_sub(earned_today))
+        }
+        None => uncapped,
+    };
+    PointsQuote {
+        base_points,
+        category_bonus,
+        first_transaction_bonus,
+        capped: uncapped - total,
+        total,
+    }
+}
+
+/// Evaluates the rules for a transfer that is being executed and records the
+/// outcome. Returns the points to award to the sender.
+pub(crate) fn award(tx_id: u64, user_id: u64, amount: u64, category: Option<&Category>) -> u64 {
+    let points = quote(user_id, amount, category).total;
+    let mut state = earning_state(user_id);
+    let today = current_time() / NANOS_PER_DAY;
+    if state.day != today {
+        state.day = today;
+        state.earned_today = 0;
+    }
+    state.earned_today = state.earned_today.saturating_add(points);
+    state.has_transacted = true;
+    EARNING_STATE.with(|states| states.borrow_mut().insert(user_id, state));
+    AWARDED_POINTS.with(|awarded| awarded.borrow_mut().insert(tx_id, points));
+    points
+}
+
+/// Points the sender of `tx_id` earned for it.
+pub(crate) fn awarded_for(tx_id: u64, amount: u64) -> u64 {
+    AWARDED_POINTS
+        .with(|awarded| awarded.borrow().get(&tx_id))
+        .unwrap_or(amount / LEGACY_UNITS_PER_POINT)
+}
+
+/// Points a transfer would earn right now, without executing it.
+pub(crate) fn preview(payload: &TransactionPayload) -> u64 {
+    quote(
+        payload.from_user_id,
+        payload.amount,
+        payload.category.as_ref(),
+    )
+    .total
+}
+
+#[ic_cdk::query]
+fn simulate_points(payload: TransactionPayload) -> Result<PointsQuote, WalletError> {
+    perf::instrument("simulate_points", || {
+        ensure_not_restoring()?;
+
+        hardening::check(&payload)?;
+        if let Some(category) = &payload.category {
+            budgets::validate_category(category)?;
+        }
+        Ok(quote(
+            payload.from_user_id,
+            payload.amount,
+            payload.category.as_ref(),
+        ))
+    })
+}
+
+#[ic_cdk::query]
+fn get_earning_rules() -> EarningRules {
+    perf::measure("get_earning_rules", earning_rules)
+}
+
+#[ic_cdk::update]
+fn set_earning_rules(rules: EarningRules) -> Result<(), WalletError> {
+    perf::instrument("set_earning_rules", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if rules.units_per_point == 0 {
+            return Err(WalletError::invalid(
+                "units_per_point",
+                "must be greater than 0",
+            ));
+        }
+        if rules.category_multipliers.len() > MAX_CATEGORY_MULTIPLIERS {
+            return Err(WalletError::invalid(
+                "category_multipliers",
+                &format!("must have at most {} entries", MAX_CATEGORY_MULTIPLIERS),
+            ));
+        }
+        for (index, multiplier) in rules.category_multipliers.iter().enumerate() {
+            budgets::validate_category(&multiplier.category)?;
+            if multiplier.multiplier_percent > MAX_MULTIPLIER_PERCENT {
+                return Err(WalletError::invalid(
+                    "multiplier_percent",
+                    &format!("must be at most {}", MAX_MULTIPLIER_PERCENT),
+                ));
+            }
+            if rules.category_multipliers[..index]
+                .iter()
+                .any(|other| other.category == multiplier.category)
+            {
+                return Err(WalletError::invalid(
+                    "category_multipliers",
+                    "must not repeat a category",
+                ));
+            }
+        }
+
+        EARNING_RULES
+            .with(|cell| cell.borrow_mut().set(rules))
+            .expect("Cannot update the earning rules");
+        Ok(())
+    })
+}
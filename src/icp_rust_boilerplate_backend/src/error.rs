@@ -0,0 +1,267 @@
+use crate::hardening::FieldError;
+use std::fmt;
+
+/// Error returned by every v2 endpoint. Variants carry typed fields so
+/// clients can react to a failure without parsing its text.
+#[derive(candid::CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) enum WalletError {
+    InvalidPayload {
+        field: String,
+        reason: String,
+    },
+    // Every field of the payload that failed input hardening
+    InvalidFields {
+        errors: Vec<FieldError>,
+    },
+    NotFound {
+        entity: String,
+        id: u64,
+    },
+    NotFoundByKey {
+        entity: String,
+        key: String,
+    },
+    AlreadyExists {
+        entity: String,
+        field: String,
+    },
+    InsufficientBalance {
+        available: u64,
+        required: u64,
+    },
+    InsufficientPoints {
+        available: u64,
+        required: u64,
+    },
+    Overflow {
+        field: String,
+    },
+    Unauthorized {
+        reason: String,
+    },
+    RestoreInProgress,
+    Paused {
+        reason: String,
+    },
+    // The canister is read-only, for instance while it is being migrated
+    MaintenanceMode {
+        reason: String,
+    },
+    InvalidState {
+        reason: String,
+    },
+    Internal {
+        reason: String,
+    },
+    // The transfer was held for a risk review instead of executing
+    UnderReview {
+        review_id: u64,
+    },
+    // The recipient accepts incoming transfers; the funds are held until
+    // they do
+    AwaitingAcceptance {
+        incoming_id: u64,
+    },
+    // The transfer would exceed the sender's cap for this recipient; the
+    // sender can confirm an override and send again
+    CounterpartyLimitExceeded {
+        counterparty_id: u64,
+        remaining: u64,
+    },
+    // The owner locked the account down; nothing leaves it until it is
+    // unlocked
+    AccountLocked {
+        user_id: u64,
+    },
+}
+
+impl WalletError {
+    pub(crate) fn invalid(field: &str, reason: &str) -> Self {
+        WalletError::InvalidPayload {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub(crate) fn not_found(entity: &str, id: u64) -> Self {
+        WalletError::NotFound {
+            entity: entity.to_string(),
+            id,
+        }
+    }
+
+    /// Stable machine-readable code of the error, the key its message is
+    /// looked up by in the message catalog.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            WalletError::InvalidPayload { .. } => "INVALID_PAYLOAD",
+            WalletError::InvalidFields { .. } => "INVALID_FIELDS",
+            WalletError::NotFound { .. } => "NOT_FOUND",
+            WalletError::NotFoundByKey { .. } => "NOT_FOUND_BY_KEY",
+            WalletError::AlreadyExists { .. } => "ALREADY_EXISTS",
+            WalletError::InsufficientBalance { .. } => "INSUFFICIENT_BALANCE",
+            WalletError::InsufficientPoints { .. } => "INSUFFICIENT_POINTS",
+            WalletError::Overflow { .. } => "OVERFLOW",
+            WalletError::Unauthorized { .. } => "UNAUTHORIZED",
+            WalletError::RestoreInProgress => "RESTORE_IN_PROGRESS",
+            WalletError::Paused { .. } => "PAUSED",
+            WalletError::MaintenanceMode { .. } => "MAINTENANCE_MODE",
+            WalletError::InvalidState { .. } => "INVALID_STATE",
+            WalletError::Internal { .. } => "INTERNAL",
+            WalletError::UnderReview { .. } => "UNDER_REVIEW",
+            WalletError::AwaitingAcceptance { .. } => "AWAITING_ACCEPTANCE",
+            WalletError::CounterpartyLimitExceeded { .. } => "COUNTERPARTY_LIMIT_EXCEEDED",
+            WalletError::AccountLocked { .. } => "ACCOUNT_LOCKED",
+        }
+    }
+
+    /// The fields of the error by name, for the `{name}` placeholders of a
+    /// catalog template.
+    pub(crate) fn params(&self) -> Vec<(&'static str, String)> {
+        match self {
+            WalletError::InvalidPayload { field, reason } => {
+                vec![("field", field.clone()), ("reason", reason.clone())]
+            }
+            WalletError::InvalidFields { errors } => vec![
+                (
+                    "fields",
+                    errors
+                        .iter()
+                        .map(|error| error.field.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                ("count", errors.len().to_string()),
+            ],
+            WalletError::NotFound { entity, id } => {
+                vec![("entity", entity.clone()), ("id", id.to_string())]
+            }
+            WalletError::NotFoundByKey { entity, key } => {
+                vec![("entity", entity.clone()), ("key", key.clone())]
+            }
+            WalletError::AlreadyExists { entity, field } => {
+                vec![("entity", entity.clone()), ("field", field.clone())]
+            }
+            WalletError::InsufficientBalance {
+                available,
+                required,
+            }
+            | WalletError::InsufficientPoints {
+                available,
+                required,
+            } => vec![
+                ("available", available.to_string()),
+                ("required", required.to_string()),
+            ],
+            WalletError::Overflow { field } => vec![("field", field.clone())],
+            WalletError::Unauthorized { reason }
+            | WalletError::Paused { reason }
+            | WalletError::MaintenanceMode { reason }
+            | WalletError::InvalidState { reason }
+            | WalletError::Internal { reason } => vec![("reason", reason.clone())],
+            WalletError::RestoreInProgress => Vec::new(),
+            WalletError::UnderReview { review_id } => vec![("review_id", review_id.to_string())],
+            WalletError::AwaitingAcceptance { incoming_id } => {
+                vec![("incoming_id", incoming_id.to_string())]
+            }
+            WalletError::CounterpartyLimitExceeded {
+                counterparty_id,
+                remaining,
+            } => vec![
+                ("counterparty_id", counterparty_id.to_string()),
+                ("remaining", remaining.to_string()),
+            ],
+            WalletError::AccountLocked { user_id } => vec![("user_id", user_id.to_string())],
+        }
+    }
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::InvalidPayload { field, reason } => {
+                write!(f, "Invalid '{}': {}", field, reason)
+            }
+            WalletError::InvalidFields { errors } => {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|error| format!("'{}' {}", error.field, error.reason))
+                    .collect();
+                write!(f, "Invalid fields: {}", errors.join("; "))
+            }
+            WalletError::NotFound { entity, id } => {
+                write!(f, "{} {} not found", capitalize(entity), id)
+            }
+            WalletError::NotFoundByKey { entity, key } => {
+                write!(f, "{} '{}' not found", capitalize(entity), key)
+            }
+            WalletError::AlreadyExists { entity, field } => {
+                write!(
+                    f,
+                    "{} with this {} already exists",
+                    capitalize(entity),
+                    field
+                )
+            }
+            WalletError::InsufficientBalance {
+                available,
+                required,
+            } => write!(
+                f,
+                "Insufficient balance: {} available, {} required",
+                available, required
+            ),
+            WalletError::InsufficientPoints {
+                available,
+                required,
+            } => write!(
+                f,
+                "Insufficient points: {} available, {} required",
+                available, required
+            ),
+            WalletError::Overflow { field } => write!(f, "'{}' would overflow", field),
+            WalletError::Unauthorized { reason } => write!(f, "Unauthorized: {}", reason),
+            WalletError::RestoreInProgress => write!(
+                f,
+                "Canister is being restored from a backup, try again later"
+            ),
+            WalletError::Paused { reason } => write!(f, "Canister is paused: {}", reason),
+            WalletError::MaintenanceMode { reason } => {
+                write!(f, "Canister is read-only for maintenance: {}", reason)
+            }
+            WalletError::InvalidState { reason } => write!(f, "{}", reason),
+            WalletError::Internal { reason } => write!(f, "Internal error: {}", reason),
+            WalletError::UnderReview { review_id } => write!(
+                f,
+                "Transfer is held for review {} and runs once approved",
+                review_id
+            ),
+            WalletError::AwaitingAcceptance { incoming_id } => write!(
+                f,
+                "Transfer is held as incoming transfer {} until the recipient accepts it",
+                incoming_id
+            ),
+            WalletError::CounterpartyLimitExceeded {
+                counterparty_id,
+                remaining,
+            } => write!(
+                f,
+                "Transfer exceeds the limit for user {}: {} left in this period",
+                counterparty_id, remaining
+            ),
+            WalletError::AccountLocked { user_id } => write!(
+                f,
+                "Account {} is locked; outgoing operations resume once it is unlocked",
+                user_id
+            ),
+        }
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
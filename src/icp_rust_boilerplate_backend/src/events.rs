@@ -0,0 +1,412 @@
+//! Global event journal. Every state change worth syncing is appended with a
+//! monotonically increasing sequence number, so frontends and indexers can
+//! poll `get_events_since` instead of re-reading whole histories. Old events
+//! are pruned by the retention job. `get_activity_feed` reads the same
+//! journal backwards as one user's activity.
+
+use crate::auth::{self, ensure_owner, user_of};
+use crate::backup::ensure_not_restoring;
+use crate::lockdown::UnlockMethod;
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{
+    current_time, ensure_admin, IdCell, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_EVENTS_PER_PAGE: u64 = 500;
+// Whatever the retention period, never more than the latest 100k events
+// are kept
+const MAX_RETAINED_EVENTS: u64 = 100_000;
+const MAX_FEED_ITEMS: u64 = 100;
+// The feed reads the journal backwards this many events at a time, and
+// returns a cursor after reading this many in total even if the page is not
+// full, so a quiet account cannot make a query read the whole journal
+const FEED_SCAN_WINDOW: u64 = 500;
+const MAX_FEED_SCANNED: u64 = 10_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum EventKind {
+    UserCreated {
+        user_id: u64,
+    },
+    FundsDeposited {
+        user_id: u64,
+        amount: u64,
+    },
+    TransferExecuted {
+        tx_id: u64,
+        from_user_id: u64,
+        to_user_id: u64,
+        amount: u64,
+    },
+    PointsAwarded {
+        user_id: u64,
+        points: u64,
+    },
+    PointsRedeemed {
+        user_id: u64,
+        points: u64,
+    },
+    PointsTransferred {
+        transfer_id: u64,
+        from_user_id: u64,
+        to_user_id: u64,
+        points: u64,
+    },
+    ReconciliationBreak {
+        expected_total: u64,
+        actual_total: u64,
+    },
+    NewDeviceSeen {
+        user_id: u64,
+        principal: Principal,
+    },
+    ExternalTransferSent {
+        transfer_id: u64,
+        user_id: u64,
+        peer_canister: Principal,
+        amount: u64,
+    },
+    ExternalTransferReceived {
+        transfer_id: u64,
+        user_id: u64,
+        peer_canister: Principal,
+        amount: u64,
+    },
+    BalanceAdjusted {
+        adjustment_id: u64,
+        user_id: u64,
+        delta: i64,
+    },
+    // A transfer waiting for its recipient to accept it
+    TransferRequested {
+        incoming_id: u64,
+        from_user_id: u64,
+        to_user_id: u64,
+        amount: u64,
+    },
+    LowBalanceAlert {
+        alert_id: u64,
+        user_id: u64,
+        balance: u64,
+    },
+    TransferReviewRejected {
+        review_id: u64,
+        user_id: u64,
+    },
+    DisputeResolved {
+        dispute_id: u64,
+        from_user_id: u64,
+        to_user_id: u64,
+        refunded: bool,
+    },
+    AccountLocked {
+        user_id: u64,
+    },
+    AccountUnlocked {
+        user_id: u64,
+        method: UnlockMethod,
+    },
+}
+
+/// What an event means to a user, for filtering their activity feed.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ActivityType {
+    // Account creation, new devices and lockdowns
+    Account,
+    Deposit,
+    Transfer,
+    PointsEarned,
+    PointsRedemption,
+    PointsTransfer,
+    Request,
+    Alert,
+    // Adjustments, review decisions and dispute resolutions by admins
+    AdminAction,
+}
+
+impl EventKind {
+    fn involves(&self, user_id: u64) -> bool {
+        match *self {
+            EventKind::UserCreated { user_id: id }
+            | EventKind::FundsDeposited { user_id: id, .. }
+            | EventKind::PointsAwarded { user_id: id, .. }
+            | EventKind::PointsRedeemed { user_id: id, .. }
+            | EventKind::NewDeviceSeen { user_id: id, .. }
+            | EventKind::ExternalTransferSent { user_id: id, .. }
+            | EventKind::ExternalTransferReceived { user_id: id, .. }
+            | EventKind::BalanceAdjusted { user_id: id, .. }
+            | EventKind::LowBalanceAlert { user_id: id, .. }
+            | EventKind::TransferReviewRejected { user_id: id, .. }
+            | EventKind::AccountLocked { user_id: id }
+            | EventKind::AccountUnlocked { user_id: id, .. } => id == user_id,
+            EventKind::TransferExecuted {
+                from_user_id,
+                to_user_id,
+                ..
+            }
+            | EventKind::PointsTransferred {
+                from_user_id,
+                to_user_id,
+                ..
+            }
+            | EventKind::TransferRequested {
+                from_user_id,
+                to_user_id,
+                ..
+            }
+            | EventKind::DisputeResolved {
+                from_user_id,
+                to_user_id,
+                ..
+            } => from_user_id == user_id || to_user_id == user_id,
+            EventKind::ReconciliationBreak { .. } => false,
+        }
+    }
+
+    fn activity_type(&self) -> Option<ActivityType> {
+        match self {
+            EventKind::UserCreated { .. }
+            | EventKind::NewDeviceSeen { .. }
+            | EventKind::AccountLocked { .. }
+            | EventKind::AccountUnlocked { .. } => Some(ActivityType::Account),
+            EventKind::FundsDeposited { .. } => Some(ActivityType::Deposit),
+            EventKind::TransferExecuted { .. }
+            | EventKind::ExternalTransferSent { .. }
+            | EventKind::ExternalTransferReceived { .. } => Some(ActivityType::Transfer),
+            EventKind::PointsAwarded { .. } => Some(ActivityType::PointsEarned),
+            EventKind::PointsRedeemed { .. } => Some(ActivityType::PointsRedemption),
+            EventKind::PointsTransferred { .. } => Some(ActivityType::PointsTransfer),
+            EventKind::TransferRequested { .. } => Some(ActivityType::Request),
+            EventKind::LowBalanceAlert { .. } => Some(ActivityType::Alert),
+            EventKind::BalanceAdjusted { .. }
+            | EventKind::TransferReviewRejected { .. }
+            | EventKind::DisputeResolved { .. } => Some(ActivityType::AdminAction),
+            EventKind::ReconciliationBreak { .. } => None,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Event {
+    seq: u64,
+    at: u64,
+    kind: EventKind,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct EventPage {
+    events: Vec<Event>,
+    // Pass back as `since` to continue; unchanged when nothing new happened
+    last_seq: u64,
+    // Oldest event still retained. A client whose cursor is below
+    // `oldest_seq - 1` missed pruned events and has to resynchronize
+    oldest_seq: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ActivityPage {
+    // Newest first
+    items: Vec<Event>,
+    // Pass back as `cursor` for older items; `None` once the oldest retained
+    // event was read
+    next_cursor: Option<u64>,
+}
+
+impl Storable for Event {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static EVENT_STORAGE: RefCell<StableBTreeMap<u64, Event, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // Kept apart from the journal so pruning never reuses a sequence number
+    static EVENT_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), 1)
+            .expect("Cannot create the event sequence")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("events.event_storage", 31, &EVENT_STORAGE),
+        manifest::cell("events.event_seq", 32, &EVENT_SEQ),
+    ]
+}
+
+/// Appends an event to the journal. Sequence numbers start at 1.
+pub(crate) fn record(kind: EventKind) {
+    let seq = EVENT_SEQ.with(|seq| *seq.borrow().get());
+    EVENT_SEQ
+        .with(|cell| cell.borrow_mut().set(seq + 1))
+        .expect("Cannot increment the event sequence");
+    let event = Event {
+        seq,
+        at: current_time(),
+        kind,
+    };
+    EVENT_STORAGE.with(|storage| storage.borrow_mut().insert(seq, event));
+}
+
+/// Removes the events recorded before `cutoff` and those beyond the latest
+/// `MAX_RETAINED_EVENTS`; returns how many were removed.
+pub(crate) fn prune(cutoff: u64) -> u64 {
+    let next_seq = EVENT_SEQ.with(|seq| *seq.borrow().get());
+    let min_retained_seq = next_seq.saturating_sub(MAX_RETAINED_EVENTS);
+    EVENT_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let stale: Vec<u64> = storage
+            .iter()
+            .take_while(|(seq, event)| *seq < min_retained_seq || event.at < cutoff)
+            .map(|(seq, _)| seq)
+            .collect();
+        for seq in &stale {
+            storage.remove(seq);
+        }
+        stale.len() as u64
+    })
+}
+
+/// Returns the events among the next `limit` after `since`. Controllers see
+/// every event; other callers only those involving their account.
+#[ic_cdk::query]
+pub(crate) fn get_events_since(since: u64, limit: u64) -> Result<EventPage, WalletError> {
+    perf::instrument("get_events_since", || {
+        ensure_not_restoring()?;
+
+        let user_id = match ensure_admin() {
+            Ok(()) => None,
+            Err(_) => Some(user_of(auth::caller()).ok_or(WalletError::Unauthorized {
+                reason: "caller does not own an account".to_string(),
+            })?),
+        };
+        if limit == 0 || limit > MAX_EVENTS_PER_PAGE {
+            return Err(WalletError::invalid(
+                "limit",
+                &format!("must be between 1 and {}", MAX_EVENTS_PER_PAGE),
+            ));
+        }
+
+        EVENT_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            let oldest_seq = match storage.first_key_value() {
+                Some((seq, _)) => seq,
+                None => EVENT_SEQ.with(|seq| *seq.borrow().get()),
+            };
+
+            // Events hidden from the caller still count against `limit` and
+            // advance the cursor
+            let mut last_seq = since;
+            let mut events = Vec::new();
+            for (seq, event) in storage.range(since + 1..).take(limit as usize) {
+                last_seq = seq;
+                let visible = match user_id {
+                    Some(user_id) => event.kind.involves(user_id),
+                    None => true,
+                };
+                if visible {
+                    events.push(event);
+                }
+            }
+            Ok(EventPage {
+                events,
+                last_seq,
+                oldest_seq,
+            })
+        })
+    })
+}
+
+/// The events involving `user_id`, newest first, only those of `types` when
+/// given. Pass `next_cursor` back as `cursor` to page to older events.
+#[ic_cdk::query]
+fn get_activity_feed(
+    user_id: u64,
+    cursor: Option<u64>,
+    limit: u64,
+    types: Option<Vec<ActivityType>>,
+) -> Result<ActivityPage, WalletError> {
+    perf::instrument("get_activity_feed", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        if ensure_admin().is_err() {
+            ensure_owner(user_id)?;
+        }
+        if limit == 0 || limit > MAX_FEED_ITEMS {
+            return Err(WalletError::invalid(
+                "limit",
+                &format!("must be between 1 and {}", MAX_FEED_ITEMS),
+            ));
+        }
+
+        let wanted = |event: &Event| {
+            event.kind.involves(user_id)
+                && match (&types, event.kind.activity_type()) {
+                    (_, None) => false,
+                    (None, Some(_)) => true,
+                    (Some(types), Some(activity_type)) => types.contains(&activity_type),
+                }
+        };
+        EVENT_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            let Some((oldest_seq, _)) = storage.first_key_value() else {
+                return Ok(ActivityPage {
+                    items: Vec::new(),
+                    next_cursor: None,
+                });
+            };
+            let next_seq = EVENT_SEQ.with(|seq| *seq.borrow().get());
+            // Exclusive upper bound of the events still to read
+            let mut before = cursor.unwrap_or(next_seq).min(next_seq);
+            let mut scanned = 0;
+            let mut items = Vec::new();
+            while before > oldest_seq {
+                if scanned >= MAX_FEED_SCANNED {
+                    return Ok(ActivityPage {
+                        items,
+                        next_cursor: Some(before),
+                    });
+                }
+                let start = before.saturating_sub(FEED_SCAN_WINDOW).max(oldest_seq);
+                let window: Vec<(u64, Event)> = storage.range(start..before).collect();
+                scanned += before - start;
+                for (seq, event) in window.into_iter().rev() {
+                    if !wanted(&event) {
+                        continue;
+                    }
+                    items.push(event);
+                    if items.len() as u64 == limit {
+                        return Ok(ActivityPage {
+                            items,
+                            next_cursor: (seq > oldest_seq).then_some(seq),
+                        });
+                    }
+                }
+                before = start;
+            }
+            Ok(ActivityPage {
+                items,
+                next_cursor: None,
+            })
+        })
+    })
+}
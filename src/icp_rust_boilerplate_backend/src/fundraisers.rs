@@ -0,0 +1,448 @@
+//! Fundraisers: a user opens a pool with a goal and a deadline, and any user
+//! can contribute to it from their balance. Contributions wait in escrow
+//! until the deadline, when a timer pays them out to the organizer if the
+//! goal was met, or refunds every contributor if it was not.
+
+use crate::alerts;
+use crate::auth::caller_user_id;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::ledger::{self, EntryKind, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::{counterparties, devices, holds, lockdown, pause, perf, token};
+use crate::{current_time, ensure_admin, next_id, Memory, WalletError, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_DURATION_DAYS: u32 = 180;
+const MAX_TITLE_LENGTH: usize = 100;
+const MAX_OPEN_FUNDRAISERS_PER_USER: usize = 10;
+const SETTLEMENT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum FundraiserStatus {
+    Open,
+    // The goal was met and the organizer received the funds
+    PaidOut { at: u64 },
+    // The goal was missed and every contribution was returned
+    Refunded { at: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Fundraiser {
+    id: u64,
+    organizer_user_id: u64,
+    title: String,
+    goal: u64,
+    raised: u64,
+    contribution_count: u64,
+    deadline: u64,
+    created_at: u64,
+    status: FundraiserStatus,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct FundraiserPayload {
+    title: String,
+    goal: u64,
+    duration_days: u32,
+}
+
+impl Harden for FundraiserPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.text("title", &self.title, TextKind::Name);
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Contribution {
+    id: u64,
+    fundraiser_id: u64,
+    user_id: u64,
+    amount: u64,
+    created_at: u64,
+    refunded_at: Option<u64>,
+}
+
+impl Storable for Fundraiser {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Contribution {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static FUNDRAISER_STORAGE: RefCell<StableBTreeMap<u64, Fundraiser, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))
+    ));
+
+    // Keyed by (fundraiser id, contribution id)
+    static CONTRIBUTION_STORAGE: RefCell<StableBTreeMap<(u64, u64), Contribution, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("fundraisers.fundraiser_storage", 79, &FUNDRAISER_STORAGE),
+        manifest::map(
+            "fundraisers.contribution_storage",
+            80,
+            &CONTRIBUTION_STORAGE,
+        ),
+    ]
+}
+
+fn get_fundraiser_record(fundraiser_id: u64) -> Result<Fundraiser, WalletError> {
+    FUNDRAISER_STORAGE
+        .with(|storage| storage.borrow().get(&fundraiser_id))
+        .ok_or(WalletError::not_found("fundraiser", fundraiser_id))
+}
+
+fn save_fundraiser(fundraiser: &Fundraiser) {
+    FUNDRAISER_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(fundraiser.id, fundraiser.clone())
+    });
+}
+
+fn contributions_of(fundraiser_id: u64) -> Vec<Contribution> {
+    CONTRIBUTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range((fundraiser_id, 0)..=(fundraiser_id, u64::MAX))
+            .map(|(_, contribution)| contribution)
+            .collect()
+    })
+}
+
+/// Contributions held in escrow for fundraisers that are not settled yet.
+pub(crate) fn escrowed_amount() -> u128 {
+    FUNDRAISER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, fundraiser)| fundraiser.status == FundraiserStatus::Open)
+            .flat_map(|(id, _)| contributions_of(id))
+            .filter(|contribution| contribution.refunded_at.is_none())
+            .map(|contribution| contribution.amount as u128)
+            .sum()
+    })
+}
+
+pub(crate) fn start_settlement_job() {
+    ic_cdk_timers::set_timer_interval(SETTLEMENT_INTERVAL, settle_due);
+}
+
+fn settle_due() {
+    if ensure_writable().is_err() || pause::ensure_transfers_allowed().is_err() {
+        return;
+    }
+
+    let now = current_time();
+    let due: Vec<Fundraiser> = FUNDRAISER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, fundraiser)| fundraiser)
+            .filter(|fundraiser| {
+                fundraiser.status == FundraiserStatus::Open && fundraiser.deadline <= now
+            })
+            .collect()
+    });
+    for fundraiser in due {
+        if fundraiser.raised >= fundraiser.goal {
+            pay_out(fundraiser, now);
+        } else {
+            refund(fundraiser, now);
+        }
+    }
+}
+
+fn pay_out(mut fundraiser: Fundraiser, now: u64) {
+    // Left open to be retried if the organizer cannot be credited
+    let paid = ledger::transfer(
+        EntryKind::FundraiserPayout {
+            fundraiser_id: fundraiser.id,
+        },
+        LedgerAccount::Escrow,
+        ledger::user(fundraiser.organizer_user_id),
+        fundraiser.raised,
+    );
+    if paid.is_err() {
+        return;
+    }
+    fundraiser.status = FundraiserStatus::PaidOut { at: now };
+    save_fundraiser(&fundraiser);
+    notify(
+        fundraiser.organizer_user_id,
+        NotificationKind::Fundraiser,
+        format!(
+            "Fundraiser {} reached its goal and {} was paid out to you",
+            fundraiser.id,
+            token::format_amount(fundraiser.raised)
+        ),
+    );
+}
+
+fn refund(mut fundraiser: Fundraiser, now: u64) {
+    let mut pending = false;
+    for mut contribution in contributions_of(fundraiser.id) {
+        if contribution.refunded_at.is_some() {
+            continue;
+        }
+        // Retried on the next run if the contributor cannot be credited
+        let refunded = ledger::transfer(
+            EntryKind::FundraiserRefund {
+                fundraiser_id: fundraiser.id,
+                contribution_id: contribution.id,
+            },
+            LedgerAccount::Escrow,
+            ledger::user(contribution.user_id),
+            contribution.amount,
+        );
+        if refunded.is_err() {
+            pending = true;
+            continue;
+        }
+        contribution.refunded_at = Some(now);
+        notify(
+            contribution.user_id,
+            NotificationKind::Fundraiser,
+            format!(
+                "Fundraiser {} missed its goal and your {} was refunded",
+                fundraiser.id,
+                token::format_amount(contribution.amount)
+            ),
+        );
+        CONTRIBUTION_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert((fundraiser.id, contribution.id), contribution)
+        });
+    }
+    if pending {
+        return;
+    }
+    fundraiser.status = FundraiserStatus::Refunded { at: now };
+    save_fundraiser(&fundraiser);
+    notify(
+        fundraiser.organizer_user_id,
+        NotificationKind::Fundraiser,
+        format!(
+            "Fundraiser {} missed its goal and all contributions were refunded",
+            fundraiser.id
+        ),
+    );
+}
+
+/// Opens a fundraiser organized by the caller.
+#[ic_cdk::update]
+fn create_fundraiser(payload: FundraiserPayload) -> Result<Fundraiser, WalletError> {
+    perf::instrument("create_fundraiser", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+        hardening::check(&payload)?;
+
+        let organizer_user_id = caller_user_id()?;
+        let title = payload.title.trim().to_string();
+        if title.is_empty() || title.chars().count() > MAX_TITLE_LENGTH {
+            return Err(WalletError::invalid(
+                "title",
+                &format!("must be between 1 and {} characters", MAX_TITLE_LENGTH),
+            ));
+        }
+        if payload.goal == 0 {
+            return Err(WalletError::invalid("goal", "must be greater than 0"));
+        }
+        token::validate_amount("goal", payload.goal)?;
+        if payload.duration_days == 0 || payload.duration_days > MAX_DURATION_DAYS {
+            return Err(WalletError::invalid(
+                "duration_days",
+                &format!("must be between 1 and {}", MAX_DURATION_DAYS),
+            ));
+        }
+        let open = FUNDRAISER_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, fundraiser)| {
+                    fundraiser.organizer_user_id == organizer_user_id
+                        && fundraiser.status == FundraiserStatus::Open
+                })
+                .count()
+        });
+        if open >= MAX_OPEN_FUNDRAISERS_PER_USER {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "At most {} fundraisers can be open at a time",
+                    MAX_OPEN_FUNDRAISERS_PER_USER
+                ),
+            });
+        }
+
+        let now = current_time();
+        let fundraiser = Fundraiser {
+            id: next_id(),
+            organizer_user_id,
+            title,
+            goal: payload.goal,
+            raised: 0,
+            contribution_count: 0,
+            deadline: now + payload.duration_days as u64 * NANOS_PER_DAY,
+            created_at: now,
+            status: FundraiserStatus::Open,
+        };
+        save_fundraiser(&fundraiser);
+        Ok(fundraiser)
+    })
+}
+
+/// Moves `amount` from the caller's balance into the fundraiser's escrow.
+#[ic_cdk::update]
+fn contribute_to_fundraiser(fundraiser_id: u64, amount: u64) -> Result<Contribution, WalletError> {
+    perf::instrument("contribute_to_fundraiser", || {
+        ensure_writable()?;
+        pause::ensure_transfers_allowed()?;
+
+        let user_id = caller_user_id()?;
+        devices::record_activity(user_id);
+        let mut fundraiser = get_fundraiser_record(fundraiser_id)?;
+        let now = current_time();
+        if fundraiser.status != FundraiserStatus::Open || now >= fundraiser.deadline {
+            return Err(WalletError::InvalidState {
+                reason: format!("Fundraiser {} is closed", fundraiser_id),
+            });
+        }
+        if amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", amount)?;
+        if user_id != fundraiser.organizer_user_id {
+            counterparties::ensure_transfer_permitted(
+                user_id,
+                fundraiser.organizer_user_id,
+                "fundraiser_id",
+            )?;
+        }
+        lockdown::ensure_not_locked(user_id)?;
+        let available = holds::available_balance(user_id, ledger::user_balance(user_id));
+        if available < amount {
+            return Err(WalletError::InsufficientBalance {
+                available,
+                required: amount,
+            });
+        }
+
+        let contribution = Contribution {
+            id: next_id(),
+            fundraiser_id,
+            user_id,
+            amount,
+            created_at: now,
+            refunded_at: None,
+        };
+        ledger::transfer(
+            EntryKind::FundraiserContribution {
+                fundraiser_id,
+                contribution_id: contribution.id,
+            },
+            ledger::user(user_id),
+            LedgerAccount::Escrow,
+            amount,
+        )?;
+        alerts::check_balance(user_id, ledger::user_balance(user_id));
+
+        fundraiser.raised = fundraiser.raised.saturating_add(amount);
+        fundraiser.contribution_count += 1;
+        save_fundraiser(&fundraiser);
+        CONTRIBUTION_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert((fundraiser_id, contribution.id), contribution.clone())
+        });
+        Ok(contribution)
+    })
+}
+
+/// A fundraiser and its progress; visible to everyone.
+#[ic_cdk::query]
+fn get_fundraiser(fundraiser_id: u64) -> Result<Fundraiser, WalletError> {
+    perf::instrument("get_fundraiser", || {
+        ensure_not_restoring()?;
+
+        get_fundraiser_record(fundraiser_id)
+    })
+}
+
+/// Fundraisers still accepting contributions, closest deadline first.
+#[ic_cdk::query]
+fn list_open_fundraisers() -> Result<Vec<Fundraiser>, WalletError> {
+    perf::instrument("list_open_fundraisers", || {
+        ensure_not_restoring()?;
+
+        let now = current_time();
+        let mut fundraisers: Vec<Fundraiser> = FUNDRAISER_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, fundraiser)| fundraiser)
+                .filter(|fundraiser| {
+                    fundraiser.status == FundraiserStatus::Open && now < fundraiser.deadline
+                })
+                .collect()
+        });
+        fundraisers.sort_by_key(|fundraiser| fundraiser.deadline);
+        Ok(fundraisers)
+    })
+}
+
+/// Every contribution to the fundraiser, including refunds, for its
+/// organizer and admins. Other users only see their own contributions.
+#[ic_cdk::query]
+fn get_fundraiser_contributions(fundraiser_id: u64) -> Result<Vec<Contribution>, WalletError> {
+    perf::instrument("get_fundraiser_contributions", || {
+        ensure_not_restoring()?;
+
+        let fundraiser = get_fundraiser_record(fundraiser_id)?;
+        let contributions = contributions_of(fundraiser_id);
+        if ensure_admin().is_ok() {
+            return Ok(contributions);
+        }
+        let user_id = caller_user_id()?;
+        if user_id == fundraiser.organizer_user_id {
+            return Ok(contributions);
+        }
+        Ok(contributions
+            .into_iter()
+            .filter(|contribution| contribution.user_id == user_id)
+            .collect())
+    })
+}
@@ -0,0 +1,352 @@
+//! Gift cards: the issuer's funds are locked behind a one-time code that any
+//! user can redeem. Only the SHA-256 of a code is stored, and cards that are
+//! still unredeemed at expiry are refunded to the issuer by a timer.
+
+use crate::alerts;
+use crate::auth::{self, caller_user_id, StorablePrincipal};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::ledger::{self, EntryKind, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::{current_time, next_id, sha256_hex, Memory, WalletError, MEMORY_MANAGER};
+use crate::{devices, dust, holds, lockdown, pause, perf, token};
+use candid::{Decode, Encode};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+const MAX_EXPIRY_DAYS: u32 = 365;
+// Codes are 12 random bytes, shown as 6 groups of 4 hex digits
+const CODE_BYTES: usize = 12;
+const REFUND_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// A principal that fails 5 redemptions within an hour is locked out for an hour
+const MAX_FAILED_REDEMPTIONS: u32 = 5;
+const REDEMPTION_WINDOW: u64 = NANOS_PER_HOUR;
+const REDEMPTION_LOCKOUT: u64 = NANOS_PER_HOUR;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum GiftCardStatus {
+    Active,
+    Redeemed { by_user_id: u64, at: u64 },
+    Refunded { at: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct GiftCard {
+    id: u64,
+    issuer_user_id: u64,
+    amount: u64,
+    created_at: u64,
+    expires_at: u64,
+    status: GiftCardStatus,
+}
+
+/// Returned once by `mint_gift_card`; the code cannot be recovered later.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct MintedGiftCard {
+    gift_card: GiftCard,
+    code: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct GiftCardPayload {
+    amount: u64,
+    expires_in_days: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RedemptionAttempts {
+    failures: u32,
+    window_started_at: u64,
+    locked_until: u64,
+}
+
+impl Storable for GiftCard {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RedemptionAttempts {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Gift cards keyed by the hex SHA-256 of their code
+    static GIFT_CARD_STORAGE: RefCell<StableBTreeMap<String, GiftCard, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static REDEMPTION_ATTEMPTS: RefCell<StableBTreeMap<StorablePrincipal, RedemptionAttempts, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("giftcards.gift_card_storage", 21, &GIFT_CARD_STORAGE),
+        manifest::map("giftcards.redemption_attempts", 22, &REDEMPTION_ATTEMPTS),
+    ]
+}
+
+// Codes are matched case-insensitively and without separators
+fn code_hash(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    sha256_hex(normalized.as_bytes())
+}
+
+fn format_code(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    hex.as_bytes()
+        .chunks(4)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Funds locked in gift cards that are still active.
+pub(crate) fn escrowed_amount() -> u128 {
+    GIFT_CARD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, card)| card.status == GiftCardStatus::Active)
+            .map(|(_, card)| card.amount as u128)
+            .sum()
+    })
+}
+
+// Moves the card amount from the issuer into escrow
+fn debit_issuer(user_id: u64, amount: u64, card_id: u64) -> Result<(), WalletError> {
+    lockdown::ensure_not_locked(user_id)?;
+    let balance = ledger::user_balance(user_id);
+    let available = holds::available_balance(user_id, balance);
+    if available < amount {
+        return Err(WalletError::InsufficientBalance {
+            available,
+            required: amount,
+        });
+    }
+    ledger::transfer(
+        EntryKind::GiftCardIssued { card_id },
+        ledger::user(user_id),
+        LedgerAccount::Escrow,
+        amount,
+    )?;
+    alerts::check_balance(user_id, ledger::user_balance(user_id));
+    Ok(())
+}
+
+// Releases the amount of `card` from escrow to `user_id`
+fn credit(user_id: u64, card: &GiftCard) -> Result<(), WalletError> {
+    ledger::transfer(
+        EntryKind::GiftCardSettled { card_id: card.id },
+        LedgerAccount::Escrow,
+        ledger::user(user_id),
+        card.amount,
+    )?;
+    Ok(())
+}
+
+fn ensure_not_locked_out(now: u64) -> Result<(), WalletError> {
+    let attempts = REDEMPTION_ATTEMPTS
+        .with(|attempts| attempts.borrow().get(&StorablePrincipal(auth::caller())));
+    match attempts {
+        Some(attempts) if now < attempts.locked_until => Err(WalletError::InvalidState {
+            reason: format!(
+                "Too many failed redemptions, try again at {}",
+                attempts.locked_until
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn record_failed_redemption(now: u64) {
+    let caller = StorablePrincipal(auth::caller());
+    REDEMPTION_ATTEMPTS.with(|attempts| {
+        let mut attempts = attempts.borrow_mut();
+        let mut entry = attempts.get(&caller).unwrap_or_default();
+        if now >= entry.window_started_at + REDEMPTION_WINDOW {
+            entry.failures = 0;
+            entry.window_started_at = now;
+        }
+        entry.failures += 1;
+        if entry.failures >= MAX_FAILED_REDEMPTIONS {
+            entry.locked_until = now + REDEMPTION_LOCKOUT;
+            entry.failures = 0;
+        }
+        attempts.insert(caller, entry);
+    });
+}
+
+pub(crate) fn start_refund_job() {
+    ic_cdk_timers::set_timer_interval(REFUND_CHECK_INTERVAL, refund_expired);
+}
+
+fn refund_expired() {
+    if ensure_writable().is_err() || pause::ensure_transfers_allowed().is_err() {
+        return;
+    }
+
+    let now = current_time();
+    let expired: Vec<(String, GiftCard)> = GIFT_CARD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, card)| card.status == GiftCardStatus::Active && card.expires_at <= now)
+            .collect()
+    });
+    for (hash, mut card) in expired {
+        // Left active to be retried if the issuer cannot be credited
+        if credit(card.issuer_user_id, &card).is_err() {
+            continue;
+        }
+        card.status = GiftCardStatus::Refunded { at: now };
+        notify(
+            card.issuer_user_id,
+            NotificationKind::GiftCard,
+            format!(
+                "Gift card {} expired unredeemed and {} was refunded",
+                card.id, card.amount
+            ),
+        );
+        GIFT_CARD_STORAGE.with(|storage| storage.borrow_mut().insert(hash, card));
+    }
+}
+
+#[ic_cdk::update]
+async fn mint_gift_card(payload: GiftCardPayload) -> Result<MintedGiftCard, WalletError> {
+    perf::instrument_async("mint_gift_card", async move {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+        pause::ensure_transfers_allowed()?;
+
+        let issuer_user_id = caller_user_id()?;
+        devices::record_activity(issuer_user_id);
+        if payload.amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", payload.amount)?;
+        dust::ensure_above_minimum("amount", payload.amount)?;
+        if payload.expires_in_days == 0 || payload.expires_in_days > MAX_EXPIRY_DAYS {
+            return Err(WalletError::invalid(
+                "expires_in_days",
+                "must be between 1 and 365",
+            ));
+        }
+
+        let (random,) = raw_rand()
+            .await
+            .map_err(|(_, message)| WalletError::Internal {
+                reason: format!("Cannot generate a gift card code: {}", message),
+            })?;
+        let code = format_code(&random[..CODE_BYTES]);
+
+        // The balance may have changed while waiting for randomness, so the
+        // funds are only locked now
+        ensure_writable()?;
+        pause::ensure_transfers_allowed()?;
+        let card_id = next_id();
+        debit_issuer(issuer_user_id, payload.amount, card_id)?;
+
+        let now = current_time();
+        let gift_card = GiftCard {
+            id: card_id,
+            issuer_user_id,
+            amount: payload.amount,
+            created_at: now,
+            expires_at: now + payload.expires_in_days as u64 * NANOS_PER_DAY,
+            status: GiftCardStatus::Active,
+        };
+        GIFT_CARD_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(code_hash(&code), gift_card.clone())
+        });
+        Ok(MintedGiftCard { gift_card, code })
+    })
+    .await
+}
+
+#[ic_cdk::update]
+fn redeem_gift_card(code: String) -> Result<GiftCard, WalletError> {
+    perf::instrument("redeem_gift_card", || {
+        ensure_writable()?;
+        pause::ensure_transfers_allowed()?;
+
+        let user_id = caller_user_id()?;
+        let now = current_time();
+        ensure_not_locked_out(now)?;
+
+        let hash = code_hash(&code);
+        let card = GIFT_CARD_STORAGE
+            .with(|storage| storage.borrow().get(&hash))
+            .filter(|card| card.status == GiftCardStatus::Active && now < card.expires_at);
+        let Some(mut card) = card else {
+            record_failed_redemption(now);
+            return Err(WalletError::NotFoundByKey {
+                entity: "gift card".to_string(),
+                key: "code".to_string(),
+            });
+        };
+
+        credit(user_id, &card)?;
+        card.status = GiftCardStatus::Redeemed {
+            by_user_id: user_id,
+            at: now,
+        };
+        GIFT_CARD_STORAGE.with(|storage| storage.borrow_mut().insert(hash, card.clone()));
+        if card.issuer_user_id != user_id {
+            notify(
+                card.issuer_user_id,
+                NotificationKind::GiftCard,
+                format!("Gift card {} was redeemed", card.id),
+            );
+        }
+        Ok(card)
+    })
+}
+
+#[ic_cdk::query]
+fn list_my_gift_cards() -> Result<Vec<GiftCard>, WalletError> {
+    perf::instrument("list_my_gift_cards", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        Ok(GIFT_CARD_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, card)| card)
+                .filter(|card| card.issuer_user_id == user_id)
+                .collect()
+        }))
+    })
+}
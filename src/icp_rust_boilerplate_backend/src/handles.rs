@@ -0,0 +1,357 @@
+//! Wallet handles. A user claims a readable address such as `alice.wallet`
+//! that anyone can resolve to their account, including other canisters:
+//! `resolve_handle` is a public query, transfers name recipients by handle
+//! with `to_handle`, and peer wallets can use a handle as `recipient_ref`.
+//!
+//! Registrations last a year. The holder is reminded a week before theirs
+//! expires and can renew from 30 days before expiry. An expired handle stops
+//! resolving but stays reserved for its holder for a 30-day grace period,
+//! after which a timer releases it for anyone to claim.
+
+use crate::auth::ensure_owner;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::validation::is_reserved_username;
+use crate::{
+    current_time, perf, username, Memory, TransactionPayload, WalletError, MEMORY_MANAGER,
+    USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const HANDLE_SUFFIX: &str = ".wallet";
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const REGISTRATION_PERIOD: u64 = 365 * NANOS_PER_DAY;
+const RENEWAL_WINDOW: u64 = 30 * NANOS_PER_DAY;
+const REMINDER_WINDOW: u64 = 7 * NANOS_PER_DAY;
+const GRACE_PERIOD: u64 = 30 * NANOS_PER_DAY;
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MIN_NAME_LEN: usize = 3;
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredHandle {
+    user_id: u64,
+    registered_at: u64,
+    expires_at: u64,
+    renewal_reminded: bool,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct HandleRegistration {
+    // The full handle, such as "alice.wallet"
+    handle: String,
+    user_id: u64,
+    registered_at: u64,
+    expires_at: u64,
+    // Expired, and reserved for the holder until `grace_ends_at`
+    expired: bool,
+    grace_ends_at: u64,
+}
+
+impl Storable for StoredHandle {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Keyed by the name without the suffix
+    static HANDLES: RefCell<StableBTreeMap<String, StoredHandle, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96)))
+    ));
+
+    // Users to the name they hold
+    static HANDLE_HOLDERS: RefCell<StableBTreeMap<u64, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("handles.handles", 96, &HANDLES),
+        manifest::map("handles.handle_holders", 97, &HANDLE_HOLDERS),
+    ]
+}
+
+// Handles compare in lowercase, with or without the suffix
+fn normalize(handle: &str) -> String {
+    let handle = handle.trim().to_lowercase();
+    match handle.strip_suffix(HANDLE_SUFFIX) {
+        Some(name) => name.to_string(),
+        None => handle,
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), WalletError> {
+    let valid = (MIN_NAME_LEN..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-');
+    if !valid {
+        return Err(WalletError::invalid(
+            "name",
+            &format!(
+                "must be {} to {} lowercase letters, digits or inner hyphens",
+                MIN_NAME_LEN, MAX_NAME_LEN
+            ),
+        ));
+    }
+    if is_reserved_username(name) {
+        return Err(WalletError::invalid("name", "is reserved"));
+    }
+    Ok(())
+}
+
+fn view(name: &str, handle: &StoredHandle) -> HandleRegistration {
+    HandleRegistration {
+        handle: format!("{}{}", name, HANDLE_SUFFIX),
+        user_id: handle.user_id,
+        registered_at: handle.registered_at,
+        expires_at: handle.expires_at,
+        expired: current_time() >= handle.expires_at,
+        grace_ends_at: handle.expires_at.saturating_add(GRACE_PERIOD),
+    }
+}
+
+fn is_lapsed(handle: &StoredHandle, now: u64) -> bool {
+    now >= handle.expires_at.saturating_add(GRACE_PERIOD)
+}
+
+fn save(name: &str, handle: StoredHandle) {
+    HANDLE_HOLDERS.with(|holders| {
+        holders
+            .borrow_mut()
+            .insert(handle.user_id, name.to_string())
+    });
+    HANDLES.with(|storage| storage.borrow_mut().insert(name.to_string(), handle));
+}
+
+fn remove(name: &str) -> Option<StoredHandle> {
+    let handle = HANDLES.with(|storage| storage.borrow_mut().remove(&name.to_string()))?;
+    HANDLE_HOLDERS.with(|holders| holders.borrow_mut().remove(&handle.user_id));
+    Some(handle)
+}
+
+fn held_by(user_id: u64) -> Option<(String, StoredHandle)> {
+    let name = HANDLE_HOLDERS.with(|holders| holders.borrow().get(&user_id))?;
+    let handle = HANDLES.with(|storage| storage.borrow().get(&name))?;
+    Some((name, handle))
+}
+
+/// The account an unexpired handle points to.
+pub(crate) fn lookup(handle: &str) -> Option<u64> {
+    HANDLES
+        .with(|storage| storage.borrow().get(&normalize(handle)))
+        .filter(|handle| current_time() < handle.expires_at)
+        .map(|handle| handle.user_id)
+}
+
+/// Fills in `to_user_id` of a transfer addressed by handle.
+pub(crate) fn resolve_recipient(
+    mut payload: TransactionPayload,
+) -> Result<TransactionPayload, WalletError> {
+    let Some(handle) = &payload.to_handle else {
+        return Ok(payload);
+    };
+    let user_id = lookup(handle).ok_or(WalletError::NotFoundByKey {
+        entity: "handle".to_string(),
+        key: handle.clone(),
+    })?;
+    if payload.to_user_id != 0 && payload.to_user_id != user_id {
+        return Err(WalletError::invalid(
+            "to_handle",
+            "names a different user than to_user_id",
+        ));
+    }
+    payload.to_user_id = user_id;
+    Ok(payload)
+}
+
+pub(crate) fn start_expiry_job() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_CHECK_INTERVAL, check_expiries);
+}
+
+fn check_expiries() {
+    if ensure_writable().is_err() {
+        return;
+    }
+    let now = current_time();
+    let handles: Vec<(String, StoredHandle)> =
+        HANDLES.with(|storage| storage.borrow().iter().collect());
+    for (name, mut handle) in handles {
+        if is_lapsed(&handle, now) {
+            remove(&name);
+            notify(
+                handle.user_id,
+                NotificationKind::Handle,
+                format!(
+                    "Your handle {}{} lapsed and is available to others",
+                    name, HANDLE_SUFFIX
+                ),
+            );
+        } else if !handle.renewal_reminded
+            && now.saturating_add(REMINDER_WINDOW) >= handle.expires_at
+        {
+            handle.renewal_reminded = true;
+            notify(
+                handle.user_id,
+                NotificationKind::Handle,
+                format!(
+                    "Your handle {}{} expires soon; renew it to keep it",
+                    name, HANDLE_SUFFIX
+                ),
+            );
+            save(&name, handle);
+        }
+    }
+}
+
+/// Registers `name` (with or without ".wallet") for `user_id` for a year.
+#[ic_cdk::update]
+fn claim_handle(user_id: u64, name: String) -> Result<HandleRegistration, WalletError> {
+    perf::instrument("claim_handle", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        let name = normalize(&name);
+        hardening::check_args(|fields| {
+            fields.required_text("name", &name, TextKind::Alias);
+            fields.rule(validate_name(&name));
+        })?;
+        if let Some((held, _)) = held_by(user_id) {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "User {} already holds {}{}; release it first",
+                    user_id, held, HANDLE_SUFFIX
+                ),
+            });
+        }
+        // A handle must not pass for somebody else's username
+        if username::lookup(&name).is_some_and(|holder| holder != user_id) {
+            return Err(WalletError::invalid("name", "is another user's username"));
+        }
+        let now = current_time();
+        if let Some(existing) = HANDLES.with(|storage| storage.borrow().get(&name)) {
+            if !is_lapsed(&existing, now) {
+                return Err(WalletError::AlreadyExists {
+                    entity: "handle".to_string(),
+                    field: "name".to_string(),
+                });
+            }
+            remove(&name);
+        }
+
+        let handle = StoredHandle {
+            user_id,
+            registered_at: now,
+            expires_at: now.saturating_add(REGISTRATION_PERIOD),
+            renewal_reminded: false,
+        };
+        let registration = view(&name, &handle);
+        save(&name, handle);
+        Ok(registration)
+    })
+}
+
+/// Extends the user's registration by a year from when it expires.
+#[ic_cdk::update]
+fn renew_handle(user_id: u64) -> Result<HandleRegistration, WalletError> {
+    perf::instrument("renew_handle", || {
+        ensure_writable()?;
+        ensure_owner(user_id)?;
+
+        let (name, mut handle) = held_by(user_id).ok_or(WalletError::NotFoundByKey {
+            entity: "handle of user".to_string(),
+            key: user_id.to_string(),
+        })?;
+        let now = current_time();
+        if is_lapsed(&handle, now) {
+            return Err(WalletError::InvalidState {
+                reason: format!("{}{} has lapsed", name, HANDLE_SUFFIX),
+            });
+        }
+        if now.saturating_add(RENEWAL_WINDOW) < handle.expires_at {
+            return Err(WalletError::InvalidState {
+                reason: "Handles can be renewed from 30 days before they expire".to_string(),
+            });
+        }
+        // Renewing in the grace period does not give back the days missed
+        handle.expires_at = handle
+            .expires_at
+            .max(now)
+            .saturating_add(REGISTRATION_PERIOD);
+        handle.renewal_reminded = false;
+        let registration = view(&name, &handle);
+        save(&name, handle);
+        Ok(registration)
+    })
+}
+
+/// Gives up the user's handle; anyone can claim it right away.
+#[ic_cdk::update]
+fn release_handle(user_id: u64) -> Result<(), WalletError> {
+    perf::instrument("release_handle", || {
+        ensure_writable()?;
+        ensure_owner(user_id)?;
+
+        let (name, _) = held_by(user_id).ok_or(WalletError::NotFoundByKey {
+            entity: "handle of user".to_string(),
+            key: user_id.to_string(),
+        })?;
+        remove(&name);
+        Ok(())
+    })
+}
+
+/// The handle the user holds, if any, including one in its grace period.
+#[ic_cdk::query]
+fn get_handle(user_id: u64) -> Result<Option<HandleRegistration>, WalletError> {
+    perf::instrument("get_handle", || {
+        ensure_not_restoring()?;
+        ensure_owner(user_id)?;
+
+        Ok(held_by(user_id).map(|(name, handle)| view(&name, &handle)))
+    })
+}
+
+/// The account an unexpired handle points to. Open to every caller,
+/// including other canisters.
+#[ic_cdk::query]
+fn resolve_handle(name: String) -> Result<HandleRegistration, WalletError> {
+    perf::instrument("resolve_handle", || {
+        ensure_not_restoring()?;
+
+        let key = normalize(&name);
+        HANDLES
+            .with(|storage| storage.borrow().get(&key))
+            .filter(|handle| current_time() < handle.expires_at)
+            .map(|handle| view(&key, &handle))
+            .ok_or(WalletError::NotFoundByKey {
+                entity: "handle".to_string(),
+                key: name,
+            })
+    })
+}
@@ -0,0 +1,198 @@
+//! Input hardening. Every payload type lists its text, byte and list fields
+//! in a `Harden` impl next to its definition, and the endpoints taking it
+//! run `hardening::check` before anything else reads the payload. The check
+//! visits every field and reports all offending ones at once as
+//! `WalletError::InvalidFields`, rather than stopping at the first.
+//!
+//! Text is rejected when it is longer than the ceiling of its kind, or
+//! contains control characters or Unicode bidirectional overrides, which
+//! could hide or reorder what other users are shown. Only free text such as
+//! memos and reasons may contain line breaks and tabs. Candid already
+//! guarantees that text is valid UTF-8.
+//!
+//! Endpoints taking text as plain arguments, such as a reason or a label,
+//! run the same checks through `check_args`.
+//!
+//! The ceilings are absolute: module rules and the runtime validation rules
+//! can be stricter, never looser.
+
+use crate::WalletError;
+
+pub(crate) const MAX_NAME_LEN: usize = 100;
+pub(crate) const MAX_EMAIL_LEN: usize = 254;
+pub(crate) const MAX_PHONE_LEN: usize = 32;
+pub(crate) const MAX_ALIAS_LEN: usize = 64;
+pub(crate) const MAX_MEMO_LEN: usize = 100;
+const MAX_REASON_LEN: usize = 500;
+const MAX_LINK_LEN: usize = 256;
+
+/// One offending field of a payload. Nested fields are named by path, such
+/// as `metadata[2].key`.
+#[derive(candid::CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct FieldError {
+    pub(crate) field: String,
+    pub(crate) reason: String,
+}
+
+/// The kinds of text a payload carries, each with its own ceiling.
+#[derive(Clone, Copy)]
+pub(crate) enum TextKind {
+    // Names of people, plans, templates and fundraisers
+    Name,
+    Email,
+    Phone,
+    // Usernames, handles, codes, references, keys and other identifiers
+    Alias,
+    Memo,
+    // Reasons, notes and message templates
+    Reason,
+    // URLs and content references, such as avatars
+    Link,
+}
+
+impl TextKind {
+    fn max_len(self) -> usize {
+        match self {
+            TextKind::Name => MAX_NAME_LEN,
+            TextKind::Email => MAX_EMAIL_LEN,
+            TextKind::Phone => MAX_PHONE_LEN,
+            TextKind::Alias => MAX_ALIAS_LEN,
+            TextKind::Memo => MAX_MEMO_LEN,
+            TextKind::Reason => MAX_REASON_LEN,
+            TextKind::Link => MAX_LINK_LEN,
+        }
+    }
+
+    fn multiline(self) -> bool {
+        matches!(self, TextKind::Memo | TextKind::Reason)
+    }
+}
+
+// Embeddings, overrides and isolates, which change how surrounding text
+// is laid out
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Collects the errors of every field of a payload.
+#[derive(Default)]
+pub(crate) struct FieldChecker {
+    errors: Vec<FieldError>,
+}
+
+impl FieldChecker {
+    pub(crate) fn reject(&mut self, field: &str, reason: &str) {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    fn has_error(&self, field: &str) -> bool {
+        self.errors.iter().any(|error| error.field == field)
+    }
+
+    pub(crate) fn text(&mut self, field: &str, value: &str, kind: TextKind) {
+        let max_len = kind.max_len();
+        if value.chars().count() > max_len {
+            self.reject(field, &format!("must be at most {} characters", max_len));
+        } else if value
+            .chars()
+            .any(|c| c.is_control() && !(kind.multiline() && matches!(c, '\n' | '\t')))
+        {
+            self.reject(field, "must not contain control characters");
+        } else if value.chars().any(is_bidi_control) {
+            self.reject(field, "must not contain bidirectional override characters");
+        }
+    }
+
+    /// Like `text`, and rejects text that is empty or only whitespace.
+    pub(crate) fn required_text(&mut self, field: &str, value: &str, kind: TextKind) {
+        if value.trim().is_empty() {
+            self.reject(field, "must not be empty");
+        } else {
+            self.text(field, value, kind);
+        }
+    }
+
+    pub(crate) fn optional_text(&mut self, field: &str, value: &Option<String>, kind: TextKind) {
+        if let Some(value) = value {
+            self.text(field, value, kind);
+        }
+    }
+
+    pub(crate) fn bytes(&mut self, field: &str, value: &[u8], max_len: usize) {
+        if value.len() > max_len {
+            self.reject(field, &format!("must be at most {} bytes", max_len));
+        }
+    }
+
+    pub(crate) fn optional_bytes(&mut self, field: &str, value: &Option<Vec<u8>>, max_len: usize) {
+        if let Some(value) = value {
+            self.bytes(field, value, max_len);
+        }
+    }
+
+    /// Checks the length of a list; its items are checked with `item`.
+    pub(crate) fn list<T>(&mut self, field: &str, items: &[T], max_len: usize) {
+        if items.len() > max_len {
+            self.reject(field, &format!("must contain at most {} items", max_len));
+        }
+    }
+
+    /// Runs `harden` on one item of a list, naming its fields by path.
+    pub(crate) fn item(&mut self, field: &str, index: usize, harden: impl FnOnce(&mut Self)) {
+        let mut item = FieldChecker::default();
+        harden(&mut item);
+        for error in item.errors {
+            self.reject(
+                &format!("{}[{}].{}", field, index, error.field),
+                &error.reason,
+            );
+        }
+    }
+
+    /// Folds in the outcome of a module's own rule, such as an email
+    /// pattern. Fields that already failed hardening keep that error alone.
+    pub(crate) fn rule(&mut self, result: Result<(), WalletError>) {
+        match result {
+            Ok(()) => {}
+            Err(WalletError::InvalidPayload { field, reason }) => {
+                if !self.has_error(&field) {
+                    self.reject(&field, &reason);
+                }
+            }
+            Err(WalletError::InvalidFields { errors }) => self.errors.extend(errors),
+            Err(error) => self.reject("payload", &error.to_string()),
+        }
+    }
+
+    fn finish(self) -> Result<(), WalletError> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        Err(WalletError::InvalidFields {
+            errors: self.errors,
+        })
+    }
+}
+
+/// Implemented by every payload type that carries text, bytes or lists.
+pub(crate) trait Harden {
+    fn harden(&self, fields: &mut FieldChecker);
+}
+
+/// Every offending field of `payload`, or `Ok` if there is none.
+pub(crate) fn check(payload: &impl Harden) -> Result<(), WalletError> {
+    let mut fields = FieldChecker::default();
+    payload.harden(&mut fields);
+    fields.finish()
+}
+
+/// Checks the arguments of an endpoint that takes its text one argument at
+/// a time rather than as a payload, reporting them as `check` does.
+pub(crate) fn check_args(harden: impl FnOnce(&mut FieldChecker)) -> Result<(), WalletError> {
+    let mut fields = FieldChecker::default();
+    harden(&mut fields);
+    fields.finish()
+}
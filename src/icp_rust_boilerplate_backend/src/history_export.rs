@@ -0,0 +1,280 @@
+//! Chunked history exports. `start_history_export` copies the user's
+//! matching transactions into a snapshot split into fixed-size chunks, which
+//! `get_history_chunk` then returns one at a time, so no single response
+//! grows with the size of a history. A snapshot does not change as new
+//! transactions arrive or old ones are pruned, and it is deleted a day after
+//! it was taken. Transactions already moved to the archive canister are not
+//! included.
+
+use crate::auth::{self, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::receipts::Direction;
+use crate::{
+    current_time, next_id, perf, Memory, Transaction, WalletError, MEMORY_MANAGER,
+    TRANSACTION_STORAGE, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const CHUNK_SIZE: usize = 500;
+const EXPORT_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_EXPORTS_PER_USER: usize = 3;
+
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct HistoryFilter {
+    direction: Option<Direction>,
+    counterparty_user_id: Option<u64>,
+    // Creation time bounds, inclusive, in nanoseconds
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct HistoryExport {
+    id: u64,
+    user_id: u64,
+    // Only the principal that started the export can read it
+    requested_by: Principal,
+    filter: HistoryFilter,
+    transaction_count: u64,
+    chunk_count: u64,
+    created_at: u64,
+    expires_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct HistoryChunk {
+    export_id: u64,
+    chunk_index: u64,
+    chunk_count: u64,
+    transactions: Vec<Transaction>,
+    // True on the last chunk
+    done: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredChunk(Vec<Transaction>);
+
+impl Storable for HistoryExport {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StoredChunk {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static HISTORY_EXPORTS: RefCell<StableBTreeMap<u64, HistoryExport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    // Keyed by (export id, chunk index)
+    static HISTORY_CHUNKS: RefCell<StableBTreeMap<(u64, u64), StoredChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("history_export.history_exports", 74, &HISTORY_EXPORTS),
+        manifest::map("history_export.history_chunks", 75, &HISTORY_CHUNKS),
+    ]
+}
+
+impl HistoryFilter {
+    fn matches(&self, transaction: &Transaction, user_id: u64) -> bool {
+        let outgoing = transaction.from_user_id.0 == user_id;
+        let counterparty = if outgoing {
+            transaction.to_user_id.0
+        } else {
+            transaction.from_user_id.0
+        };
+        transaction.involves(user_id)
+            && self
+                .direction
+                .is_none_or(|direction| (direction == Direction::Outgoing) == outgoing)
+            && self
+                .counterparty_user_id
+                .is_none_or(|id| id == counterparty)
+            && self
+                .since
+                .is_none_or(|since| transaction.created_at >= since)
+            && self
+                .until
+                .is_none_or(|until| transaction.created_at <= until)
+    }
+}
+
+fn delete_export(export_id: u64, chunk_count: u64) {
+    HISTORY_EXPORTS.with(|exports| exports.borrow_mut().remove(&export_id));
+    HISTORY_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for chunk_index in 0..chunk_count {
+            chunks.remove(&(export_id, chunk_index));
+        }
+    });
+}
+
+fn delete_expired_exports(now: u64) {
+    let expired: Vec<(u64, u64)> = HISTORY_EXPORTS.with(|exports| {
+        exports
+            .borrow()
+            .iter()
+            .filter(|(_, export)| export.expires_at <= now)
+            .map(|(id, export)| (id, export.chunk_count))
+            .collect()
+    });
+    for (export_id, chunk_count) in expired {
+        delete_export(export_id, chunk_count);
+    }
+}
+
+// The export, if it is still live and the caller started it
+fn caller_export(export_id: u64) -> Result<HistoryExport, WalletError> {
+    HISTORY_EXPORTS
+        .with(|exports| exports.borrow().get(&export_id))
+        .filter(|export| {
+            export.requested_by == auth::caller() && current_time() < export.expires_at
+        })
+        .ok_or(WalletError::not_found("history export", export_id))
+}
+
+/// Snapshots the user's transactions that match `filter`, oldest first, and
+/// returns the export to read with `get_history_chunk`.
+#[ic_cdk::update]
+fn start_history_export(user_id: u64, filter: HistoryFilter) -> Result<HistoryExport, WalletError> {
+    perf::instrument("start_history_export", || {
+        ensure_writable()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+        if let (Some(since), Some(until)) = (filter.since, filter.until) {
+            if since > until {
+                return Err(WalletError::invalid("since", "must not be after until"));
+            }
+        }
+
+        let now = current_time();
+        delete_expired_exports(now);
+        let live = HISTORY_EXPORTS.with(|exports| {
+            exports
+                .borrow()
+                .iter()
+                .filter(|(_, export)| export.user_id == user_id)
+                .count()
+        });
+        if live >= MAX_EXPORTS_PER_USER {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "At most {} exports can be open at a time; delete one first",
+                    MAX_EXPORTS_PER_USER
+                ),
+            });
+        }
+
+        let id = next_id();
+        let mut transaction_count = 0u64;
+        let mut chunk_count = 0u64;
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        let store_chunk = |chunk: Vec<Transaction>, chunk_count: &mut u64| {
+            HISTORY_CHUNKS.with(|chunks| {
+                chunks
+                    .borrow_mut()
+                    .insert((id, *chunk_count), StoredChunk(chunk))
+            });
+            *chunk_count += 1;
+        };
+        TRANSACTION_STORAGE.with(|storage| {
+            for (_, transaction) in storage.borrow().iter() {
+                if !filter.matches(&transaction, user_id) {
+                    continue;
+                }
+                transaction_count += 1;
+                chunk.push(transaction);
+                if chunk.len() == CHUNK_SIZE {
+                    store_chunk(std::mem::take(&mut chunk), &mut chunk_count);
+                }
+            }
+        });
+        // An empty history still has one, empty, chunk to read
+        if !chunk.is_empty() || chunk_count == 0 {
+            store_chunk(chunk, &mut chunk_count);
+        }
+
+        let export = HistoryExport {
+            id,
+            user_id,
+            requested_by: auth::caller(),
+            filter,
+            transaction_count,
+            chunk_count,
+            created_at: now,
+            expires_at: now + EXPORT_TTL_NANOS,
+        };
+        HISTORY_EXPORTS.with(|exports| exports.borrow_mut().insert(id, export.clone()));
+        Ok(export)
+    })
+}
+
+#[ic_cdk::query]
+fn get_history_chunk(export_id: u64, chunk_index: u64) -> Result<HistoryChunk, WalletError> {
+    perf::instrument("get_history_chunk", || {
+        ensure_not_restoring()?;
+
+        let export = caller_export(export_id)?;
+        if chunk_index >= export.chunk_count {
+            return Err(WalletError::invalid(
+                "chunk_index",
+                &format!("must be below {}", export.chunk_count),
+            ));
+        }
+        let StoredChunk(transactions) = HISTORY_CHUNKS
+            .with(|chunks| chunks.borrow().get(&(export_id, chunk_index)))
+            .ok_or(WalletError::Internal {
+                reason: format!("Chunk {} of export {} is missing", chunk_index, export_id),
+            })?;
+        Ok(HistoryChunk {
+            export_id,
+            chunk_index,
+            chunk_count: export.chunk_count,
+            transactions,
+            done: chunk_index + 1 == export.chunk_count,
+        })
+    })
+}
+
+/// Deletes an export before it expires.
+#[ic_cdk::update]
+fn delete_history_export(export_id: u64) -> Result<(), WalletError> {
+    perf::instrument("delete_history_export", || {
+        ensure_writable()?;
+
+        let export = caller_export(export_id)?;
+        delete_export(export.id, export.chunk_count);
+        Ok(())
+    })
+}
@@ -0,0 +1,481 @@
+//! Holds on funds. A hold reserves part of a balance for a beneficiary
+//! without moving it, as escrow or a card-style authorization would. The
+//! beneficiary later captures it, which transfers the funds, or releases it;
+//! holds nobody settled expire on their own. Active holds are also indexed
+//! by user, so the available balance every transfer checks does not walk
+//! every hold.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::budgets::Category;
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::perf;
+use crate::{
+    check_transfer_with, counterparties, current_time, devices, execute_transfer, incoming,
+    lockdown, next_id, risk, vesting, Memory, Transaction, TransactionPayload, WalletError,
+    MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_HOLD_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum HoldStatus {
+    Active,
+    Captured { tx_id: u64, amount: u64 },
+    Released,
+    Expired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Hold {
+    id: u64,
+    user_id: u64,
+    beneficiary_user_id: u64,
+    amount: u64,
+    reason: String,
+    created_at: u64,
+    expires_at: u64,
+    status: HoldStatus,
+}
+
+impl Hold {
+    // Expired holds stop counting as soon as they expire, before the timer
+    // gets to mark them
+    fn is_active(&self, now: u64) -> bool {
+        self.status == HoldStatus::Active && now < self.expires_at
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    /// The status, with a hold past its expiry reported as expired.
+    pub(crate) fn status(&self, now: u64) -> HoldStatus {
+        if self.status == HoldStatus::Active && now >= self.expires_at {
+            HoldStatus::Expired
+        } else {
+            self.status
+        }
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct HoldPayload {
+    user_id: u64,
+    beneficiary_user_id: u64,
+    amount: u64,
+    reason: String,
+    ttl_seconds: u64,
+}
+
+impl Harden for HoldPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.required_text("reason", &self.reason, TextKind::Reason);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct BalanceDetails {
+    user_id: u64,
+    total: u64,
+    held: u64,
+    available: u64,
+}
+
+impl Storable for Hold {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static HOLD_STORAGE: RefCell<StableBTreeMap<u64, Hold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    // Active holds by (user_id, hold_id)
+    static ACTIVE_HOLDS: RefCell<StableBTreeMap<(u64, u64), Hold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(119)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("holds.hold_storage", 40, &HOLD_STORAGE),
+        manifest::map("holds.active_holds", 119, &ACTIVE_HOLDS),
+    ]
+}
+
+pub(crate) fn get_hold_record(hold_id: u64) -> Result<Hold, WalletError> {
+    HOLD_STORAGE
+        .with(|storage| storage.borrow().get(&hold_id))
+        .ok_or(WalletError::not_found("hold", hold_id))
+}
+
+fn save_hold(hold: &Hold) {
+    HOLD_STORAGE.with(|storage| storage.borrow_mut().insert(hold.id, hold.clone()));
+    index_hold(hold);
+}
+
+fn index_hold(hold: &Hold) {
+    ACTIVE_HOLDS.with(|index| {
+        let mut index = index.borrow_mut();
+        if hold.status == HoldStatus::Active {
+            index.insert((hold.user_id, hold.id), hold.clone());
+        } else {
+            index.remove(&(hold.user_id, hold.id));
+        }
+    });
+}
+
+/// Indexes the active holds of a canister that placed them before the index
+/// existed.
+pub(crate) fn seed_index_if_needed() {
+    if !ACTIVE_HOLDS.with(|index| index.borrow().is_empty()) {
+        return;
+    }
+    let holds: Vec<Hold> = HOLD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, hold)| hold)
+            .filter(|hold| hold.status == HoldStatus::Active)
+            .collect()
+    });
+    for hold in &holds {
+        index_hold(hold);
+    }
+}
+
+/// Total of the active holds on `user_id`'s funds, including transfers
+/// reserved while they wait for a risk review and received funds still
+/// locked by a time lock or vesting schedule.
+pub(crate) fn held_amount(user_id: u64) -> u64 {
+    let now = current_time();
+    let held = ACTIVE_HOLDS.with(|index| {
+        index
+            .borrow()
+            .range((user_id, 0)..=(user_id, u64::MAX))
+            .map(|(_, hold)| hold)
+            .filter(|hold| hold.is_active(now))
+            .fold(0u64, |total, hold| total.saturating_add(hold.amount))
+    });
+    held.saturating_add(risk::amount_in_review(user_id))
+        .saturating_add(vesting::locked_amount(user_id))
+}
+
+/// The part of `balance` that is not held and can be spent.
+pub(crate) fn available_balance(user_id: u64, balance: u64) -> u64 {
+    balance.saturating_sub(held_amount(user_id))
+}
+
+/// The hold settled by the caller, who must be its beneficiary.
+pub(crate) fn active_hold_of_beneficiary(hold_id: u64) -> Result<Hold, WalletError> {
+    let user_id = caller_user_id()?;
+    let hold = get_hold_record(hold_id)?;
+    if hold.beneficiary_user_id != user_id {
+        return Err(WalletError::Unauthorized {
+            reason: format!("caller is not the beneficiary of hold {}", hold_id),
+        });
+    }
+    if !hold.is_active(current_time()) {
+        return Err(WalletError::InvalidState {
+            reason: format!("Hold {} is no longer active", hold_id),
+        });
+    }
+    Ok(hold)
+}
+
+/// Places a hold already checked against the available balance.
+pub(crate) fn place(
+    user_id: u64,
+    beneficiary_user_id: u64,
+    amount: u64,
+    reason: String,
+    ttl_seconds: u64,
+) -> Hold {
+    let now = current_time();
+    let hold = Hold {
+        id: next_id(),
+        user_id,
+        beneficiary_user_id,
+        amount,
+        reason,
+        created_at: now,
+        expires_at: now + ttl_seconds * NANOS_PER_SECOND,
+        status: HoldStatus::Active,
+    };
+    save_hold(&hold);
+    hold
+}
+
+/// Transfers `amount` of an active hold to its beneficiary and releases the
+/// rest. The hold stays active if the transfer fails.
+pub(crate) fn capture(
+    mut hold: Hold,
+    amount: u64,
+    category: Option<Category>,
+    memo: Option<String>,
+) -> Result<(Hold, Transaction), WalletError> {
+    // The hold must stop counting against the balance it is paid from
+    let active = hold.clone();
+    hold.status = HoldStatus::Released;
+    save_hold(&hold);
+    let payload = TransactionPayload {
+        from_user_id: hold.user_id,
+        to_user_id: hold.beneficiary_user_id,
+        amount,
+        category,
+        memo,
+        to_handle: None,
+    };
+    if let Err(err) = check_transfer_with(&payload, || Ok(())) {
+        save_hold(&active);
+        return Err(err);
+    }
+    let transaction = execute_transfer(payload);
+
+    hold.status = HoldStatus::Captured {
+        tx_id: transaction.id.0,
+        amount,
+    };
+    save_hold(&hold);
+    Ok((hold, transaction))
+}
+
+/// Releases an active hold without notifying anyone.
+pub(crate) fn release(mut hold: Hold) -> Hold {
+    hold.status = HoldStatus::Released;
+    save_hold(&hold);
+    incoming::released(&hold);
+    hold
+}
+
+pub(crate) fn start_expiry_job() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_CHECK_INTERVAL, expire_holds);
+}
+
+fn expire_holds() {
+    if ensure_writable().is_err() {
+        return;
+    }
+    let now = current_time();
+    let expired: Vec<Hold> = HOLD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, hold)| hold)
+            .filter(|hold| hold.status == HoldStatus::Active && now >= hold.expires_at)
+            .collect()
+    });
+    for mut hold in expired {
+        hold.status = HoldStatus::Expired;
+        save_hold(&hold);
+        if incoming::returned(&hold) {
+            continue;
+        }
+        notify(
+            hold.user_id,
+            NotificationKind::Hold,
+            format!(
+                "The hold of {} for \"{}\" expired",
+                hold.amount, hold.reason
+            ),
+        );
+    }
+}
+
+/// Removes the settled holds that expired, or would have, before `cutoff`;
+/// returns their ids.
+pub(crate) fn prune(cutoff: u64) -> Vec<u64> {
+    let now = current_time();
+    HOLD_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let stale: Vec<u64> = storage
+            .iter()
+            .filter(|(_, hold)| hold.status(now) != HoldStatus::Active && hold.expires_at < cutoff)
+            .map(|(id, _)| id)
+            .collect();
+        for id in &stale {
+            storage.remove(id);
+        }
+        stale
+    })
+}
+
+#[ic_cdk::update]
+fn place_hold(payload: HoldPayload) -> Result<Hold, WalletError> {
+    perf::instrument("place_hold", || {
+        ensure_writable()?;
+
+        hardening::check(&payload)?;
+        let user = USER_STORAGE
+            .with(|storage| storage.borrow().get(&payload.user_id))
+            .ok_or(WalletError::not_found("user", payload.user_id))?;
+        ensure_owner(payload.user_id)?;
+        devices::record_activity(payload.user_id);
+
+        if payload.amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        if payload.beneficiary_user_id == payload.user_id {
+            return Err(WalletError::invalid(
+                "beneficiary_user_id",
+                "must be a different user",
+            ));
+        }
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&payload.beneficiary_user_id))
+        {
+            return Err(WalletError::not_found(
+                "beneficiary",
+                payload.beneficiary_user_id,
+            ));
+        }
+        counterparties::ensure_transfer_permitted(
+            payload.user_id,
+            payload.beneficiary_user_id,
+            "beneficiary_user_id",
+        )?;
+        let reason = payload.reason.trim().to_string();
+        if payload.ttl_seconds == 0 || payload.ttl_seconds > MAX_HOLD_TTL_SECONDS {
+            return Err(WalletError::invalid(
+                "ttl_seconds",
+                &format!("must be between 1 and {}", MAX_HOLD_TTL_SECONDS),
+            ));
+        }
+        lockdown::ensure_not_locked(user.id.0)?;
+        let available = available_balance(user.id.0, user.balance);
+        if available < payload.amount {
+            return Err(WalletError::InsufficientBalance {
+                available,
+                required: payload.amount,
+            });
+        }
+
+        Ok(place(
+            payload.user_id,
+            payload.beneficiary_user_id,
+            payload.amount,
+            reason,
+            payload.ttl_seconds,
+        ))
+    })
+}
+
+/// Transfers `amount` of the hold, or all of it, to the beneficiary. Whatever
+/// is not captured is released.
+#[ic_cdk::update]
+fn capture_hold(hold_id: u64, amount: Option<u64>) -> Result<Hold, WalletError> {
+    perf::instrument("capture_hold", || {
+        ensure_writable()?;
+
+        let hold = active_hold_of_beneficiary(hold_id)?;
+        let amount = amount.unwrap_or(hold.amount);
+        if amount == 0 || amount > hold.amount {
+            return Err(WalletError::invalid(
+                "amount",
+                &format!("must be between 1 and {}", hold.amount),
+            ));
+        }
+
+        let memo = Some(format!("Capture of hold {}", hold.id));
+        let (hold, _) = capture(hold, amount, None, memo)?;
+        Ok(hold)
+    })
+}
+
+#[ic_cdk::update]
+fn release_hold(hold_id: u64) -> Result<Hold, WalletError> {
+    perf::instrument("release_hold", || {
+        ensure_writable()?;
+
+        let hold = release(active_hold_of_beneficiary(hold_id)?);
+        notify(
+            hold.user_id,
+            NotificationKind::Hold,
+            format!(
+                "The hold of {} for \"{}\" was released",
+                hold.amount, hold.reason
+            ),
+        );
+        Ok(hold)
+    })
+}
+
+#[ic_cdk::query]
+fn get_hold(hold_id: u64) -> Result<Hold, WalletError> {
+    perf::instrument("get_hold", || {
+        ensure_not_restoring()?;
+
+        let hold = get_hold_record(hold_id)?;
+        let user_id = caller_user_id()?;
+        if user_id != hold.user_id && user_id != hold.beneficiary_user_id {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller is not a party of hold {}", hold_id),
+            });
+        }
+        Ok(hold)
+    })
+}
+
+/// Holds on `user_id`'s funds and holds in its favour, active ones only
+/// unless `include_settled` is set.
+#[ic_cdk::query]
+fn list_holds(user_id: u64, include_settled: bool) -> Result<Vec<Hold>, WalletError> {
+    perf::instrument("list_holds", || {
+        ensure_not_restoring()?;
+        ensure_owner(user_id)?;
+
+        let now = current_time();
+        Ok(HOLD_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, hold)| hold)
+                .filter(|hold| hold.user_id == user_id || hold.beneficiary_user_id == user_id)
+                .filter(|hold| include_settled || hold.is_active(now))
+                .collect()
+        }))
+    })
+}
+
+#[ic_cdk::query]
+fn get_balance_details(user_id: u64) -> Result<BalanceDetails, WalletError> {
+    perf::instrument("get_balance_details", || {
+        ensure_not_restoring()?;
+
+        let user = USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("user", user_id))?;
+        ensure_owner(user_id)?;
+        let held = held_amount(user_id).min(user.balance);
+        Ok(BalanceDetails {
+            user_id,
+            total: user.balance,
+            held,
+            available: user.balance - held,
+        })
+    })
+}
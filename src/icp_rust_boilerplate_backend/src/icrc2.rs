@@ -0,0 +1,514 @@
+//! ICRC-2 approve/transfer_from over the internal wallet balances, so other
+//! canisters can pull funds within an allowance the owner granted them.
+//!
+//! A wallet account is the owner principal with the default subaccount.
+//! Spenders can be any principal and subaccount. The wallet charges no fees.
+//!
+//! Pulls go through the same checks as direct transfers. One that a direct
+//! transfer would hold for risk review or for the recipient's acceptance is
+//! rejected, since a spender has no way to follow it up. Retries of a pull
+//! that set `created_at_time` are answered with `Duplicate` for as long as
+//! the time is accepted.
+
+use crate::backup::ensure_writable;
+use crate::hardening::{self, FieldChecker, Harden};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{
+    auth, check_transfer_with, current_time, devices, execute_transfer, incoming, next_id, risk,
+    sha256_hex, verification, Memory, TransactionPayload, WalletError, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Requests are accepted up to 24 hours after `created_at_time`, allowing
+// for 2 minutes of clock drift
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+const SUBACCOUNT_LEN: usize = 32;
+// The ICRC-1 default for ledgers that do not announce their own
+const MAX_MEMO_LEN: usize = 32;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    fn subaccount_bytes(&self) -> Option<[u8; 32]> {
+        match &self.subaccount {
+            None => Some([0; 32]),
+            Some(subaccount) => subaccount.as_slice().try_into().ok(),
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        self.subaccount_bytes() == Some([0; 32])
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ApproveArgs {
+    from_subaccount: Option<Vec<u8>>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+impl Harden for ApproveArgs {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.optional_bytes("from_subaccount", &self.from_subaccount, SUBACCOUNT_LEN);
+        fields.optional_bytes(
+            "spender.subaccount",
+            &self.spender.subaccount,
+            SUBACCOUNT_LEN,
+        );
+        fields.optional_bytes("memo", &self.memo, MAX_MEMO_LEN);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct AllowanceArgs {
+    account: Account,
+    spender: Account,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct Allowance {
+    allowance: Nat,
+    expires_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+impl Harden for TransferFromArgs {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.optional_bytes(
+            "spender_subaccount",
+            &self.spender_subaccount,
+            SUBACCOUNT_LEN,
+        );
+        fields.optional_bytes("from.subaccount", &self.from.subaccount, SUBACCOUNT_LEN);
+        fields.optional_bytes("to.subaccount", &self.to.subaccount, SUBACCOUNT_LEN);
+        fields.optional_bytes("memo", &self.memo, MAX_MEMO_LEN);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Allowances are keyed by the approving wallet user and the spender account
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct AllowanceKey {
+    user_id: u64,
+    spender: Principal,
+    spender_subaccount: [u8; 32],
+}
+
+impl Storable for AllowanceKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let principal = self.spender.as_slice();
+        let mut bytes = self.user_id.to_be_bytes().to_vec();
+        bytes.push(principal.len() as u8);
+        bytes.extend_from_slice(principal);
+        bytes.extend_from_slice(&self.spender_subaccount);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let principal_len = bytes[8] as usize;
+        let principal_end = 9 + principal_len;
+        AllowanceKey {
+            user_id: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            spender: Principal::from_slice(&bytes[9..principal_end]),
+            spender_subaccount: bytes[principal_end..].try_into().unwrap(),
+        }
+    }
+
+    // User id, principal length, principal of at most 29 bytes and subaccount
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 8 + 1 + 29 + 32,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredAllowance {
+    amount: u64,
+    expires_at: Option<u64>,
+}
+
+impl StoredAllowance {
+    fn current(&self, now: u64) -> u64 {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= now => 0,
+            _ => self.amount,
+        }
+    }
+}
+
+impl Storable for StoredAllowance {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static ALLOWANCE_STORAGE: RefCell<StableBTreeMap<AllowanceKey, StoredAllowance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    // Pulls that set `created_at_time` to their transaction id, keyed by
+    // `dedup_key` so the oldest come first
+    static TRANSFER_FROM_DEDUP: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(115)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("icrc2.allowance_storage", 17, &ALLOWANCE_STORAGE),
+        manifest::map("icrc2.transfer_from_dedup", 115, &TRANSFER_FROM_DEDUP),
+    ]
+}
+
+fn nat_to_u64(amount: &Nat) -> Option<u64> {
+    u64::try_from(&amount.0).ok()
+}
+
+// Wallet user behind an account, which must use the default subaccount
+fn wallet_user(account: &Account) -> Option<u64> {
+    if !account.is_default() {
+        return None;
+    }
+    auth::user_of(account.owner)
+}
+
+fn allowance_key(user_id: u64, spender: &Account) -> Option<AllowanceKey> {
+    Some(AllowanceKey {
+        user_id,
+        spender: spender.owner,
+        spender_subaccount: spender.subaccount_bytes()?,
+    })
+}
+
+fn current_allowance(key: &AllowanceKey, now: u64) -> u64 {
+    ALLOWANCE_STORAGE
+        .with(|storage| storage.borrow().get(key))
+        .map_or(0, |allowance| allowance.current(now))
+}
+
+enum TimeCheck {
+    TooOld,
+    CreatedInFuture,
+}
+
+fn check_created_at(created_at_time: Option<u64>, now: u64) -> Result<(), TimeCheck> {
+    match created_at_time {
+        Some(created_at) if created_at + TX_WINDOW + PERMITTED_DRIFT < now => {
+            Err(TimeCheck::TooOld)
+        }
+        Some(created_at) if created_at > now + PERMITTED_DRIFT => Err(TimeCheck::CreatedInFuture),
+        _ => Ok(()),
+    }
+}
+
+// The creation time, zero-padded so keys sort by it, and a hash of the
+// spender and the arguments
+fn dedup_key(created_at_time: u64, spender: &Account, args: &TransferFromArgs) -> String {
+    let mut bytes = spender.owner.as_slice().to_vec();
+    bytes.extend_from_slice(&spender.subaccount_bytes().unwrap_or_default());
+    bytes.extend_from_slice(&Encode!(args).unwrap());
+    format!("{:020}/{}", created_at_time, sha256_hex(&bytes))
+}
+
+// Forgets the pulls whose creation time is no longer accepted
+fn prune_dedup(now: u64) {
+    let cutoff = format!("{:020}", now.saturating_sub(TX_WINDOW + PERMITTED_DRIFT));
+    TRANSFER_FROM_DEDUP.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let expired: Vec<String> = storage
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|key| *key < cutoff)
+            .collect();
+        for key in expired {
+            storage.remove(&key);
+        }
+    });
+}
+
+fn is_nonzero_fee(fee: &Option<Nat>) -> bool {
+    fee.as_ref().is_some_and(|fee| nat_to_u64(fee) != Some(0))
+}
+
+#[ic_cdk::update]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    perf::instrument("icrc2_approve", || {
+        if ensure_writable().is_err() {
+            return Err(ApproveError::TemporarilyUnavailable);
+        }
+
+        let now = current_time();
+        let generic = |message: &str| ApproveError::GenericError {
+            error_code: Nat::from(0u64),
+            message: message.to_string(),
+        };
+
+        hardening::check(&args).map_err(|error| generic(&error.to_string()))?;
+        if is_nonzero_fee(&args.fee) {
+            return Err(ApproveError::BadFee {
+                expected_fee: Nat::from(0u64),
+            });
+        }
+        match check_created_at(args.created_at_time, now) {
+            Err(TimeCheck::TooOld) => return Err(ApproveError::TooOld),
+            Err(TimeCheck::CreatedInFuture) => {
+                return Err(ApproveError::CreatedInFuture { ledger_time: now })
+            }
+            Ok(()) => {}
+        }
+        if let Some(expires_at) = args.expires_at {
+            if expires_at <= now {
+                return Err(ApproveError::Expired { ledger_time: now });
+            }
+        }
+
+        let caller = auth::caller();
+        let from = Account {
+            owner: caller,
+            subaccount: args.from_subaccount,
+        };
+        let user_id =
+            wallet_user(&from).ok_or_else(|| generic("caller does not own a wallet account"))?;
+        if args.spender.owner == caller {
+            return Err(generic("an account cannot approve itself"));
+        }
+        let key = allowance_key(user_id, &args.spender)
+            .ok_or_else(|| generic("spender subaccount must be 32 bytes"))?;
+        let amount =
+            nat_to_u64(&args.amount).ok_or_else(|| generic("amount does not fit in 64 bits"))?;
+        if amount > 0 {
+            verification::ensure_verified(user_id).map_err(|error| generic(&error.to_string()))?;
+        }
+
+        let current = current_allowance(&key, now);
+        if let Some(expected) = &args.expected_allowance {
+            if nat_to_u64(expected) != Some(current) {
+                return Err(ApproveError::AllowanceChanged {
+                    current_allowance: Nat::from(current),
+                });
+            }
+        }
+
+        ALLOWANCE_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            if amount == 0 {
+                storage.remove(&key);
+            } else {
+                storage.insert(
+                    key,
+                    StoredAllowance {
+                        amount,
+                        expires_at: args.expires_at,
+                    },
+                );
+            }
+        });
+        devices::record_activity(user_id);
+        if amount > 0 {
+            devices::reinstate(user_id, args.spender.owner);
+        }
+        Ok(Nat::from(next_id()))
+    })
+}
+
+#[ic_cdk::query]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    perf::measure("icrc2_allowance", || {
+        let now = current_time();
+        let stored = wallet_user(&args.account)
+            .and_then(|user_id| allowance_key(user_id, &args.spender))
+            .and_then(|key| ALLOWANCE_STORAGE.with(|storage| storage.borrow().get(&key)))
+            .filter(|allowance| allowance.current(now) > 0);
+        match stored {
+            Some(allowance) => Allowance {
+                allowance: Nat::from(allowance.amount),
+                expires_at: allowance.expires_at,
+            },
+            None => Allowance {
+                allowance: Nat::from(0u64),
+                expires_at: None,
+            },
+        }
+    })
+}
+
+#[ic_cdk::update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    perf::instrument("icrc2_transfer_from", || {
+        if ensure_writable().is_err() {
+            return Err(TransferFromError::TemporarilyUnavailable);
+        }
+
+        let now = current_time();
+        let generic = |message: &str| TransferFromError::GenericError {
+            error_code: Nat::from(0u64),
+            message: message.to_string(),
+        };
+
+        hardening::check(&args).map_err(|error| generic(&error.to_string()))?;
+        if is_nonzero_fee(&args.fee) {
+            return Err(TransferFromError::BadFee {
+                expected_fee: Nat::from(0u64),
+            });
+        }
+        match check_created_at(args.created_at_time, now) {
+            Err(TimeCheck::TooOld) => return Err(TransferFromError::TooOld),
+            Err(TimeCheck::CreatedInFuture) => {
+                return Err(TransferFromError::CreatedInFuture { ledger_time: now })
+            }
+            Ok(()) => {}
+        }
+
+        let spender = Account {
+            owner: auth::caller(),
+            subaccount: args.spender_subaccount.clone(),
+        };
+        prune_dedup(now);
+        let dedup_key = args
+            .created_at_time
+            .map(|created_at_time| dedup_key(created_at_time, &spender, &args));
+        if let Some(duplicate_of) = dedup_key
+            .as_ref()
+            .and_then(|key| TRANSFER_FROM_DEDUP.with(|storage| storage.borrow().get(key)))
+        {
+            return Err(TransferFromError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            });
+        }
+
+        let from_user_id =
+            wallet_user(&args.from).ok_or_else(|| generic("from is not a wallet account"))?;
+        let to_user_id =
+            wallet_user(&args.to).ok_or_else(|| generic("to is not a wallet account"))?;
+        let key = allowance_key(from_user_id, &spender)
+            .ok_or_else(|| generic("spender subaccount must be 32 bytes"))?;
+        let amount =
+            nat_to_u64(&args.amount).ok_or_else(|| generic("amount does not fit in 64 bits"))?;
+
+        if devices::is_revoked(from_user_id, spender.owner) {
+            return Err(generic("spender was revoked by the account owner"));
+        }
+        devices::record_activity(from_user_id);
+
+        let allowance = current_allowance(&key, now);
+        if allowance < amount {
+            return Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(allowance),
+            });
+        }
+
+        // Wallet memos are text, so a memo that is not would be lost
+        let memo = args
+            .memo
+            .map(String::from_utf8)
+            .transpose()
+            .map_err(|_| generic("memo must be UTF-8 text"))?;
+        let payload = TransactionPayload {
+            from_user_id,
+            to_user_id,
+            amount,
+            category: None,
+            memo,
+            to_handle: None,
+        };
+        // The allowance stands in for the owner's authorization
+        let (_, to_user) =
+            check_transfer_with(&payload, || Ok(())).map_err(|error| match error {
+                WalletError::InsufficientBalance { available, .. } => {
+                    TransferFromError::InsufficientFunds {
+                        balance: Nat::from(available),
+                    }
+                }
+                error => generic(&error.to_string()),
+            })?;
+        let assessment = risk::assess(&payload, &to_user);
+        if risk::needs_review(&assessment) {
+            return Err(generic(
+                "the transfer needs a risk review; the owner must send it directly",
+            ));
+        }
+        if incoming::requires_acceptance(to_user_id) {
+            return Err(generic(
+                "the recipient accepts incoming transfers; the owner must send it directly",
+            ));
+        }
+
+        ALLOWANCE_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            if let Some(mut stored) = storage.get(&key) {
+                stored.amount -= amount;
+                if stored.amount == 0 {
+                    storage.remove(&key);
+                } else {
+                    storage.insert(key, stored);
+                }
+            }
+        });
+        let transaction = execute_transfer(payload);
+        risk::record_score(transaction.id.0, assessment);
+        if let Some(key) = dedup_key {
+            TRANSFER_FROM_DEDUP.with(|storage| storage.borrow_mut().insert(key, transaction.id.0));
+        }
+        Ok(Nat::from(transaction.id.0))
+    })
+}
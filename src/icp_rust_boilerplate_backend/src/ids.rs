@@ -0,0 +1,107 @@
+//! Id allocation. Users and transactions each draw from their own counter
+//! instead of the shared `ID_COUNTER`, keyed by namespace in one stable map.
+//! A namespace starts from the shared counter's value the first time it is
+//! used, so ids handed out before the split are never reused. Allocation
+//! traps rather than wrap around when a namespace is exhausted.
+//!
+//! `UserId` and `TransactionId` wrap the raw ids on `User` and `Transaction`
+//! so the two cannot be mixed up. They are Candid newtypes, which encode
+//! exactly like the `nat64` they replace.
+
+use crate::manifest::{self, Storage};
+use crate::{Memory, ID_COUNTER, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::fmt;
+
+const USER_NAMESPACE: &str = "user";
+const TRANSACTION_NAMESPACE: &str = "transaction";
+
+#[derive(
+    candid::CandidType,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+pub(crate) struct UserId(pub(crate) u64);
+
+#[derive(
+    candid::CandidType,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+pub(crate) struct TransactionId(pub(crate) u64);
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+thread_local! {
+    // Next id of every namespace
+    static ID_COUNTERS: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map("ids.id_counters", 55, &ID_COUNTERS)]
+}
+
+fn allocate(namespace: &str) -> u64 {
+    let key = namespace.to_string();
+    let id = ID_COUNTERS
+        .with(|counters| counters.borrow().get(&key))
+        .unwrap_or_else(|| ID_COUNTER.with(|counter| *counter.borrow().get()));
+    let next = id
+        .checked_add(1)
+        .unwrap_or_else(|| ic_cdk::trap(&format!("The {} id space is exhausted", namespace)));
+    ID_COUNTERS.with(|counters| counters.borrow_mut().insert(key, next));
+    id
+}
+
+pub(crate) fn next_user_id() -> UserId {
+    UserId(allocate(USER_NAMESPACE))
+}
+
+pub(crate) fn next_transaction_id() -> TransactionId {
+    TransactionId(allocate(TRANSACTION_NAMESPACE))
+}
+
+pub(crate) fn export_counters() -> Vec<(String, u64)> {
+    ID_COUNTERS.with(|counters| counters.borrow().iter().collect())
+}
+
+pub(crate) fn import_counters(entries: Vec<(String, u64)>) {
+    ID_COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        crate::clear_map(&mut counters);
+        for (namespace, next) in entries {
+            counters.insert(namespace, next);
+        }
+    });
+}
@@ -0,0 +1,362 @@
+//! Acceptance of incoming transfers. A user who turns it on, such as a
+//! merchant or a public figure drawing spam transfers, is not credited until
+//! they accept each transfer sent to them. Until then the amount sits in a
+//! hold on the sender's funds in the recipient's favour; accepting captures
+//! the hold, declining releases it, and a transfer not accepted within the
+//! recipient's window returns to the sender when the hold expires. Each
+//! transfer is keyed by its hold, and its status follows the hold's.
+//!
+//! Only direct sends wait for acceptance; payment links, payment intents,
+//! subscription charges and other flows the recipient set up credit them at
+//! once.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::budgets::Category;
+use crate::events::{self, EventKind};
+use crate::holds::{self, Hold, HoldStatus};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::{
+    current_time, perf, spenders, token, Memory, Transaction, TransactionPayload, WalletError,
+    MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+// Holds last at most 30 days
+const MAX_WINDOW_HOURS: u32 = 30 * 24;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum IncomingStatus {
+    Pending,
+    Accepted { tx_id: u64 },
+    Declined,
+    // Not accepted in time; the sender kept the funds
+    Returned,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredIncoming {
+    from_user_id: u64,
+    to_user_id: u64,
+    amount: u64,
+    category: Option<Category>,
+    memo: Option<String>,
+    created_at: u64,
+    // The spender whose allowance the transfer was charged to, given back
+    // if it is declined or returned
+    spender: Option<Principal>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct IncomingTransfer {
+    // Also the id of the hold on the sender's funds
+    id: u64,
+    from_user_id: u64,
+    to_user_id: u64,
+    amount: u64,
+    memo: Option<String>,
+    created_at: u64,
+    expires_at: u64,
+    status: IncomingStatus,
+}
+
+impl Storable for StoredIncoming {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Users who accept incoming transfers, to the hours they have to do so
+    static ACCEPTANCE_WINDOWS: RefCell<StableBTreeMap<u64, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88)))
+    ));
+
+    static INCOMING_TRANSFERS: RefCell<StableBTreeMap<u64, StoredIncoming, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("incoming.acceptance_windows", 88, &ACCEPTANCE_WINDOWS),
+        manifest::map("incoming.incoming_transfers", 89, &INCOMING_TRANSFERS),
+    ]
+}
+
+fn acceptance_window(user_id: u64) -> Option<u32> {
+    ACCEPTANCE_WINDOWS.with(|windows| windows.borrow().get(&user_id))
+}
+
+pub(crate) fn requires_acceptance(user_id: u64) -> bool {
+    acceptance_window(user_id).is_some()
+}
+
+fn view(id: u64, incoming: StoredIncoming, hold: &Hold) -> IncomingTransfer {
+    let status = match hold.status(current_time()) {
+        HoldStatus::Active => IncomingStatus::Pending,
+        HoldStatus::Captured { tx_id, .. } => IncomingStatus::Accepted { tx_id },
+        HoldStatus::Released => IncomingStatus::Declined,
+        HoldStatus::Expired => IncomingStatus::Returned,
+    };
+    IncomingTransfer {
+        id,
+        from_user_id: incoming.from_user_id,
+        to_user_id: incoming.to_user_id,
+        amount: incoming.amount,
+        memo: incoming.memo,
+        created_at: incoming.created_at,
+        expires_at: hold.expires_at(),
+        status,
+    }
+}
+
+fn get_incoming_record(incoming_id: u64) -> Result<StoredIncoming, WalletError> {
+    INCOMING_TRANSFERS
+        .with(|storage| storage.borrow().get(&incoming_id))
+        .ok_or(WalletError::not_found("incoming transfer", incoming_id))
+}
+
+/// Reserves a checked transfer until its recipient accepts it.
+pub(crate) fn await_acceptance(
+    payload: TransactionPayload,
+    spender: Option<Principal>,
+) -> WalletError {
+    let hours = acceptance_window(payload.to_user_id).unwrap_or(MAX_WINDOW_HOURS);
+    let hold = holds::place(
+        payload.from_user_id,
+        payload.to_user_id,
+        payload.amount,
+        format!(
+            "Transfer to user {} awaiting acceptance",
+            payload.to_user_id
+        ),
+        hours as u64 * SECONDS_PER_HOUR,
+    );
+    INCOMING_TRANSFERS.with(|storage| {
+        storage.borrow_mut().insert(
+            hold.id(),
+            StoredIncoming {
+                from_user_id: payload.from_user_id,
+                to_user_id: payload.to_user_id,
+                amount: payload.amount,
+                category: payload.category,
+                memo: payload.memo,
+                created_at: current_time(),
+                spender,
+            },
+        )
+    });
+    events::record(EventKind::TransferRequested {
+        incoming_id: hold.id(),
+        from_user_id: payload.from_user_id,
+        to_user_id: payload.to_user_id,
+        amount: payload.amount,
+    });
+    notify(
+        payload.to_user_id,
+        NotificationKind::IncomingTransfer,
+        format!(
+            "User {} wants to send you {}; accept incoming transfer {} within {} hours",
+            payload.from_user_id,
+            token::format_amount(payload.amount),
+            hold.id(),
+            hours
+        ),
+    );
+    WalletError::AwaitingAcceptance {
+        incoming_id: hold.id(),
+    }
+}
+
+// The transfer will not execute, so its spender gets the allowance back
+fn restore_allowance(incoming: &StoredIncoming) {
+    if let Some(spender) = incoming.spender {
+        spenders::restore_allowance(
+            incoming.from_user_id,
+            spender,
+            incoming.amount,
+            incoming.created_at,
+        );
+    }
+}
+
+/// Called when a hold expires; tells the sender if it was an incoming
+/// transfer that was not accepted in time.
+pub(crate) fn returned(hold: &Hold) -> bool {
+    let Ok(incoming) = get_incoming_record(hold.id()) else {
+        return false;
+    };
+    restore_allowance(&incoming);
+    notify(
+        incoming.from_user_id,
+        NotificationKind::IncomingTransfer,
+        format!(
+            "User {} did not accept your transfer of {} in time; it stays in your balance",
+            incoming.to_user_id,
+            token::format_amount(incoming.amount)
+        ),
+    );
+    true
+}
+
+/// Called when a hold is released, by a decline or otherwise.
+pub(crate) fn released(hold: &Hold) {
+    if let Ok(incoming) = get_incoming_record(hold.id()) {
+        restore_allowance(&incoming);
+    }
+}
+
+/// Removes the record of the transfer reserved by a pruned hold; true if
+/// there was one.
+pub(crate) fn forget(hold_id: u64) -> bool {
+    INCOMING_TRANSFERS
+        .with(|storage| storage.borrow_mut().remove(&hold_id))
+        .is_some()
+}
+
+/// Makes `user_id` accept each incoming transfer within `window_hours`, or
+/// credits transfers at once again with `None`.
+#[ic_cdk::update]
+fn set_incoming_acceptance(user_id: u64, window_hours: Option<u32>) -> Result<(), WalletError> {
+    perf::instrument("set_incoming_acceptance", || {
+        ensure_writable()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        let Some(hours) = window_hours else {
+            ACCEPTANCE_WINDOWS.with(|windows| windows.borrow_mut().remove(&user_id));
+            return Ok(());
+        };
+        if hours == 0 || hours > MAX_WINDOW_HOURS {
+            return Err(WalletError::invalid(
+                "window_hours",
+                &format!("must be between 1 and {}", MAX_WINDOW_HOURS),
+            ));
+        }
+        ACCEPTANCE_WINDOWS.with(|windows| windows.borrow_mut().insert(user_id, hours));
+        Ok(())
+    })
+}
+
+/// The hours `user_id` has to accept incoming transfers, if they must.
+#[ic_cdk::query]
+fn get_incoming_acceptance(user_id: u64) -> Result<Option<u32>, WalletError> {
+    perf::instrument("get_incoming_acceptance", || {
+        ensure_not_restoring()?;
+        ensure_owner(user_id)?;
+
+        Ok(acceptance_window(user_id))
+    })
+}
+
+/// Credits the caller with a transfer waiting for their acceptance.
+#[ic_cdk::update]
+fn accept_incoming(incoming_id: u64) -> Result<Transaction, WalletError> {
+    perf::instrument("accept_incoming", || {
+        ensure_writable()?;
+
+        let incoming = get_incoming_record(incoming_id)?;
+        let hold = holds::active_hold_of_beneficiary(incoming_id)?;
+        let (_, transaction) =
+            holds::capture(hold, incoming.amount, incoming.category, incoming.memo)?;
+        notify(
+            incoming.from_user_id,
+            NotificationKind::IncomingTransfer,
+            format!(
+                "User {} accepted your transfer of {}",
+                incoming.to_user_id,
+                token::format_amount(incoming.amount)
+            ),
+        );
+        Ok(transaction)
+    })
+}
+
+/// Refuses a transfer waiting for the caller's acceptance; the sender keeps
+/// the funds.
+#[ic_cdk::update]
+fn decline_incoming(incoming_id: u64) -> Result<IncomingTransfer, WalletError> {
+    perf::instrument("decline_incoming", || {
+        ensure_writable()?;
+
+        let incoming = get_incoming_record(incoming_id)?;
+        let hold = holds::release(holds::active_hold_of_beneficiary(incoming_id)?);
+        notify(
+            incoming.from_user_id,
+            NotificationKind::IncomingTransfer,
+            format!(
+                "User {} declined your transfer of {}; it stays in your balance",
+                incoming.to_user_id,
+                token::format_amount(incoming.amount)
+            ),
+        );
+        Ok(view(incoming_id, incoming, &hold))
+    })
+}
+
+/// Transfers the user sent or received that needed acceptance, newest
+/// first, only those still pending with `pending_only`.
+#[ic_cdk::query]
+fn list_incoming(user_id: u64, pending_only: bool) -> Result<Vec<IncomingTransfer>, WalletError> {
+    perf::instrument("list_incoming", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        let mut transfers: Vec<IncomingTransfer> = INCOMING_TRANSFERS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, incoming)| {
+                    incoming.from_user_id == user_id || incoming.to_user_id == user_id
+                })
+                .filter_map(|(id, incoming)| {
+                    let hold = holds::get_hold_record(id).ok()?;
+                    Some(view(id, incoming, &hold))
+                })
+                .filter(|transfer| !pending_only || transfer.status == IncomingStatus::Pending)
+                .collect()
+        });
+        transfers.reverse();
+        Ok(transfers)
+    })
+}
+
+/// A transfer that needed acceptance, for its sender or recipient.
+#[ic_cdk::query]
+fn get_incoming(incoming_id: u64) -> Result<IncomingTransfer, WalletError> {
+    perf::instrument("get_incoming", || {
+        ensure_not_restoring()?;
+
+        let incoming = get_incoming_record(incoming_id)?;
+        let user_id = caller_user_id()?;
+        if user_id != incoming.from_user_id && user_id != incoming.to_user_id {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller is not a party of incoming transfer {}", incoming_id),
+            });
+        }
+        let hold = holds::get_hold_record(incoming_id)?;
+        Ok(view(incoming_id, incoming, &hold))
+    })
+}
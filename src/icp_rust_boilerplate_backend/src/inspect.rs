@@ -0,0 +1,133 @@
+//! Ingress filtering. `canister_inspect_message` runs before an update call
+//! is executed, so rejecting a call there costs the canister no execution
+//! cycles. It only guards ingress messages and can be skipped by a malicious
+//! replica, so every endpoint still performs its own checks.
+
+use crate::backup::MAX_CHUNK_SIZE;
+use crate::migration::MAX_IMPORT_BATCH;
+use crate::pause;
+use crate::payment_intents::MAX_INTENT_PAYLOAD_BYTES;
+use candid::Principal;
+use ic_cdk::api::call::{accept_message, arg_data_raw_size, method_name};
+
+// Enough for every payload apart from backup chunks, user imports and
+// payment intents
+const DEFAULT_MAX_ARG_BYTES: usize = 8 * 1024;
+// Candid framing around a restore chunk
+const CHUNK_OVERHEAD_BYTES: usize = 1024;
+// Generous upper bound for one encoded user import record
+const MAX_IMPORT_RECORD_BYTES: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    /// Any caller, including the anonymous principal
+    Anyone,
+    /// Any authenticated caller
+    User,
+    /// Canister controllers only
+    Admin,
+}
+
+pub(crate) struct MethodPolicy {
+    pub(crate) role: Role,
+    pub(crate) max_arg_bytes: usize,
+}
+
+/// The role and argument size limit of every update method. Methods not
+/// listed here default to `Role::User` with `DEFAULT_MAX_ARG_BYTES`.
+pub(crate) fn method_policy(method: &str) -> MethodPolicy {
+    let role = match method {
+        "wallet_receive" => Role::Anyone,
+        "prepare_backup"
+        | "begin_restore"
+        | "restore_chunk"
+        | "finish_restore"
+        | "abort_restore"
+        | "set_points_transfers_enabled"
+        | "set_cycles_monitor"
+        | "review_dispute"
+        | "resolve_dispute"
+        | "set_archive_config"
+        | "pause"
+        | "resume"
+        | "run_reconciliation_now"
+        | "set_reconciliation_auto_pause"
+        | "set_validation_rules"
+        | "set_token_metadata"
+        | "create_campaign"
+        | "set_campaign_active"
+        | "set_earning_rules"
+        | "register_peer"
+        | "remove_peer"
+        | "import_users"
+        | "add_verifier"
+        | "remove_verifier"
+        | "set_transaction_retention"
+        | "set_risk_config"
+        | "approve_transfer_review"
+        | "reject_transfer_review"
+        | "set_cycles_deposit_rate"
+        | "reset_performance_stats"
+        | "set_unclaimed_send_expiry_days"
+        | "propose_adjustment"
+        | "approve_adjustment"
+        | "reject_adjustment"
+        | "set_message_template"
+        | "set_min_transfer_amount"
+        | "set_cashback_policy"
+        | "fund_cashback_pool"
+        | "run_cashback_distribution_now"
+        | "set_sandbox_tester"
+        | "wipe_sandbox"
+        | "set_retention_policy"
+        | "run_pruning_now"
+        | "admin_unlock_account"
+        | "replay_delivery"
+        | "replay_range" => Role::Admin,
+        _ => Role::User,
+    };
+    let max_arg_bytes = match method {
+        "restore_chunk" => MAX_CHUNK_SIZE as usize + CHUNK_OVERHEAD_BYTES,
+        "import_users" => MAX_IMPORT_BATCH * MAX_IMPORT_RECORD_BYTES,
+        "create_payment_intent" => MAX_INTENT_PAYLOAD_BYTES,
+        _ => DEFAULT_MAX_ARG_BYTES,
+    };
+    MethodPolicy {
+        role,
+        max_arg_bytes,
+    }
+}
+
+fn is_allowed(caller: Principal, policy: &MethodPolicy) -> bool {
+    match policy.role {
+        Role::Anyone => true,
+        Role::User => caller != Principal::anonymous(),
+        Role::Admin => ic_cdk::api::is_controller(&caller),
+    }
+}
+
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let policy = method_policy(&method_name());
+    // Only the controllers can do anything while the canister is fully paused
+    if pause::is_fully_paused() && policy.role != Role::Admin {
+        return;
+    }
+    // A read-only canister only takes the calls that change the pause level
+    // and the backup, which writes nothing
+    if pause::is_read_only()
+        && !matches!(
+            method_name().as_str(),
+            "pause" | "resume" | "prepare_backup"
+        )
+    {
+        return;
+    }
+    if arg_data_raw_size() > policy.max_arg_bytes {
+        return;
+    }
+    if !is_allowed(ic_cdk::caller(), &policy) {
+        return;
+    }
+    accept_message();
+}
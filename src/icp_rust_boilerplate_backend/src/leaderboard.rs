@@ -0,0 +1,297 @@
+//! Points leaderboard. Users are kept in an index ordered by points, so the
+//! top of the board is read without scanning every account. Users can opt
+//! out of rankings, and the top of the board is saved once a week. Entries
+//! show what each user's privacy settings show on the leaderboard.
+
+use crate::auth::ensure_owner;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::privacy::{self, Surface, VisibleProfile};
+use crate::{clear_map, current_time, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use candid::{Decode, Encode};
+use chrono::DateTime;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_LEADERBOARD_LIMIT: u64 = 100;
+const SNAPSHOT_SIZE: u64 = 100;
+const SNAPSHOTS_RETAINED: usize = 52;
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LeaderboardEntry {
+    rank: u64,
+    user_id: u64,
+    // Hidden fields are absent
+    username: Option<String>,
+    display_name: Option<String>,
+    avatar: Option<String>,
+    points: u64,
+}
+
+impl LeaderboardEntry {
+    fn new(rank: u64, user_id: u64, profile: VisibleProfile, points: u64) -> Self {
+        LeaderboardEntry {
+            rank,
+            user_id,
+            username: profile.username,
+            display_name: profile.display_name,
+            avatar: profile.avatar,
+            points,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LeaderboardSnapshot {
+    // ISO week, e.g. "2026-W42"
+    week: String,
+    taken_at: u64,
+    entries: Vec<LeaderboardEntry>,
+}
+
+impl Storable for LeaderboardSnapshot {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Index keys sort by descending points, then by user id
+type RankKey = (u64, u64);
+
+fn rank_key(user_id: u64, points: u64) -> RankKey {
+    (u64::MAX - points, user_id)
+}
+
+thread_local! {
+    static POINTS_INDEX: RefCell<StableBTreeMap<RankKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    // Points each user is currently indexed under
+    static INDEXED_POINTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    static RANKING_OPT_OUTS: RefCell<StableBTreeMap<u64, bool, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    static LEADERBOARD_SNAPSHOTS: RefCell<StableBTreeMap<String, LeaderboardSnapshot, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("leaderboard.points_index", 41, &POINTS_INDEX),
+        manifest::map("leaderboard.indexed_points", 42, &INDEXED_POINTS),
+        manifest::map("leaderboard.ranking_opt_outs", 43, &RANKING_OPT_OUTS),
+        manifest::map(
+            "leaderboard.leaderboard_snapshots",
+            44,
+            &LEADERBOARD_SNAPSHOTS,
+        ),
+    ]
+}
+
+fn is_opted_out(user_id: u64) -> bool {
+    RANKING_OPT_OUTS.with(|opt_outs| opt_outs.borrow().contains_key(&user_id))
+}
+
+fn unindex(user_id: u64) {
+    if let Some(points) = INDEXED_POINTS.with(|indexed| indexed.borrow_mut().remove(&user_id)) {
+        POINTS_INDEX.with(|index| index.borrow_mut().remove(&rank_key(user_id, points)));
+    }
+}
+
+/// Moves `user_id` to its new place on the board. Must be called whenever a
+/// user's points change.
+pub(crate) fn index_points(user_id: u64, points: u64) {
+    unindex(user_id);
+    // Users without points or who opted out are not ranked
+    if points == 0 || is_opted_out(user_id) {
+        return;
+    }
+    POINTS_INDEX.with(|index| index.borrow_mut().insert(rank_key(user_id, points), points));
+    INDEXED_POINTS.with(|indexed| indexed.borrow_mut().insert(user_id, points));
+}
+
+/// Rebuilds the index from the stored users.
+pub(crate) fn rebuild_index() {
+    POINTS_INDEX.with(|index| clear_map(&mut index.borrow_mut()));
+    INDEXED_POINTS.with(|indexed| clear_map(&mut indexed.borrow_mut()));
+    let users: Vec<(u64, u64)> = USER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(id, user)| (id, user.points))
+            .collect()
+    });
+    for (user_id, points) in users {
+        index_points(user_id, points);
+    }
+}
+
+fn top_entries(limit: u64) -> Vec<LeaderboardEntry> {
+    let ranked: Vec<(u64, u64)> = POINTS_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .take(limit as usize)
+            .map(|((_, user_id), points)| (user_id, points))
+            .collect()
+    });
+    ranked
+        .into_iter()
+        .zip(1u64..)
+        .map(|((user_id, points), rank)| {
+            let profile = USER_STORAGE
+                .with(|storage| storage.borrow().get(&user_id))
+                .map(|user| privacy::visible_profile(&user, Surface::Leaderboard))
+                .unwrap_or_default();
+            LeaderboardEntry::new(rank, user_id, profile, points)
+        })
+        .collect()
+}
+
+fn week_of(timestamp: u64) -> String {
+    DateTime::from_timestamp((timestamp / 1_000_000_000) as i64, 0)
+        .map(|date| date.format("%G-W%V").to_string())
+        .unwrap_or_default()
+}
+
+pub(crate) fn start_snapshot_job() {
+    ic_cdk_timers::set_timer_interval(SNAPSHOT_CHECK_INTERVAL, take_weekly_snapshot);
+}
+
+// Runs hourly and saves the board the first time it runs in a new week
+fn take_weekly_snapshot() {
+    if ensure_writable().is_err() {
+        return;
+    }
+    let now = current_time();
+    let week = week_of(now);
+    if LEADERBOARD_SNAPSHOTS.with(|snapshots| snapshots.borrow().contains_key(&week)) {
+        return;
+    }
+    let snapshot = LeaderboardSnapshot {
+        week: week.clone(),
+        taken_at: now,
+        entries: top_entries(SNAPSHOT_SIZE),
+    };
+    LEADERBOARD_SNAPSHOTS.with(|snapshots| {
+        let mut snapshots = snapshots.borrow_mut();
+        snapshots.insert(week, snapshot);
+        // Week labels sort chronologically, so the first ones are the oldest
+        let excess = (snapshots.len() as usize).saturating_sub(SNAPSHOTS_RETAINED);
+        let stale: Vec<String> = snapshots
+            .iter()
+            .take(excess)
+            .map(|(week, _)| week)
+            .collect();
+        for week in stale {
+            snapshots.remove(&week);
+        }
+    });
+}
+
+#[ic_cdk::query]
+fn get_points_leaderboard(limit: u64) -> Result<Vec<LeaderboardEntry>, WalletError> {
+    perf::instrument("get_points_leaderboard", || {
+        ensure_not_restoring()?;
+
+        if limit == 0 || limit > MAX_LEADERBOARD_LIMIT {
+            return Err(WalletError::invalid(
+                "limit",
+                &format!("must be between 1 and {}", MAX_LEADERBOARD_LIMIT),
+            ));
+        }
+        Ok(top_entries(limit))
+    })
+}
+
+/// The user's place on the board, or `None` if they have no points or opted
+/// out of rankings.
+#[ic_cdk::query]
+fn get_user_rank(user_id: u64) -> Result<Option<LeaderboardEntry>, WalletError> {
+    perf::instrument("get_user_rank", || {
+        ensure_not_restoring()?;
+
+        let user = USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("user", user_id))?;
+        let Some(points) = INDEXED_POINTS.with(|indexed| indexed.borrow().get(&user_id)) else {
+            return Ok(None);
+        };
+        // Only the users ranked above are visited
+        let ahead = POINTS_INDEX
+            .with(|index| index.borrow().range(..rank_key(user_id, points)).count() as u64);
+        Ok(Some(LeaderboardEntry::new(
+            ahead + 1,
+            user_id,
+            privacy::visible_profile(&user, Surface::Leaderboard),
+            points,
+        )))
+    })
+}
+
+#[ic_cdk::update]
+fn set_ranking_opt_out(user_id: u64, opt_out: bool) -> Result<(), WalletError> {
+    perf::instrument("set_ranking_opt_out", || {
+        ensure_writable()?;
+
+        let user = USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("user", user_id))?;
+        ensure_owner(user_id)?;
+
+        RANKING_OPT_OUTS.with(|opt_outs| {
+            let mut opt_outs = opt_outs.borrow_mut();
+            if opt_out {
+                opt_outs.insert(user_id, true);
+            } else {
+                opt_outs.remove(&user_id);
+            }
+        });
+        index_points(user_id, user.points);
+        Ok(())
+    })
+}
+
+/// Weeks with a saved leaderboard, oldest first.
+#[ic_cdk::query]
+fn list_leaderboard_weeks() -> Vec<String> {
+    perf::measure("list_leaderboard_weeks", || {
+        LEADERBOARD_SNAPSHOTS
+            .with(|snapshots| snapshots.borrow().iter().map(|(week, _)| week).collect())
+    })
+}
+
+#[ic_cdk::query]
+fn get_leaderboard_snapshot(week: String) -> Result<LeaderboardSnapshot, WalletError> {
+    perf::instrument("get_leaderboard_snapshot", || {
+        ensure_not_restoring()?;
+
+        LEADERBOARD_SNAPSHOTS
+            .with(|snapshots| snapshots.borrow().get(&week))
+            .ok_or(WalletError::NotFoundByKey {
+                entity: "leaderboard snapshot".to_string(),
+                key: week,
+            })
+    })
+}
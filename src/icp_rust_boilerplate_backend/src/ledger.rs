@@ -0,0 +1,572 @@
+//! Double-entry ledger. Every movement of funds is a journal entry whose
+//! postings debit some accounts and credit others by the same total, so
+//! funds can only move between accounts and never appear or vanish. Users
+//! have a main account each, and a savings account next to it that only
+//! autosave plans pay into and only its owner withdraws from. The system
+//! accounts are:
+//!
+//! - `Treasury`, the counterpart of funds entering or leaving the wallet:
+//!   deposits, imports, promo bonuses, cashback pool top-ups and transfers
+//!   to and from peer wallets. Its balance is minus the funds the wallet holds.
+//! - `Escrow`, funds taken from a user for a gift card, an outgoing peer
+//!   transfer, a fundraiser contribution or a send to a contact without an
+//!   account that has not settled yet.
+//! - `Fees`, transfer fees charged to users.
+//! - `Cashback`, the share of fees and the top-ups set aside to be paid
+//!   back to active users.
+//!
+//! An account's balance is its credits minus its debits. A user's balance
+//! is also cached on `User::balance`, which the ledger alone updates and
+//! which reconciliation compares against the ledger.
+
+use crate::backup::ensure_not_restoring;
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::supply::{self, Asset};
+use crate::{
+    current_time, ensure_admin, fundraisers, giftcards, next_id, peers, reconciliation, unclaimed,
+    Memory, WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_ENTRIES_PER_PAGE: u64 = 500;
+
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum LedgerAccount {
+    User { user_id: u64 },
+    Savings { user_id: u64 },
+    Treasury,
+    Escrow,
+    Fees,
+    Cashback,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum PostingSide {
+    Debit,
+    Credit,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Posting {
+    account: LedgerAccount,
+    side: PostingSide,
+    amount: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum EntryKind {
+    Deposit {
+        user_id: u64,
+    },
+    CyclesDeposit {
+        deposit_id: u64,
+    },
+    Transfer {
+        tx_id: u64,
+    },
+    // `tx_id` is the reversed transfer
+    Reversal {
+        tx_id: u64,
+    },
+    GiftCardIssued {
+        card_id: u64,
+    },
+    GiftCardSettled {
+        card_id: u64,
+    },
+    // Outgoing transfer to a peer wallet
+    ExternalTransfer {
+        transfer_id: u64,
+    },
+    InboundTransfer {
+        peer_canister: Principal,
+        transfer_id: u64,
+    },
+    PromoBonus {
+        campaign_id: u64,
+    },
+    Import {
+        user_id: u64,
+    },
+    FundraiserContribution {
+        fundraiser_id: u64,
+        contribution_id: u64,
+    },
+    FundraiserPayout {
+        fundraiser_id: u64,
+    },
+    FundraiserRefund {
+        fundraiser_id: u64,
+        contribution_id: u64,
+    },
+    // Send to a contact detail without an account, and its claim or refund
+    UnclaimedSend {
+        send_id: u64,
+    },
+    UnclaimedSendSettled {
+        send_id: u64,
+    },
+    // Run of an autosave plan from the main account into savings
+    Autosave {
+        plan_id: u64,
+        run_id: u64,
+    },
+    // Move from savings back to the main account
+    SavingsWithdrawal {
+        user_id: u64,
+    },
+    // Sweep of sub-account balances below the minimum transfer amount into
+    // the main account
+    DustConsolidation {
+        user_id: u64,
+    },
+    // Share of collected fees moved into the cashback pool
+    CashbackAccrual,
+    // Top-up of the cashback pool from the treasury by an admin
+    CashbackFunding,
+    // Payout of the cashback pool to an active user
+    Cashback {
+        distribution_id: u64,
+    },
+    // Credit or debit approved by two admins
+    ManualAdjustment {
+        adjustment_id: u64,
+    },
+    // Brings the ledger in line with balances accepted as correct: the
+    // balances held before the ledger existed, restored ones, and those an
+    // admin accepted when resuming after a reconciliation break
+    Adjustment,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct JournalEntry {
+    id: u64,
+    kind: EntryKind,
+    postings: Vec<Posting>,
+    created_at: u64,
+}
+
+impl JournalEntry {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct AccountBalance {
+    account: LedgerAccount,
+    balance: i128,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct JournalPage {
+    entries: Vec<JournalEntry>,
+    // Pass as `after` to read the next page
+    last_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredBalance(i128);
+
+impl Storable for LedgerAccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StoredBalance {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for JournalEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static JOURNAL: RefCell<StableBTreeMap<u64, JournalEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+
+    // Running balance of every account that has postings
+    static ACCOUNT_BALANCES: RefCell<StableBTreeMap<LedgerAccount, StoredBalance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("ledger.journal", 70, &JOURNAL),
+        manifest::map("ledger.account_balances", 71, &ACCOUNT_BALANCES),
+    ]
+}
+
+pub(crate) fn balance_of(account: LedgerAccount) -> i128 {
+    ACCOUNT_BALANCES
+        .with(|balances| balances.borrow().get(&account))
+        .map_or(0, |balance| balance.0)
+}
+
+/// Every account that has postings, with its balance.
+pub(crate) fn balances() -> Vec<(LedgerAccount, i128)> {
+    ACCOUNT_BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .iter()
+            .map(|(account, balance)| (account, balance.0))
+            .collect()
+    })
+}
+
+/// What the escrow account should hold: the unredeemed gift cards,
+/// unsettled peer transfers, fundraiser contributions and unclaimed sends.
+pub(crate) fn expected_escrow() -> u128 {
+    giftcards::escrowed_amount()
+        + peers::escrowed_amount()
+        + fundraisers::escrowed_amount()
+        + unclaimed::escrowed_amount()
+}
+
+fn signed(posting: &Posting) -> i128 {
+    match posting.side {
+        PostingSide::Credit => posting.amount as i128,
+        PostingSide::Debit => -(posting.amount as i128),
+    }
+}
+
+fn new_entry(kind: EntryKind, postings: Vec<Posting>) -> JournalEntry {
+    JournalEntry {
+        id: next_id(),
+        kind,
+        postings,
+        created_at: current_time(),
+    }
+}
+
+/// Records a balanced entry and applies it to the account balances. Nothing
+/// is applied unless every posting can be: users cannot go below zero or
+/// above `u64::MAX`, and the escrow and fee accounts cannot go negative.
+pub(crate) fn post(kind: EntryKind, postings: Vec<Posting>) -> Result<JournalEntry, WalletError> {
+    if postings.iter().map(signed).sum::<i128>() != 0 {
+        return Err(WalletError::Internal {
+            reason: "ledger entry debits and credits differ".to_string(),
+        });
+    }
+
+    let mut changes: BTreeMap<LedgerAccount, i128> = BTreeMap::new();
+    for posting in &postings {
+        *changes.entry(posting.account).or_default() += signed(posting);
+    }
+    let mut new_balances = Vec::with_capacity(changes.len());
+    for (account, change) in changes {
+        let current = balance_of(account);
+        let balance = current + change;
+        match account {
+            LedgerAccount::User { user_id } | LedgerAccount::Savings { user_id } => {
+                if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+                    return Err(WalletError::not_found("user", user_id));
+                }
+                if balance < 0 {
+                    return Err(WalletError::InsufficientBalance {
+                        available: current.clamp(0, u64::MAX as i128) as u64,
+                        required: (-change) as u64,
+                    });
+                }
+                if balance > u64::MAX as i128 {
+                    return Err(WalletError::Overflow {
+                        field: "balance".to_string(),
+                    });
+                }
+            }
+            LedgerAccount::Escrow | LedgerAccount::Fees | LedgerAccount::Cashback
+                if balance < 0 =>
+            {
+                return Err(WalletError::Internal {
+                    reason: format!("{:?} account would go negative", account),
+                });
+            }
+            _ => {}
+        }
+        new_balances.push((account, balance, change));
+    }
+
+    for (account, balance, change) in new_balances {
+        ACCOUNT_BALANCES.with(|balances| {
+            balances
+                .borrow_mut()
+                .insert(account, StoredBalance(balance))
+        });
+        match account {
+            LedgerAccount::Treasury if change < 0 => {
+                supply::record_minted(Asset::Token, change.unsigned_abs())
+            }
+            LedgerAccount::Treasury => supply::record_burned(Asset::Token, change as u128),
+            LedgerAccount::Fees if change > 0 => supply::record_fee(Asset::Token, change as u128),
+            _ => {}
+        }
+        if let LedgerAccount::User { user_id } = account {
+            USER_STORAGE.with(|storage| {
+                let mut storage = storage.borrow_mut();
+                if let Some(mut user) = storage.get(&user_id) {
+                    user.balance = balance as u64;
+                    storage.insert(user_id, user);
+                }
+            });
+            if change > 0 {
+                reconciliation::record_credit(change as u64);
+            } else if change < 0 {
+                reconciliation::record_debit((-change) as u64);
+            }
+        }
+    }
+    let entry = new_entry(kind, postings);
+    JOURNAL.with(|journal| journal.borrow_mut().insert(entry.id, entry.clone()));
+    Ok(entry)
+}
+
+/// Moves `amount` from one account to another.
+pub(crate) fn transfer(
+    kind: EntryKind,
+    from: LedgerAccount,
+    to: LedgerAccount,
+    amount: u64,
+) -> Result<JournalEntry, WalletError> {
+    post(
+        kind,
+        vec![
+            Posting {
+                account: from,
+                side: PostingSide::Debit,
+                amount,
+            },
+            Posting {
+                account: to,
+                side: PostingSide::Credit,
+                amount,
+            },
+        ],
+    )
+}
+
+/// Posts transfer `tx_id`: the sender pays `amount` to the recipient and
+/// `fee` to the fee account.
+pub(crate) fn post_transfer(
+    tx_id: u64,
+    from_user_id: u64,
+    to_user_id: u64,
+    amount: u64,
+    fee: u64,
+) -> Result<JournalEntry, WalletError> {
+    let debit = amount.checked_add(fee).ok_or(WalletError::Overflow {
+        field: "amount".to_string(),
+    })?;
+    let mut postings = vec![
+        Posting {
+            account: user(from_user_id),
+            side: PostingSide::Debit,
+            amount: debit,
+        },
+        Posting {
+            account: user(to_user_id),
+            side: PostingSide::Credit,
+            amount,
+        },
+    ];
+    if fee > 0 {
+        postings.push(Posting {
+            account: LedgerAccount::Fees,
+            side: PostingSide::Credit,
+            amount: fee,
+        });
+    }
+    post(EntryKind::Transfer { tx_id }, postings)
+}
+
+pub(crate) fn user(user_id: u64) -> LedgerAccount {
+    LedgerAccount::User { user_id }
+}
+
+pub(crate) fn user_balance(user_id: u64) -> u64 {
+    balance_of(user(user_id)).clamp(0, u64::MAX as i128) as u64
+}
+
+pub(crate) fn savings_balance(user_id: u64) -> u64 {
+    balance_of(LedgerAccount::Savings { user_id }).clamp(0, u64::MAX as i128) as u64
+}
+
+/// Credits a user with funds entering the wallet.
+pub(crate) fn deposit(kind: EntryKind, user_id: u64, amount: u64) -> Result<u64, WalletError> {
+    transfer(kind, LedgerAccount::Treasury, user(user_id), amount)?;
+    Ok(user_balance(user_id))
+}
+
+/// Users whose cached balance differs from their ledger account.
+pub(crate) fn mismatched_accounts() -> u64 {
+    USER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(id, user)| user.balance as i128 != balance_of(self::user(*id)))
+            .count() as u64
+    })
+}
+
+/// Posts one adjustment against the treasury that makes every user account
+/// match the cached balance and the escrow account match the unredeemed gift
+/// cards, unsettled peer transfers, fundraiser contributions and unclaimed
+/// sends. Accounts of users that no longer exist are emptied.
+pub(crate) fn adopt_cached_balances() {
+    let mut targets: BTreeMap<LedgerAccount, i128> = ACCOUNT_BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .iter()
+            .filter(|(account, _)| matches!(account, LedgerAccount::User { .. }))
+            .map(|(account, _)| (account, 0))
+            .collect()
+    });
+    USER_STORAGE.with(|storage| {
+        for (id, user) in storage.borrow().iter() {
+            targets.insert(self::user(id), user.balance as i128);
+        }
+    });
+    targets.insert(LedgerAccount::Escrow, expected_escrow() as i128);
+
+    let mut postings = Vec::new();
+    let mut treasury_change = 0i128;
+    for (account, target) in targets {
+        let change = target - balance_of(account);
+        if change == 0 {
+            continue;
+        }
+        treasury_change -= change;
+        postings.push(Posting {
+            account,
+            side: if change > 0 {
+                PostingSide::Credit
+            } else {
+                PostingSide::Debit
+            },
+            amount: change.unsigned_abs() as u64,
+        });
+    }
+    if postings.is_empty() {
+        return;
+    }
+    // The treasury leg may exceed what one posting can hold
+    let side = if treasury_change > 0 {
+        PostingSide::Credit
+    } else {
+        PostingSide::Debit
+    };
+    let mut remaining = treasury_change.unsigned_abs();
+    while remaining > 0 {
+        let amount = remaining.min(u64::MAX as u128) as u64;
+        postings.push(Posting {
+            account: LedgerAccount::Treasury,
+            side,
+            amount,
+        });
+        remaining -= amount as u128;
+    }
+
+    // Applied directly rather than through `post`: the cached balances
+    // already hold the result, accounts of removed users must be emptied,
+    // and reconciliation is reseeded by the caller. The supply counters
+    // follow the treasury leg as `post` would have them.
+    ACCOUNT_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        for posting in &postings {
+            let balance = balances
+                .get(&posting.account)
+                .map_or(0, |balance| balance.0)
+                + signed(posting);
+            balances.insert(posting.account, StoredBalance(balance));
+        }
+    });
+    if treasury_change < 0 {
+        supply::record_minted(Asset::Token, treasury_change.unsigned_abs());
+    } else {
+        supply::record_burned(Asset::Token, treasury_change.unsigned_abs());
+    }
+    let entry = new_entry(EntryKind::Adjustment, postings);
+    JOURNAL.with(|journal| journal.borrow_mut().insert(entry.id, entry));
+}
+
+/// Opens the ledger on canisters that held funds before it existed.
+pub(crate) fn open_if_needed() {
+    if JOURNAL.with(|journal| journal.borrow().is_empty()) {
+        adopt_cached_balances();
+    }
+}
+
+#[ic_cdk::query]
+fn get_ledger_balances() -> Result<Vec<AccountBalance>, WalletError> {
+    perf::instrument("get_ledger_balances", || {
+        ensure_admin()?;
+
+        Ok(balances()
+            .into_iter()
+            .map(|(account, balance)| AccountBalance { account, balance })
+            .collect())
+    })
+}
+
+/// Journal entries with ids above `after`, oldest first.
+#[ic_cdk::query]
+fn get_journal_entries(after: Option<u64>, limit: u64) -> Result<JournalPage, WalletError> {
+    perf::instrument("get_journal_entries", || {
+        ensure_not_restoring()?;
+        ensure_admin()?;
+
+        if limit == 0 || limit > MAX_ENTRIES_PER_PAGE {
+            return Err(WalletError::invalid(
+                "limit",
+                &format!("must be between 1 and {}", MAX_ENTRIES_PER_PAGE),
+            ));
+        }
+        let start = after.map_or(0, |after| after.saturating_add(1));
+        let entries: Vec<JournalEntry> = JOURNAL.with(|journal| {
+            journal
+                .borrow()
+                .range(start..)
+                .take(limit as usize)
+                .map(|(_, entry)| entry)
+                .collect()
+        });
+        Ok(JournalPage {
+            last_id: entries.last().map(|entry| entry.id),
+            entries,
+        })
+    })
+}
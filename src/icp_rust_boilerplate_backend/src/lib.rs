@@ -1,18 +1,162 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Nat, Principal};
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use regex::Regex;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
 use std::{borrow::Cow, cell::RefCell};
 
+mod adjustments;
+mod alerts;
+mod api_keys;
+mod archive;
+mod auth;
+mod autosave;
+mod backup;
+mod balance_history;
+mod budgets;
+mod cache;
+mod campaigns;
+mod cashback;
+mod counterparties;
+mod cycles;
+mod deliveries;
+mod devices;
+mod directory;
+mod disputes;
+mod dust;
+mod earning;
+mod error;
+mod events;
+mod fundraisers;
+mod giftcards;
+mod handles;
+mod hardening;
+mod history_export;
+mod holds;
+mod icrc2;
+mod ids;
+mod incoming;
+mod inspect;
+mod leaderboard;
+mod ledger;
+mod localization;
+mod lockdown;
+mod manifest;
+mod merchants;
+mod migration;
+mod notifications;
+mod overview;
+mod pause;
+mod payment_intents;
+mod peers;
+mod perf;
+mod points;
+mod privacy;
+mod profile;
+mod receipts;
+mod reconciliation;
+mod recovery;
+mod retention;
+mod risk;
+mod sandbox;
+mod spenders;
+mod statements;
+mod subscriptions;
+mod supply;
+mod templates;
+#[cfg(feature = "test-hooks")]
+mod test_hooks;
+mod token;
+mod unclaimed;
+mod username;
+mod v1;
+mod validation;
+mod verification;
+mod vesting;
+
+use adjustments::Adjustment;
+use alerts::{Alert, BalanceAlertConfig, BalanceAlertPayload};
+use api_keys::{ApiKey, ApiKeyScope, CreatedApiKey};
+use archive::{ArchiveConfigPayload, ArchiveStatus};
+use autosave::{AutosavePlan, AutosaveRun, SavingsSummary};
+use backup::{
+    ensure_not_restoring, ensure_writable, BackupManifest, RestoreChunkPayload, RestoreProgress,
+    RestoreSummary,
+};
+use balance_history::{BalancePoint, Granularity};
+use budgets::{Budget, BudgetPayload, BudgetStatus, Category};
+use cache::{CachedMap, Metrics};
+use campaigns::{Campaign, CampaignPayload, CampaignStats, PromoReceipt};
+use cashback::{CashbackDistribution, CashbackPayout, CashbackPolicy, CashbackStatus};
+use counterparties::{
+    CounterpartyLimitPayload, CounterpartyLimitStatus, CounterpartyRules, CounterpartyStatus,
+};
+use cycles::{CyclesDeposit, CyclesMonitorPayload, CyclesStatus, WalletReceiveResult};
+use deliveries::{Delivery, DeliveryStats};
+use devices::Device;
+use directory::PublicProfile;
+use disputes::{Dispute, DisputeResolution, DisputeStatus};
+use dust::{DustConsolidation, TransferConstraints};
+use earning::{EarningRules, PointsQuote};
+use error::WalletError;
+use events::{ActivityPage, ActivityType, EventKind, EventPage};
+use fundraisers::{Contribution, Fundraiser, FundraiserPayload};
+use giftcards::{GiftCard, GiftCardPayload, MintedGiftCard};
+use handles::HandleRegistration;
+use hardening::{FieldChecker, Harden, TextKind};
+use history_export::{HistoryChunk, HistoryExport, HistoryFilter};
+use holds::{BalanceDetails, Hold, HoldPayload};
+use icrc2::{
+    Allowance, AllowanceArgs, ApproveArgs, ApproveError, TransferFromArgs, TransferFromError,
+};
+use ids::{TransactionId, UserId};
+use incoming::IncomingTransfer;
+use leaderboard::{LeaderboardEntry, LeaderboardSnapshot};
+use ledger::{AccountBalance, JournalPage};
+use localization::{LocalizedMessage, MessageTemplate};
+use lockdown::Lockdown;
+use manifest::{StateManifest, Storage};
+use merchants::{Merchant, MerchantPayment, PaymentLink, PaymentLinkPayload, SettlementSummary};
+use migration::{ImportReport, UserImportRecord};
+use notifications::{AdminNotice, Notification, NotificationPreferences};
+use overview::WalletOverview;
+use pause::{PauseLevel, PauseStatus};
+use payment_intents::{MerchantWebhook, PaymentIntent, PaymentIntentPayload};
+use peers::{ExternalTransfer, InboundStatus, Peer, PeerReserveArgs};
+use perf::EndpointStats;
+use points::{PointsTransfer, PointsTransferPayload};
+use privacy::PrivacySettings;
+use profile::{UserView, WhoAmI};
+use receipts::TransactionDetail;
+use reconciliation::ReconciliationReport;
+use recovery::{GuardianConfig, GuardiansPayload, RecoveryRequest};
+use retention::{PrunedCounts, RetentionPolicy, RetentionStatus};
+use risk::{RiskAssessment, RiskConfig, TransferReview};
+use sandbox::{
+    SandboxAccount, SandboxPaymentRequest, SandboxRequestPayload, SandboxTransfer,
+    SandboxTransferPayload, SandboxWipeSummary,
+};
+use spenders::{SpenderGrant, SpenderPayload};
+use statements::{Statement, StatementConfig};
+use subscriptions::{Plan, PlanPayload, SubscribePayload, Subscription, SubscriptionCharge};
+use supply::{Asset, TotalSupply, TreasuryBalances};
+use templates::{TransferTemplate, TransferTemplatePayload};
+use token::TokenMetadata;
+use unclaimed::{UnclaimedSend, UnclaimedSendPayload};
+use v1::{ApiVersion, Message};
+use validation::ValidationRules;
+use verification::{ContactChannel, ContactUpdatePayload, VerificationStatus};
+use vesting::{LockedTransfer, UpcomingUnlock};
+
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct User {
-    id: u64,
+    id: UserId,
     first_name: String,
     last_name: String,
     username: String,
@@ -21,15 +165,26 @@ struct User {
     created_at: u64,
     balance: u64, // Simplified balance for the demo
     points: u64,  // Points for rewards
+    // Absent until the contact detail is verified, and again after it changes
+    email_verified_at: Option<u64>,
+    phone_verified_at: Option<u64>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Transaction {
-    id: u64,
-    from_user_id: u64,
-    to_user_id: u64,
+    id: TransactionId,
+    from_user_id: UserId,
+    to_user_id: UserId,
     amount: u64,
     created_at: u64,
+    memo: Option<String>, // Absent on transactions recorded before memos existed
+}
+
+impl Transaction {
+    // Whether `user_id` sent or received the transaction
+    fn involves(&self, user_id: u64) -> bool {
+        self.from_user_id.0 == user_id || self.to_user_id.0 == user_id
+    }
 }
 
 impl Storable for User {
@@ -40,11 +195,10 @@ impl Storable for User {
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
-}
 
-impl BoundedStorable for User {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    // Memos, contacts and new balances keep growing these records, so their
+    // encoded size is deliberately left uncapped
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 impl Storable for Transaction {
@@ -55,11 +209,8 @@ impl Storable for Transaction {
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
-}
 
-impl BoundedStorable for Transaction {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 thread_local! {
@@ -72,30 +223,68 @@ thread_local! {
             .expect("Cannot create a counter")
     );
 
-    static USER_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    static USER_STORAGE: RefCell<CachedMap<u64, User>> =
+        RefCell::new(CachedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+            cache::USER_CACHE_CAPACITY,
     ));
 
-    static TRANSACTION_STORAGE: RefCell<StableBTreeMap<u64, Transaction, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    static TRANSACTION_STORAGE: RefCell<CachedMap<u64, Transaction>> =
+        RefCell::new(CachedMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+            cache::TRANSACTION_CACHE_CAPACITY,
     ));
 }
 
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::cell("id_counter", 0, &ID_COUNTER),
+        manifest::cached_map("user_storage", 1, &USER_STORAGE),
+        manifest::cached_map("transaction_storage", 2, &TRANSACTION_STORAGE),
+    ]
+}
+
 #[derive(candid::CandidType, Deserialize, Serialize)]
 struct UserPayload {
     first_name: String,
     last_name: String,
     email: String,
     phone_number: String,
+    username: Option<String>, // Generated from the name when omitted
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
+#[derive(candid::CandidType, Clone, Deserialize, Serialize)]
 struct TransactionPayload {
     from_user_id: u64,
     to_user_id: u64,
     amount: u64,
+    category: Option<Category>, // Counts towards the sender's budget for that category
+    memo: Option<String>,
+    // Pays the holder of this handle instead of naming `to_user_id`
+    to_handle: Option<String>,
+}
+
+impl Harden for UserPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        validation::check_account_fields(
+            fields,
+            &self.first_name,
+            &self.last_name,
+            &self.email,
+            &self.phone_number,
+        );
+        fields.optional_text("username", &self.username, TextKind::Alias);
+    }
+}
+
+impl Harden for TransactionPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        if let Some(category) = &self.category {
+            category.harden(fields);
+        }
+        fields.optional_text("memo", &self.memo, TextKind::Memo);
+        fields.optional_text("to_handle", &self.to_handle, TextKind::Alias);
+    }
 }
 
 #[derive(candid::CandidType, Deserialize, Serialize)]
@@ -111,259 +300,582 @@ struct DepositPayload {
     amount: u64,
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
-enum Message {
-    Success(String),
-    Error(String),
-    NotFound(String),
-    InvalidPayload(String),
-    Unauthorized(String),
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DepositReceipt {
+    user_id: u64,
+    amount: u64,
+    new_balance: u64,
 }
 
-#[ic_cdk::update]
-fn create_user(payload: UserPayload) -> Result<User, Message> {
-    if payload.first_name.is_empty()
-        || payload.last_name.is_empty()
-        || payload.email.is_empty()
-        || payload.phone_number.is_empty()
-    {
-        return Err(Message::InvalidPayload(
-            "Ensure 'first_name', 'last_name', 'email', and 'phone_number' are provided."
-                .to_string(),
-        ));
-    }
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct RedemptionReceipt {
+    user_id: u64,
+    points_redeemed: u64,
+    remaining_points: u64,
+}
 
-    let email_regex = Regex::new(r"^[^\s@]+@[^\s@]+\.[^\s@]+$").unwrap();
-    if !email_regex.is_match(&payload.email) {
-        return Err(Message::InvalidPayload(
-            "Invalid email address format".to_string(),
-        ));
-    }
+#[ic_cdk::update]
+fn v2_create_user(payload: UserPayload) -> Result<User, WalletError> {
+    perf::instrument("v2_create_user", || {
+        ensure_writable()?;
+        let owner = auth::caller();
+        auth::ensure_can_own_account(owner)?;
+
+        hardening::check(&payload)?;
+
+        // Ensure the email is unique for each user
+        let is_email_unique = USER_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .all(|(_, user)| user.email != payload.email)
+        });
+        if !is_email_unique {
+            return Err(WalletError::AlreadyExists {
+                entity: "user".to_string(),
+                field: "email".to_string(),
+            });
+        }
 
-    let phone_regex = Regex::new(r"^\+?[1-9]\d{1,14}$").unwrap(); // Basic regex for international phone numbers
-    if !phone_regex.is_match(&payload.phone_number) {
-        return Err(Message::InvalidPayload(
-            "Invalid phone number format".to_string(),
-        ));
-    }
+        let username =
+            username::claim_or_generate(payload.username, &payload.first_name, &payload.last_name)?;
+
+        let id = ids::next_user_id().0;
+
+        let user = User {
+            id: UserId(id),
+            username,
+            first_name: payload.first_name,
+            last_name: payload.last_name,
+            email: payload.email,
+            phone_number: payload.phone_number,
+            created_at: current_time(),
+            balance: 0, // Initialize balance to 0
+            points: 0,  // Initialize points to 0
+            email_verified_at: None,
+            phone_verified_at: None,
+        };
+        USER_STORAGE.with(|storage| storage.borrow_mut().insert(id, user.clone()));
+        username::index_username(&user.username, id);
+        directory::index_user(&user);
+        auth::bind_owner(id, owner);
+        devices::record_activity(id);
+        events::record(EventKind::UserCreated { user_id: id });
+        unclaimed::announce_pending(&user);
+        Ok(user)
+    })
+}
 
-    // Ensure the email is unique for each user
-    let is_email_unique = USER_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .all(|(_, user)| user.email != payload.email)
-    });
-    if !is_email_unique {
-        return Err(Message::InvalidPayload("Email already exists".to_string()));
-    }
+#[ic_cdk::update]
+fn v2_deposit_funds(payload: DepositPayload) -> Result<DepositReceipt, WalletError> {
+    perf::instrument("v2_deposit_funds", || {
+        ensure_writable()?;
+        pause::ensure_transfers_allowed()?;
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+        if payload.amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", payload.amount)?;
+
+        let new_balance = ledger::deposit(
+            ledger::EntryKind::Deposit {
+                user_id: payload.user_id,
+            },
+            payload.user_id,
+            payload.amount,
+        )?;
+        let receipt = DepositReceipt {
+            user_id: payload.user_id,
+            amount: payload.amount,
+            new_balance,
+        };
+        events::record(EventKind::FundsDeposited {
+            user_id: payload.user_id,
+            amount: payload.amount,
+        });
+        Ok(receipt)
+    })
+}
 
-    // Generate a username by concatenating the first and last name, making it to be of defined length
-    let username = format!(
-        "{}{}",
-        payload.first_name.to_lowercase(),
-        payload.last_name.to_lowercase()
-    )
-    .chars()
-    .take(10)
-    .collect::<String>();
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct TransferPreview {
+    from_user_id: u64,
+    to_user_id: u64,
+    amount: u64,
+    sender_balance_after: u64,
+    recipient_balance_after: u64,
+    points_earned: u64,
+}
 
-    let user = User {
-        id,
-        username,
-        first_name: payload.first_name,
-        last_name: payload.last_name,
-        email: payload.email,
-        phone_number: payload.phone_number,
-        created_at: current_time(),
-        balance: 0, // Initialize balance to 0
-        points: 0,  // Initialize points to 0
-    };
-    USER_STORAGE.with(|storage| storage.borrow_mut().insert(id, user.clone()));
-    Ok(user)
+// Every rule a transfer must satisfy lives here so that `send_transaction`
+// and `validate_transfer` can never disagree
+fn check_transfer(payload: &TransactionPayload) -> Result<(User, User), WalletError> {
+    // The owner or one of its authorized spenders within today's cap
+    check_transfer_with(payload, || {
+        spenders::ensure_can_spend(payload.from_user_id, payload.amount)
+    })
 }
 
-#[ic_cdk::update]
-fn deposit_funds(payload: DepositPayload) -> Result<Message, Message> {
+// Same rules, with `authorize` deciding whether the caller may debit the sender
+fn check_transfer_with(
+    payload: &TransactionPayload,
+    authorize: impl FnOnce() -> Result<(), WalletError>,
+) -> Result<(User, User), WalletError> {
+    pause::ensure_transfers_allowed()?;
     if payload.amount == 0 {
-        return Err(Message::InvalidPayload(
-            "Amount must be greater than 0.".to_string(),
-        ));
+        return Err(WalletError::invalid("amount", "must be greater than 0"));
     }
+    token::validate_amount("amount", payload.amount)?;
+    dust::ensure_above_minimum("amount", payload.amount)?;
 
-    USER_STORAGE.with(|storage| {
-        let mut user_storage = storage.borrow_mut();
-        if let Some(mut user) = user_storage.remove(&payload.user_id) {
-            user.balance += payload.amount;
-            user_storage.insert(payload.user_id, user);
-            Ok(Message::Success(format!(
-                "Deposited {} units of currency to user {}",
-                payload.amount, payload.user_id
-            )))
-        } else {
-            Err(Message::NotFound("User not found".to_string()))
-        }
-    })
-}
+    hardening::check(payload)?;
+    if let Some(category) = &payload.category {
+        budgets::validate_category(category)?;
+    }
 
-#[ic_cdk::update]
-fn send_transaction(payload: TransactionPayload) -> Result<Transaction, Message> {
-    if payload.amount == 0 {
-        return Err(Message::InvalidPayload(
-            "Amount must be greater than 0.".to_string(),
+    if payload.from_user_id == payload.to_user_id {
+        return Err(WalletError::invalid(
+            "to_user_id",
+            "sender and recipient must be different users",
         ));
     }
 
-    let from_user = USER_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, user)| user.id == payload.from_user_id)
-            .map(|(_, user)| user.clone())
-    });
+    let from_user = USER_STORAGE
+        .with(|storage| storage.borrow().get(&payload.from_user_id))
+        .ok_or(WalletError::not_found("sender", payload.from_user_id))?;
+    lockdown::ensure_not_locked(payload.from_user_id)?;
+    authorize()?;
+
+    let to_user = USER_STORAGE
+        .with(|storage| storage.borrow().get(&payload.to_user_id))
+        .ok_or(WalletError::not_found("recipient", payload.to_user_id))?;
+    counterparties::ensure_transfer_permitted(
+        payload.from_user_id,
+        payload.to_user_id,
+        "to_user_id",
+    )?;
+    counterparties::ensure_within_limit(payload.from_user_id, payload.to_user_id, payload.amount)?;
+
+    // Held funds stay in the balance but cannot be spent
+    let available = holds::available_balance(from_user.id.0, from_user.balance);
+    if available < payload.amount {
+        return Err(WalletError::InsufficientBalance {
+            available,
+            required: payload.amount,
+        });
+    }
 
-    if from_user.is_none() {
-        return Err(Message::NotFound("Sender not found".to_string()));
+    if to_user.balance.checked_add(payload.amount).is_none() {
+        return Err(WalletError::Overflow {
+            field: "balance".to_string(),
+        });
     }
 
-    let to_user = USER_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, user)| user.id == payload.to_user_id)
-            .map(|(_, user)| user.clone())
-    });
+    Ok((from_user, to_user))
+}
 
-    if to_user.is_none() {
-        return Err(Message::NotFound("Recipient not found".to_string()));
-    }
+#[ic_cdk::query]
+fn v2_validate_transfer(payload: TransactionPayload) -> Result<TransferPreview, WalletError> {
+    perf::instrument("v2_validate_transfer", || {
+        ensure_not_restoring()?;
+
+        hardening::check(&payload)?;
+        let payload = handles::resolve_recipient(payload)?;
+        let (from_user, to_user) = check_transfer(&payload)?;
+        Ok(TransferPreview {
+            from_user_id: payload.from_user_id,
+            to_user_id: payload.to_user_id,
+            amount: payload.amount,
+            sender_balance_after: from_user.balance - payload.amount,
+            recipient_balance_after: to_user.balance + payload.amount,
+            points_earned: earning::preview(&payload),
+        })
+    })
+}
 
-    let mut from_user = from_user.unwrap();
-    let mut to_user = to_user.unwrap();
+#[ic_cdk::update]
+fn v2_send_transaction(payload: TransactionPayload) -> Result<Transaction, WalletError> {
+    perf::instrument("v2_send_transaction", || {
+        ensure_writable()?;
 
-    if from_user.balance < payload.amount {
-        return Err(Message::Error("Insufficient balance.".to_string()));
-    }
+        hardening::check(&payload)?;
+        let payload = handles::resolve_recipient(payload)?;
+        send_transfer(payload, Screening::Hold)
+    })
+}
 
-    from_user.balance -= payload.amount;
-    to_user.balance += payload.amount;
+// What `send_transfer` does with a transfer scoring above the risk threshold
+// or to a recipient who accepts incoming transfers
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Screening {
+    // Holds it for review or for the recipient's acceptance
+    Hold,
+    // Rejects it, for callers whose transfer must execute at once or not at
+    // all, such as a payment link that would otherwise stay unpaid
+    Reject,
+}
 
-    USER_STORAGE.with(|storage| {
-        storage.borrow_mut().insert(from_user.id, from_user.clone());
-        storage.borrow_mut().insert(to_user.id, to_user.clone());
-    });
+// Checks, scores and executes a transfer, unless screening flags it
+fn send_transfer(
+    payload: TransactionPayload,
+    screening: Screening,
+) -> Result<Transaction, WalletError> {
+    let (_, to_user) = check_transfer(&payload)?;
+    let assessment = risk::assess(&payload, &to_user);
+    let needs_review = risk::needs_review(&assessment);
+    let requires_acceptance = incoming::requires_acceptance(payload.to_user_id);
+    if screening == Screening::Reject && (needs_review || requires_acceptance) {
+        return Err(WalletError::InvalidState {
+            reason: if needs_review {
+                "The transfer needs a risk review; send it as a plain transfer instead"
+            } else {
+                "The recipient accepts incoming transfers; send it as a plain transfer instead"
+            }
+            .to_string(),
+        });
+    }
+    // Charged up front so pending transfers count against the cap, and
+    // given back if they never execute
+    let spender = spenders::consume_allowance(payload.from_user_id, payload.amount);
+    devices::record_activity(payload.from_user_id);
+    if needs_review {
+        return Err(risk::hold_for_review(payload, assessment, spender));
+    }
+    if requires_acceptance {
+        return Err(incoming::await_acceptance(payload, spender));
+    }
+    let transaction = execute_transfer(payload);
+    risk::record_score(transaction.id.0, assessment);
+    Ok(transaction)
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+// Moves the funds of a transfer that already passed `check_transfer`
+fn execute_transfer(payload: TransactionPayload) -> Transaction {
+    let id = ids::next_transaction_id().0;
+    ledger::post_transfer(
+        id,
+        payload.from_user_id,
+        payload.to_user_id,
+        payload.amount,
+        receipts::TRANSFER_FEE,
+    )
+    .unwrap_or_else(|error| ic_cdk::trap(&format!("Cannot post transfer {}: {}", id, error)));
+    let from_balance = ledger::user_balance(payload.from_user_id);
+    let to_balance = ledger::user_balance(payload.to_user_id);
+    alerts::check_balance(payload.from_user_id, from_balance);
+    counterparties::record_transfer(payload.from_user_id, payload.to_user_id, payload.amount);
+
+    // Evaluated before the transaction is stored, while it is not yet part
+    // of the sender's history
+    let points = earning::award(
+        id,
+        payload.from_user_id,
+        payload.amount,
+        payload.category.as_ref(),
+    );
 
     let transaction = Transaction {
-        id,
-        from_user_id: payload.from_user_id,
-        to_user_id: payload.to_user_id,
+        id: TransactionId(id),
+        from_user_id: UserId(payload.from_user_id),
+        to_user_id: UserId(payload.to_user_id),
         amount: payload.amount,
         created_at: current_time(),
+        memo: payload.memo,
     };
 
     TRANSACTION_STORAGE.with(|storage| storage.borrow_mut().insert(id, transaction.clone()));
+    risk::record_send(&transaction);
+    receipts::record_balances_after(id, from_balance, to_balance);
+    events::record(EventKind::TransferExecuted {
+        tx_id: id,
+        from_user_id: payload.from_user_id,
+        to_user_id: payload.to_user_id,
+        amount: payload.amount,
+    });
+    notifications::notify(
+        payload.to_user_id,
+        notifications::NotificationKind::IncomingTransfer,
+        format!(
+            "Received {} from user {}",
+            token::format_amount(payload.amount),
+            payload.from_user_id
+        ),
+    );
+    if let Some(category) = payload.category {
+        budgets::record_category(id, category);
+    }
 
     // Award points for the transaction
-    let points = payload.amount / 10; // Award 1 point for every 10 units of currency
     USER_STORAGE.with(|storage| {
         let mut user_storage = storage.borrow_mut();
         if let Some(mut from_user) = user_storage.remove(&payload.from_user_id) {
-            from_user.points += points;
+            let awarded = points.min(u64::MAX - from_user.points);
+            from_user.points += awarded;
+            supply::record_minted(Asset::Points, awarded as u128);
+            leaderboard::index_points(payload.from_user_id, from_user.points);
             user_storage.insert(payload.from_user_id, from_user);
         }
     });
+    if points > 0 {
+        events::record(EventKind::PointsAwarded {
+            user_id: payload.from_user_id,
+            points,
+        });
+    }
 
-    Ok(transaction)
+    transaction
 }
 
 #[ic_cdk::update]
-fn redeem_points(payload: PointsPayload) -> Result<Message, Message> {
-    USER_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(mut user) = storage.remove(&payload.user_id) {
-            if user.points >= payload.points {
-                user.points -= payload.points;
-                storage.insert(payload.user_id, user);
-                Ok(Message::Success(format!(
-                    "Redeemed {} points from user {}",
-                    payload.points, payload.user_id
-                )))
+fn v2_redeem_points(payload: PointsPayload) -> Result<RedemptionReceipt, WalletError> {
+    perf::instrument("v2_redeem_points", || {
+        ensure_writable()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id)) {
+            return Err(WalletError::not_found("user", payload.user_id));
+        }
+        auth::ensure_owner(payload.user_id)?;
+        lockdown::ensure_not_locked(payload.user_id)?;
+
+        let receipt = USER_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            if let Some(mut user) = storage.remove(&payload.user_id) {
+                if user.points >= payload.points {
+                    user.points -= payload.points;
+                    supply::record_burned(Asset::Points, payload.points as u128);
+                    let remaining_points = user.points;
+                    storage.insert(payload.user_id, user);
+                    Ok(RedemptionReceipt {
+                        user_id: payload.user_id,
+                        points_redeemed: payload.points,
+                        remaining_points,
+                    })
+                } else {
+                    let available = user.points;
+                    storage.insert(payload.user_id, user); // Re-insert user in case of error
+                    Err(WalletError::InsufficientPoints {
+                        available,
+                        required: payload.points,
+                    })
+                }
             } else {
-                storage.insert(payload.user_id, user); // Re-insert user in case of error
-                Err(Message::Error("Insufficient points.".to_string()))
+                Err(WalletError::not_found("user", payload.user_id))
             }
-        } else {
-            Err(Message::NotFound("User not found".to_string()))
-        }
+        })?;
+        leaderboard::index_points(payload.user_id, receipt.remaining_points);
+        events::record(EventKind::PointsRedeemed {
+            user_id: payload.user_id,
+            points: payload.points,
+        });
+        Ok(receipt)
     })
 }
 
 #[ic_cdk::query]
-fn get_transaction_history(user_id: u64) -> Result<Vec<Transaction>, Message> {
-    TRANSACTION_STORAGE.with(|storage| {
-        let transactions: Vec<Transaction> = storage
-            .borrow()
-            .iter()
-            .filter(|(_, transaction)| {
-                transaction.from_user_id == user_id || transaction.to_user_id == user_id
-            })
-            .map(|(_, transaction)| transaction.clone())
-            .collect();
-
-        if transactions.is_empty() {
-            Err(Message::NotFound("No transactions found".to_string()))
-        } else {
-            Ok(transactions)
+fn v2_get_transaction_history(user_id: u64) -> Result<Vec<Transaction>, WalletError> {
+    perf::instrument("v2_get_transaction_history", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
         }
+
+        Ok(TRANSACTION_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, transaction)| transaction.involves(user_id))
+                .map(|(_, transaction)| transaction.clone())
+                .collect()
+        }))
     })
 }
 
 #[ic_cdk::query]
-fn get_user_balance(user_id: u64) -> Result<u64, Message> {
-    USER_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, user)| user.id == user_id)
-            .map(|(_, user)| user.balance)
-            .ok_or(Message::NotFound("User not found".to_string()))
+fn v2_get_user_balance(user_id: u64) -> Result<u64, WalletError> {
+    perf::instrument("v2_get_user_balance", || {
+        ensure_not_restoring()?;
+
+        USER_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .get(&user_id)
+                .map(|user| user.balance)
+                .ok_or(WalletError::not_found("user", user_id))
+        })
     })
 }
 
 #[ic_cdk::query]
-fn get_user_points(user_id: u64) -> Result<u64, Message> {
-    USER_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .find(|(_, user)| user.id == user_id)
-            .map(|(_, user)| user.points)
-            .ok_or(Message::NotFound("User not found".to_string()))
+fn v2_get_user_points(user_id: u64) -> Result<u64, WalletError> {
+    perf::instrument("v2_get_user_points", || {
+        ensure_not_restoring()?;
+
+        USER_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .get(&user_id)
+                .map(|user| user.points)
+                .ok_or(WalletError::not_found("user", user_id))
+        })
     })
 }
 
+#[ic_cdk::init]
+fn init() {
+    supply::seed_if_needed();
+    start_timers();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // Accounts created before the username and points indexes existed are
+    // indexed here
+    username::rebuild_index();
+    leaderboard::rebuild_index();
+    directory::rebuild_index();
+    // Balances held before the ledger existed are posted as its opening
+    // entry
+    ledger::open_if_needed();
+    // As are the balances and points held before supply accounting existed
+    supply::seed_if_needed();
+    // And what users sent their capped counterparties before it was kept
+    counterparties::seed_sent_windows_if_needed();
+    // And the risk aggregates of senders scored before they were kept
+    risk::seed_aggregates_if_needed();
+    // And the holds and locks placed before they were indexed by user
+    holds::seed_index_if_needed();
+    vesting::seed_totals_if_needed();
+    // Timers do not survive upgrades and must be registered again
+    start_timers();
+}
+
+fn start_timers() {
+    budgets::start_budget_monitor();
+    recovery::start_recovery_monitor();
+    subscriptions::start_billing_job();
+    giftcards::start_refund_job();
+    cycles::start_cycles_monitor();
+    archive::start_archive_job();
+    reconciliation::start_reconciliation_job();
+    holds::start_expiry_job();
+    leaderboard::start_snapshot_job();
+    peers::start_recovery_job();
+    statements::start_statement_job();
+    vesting::start_release_job();
+    fundraisers::start_settlement_job();
+    autosave::start_autosave_job();
+    cashback::start_cashback_job();
+    handles::start_expiry_job();
+    retention::start_pruning_job();
+    balance_history::start_snapshot_job();
+    unclaimed::start_refund_job();
+}
+
 fn current_time() -> u64 {
+    #[cfg(feature = "test-hooks")]
+    if let Some(now) = test_hooks::mock_time() {
+        return now;
+    }
     time()
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    Unauthorized { msg: String },
+fn next_id() -> u64 {
+    let id = ID_COUNTER.with(|counter| *counter.borrow().get());
+    let next = id
+        .checked_add(1)
+        .unwrap_or_else(|| ic_cdk::trap("The id space is exhausted"));
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(next))
+        .expect("Cannot increment ID counter");
+    id
+}
+
+fn clear_map<K: Storable + Ord + Clone, V: Storable>(map: &mut StableBTreeMap<K, V, Memory>) {
+    let keys: Vec<K> = map.iter().map(|(key, _)| key).collect();
+    for key in keys {
+        map.remove(&key);
+    }
+}
+
+// Controllers of the canister act as its administrators
+fn ensure_admin() -> Result<(), WalletError> {
+    if !ic_cdk::api::is_controller(&auth::caller()) {
+        return Err(WalletError::Unauthorized {
+            reason: "only canister controllers can call this method".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
 }
 
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardening::{
+        MAX_ALIAS_LEN, MAX_EMAIL_LEN, MAX_MEMO_LEN, MAX_NAME_LEN, MAX_PHONE_LEN,
+    };
+
+    // Text of `len` characters, each of the widest UTF-8 encoding
+    fn widest_text(len: usize) -> String {
+        "\u{1F600}".repeat(len)
+    }
+
+    // Encodes `value`, checks it against the declared bound and that it
+    // decodes back to the same bytes
+    fn assert_fits<T: Storable>(value: &T) {
+        let bytes = value.to_bytes().into_owned();
+        if let Bound::Bounded { max_size, .. } = T::BOUND {
+            assert!(
+                bytes.len() <= max_size as usize,
+                "{} bytes exceed the bound of {}",
+                bytes.len(),
+                max_size
+            );
+        }
+        let decoded = T::from_bytes(Cow::Owned(bytes.clone()));
+        assert_eq!(decoded.to_bytes().into_owned(), bytes);
+    }
+
+    #[test]
+    fn maximal_user_round_trips() {
+        assert_fits(&User {
+            id: UserId(u64::MAX),
+            first_name: widest_text(MAX_NAME_LEN),
+            last_name: widest_text(MAX_NAME_LEN),
+            username: widest_text(MAX_ALIAS_LEN),
+            email: widest_text(MAX_EMAIL_LEN),
+            phone_number: widest_text(MAX_PHONE_LEN),
+            created_at: u64::MAX,
+            balance: u64::MAX,
+            points: u64::MAX,
+            email_verified_at: Some(u64::MAX),
+            phone_verified_at: Some(u64::MAX),
+        });
+    }
+
+    #[test]
+    fn maximal_transaction_round_trips() {
+        assert_fits(&Transaction {
+            id: TransactionId(u64::MAX),
+            from_user_id: UserId(u64::MAX),
+            to_user_id: UserId(u64::MAX),
+            amount: u64::MAX,
+            created_at: u64::MAX,
+            memo: Some(widest_text(MAX_MEMO_LEN)),
+        });
+    }
+
+    #[test]
+    fn transaction_without_memo_round_trips() {
+        assert_fits(&Transaction::default());
+    }
+}
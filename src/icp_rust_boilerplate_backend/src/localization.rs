@@ -0,0 +1,305 @@
+//! Localized messages. Every error has a stable code (`WalletError::code`)
+//! that clients can branch on, and a human-readable text that is looked up
+//! by that code in a message catalog for the caller's locale. Admins add
+//! languages by storing templates in the catalog, with `{name}` placeholders
+//! filled from the error's fields; no redeploy is needed.
+//!
+//! A message missing from the caller's locale falls back to its base
+//! language (`pt` for `pt-br`), then to an `en` template, then to the
+//! built-in English text. Free-form reasons carried by some errors are
+//! inserted as they are.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::{current_time, ensure_admin, perf, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::BTreeSet;
+use std::{borrow::Cow, cell::RefCell};
+
+const DEFAULT_LOCALE: &str = "en";
+const MAX_LOCALE_LENGTH: usize = 16;
+const MAX_CODE_LENGTH: usize = 64;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct MessageTemplate {
+    locale: String,
+    code: String,
+    template: String,
+    updated_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct LocalizedMessage {
+    code: String,
+    // The locale the message was found in, after falling back
+    locale: String,
+    message: String,
+}
+
+impl LocalizedMessage {
+    pub(crate) fn into_message(self) -> String {
+        self.message
+    }
+}
+
+impl Storable for MessageTemplate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Keyed by "locale/code"
+    static MESSAGE_CATALOG: RefCell<StableBTreeMap<String, MessageTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))
+    ));
+
+    static USER_LOCALES: RefCell<StableBTreeMap<u64, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("localization.message_catalog", 90, &MESSAGE_CATALOG),
+        manifest::map("localization.user_locales", 91, &USER_LOCALES),
+    ]
+}
+
+fn catalog_key(locale: &str, code: &str) -> String {
+    format!("{}/{}", locale, code)
+}
+
+// Locales are language tags such as "en", "fr" or "pt-br", compared in
+// lowercase
+fn normalize_locale(locale: &str) -> Result<String, WalletError> {
+    let locale = locale.trim().to_lowercase();
+    if locale.is_empty() || locale.len() > MAX_LOCALE_LENGTH {
+        return Err(WalletError::invalid(
+            "locale",
+            &format!("must be between 1 and {} characters", MAX_LOCALE_LENGTH),
+        ));
+    }
+    let valid = locale.starts_with(|c: char| c.is_ascii_lowercase())
+        && locale
+            .split('-')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()));
+    if !valid {
+        return Err(WalletError::invalid(
+            "locale",
+            "must be a language tag such as 'en' or 'pt-br'",
+        ));
+    }
+    Ok(locale)
+}
+
+fn validate_code(code: &str) -> Result<(), WalletError> {
+    if code.is_empty()
+        || code.len() > MAX_CODE_LENGTH
+        || !code
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(WalletError::invalid(
+            "code",
+            &format!(
+                "must be 1 to {} uppercase letters, digits or underscores",
+                MAX_CODE_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn locale_of(user_id: u64) -> Option<String> {
+    USER_LOCALES.with(|locales| locales.borrow().get(&user_id))
+}
+
+fn caller_locale() -> String {
+    caller_user_id()
+        .ok()
+        .and_then(locale_of)
+        .unwrap_or_else(|| DEFAULT_LOCALE.to_string())
+}
+
+fn render(template: &str, params: &[(&str, String)]) -> String {
+    params
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+fn localize_in(
+    locale: &str,
+    code: &str,
+    params: &[(&str, String)],
+    fallback: String,
+) -> LocalizedMessage {
+    let mut candidates = vec![locale.to_string()];
+    if let Some((language, _)) = locale.split_once('-') {
+        candidates.push(language.to_string());
+    }
+    candidates.push(DEFAULT_LOCALE.to_string());
+    let found = MESSAGE_CATALOG.with(|catalog| {
+        let catalog = catalog.borrow();
+        candidates
+            .iter()
+            .find_map(|candidate| catalog.get(&catalog_key(candidate, code)))
+    });
+    match found {
+        Some(template) => LocalizedMessage {
+            code: code.to_string(),
+            message: render(&template.template, params),
+            locale: template.locale,
+        },
+        None => LocalizedMessage {
+            code: code.to_string(),
+            locale: DEFAULT_LOCALE.to_string(),
+            message: fallback,
+        },
+    }
+}
+
+/// The message with `code` in the caller's locale, or `fallback` if no
+/// catalog has it.
+pub(crate) fn localize(
+    code: &str,
+    params: &[(&str, String)],
+    fallback: String,
+) -> LocalizedMessage {
+    localize_in(&caller_locale(), code, params, fallback)
+}
+
+pub(crate) fn localize_error(error: &WalletError) -> LocalizedMessage {
+    localize(error.code(), &error.params(), error.to_string())
+}
+
+/// Sets the locale messages are shown to `user_id` in, or returns to the
+/// default with `None`.
+#[ic_cdk::update]
+fn set_locale(user_id: u64, locale: Option<String>) -> Result<String, WalletError> {
+    perf::instrument("set_locale", || {
+        ensure_writable()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        let Some(locale) = locale else {
+            USER_LOCALES.with(|locales| locales.borrow_mut().remove(&user_id));
+            return Ok(DEFAULT_LOCALE.to_string());
+        };
+        hardening::check_args(|fields| fields.required_text("locale", &locale, TextKind::Alias))?;
+        let locale = normalize_locale(&locale)?;
+        USER_LOCALES.with(|locales| locales.borrow_mut().insert(user_id, locale.clone()));
+        Ok(locale)
+    })
+}
+
+#[ic_cdk::query]
+fn get_locale(user_id: u64) -> Result<String, WalletError> {
+    perf::instrument("get_locale", || {
+        ensure_not_restoring()?;
+        ensure_owner(user_id)?;
+
+        Ok(locale_of(user_id).unwrap_or_else(|| DEFAULT_LOCALE.to_string()))
+    })
+}
+
+/// Stores the template for `code` in `locale`, or removes it with `None`.
+#[ic_cdk::update]
+fn set_message_template(
+    locale: String,
+    code: String,
+    template: Option<String>,
+) -> Result<(), WalletError> {
+    perf::instrument("set_message_template", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let locale = normalize_locale(&locale)?;
+        validate_code(&code)?;
+        let key = catalog_key(&locale, &code);
+        let Some(template) = template else {
+            MESSAGE_CATALOG.with(|catalog| catalog.borrow_mut().remove(&key));
+            return Ok(());
+        };
+        hardening::check_args(|fields| {
+            fields.required_text("template", &template, TextKind::Reason)
+        })?;
+        MESSAGE_CATALOG.with(|catalog| {
+            catalog.borrow_mut().insert(
+                key,
+                MessageTemplate {
+                    locale,
+                    code,
+                    template,
+                    updated_at: current_time(),
+                },
+            )
+        });
+        Ok(())
+    })
+}
+
+/// The templates stored for `locale`, or for every locale with `None`.
+#[ic_cdk::query]
+fn get_message_catalog(locale: Option<String>) -> Result<Vec<MessageTemplate>, WalletError> {
+    perf::instrument("get_message_catalog", || {
+        ensure_not_restoring()?;
+
+        let locale = locale.map(|locale| normalize_locale(&locale)).transpose()?;
+        Ok(MESSAGE_CATALOG.with(|catalog| {
+            catalog
+                .borrow()
+                .iter()
+                .map(|(_, template)| template)
+                .filter(|template| {
+                    locale
+                        .as_ref()
+                        .is_none_or(|locale| &template.locale == locale)
+                })
+                .collect()
+        }))
+    })
+}
+
+/// The locales messages are available in; English always is.
+#[ic_cdk::query]
+fn list_locales() -> Result<Vec<String>, WalletError> {
+    perf::instrument("list_locales", || {
+        ensure_not_restoring()?;
+
+        let mut locales: BTreeSet<String> = MESSAGE_CATALOG.with(|catalog| {
+            catalog
+                .borrow()
+                .iter()
+                .map(|(_, template)| template.locale)
+                .collect()
+        });
+        locales.insert(DEFAULT_LOCALE.to_string());
+        Ok(locales.into_iter().collect())
+    })
+}
+
+/// The code of an error an endpoint returned and its text in the caller's
+/// locale.
+#[ic_cdk::query]
+fn describe_error(error: WalletError) -> LocalizedMessage {
+    perf::measure("describe_error", || localize_error(&error))
+}
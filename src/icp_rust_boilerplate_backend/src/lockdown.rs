@@ -0,0 +1,272 @@
+//! Emergency lockdown. A user who suspects their account is compromised
+//! calls `lock_my_account`, and from then on nothing leaves the account:
+//! transfers, holds and their captures, payments from spenders, gift cards,
+//! contributions, unclaimed and external sends and points transfers are all
+//! rejected with `AccountLocked`, and guardians cannot be changed. Incoming
+//! funds still arrive.
+//!
+//! Unlocking takes one of three routes, so a stolen key alone cannot undo a
+//! lock at once:
+//! - the owner requests it and confirms after `UNLOCK_DELAY`; locking again
+//!   cancels a pending request;
+//! - the account's guardians approve it, as many as their recovery
+//!   threshold;
+//! - a controller unlocks it.
+//!
+//! Every lock and unlock is journaled as an event and raises a security
+//! notification to the owner and a notice to the controllers.
+
+use crate::auth::{self, caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::events::{self, EventKind};
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, notify_admins, NotificationKind};
+use crate::{
+    current_time, ensure_admin, perf, recovery, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+// An owner's unlock request can be confirmed after 24 hours
+const UNLOCK_DELAY: u64 = 24 * NANOS_PER_HOUR;
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum UnlockMethod {
+    // Requested by the owner and confirmed after the delay
+    DelayedConfirmation,
+    GuardianApproval,
+    Admin,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Lockdown {
+    user_id: u64,
+    locked_at: u64,
+    reason: Option<String>,
+    // Set while an owner's unlock request is pending
+    unlock_requested_at: Option<u64>,
+    unlock_available_at: Option<u64>,
+    guardian_approvals: Vec<Principal>,
+}
+
+impl Storable for Lockdown {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Accounts currently locked down
+    static LOCKDOWNS: RefCell<StableBTreeMap<u64, Lockdown, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map("lockdown.lockdowns", 109, &LOCKDOWNS)]
+}
+
+fn lockdown_of(user_id: u64) -> Option<Lockdown> {
+    LOCKDOWNS.with(|storage| storage.borrow().get(&user_id))
+}
+
+fn save_lockdown(lockdown: &Lockdown) {
+    LOCKDOWNS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(lockdown.user_id, lockdown.clone())
+    });
+}
+
+fn not_locked(user_id: u64) -> WalletError {
+    WalletError::InvalidState {
+        reason: format!("Account {} is not locked", user_id),
+    }
+}
+
+/// Rejects outgoing operations of a locked account.
+pub(crate) fn ensure_not_locked(user_id: u64) -> Result<(), WalletError> {
+    if LOCKDOWNS.with(|storage| storage.borrow().contains_key(&user_id)) {
+        return Err(WalletError::AccountLocked { user_id });
+    }
+    Ok(())
+}
+
+fn unlock(user_id: u64, method: UnlockMethod) {
+    LOCKDOWNS.with(|storage| storage.borrow_mut().remove(&user_id));
+    events::record(EventKind::AccountUnlocked { user_id, method });
+    notify(
+        user_id,
+        NotificationKind::Security,
+        format!("Your account was unlocked ({:?})", method),
+    );
+    notify_admins(format!("Account {} was unlocked ({:?})", user_id, method));
+}
+
+/// Locks the caller's account at once. Locking an account that is already
+/// locked cancels any pending unlock.
+#[ic_cdk::update]
+fn lock_my_account(reason: Option<String>) -> Result<Lockdown, WalletError> {
+    perf::instrument("lock_my_account", || {
+        ensure_writable()?;
+
+        hardening::check_args(|fields| fields.optional_text("reason", &reason, TextKind::Reason))?;
+        let user_id = caller_user_id()?;
+
+        let now = current_time();
+        let lockdown = match lockdown_of(user_id) {
+            Some(mut lockdown) => {
+                lockdown.unlock_requested_at = None;
+                lockdown.unlock_available_at = None;
+                lockdown.guardian_approvals.clear();
+                lockdown.reason = reason.or(lockdown.reason);
+                lockdown
+            }
+            None => Lockdown {
+                user_id,
+                locked_at: now,
+                reason,
+                unlock_requested_at: None,
+                unlock_available_at: None,
+                guardian_approvals: Vec::new(),
+            },
+        };
+        save_lockdown(&lockdown);
+        events::record(EventKind::AccountLocked { user_id });
+        notify(
+            user_id,
+            NotificationKind::Security,
+            "Your account was locked. Outgoing operations are blocked until it is unlocked"
+                .to_string(),
+        );
+        notify_admins(format!("User {} locked their account", user_id));
+        Ok(lockdown)
+    })
+}
+
+/// Starts the owner's unlock; `confirm_unlock` completes it once the delay
+/// has passed.
+#[ic_cdk::update]
+fn request_unlock(user_id: u64) -> Result<Lockdown, WalletError> {
+    perf::instrument("request_unlock", || {
+        ensure_writable()?;
+        ensure_owner(user_id)?;
+
+        let mut lockdown = lockdown_of(user_id).ok_or_else(|| not_locked(user_id))?;
+        if lockdown.unlock_requested_at.is_some() {
+            return Err(WalletError::AlreadyExists {
+                entity: "unlock request".to_string(),
+                field: "user_id".to_string(),
+            });
+        }
+        let now = current_time();
+        let available_at = now + UNLOCK_DELAY;
+        lockdown.unlock_requested_at = Some(now);
+        lockdown.unlock_available_at = Some(available_at);
+        save_lockdown(&lockdown);
+        notify(
+            user_id,
+            NotificationKind::Security,
+            format!(
+                "An unlock was requested and can be confirmed at {}. Lock the account again if this was not you",
+                available_at
+            ),
+        );
+        Ok(lockdown)
+    })
+}
+
+#[ic_cdk::update]
+fn confirm_unlock(user_id: u64) -> Result<(), WalletError> {
+    perf::instrument("confirm_unlock", || {
+        ensure_writable()?;
+        ensure_owner(user_id)?;
+
+        let lockdown = lockdown_of(user_id).ok_or_else(|| not_locked(user_id))?;
+        let Some(available_at) = lockdown.unlock_available_at else {
+            return Err(WalletError::InvalidState {
+                reason: "Request an unlock with request_unlock first".to_string(),
+            });
+        };
+        if current_time() < available_at {
+            return Err(WalletError::InvalidState {
+                reason: format!("The unlock can be confirmed from {}", available_at),
+            });
+        }
+        unlock(user_id, UnlockMethod::DelayedConfirmation);
+        Ok(())
+    })
+}
+
+/// Called by a guardian of `user_id`. The account unlocks once as many
+/// guardians as its recovery threshold approved.
+#[ic_cdk::update]
+fn approve_unlock(user_id: u64) -> Result<Option<Lockdown>, WalletError> {
+    perf::instrument("approve_unlock", || {
+        ensure_writable()?;
+
+        let guardian = auth::caller();
+        let config = recovery::guardian_config(user_id)
+            .ok_or(WalletError::not_found("guardian config", user_id))?;
+        if !config.is_guardian(guardian) {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller is not a guardian of user {}", user_id),
+            });
+        }
+        let mut lockdown = lockdown_of(user_id).ok_or_else(|| not_locked(user_id))?;
+        if !lockdown.guardian_approvals.contains(&guardian) {
+            lockdown.guardian_approvals.push(guardian);
+        }
+        if lockdown.guardian_approvals.len() >= config.threshold() as usize {
+            unlock(user_id, UnlockMethod::GuardianApproval);
+            return Ok(None);
+        }
+        save_lockdown(&lockdown);
+        Ok(Some(lockdown))
+    })
+}
+
+#[ic_cdk::update]
+fn admin_unlock_account(user_id: u64) -> Result<(), WalletError> {
+    perf::instrument("admin_unlock_account", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if lockdown_of(user_id).is_none() {
+            return Err(not_locked(user_id));
+        }
+        unlock(user_id, UnlockMethod::Admin);
+        Ok(())
+    })
+}
+
+/// The lockdown of `user_id`, or `None` if the account is not locked. For
+/// the owner, the controllers and the account's guardians.
+#[ic_cdk::query]
+fn get_lockdown_status(user_id: u64) -> Result<Option<Lockdown>, WalletError> {
+    perf::instrument("get_lockdown_status", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        let is_guardian = recovery::guardian_config(user_id)
+            .is_some_and(|config| config.is_guardian(auth::caller()));
+        if !is_guardian && ensure_admin().is_err() {
+            ensure_owner(user_id)?;
+        }
+        Ok(lockdown_of(user_id))
+    })
+}
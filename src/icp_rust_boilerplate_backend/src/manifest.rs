@@ -0,0 +1,339 @@
+//! State manifest for migrations. `export_state_manifest` lists every
+//! stable storage with its record count and a SHA-256 over its encoded
+//! records, in key order, so the state before and after a cutover can be
+//! compared storage by storage. Each module lists its own storages in
+//! `storages`, which test snapshots copy as well.
+//!
+//! The manifest reads every record, so it is meant to be taken while the
+//! canister is read-only and nothing changes in between.
+
+use crate::cache::CachedMap;
+use crate::{ensure_admin, perf, Memory, WalletError};
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct StorageManifest {
+    storage: String,
+    memory_id: u8,
+    records: u64,
+    sha256: String,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct StateManifest {
+    created_at: u64,
+    read_only: bool,
+    storages: Vec<StorageManifest>,
+    // SHA-256 over the hashes of all storages, in manifest order
+    sha256: String,
+}
+
+/// An encoded key and value. A cell's single record has an empty key.
+#[cfg(feature = "test-hooks")]
+pub(crate) type Record = (Vec<u8>, Vec<u8>);
+
+/// One stable storage of a module.
+pub(crate) trait Storage {
+    fn memory_id(&self) -> u8;
+
+    fn describe(&self) -> StorageManifest;
+
+    /// Every record in key order.
+    #[cfg(feature = "test-hooks")]
+    fn export(&self) -> Vec<Record>;
+
+    /// Replaces every record with `records`, as `export` returned them.
+    #[cfg(feature = "test-hooks")]
+    fn import(&self, records: &[Record]);
+}
+
+struct MapStorage<K: Storable + Ord + Clone + 'static, V: Storable + 'static> {
+    name: &'static str,
+    memory_id: u8,
+    map: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+}
+
+struct CachedMapStorage<K: Storable + Ord + Clone + 'static, V: Storable + Clone + 'static> {
+    name: &'static str,
+    memory_id: u8,
+    map: &'static LocalKey<RefCell<CachedMap<K, V>>>,
+}
+
+struct CellStorage<T: Storable + 'static> {
+    name: &'static str,
+    memory_id: u8,
+    cell: &'static LocalKey<RefCell<Cell<T, Memory>>>,
+}
+
+/// A stable map.
+pub(crate) fn map<K: Storable + Ord + Clone, V: Storable>(
+    name: &'static str,
+    memory_id: u8,
+    map: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+) -> Box<dyn Storage> {
+    Box::new(MapStorage {
+        name,
+        memory_id,
+        map,
+    })
+}
+
+/// A stable map behind a cache.
+pub(crate) fn cached_map<K: Storable + Ord + Clone, V: Storable + Clone>(
+    name: &'static str,
+    memory_id: u8,
+    map: &'static LocalKey<RefCell<CachedMap<K, V>>>,
+) -> Box<dyn Storage> {
+    Box::new(CachedMapStorage {
+        name,
+        memory_id,
+        map,
+    })
+}
+
+/// A cell holding a single value.
+pub(crate) fn cell<T: Storable>(
+    name: &'static str,
+    memory_id: u8,
+    cell: &'static LocalKey<RefCell<Cell<T, Memory>>>,
+) -> Box<dyn Storage> {
+    Box::new(CellStorage {
+        name,
+        memory_id,
+        cell,
+    })
+}
+
+impl<K: Storable + Ord + Clone, V: Storable> Storage for MapStorage<K, V> {
+    fn memory_id(&self) -> u8 {
+        self.memory_id
+    }
+
+    fn describe(&self) -> StorageManifest {
+        self.map
+            .with(|map| describe(self.name, self.memory_id, map.borrow().iter()))
+    }
+
+    #[cfg(feature = "test-hooks")]
+    fn export(&self) -> Vec<Record> {
+        self.map
+            .with(|map| map.borrow().iter().map(encode).collect())
+    }
+
+    #[cfg(feature = "test-hooks")]
+    fn import(&self, records: &[Record]) {
+        self.map.with(|map| {
+            let mut map = map.borrow_mut();
+            crate::clear_map(&mut map);
+            for (key, value) in records {
+                map.insert(decode(key), decode(value));
+            }
+        });
+    }
+}
+
+impl<K: Storable + Ord + Clone, V: Storable + Clone> Storage for CachedMapStorage<K, V> {
+    fn memory_id(&self) -> u8 {
+        self.memory_id
+    }
+
+    fn describe(&self) -> StorageManifest {
+        self.map
+            .with(|map| describe(self.name, self.memory_id, map.borrow().iter()))
+    }
+
+    #[cfg(feature = "test-hooks")]
+    fn export(&self) -> Vec<Record> {
+        self.map
+            .with(|map| map.borrow().iter().map(encode).collect())
+    }
+
+    #[cfg(feature = "test-hooks")]
+    fn import(&self, records: &[Record]) {
+        self.map.with(|map| {
+            let mut map = map.borrow_mut();
+            map.clear();
+            for (key, value) in records {
+                map.insert(decode(key), decode(value));
+            }
+        });
+    }
+}
+
+impl<T: Storable> Storage for CellStorage<T> {
+    fn memory_id(&self) -> u8 {
+        self.memory_id
+    }
+
+    fn describe(&self) -> StorageManifest {
+        self.cell
+            .with(|cell| describe_value(self.name, self.memory_id, cell.borrow().get()))
+    }
+
+    #[cfg(feature = "test-hooks")]
+    fn export(&self) -> Vec<Record> {
+        self.cell
+            .with(|cell| vec![(Vec::new(), cell.borrow().get().to_bytes().into_owned())])
+    }
+
+    #[cfg(feature = "test-hooks")]
+    fn import(&self, records: &[Record]) {
+        if let Some((_, value)) = records.first() {
+            self.cell
+                .with(|cell| cell.borrow_mut().set(decode(value)))
+                .unwrap_or_else(|_| panic!("Cannot restore {}", self.name));
+        }
+    }
+}
+
+#[cfg(feature = "test-hooks")]
+fn encode<K: Storable, V: Storable>((key, value): (K, V)) -> Record {
+    (key.to_bytes().into_owned(), value.to_bytes().into_owned())
+}
+
+#[cfg(feature = "test-hooks")]
+fn decode<T: Storable>(bytes: &[u8]) -> T {
+    T::from_bytes(std::borrow::Cow::Borrowed(bytes))
+}
+
+fn hex(digest: impl AsRef<[u8]>) -> String {
+    digest
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    // Length-prefixed, so record boundaries are part of the hash
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+// Describes a map from its entries in key order
+fn describe<K: Storable, V: Storable>(
+    storage: &str,
+    memory_id: u8,
+    entries: impl Iterator<Item = (K, V)>,
+) -> StorageManifest {
+    let mut hasher = Sha256::new();
+    let mut records = 0u64;
+    for (key, value) in entries {
+        hash_bytes(&mut hasher, &key.to_bytes());
+        hash_bytes(&mut hasher, &value.to_bytes());
+        records += 1;
+    }
+    StorageManifest {
+        storage: storage.to_string(),
+        memory_id,
+        records,
+        sha256: hex(hasher.finalize()),
+    }
+}
+
+// Describes a cell from the value it holds
+fn describe_value<T: Storable>(storage: &str, memory_id: u8, value: &T) -> StorageManifest {
+    let mut hasher = Sha256::new();
+    hash_bytes(&mut hasher, &value.to_bytes());
+    StorageManifest {
+        storage: storage.to_string(),
+        memory_id,
+        records: 1,
+        sha256: hex(hasher.finalize()),
+    }
+}
+
+/// Every stable storage of the canister, in memory id order.
+pub(crate) fn all_storages() -> Vec<Box<dyn Storage>> {
+    let mut storages: Vec<Box<dyn Storage>> = [
+        crate::storages(),
+        crate::adjustments::storages(),
+        crate::alerts::storages(),
+        crate::api_keys::storages(),
+        crate::archive::storages(),
+        crate::auth::storages(),
+        crate::autosave::storages(),
+        crate::backup::storages(),
+        crate::balance_history::storages(),
+        crate::budgets::storages(),
+        crate::campaigns::storages(),
+        crate::cashback::storages(),
+        crate::counterparties::storages(),
+        crate::cycles::storages(),
+        crate::deliveries::storages(),
+        crate::devices::storages(),
+        crate::directory::storages(),
+        crate::disputes::storages(),
+        crate::dust::storages(),
+        crate::earning::storages(),
+        crate::events::storages(),
+        crate::fundraisers::storages(),
+        crate::giftcards::storages(),
+        crate::handles::storages(),
+        crate::history_export::storages(),
+        crate::holds::storages(),
+        crate::icrc2::storages(),
+        crate::incoming::storages(),
+        crate::ids::storages(),
+        crate::leaderboard::storages(),
+        crate::ledger::storages(),
+        crate::localization::storages(),
+        crate::lockdown::storages(),
+        crate::merchants::storages(),
+        crate::notifications::storages(),
+        crate::pause::storages(),
+        crate::payment_intents::storages(),
+        crate::peers::storages(),
+        crate::points::storages(),
+        crate::privacy::storages(),
+        crate::profile::storages(),
+        crate::receipts::storages(),
+        crate::reconciliation::storages(),
+        crate::recovery::storages(),
+        crate::retention::storages(),
+        crate::risk::storages(),
+        crate::sandbox::storages(),
+        crate::spenders::storages(),
+        crate::statements::storages(),
+        crate::subscriptions::storages(),
+        crate::supply::storages(),
+        crate::templates::storages(),
+        crate::token::storages(),
+        crate::unclaimed::storages(),
+        crate::username::storages(),
+        crate::validation::storages(),
+        crate::verification::storages(),
+        crate::vesting::storages(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    storages.sort_by_key(|storage| storage.memory_id());
+    storages
+}
+
+#[ic_cdk::query]
+fn export_state_manifest() -> Result<StateManifest, WalletError> {
+    perf::instrument("export_state_manifest", || {
+        ensure_admin()?;
+
+        let storages: Vec<StorageManifest> = all_storages()
+            .iter()
+            .map(|storage| storage.describe())
+            .collect();
+
+        let mut hasher = Sha256::new();
+        for storage in &storages {
+            hasher.update(storage.sha256.as_bytes());
+        }
+        Ok(StateManifest {
+            created_at: crate::current_time(),
+            read_only: crate::pause::is_read_only(),
+            storages,
+            sha256: hex(hasher.finalize()),
+        })
+    })
+}
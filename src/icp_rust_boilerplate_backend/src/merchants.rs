@@ -0,0 +1,364 @@
+//! Merchant accounts. A merchant issues payment links for an amount and its
+//! own reference; the link's text payload can be shown as a QR code, and
+//! paying it records a receipt carrying that reference.
+
+use crate::auth::caller_user_id;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{
+    current_time, next_id, send_transfer, Memory, Screening, TransactionPayload, WalletError,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const PAYLOAD_PREFIX: &str = "icpwallet:pay?";
+const MAX_REFERENCE_LEN: usize = 64;
+// Links expire after a day unless the merchant asks otherwise, and after 30 days at most
+const DEFAULT_LINK_TTL_SECONDS: u64 = 24 * 60 * 60;
+const MAX_LINK_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Merchant {
+    user_id: u64,
+    name: String,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PaymentLink {
+    id: u64,
+    merchant_id: u64,
+    amount: u64,
+    reference: String,
+    created_at: u64,
+    expires_at: u64,
+    // Text to encode in a QR code and pass to `pay_link`
+    payload: String,
+    paid_receipt_id: Option<u64>,
+}
+
+/// Receipt of a paid link, tied to the merchant's reference.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct MerchantPayment {
+    id: u64,
+    link_id: u64,
+    tx_id: u64,
+    merchant_id: u64,
+    payer_user_id: u64,
+    amount: u64,
+    reference: String,
+    paid_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct PaymentLinkPayload {
+    amount: u64,
+    reference: String,
+    expires_in_seconds: Option<u64>,
+}
+
+impl Harden for PaymentLinkPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.text("reference", &self.reference, TextKind::Alias);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SettlementSummary {
+    merchant_id: u64,
+    from: u64,
+    to: u64,
+    payment_count: u64,
+    total_amount: u64,
+}
+
+impl Storable for Merchant {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PaymentLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for MerchantPayment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static MERCHANT_STORAGE: RefCell<StableBTreeMap<u64, Merchant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    static PAYMENT_LINK_STORAGE: RefCell<StableBTreeMap<u64, PaymentLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static MERCHANT_PAYMENT_STORAGE: RefCell<StableBTreeMap<u64, MerchantPayment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("merchants.merchant_storage", 28, &MERCHANT_STORAGE),
+        manifest::map("merchants.payment_link_storage", 29, &PAYMENT_LINK_STORAGE),
+        manifest::map(
+            "merchants.merchant_payment_storage",
+            30,
+            &MERCHANT_PAYMENT_STORAGE,
+        ),
+    ]
+}
+
+pub(crate) fn caller_merchant_id() -> Result<u64, WalletError> {
+    let user_id = caller_user_id()?;
+    if !MERCHANT_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+        return Err(WalletError::Unauthorized {
+            reason: format!("user {} is not a merchant", user_id),
+        });
+    }
+    Ok(user_id)
+}
+
+// References end up in the link payload, so they are kept to characters
+// that need no escaping
+fn validate_reference(reference: &str) -> Result<(), WalletError> {
+    let valid_chars = reference
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if reference.is_empty() || reference.len() > MAX_REFERENCE_LEN || !valid_chars {
+        return Err(WalletError::invalid(
+            "reference",
+            "must be 1 to 64 letters, digits, '-' or '_'",
+        ));
+    }
+    Ok(())
+}
+
+fn encode_payload(link: &PaymentLink) -> String {
+    format!(
+        "{}link={}&merchant_id={}&amount={}&reference={}&expiry={}",
+        PAYLOAD_PREFIX, link.id, link.merchant_id, link.amount, link.reference, link.expires_at
+    )
+}
+
+// Returns the link id of a payload produced by `encode_payload`
+fn decode_link_id(payload: &str) -> Result<u64, WalletError> {
+    let invalid = || WalletError::invalid("payload", "is not a payment link");
+    let query = payload.strip_prefix(PAYLOAD_PREFIX).ok_or_else(invalid)?;
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("link="))
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(invalid)
+}
+
+#[ic_cdk::update]
+fn register_merchant(name: String) -> Result<Merchant, WalletError> {
+    perf::instrument("register_merchant", || {
+        ensure_writable()?;
+
+        hardening::check_args(|fields| fields.required_text("name", &name, TextKind::Name))?;
+        let user_id = caller_user_id()?;
+        let name = name.trim().to_string();
+        if MERCHANT_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::AlreadyExists {
+                entity: "merchant".to_string(),
+                field: "user_id".to_string(),
+            });
+        }
+
+        let merchant = Merchant {
+            user_id,
+            name,
+            created_at: current_time(),
+        };
+        MERCHANT_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, merchant.clone()));
+        Ok(merchant)
+    })
+}
+
+#[ic_cdk::update]
+fn create_payment_link(payload: PaymentLinkPayload) -> Result<PaymentLink, WalletError> {
+    perf::instrument("create_payment_link", || {
+        ensure_writable()?;
+
+        hardening::check(&payload)?;
+        let merchant_id = caller_merchant_id()?;
+        if payload.amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        validate_reference(&payload.reference)?;
+        let ttl = payload
+            .expires_in_seconds
+            .unwrap_or(DEFAULT_LINK_TTL_SECONDS);
+        if ttl == 0 || ttl > MAX_LINK_TTL_SECONDS {
+            return Err(WalletError::invalid(
+                "expires_in_seconds",
+                &format!("must be between 1 and {}", MAX_LINK_TTL_SECONDS),
+            ));
+        }
+
+        let now = current_time();
+        let mut link = PaymentLink {
+            id: next_id(),
+            merchant_id,
+            amount: payload.amount,
+            reference: payload.reference,
+            created_at: now,
+            expires_at: now + ttl * NANOS_PER_SECOND,
+            payload: String::new(),
+            paid_receipt_id: None,
+        };
+        link.payload = encode_payload(&link);
+        PAYMENT_LINK_STORAGE.with(|storage| storage.borrow_mut().insert(link.id, link.clone()));
+        Ok(link)
+    })
+}
+
+#[ic_cdk::update]
+fn pay_link(payload: String) -> Result<MerchantPayment, WalletError> {
+    perf::instrument("pay_link", || {
+        ensure_writable()?;
+
+        let payer_user_id = caller_user_id()?;
+        let link_id = decode_link_id(&payload)?;
+        let mut link = PAYMENT_LINK_STORAGE
+            .with(|storage| storage.borrow().get(&link_id))
+            .ok_or(WalletError::not_found("payment link", link_id))?;
+        // The stored link is authoritative; a payload edited by hand is rejected
+        if payload != link.payload {
+            return Err(WalletError::invalid(
+                "payload",
+                "does not match the payment link",
+            ));
+        }
+        if link.paid_receipt_id.is_some() {
+            return Err(WalletError::InvalidState {
+                reason: format!("Payment link {} was already paid", link_id),
+            });
+        }
+        if current_time() >= link.expires_at {
+            return Err(WalletError::InvalidState {
+                reason: format!("Payment link {} has expired", link_id),
+            });
+        }
+
+        // Rejected rather than held for review: the link would stay unpaid
+        // while the held transfer could still execute later
+        let transaction = send_transfer(
+            TransactionPayload {
+                from_user_id: payer_user_id,
+                to_user_id: link.merchant_id,
+                amount: link.amount,
+                category: None,
+                memo: Some(link.reference.clone()),
+                to_handle: None,
+            },
+            Screening::Reject,
+        )?;
+
+        let receipt = MerchantPayment {
+            id: next_id(),
+            link_id,
+            tx_id: transaction.id.0,
+            merchant_id: link.merchant_id,
+            payer_user_id,
+            amount: link.amount,
+            reference: link.reference.clone(),
+            paid_at: transaction.created_at,
+        };
+        MERCHANT_PAYMENT_STORAGE
+            .with(|storage| storage.borrow_mut().insert(receipt.id, receipt.clone()));
+        link.paid_receipt_id = Some(receipt.id);
+        PAYMENT_LINK_STORAGE.with(|storage| storage.borrow_mut().insert(link_id, link));
+        Ok(receipt)
+    })
+}
+
+#[ic_cdk::query]
+fn list_received_payments(
+    reference_filter: Option<String>,
+) -> Result<Vec<MerchantPayment>, WalletError> {
+    perf::instrument("list_received_payments", || {
+        ensure_not_restoring()?;
+
+        let merchant_id = caller_merchant_id()?;
+        // References are matched by prefix; no filter matches every payment
+        let prefix = reference_filter.unwrap_or_default();
+        Ok(MERCHANT_PAYMENT_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, payment)| payment)
+                .filter(|payment| payment.merchant_id == merchant_id)
+                .filter(|payment| payment.reference.starts_with(&prefix))
+                .collect()
+        }))
+    })
+}
+
+/// Totals the payments received in `[from, to)`, in nanoseconds.
+#[ic_cdk::query]
+fn get_settlement_summary(from: u64, to: u64) -> Result<SettlementSummary, WalletError> {
+    perf::instrument("get_settlement_summary", || {
+        ensure_not_restoring()?;
+
+        let merchant_id = caller_merchant_id()?;
+        if from >= to {
+            return Err(WalletError::invalid("to", "must be after from"));
+        }
+        let (payment_count, total_amount) = MERCHANT_PAYMENT_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, payment)| payment)
+                .filter(|payment| payment.merchant_id == merchant_id)
+                .filter(|payment| payment.paid_at >= from && payment.paid_at < to)
+                .fold((0u64, 0u64), |(count, total), payment| {
+                    (count + 1, total.saturating_add(payment.amount))
+                })
+        });
+        Ok(SettlementSummary {
+            merchant_id,
+            from,
+            to,
+            payment_count,
+            total_amount,
+        })
+    })
+}
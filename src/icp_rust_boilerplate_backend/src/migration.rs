@@ -0,0 +1,187 @@
+//! Bulk import of users from an existing off-chain system. Controllers send
+//! the accounts in chunks of up to `MAX_IMPORT_BATCH` records; each record is
+//! validated like `create_user` input and inserted with its preset balance
+//! and creation time. Records whose email is already registered are skipped,
+//! so a chunk that failed halfway can simply be sent again.
+
+use crate::backup::ensure_writable;
+use crate::cycles::ensure_not_frozen;
+use crate::events::{self, EventKind};
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::perf;
+use crate::{
+    auth, current_time, directory, ensure_admin, ids, ledger, pause, token, username, validation,
+    User, UserId, WalletError, USER_STORAGE,
+};
+use candid::Principal;
+use std::collections::BTreeMap;
+
+pub(crate) const MAX_IMPORT_BATCH: usize = 500;
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct UserImportRecord {
+    first_name: String,
+    last_name: String,
+    email: String,
+    phone_number: String,
+    // Generated from the name when omitted
+    username: Option<String>,
+    // Principal that will own the account
+    owner: Principal,
+    balance: u64,
+    // Creation time in the previous system; defaults to now
+    created_at: Option<u64>,
+}
+
+impl Harden for UserImportRecord {
+    fn harden(&self, fields: &mut FieldChecker) {
+        validation::check_account_fields(
+            fields,
+            &self.first_name,
+            &self.last_name,
+            &self.email,
+            &self.phone_number,
+        );
+        fields.optional_text("username", &self.username, TextKind::Alias);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) enum ImportOutcome {
+    Imported { user_id: u64 },
+    // An account with this email already exists
+    Duplicate { existing_user_id: u64 },
+    Failed { error: WalletError },
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ImportRecordResult {
+    // Position of the record in the submitted chunk
+    index: u64,
+    email: String,
+    outcome: ImportOutcome,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ImportReport {
+    imported: u64,
+    duplicates: u64,
+    failed: u64,
+    results: Vec<ImportRecordResult>,
+}
+
+fn validate_record(record: &UserImportRecord, now: u64) -> Result<(), WalletError> {
+    hardening::check(record)?;
+    if record.balance > 0 {
+        token::validate_amount("balance", record.balance)?;
+    }
+    if record.created_at.is_some_and(|created_at| created_at > now) {
+        return Err(WalletError::invalid(
+            "created_at",
+            "must not be in the future",
+        ));
+    }
+    auth::ensure_can_own_account(record.owner)
+}
+
+fn import_record(record: UserImportRecord, now: u64) -> Result<u64, WalletError> {
+    validate_record(&record, now)?;
+    let username =
+        username::claim_or_generate(record.username, &record.first_name, &record.last_name)?;
+
+    let id = ids::next_user_id().0;
+    let user = User {
+        id: UserId(id),
+        username,
+        first_name: record.first_name,
+        last_name: record.last_name,
+        email: record.email,
+        phone_number: record.phone_number,
+        created_at: record.created_at.unwrap_or(now),
+        // Credited through the ledger below
+        balance: 0,
+        points: 0,
+        email_verified_at: None,
+        phone_verified_at: None,
+    };
+    USER_STORAGE.with(|storage| storage.borrow_mut().insert(id, user.clone()));
+    username::index_username(&user.username, id);
+    directory::index_user(&user);
+    auth::bind_owner(id, record.owner);
+    events::record(EventKind::UserCreated { user_id: id });
+    if record.balance > 0 {
+        ledger::deposit(
+            ledger::EntryKind::Import { user_id: id },
+            id,
+            record.balance,
+        )?;
+        events::record(EventKind::FundsDeposited {
+            user_id: id,
+            amount: record.balance,
+        });
+    }
+    Ok(id)
+}
+
+/// Imports one chunk of users and reports the outcome of every record. A
+/// failing record does not stop the others.
+#[ic_cdk::update]
+fn import_users(records: Vec<UserImportRecord>) -> Result<ImportReport, WalletError> {
+    perf::instrument("import_users", || {
+        ensure_writable()?;
+        ensure_admin()?;
+        ensure_not_frozen()?;
+
+        if records.is_empty() || records.len() > MAX_IMPORT_BATCH {
+            return Err(WalletError::invalid(
+                "records",
+                &format!("must contain between 1 and {} records", MAX_IMPORT_BATCH),
+            ));
+        }
+        // Preset balances are new funds entering the wallet
+        if records.iter().any(|record| record.balance > 0) {
+            pause::ensure_transfers_allowed()?;
+        }
+
+        // Looked up once for the whole chunk instead of scanning per record
+        let mut emails: BTreeMap<String, u64> = USER_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(id, user)| (user.email, id))
+                .collect()
+        });
+        let now = current_time();
+        let mut report = ImportReport {
+            imported: 0,
+            duplicates: 0,
+            failed: 0,
+            results: Vec::with_capacity(records.len()),
+        };
+        for (index, record) in records.into_iter().enumerate() {
+            let email = record.email.clone();
+            let outcome = if let Some(&existing_user_id) = emails.get(&email) {
+                report.duplicates += 1;
+                ImportOutcome::Duplicate { existing_user_id }
+            } else {
+                match import_record(record, now) {
+                    Ok(user_id) => {
+                        report.imported += 1;
+                        emails.insert(email.clone(), user_id);
+                        ImportOutcome::Imported { user_id }
+                    }
+                    Err(error) => {
+                        report.failed += 1;
+                        ImportOutcome::Failed { error }
+                    }
+                }
+            };
+            report.results.push(ImportRecordResult {
+                index: index as u64,
+                email,
+                outcome,
+            });
+        }
+        Ok(report)
+    })
+}
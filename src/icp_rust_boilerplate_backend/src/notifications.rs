@@ -0,0 +1,385 @@
+//! Per-account notifications and operational notices for the controllers.
+//! Every notification goes through `notify`, which applies the recipient's
+//! preferences: muted kinds are dropped, and those raised during quiet hours
+//! are held back until the quiet hours end. Recovery and security
+//! notifications always go out immediately.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{
+    current_time, ensure_admin, next_id, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum NotificationKind {
+    // Progress of a guardian recovery of the account
+    AccountRecovery,
+    // A subscription charge failed or the subscription was cancelled
+    SubscriptionBilling,
+    // A gift card the user issued was redeemed or refunded
+    GiftCard,
+    // A debit left the balance below the user's alert threshold
+    LowBalance,
+    // A dispute on one of the user's transactions was opened or progressed
+    Dispute,
+    // A hold on the user's funds was released or expired
+    Hold,
+    // An unknown device acted on the account
+    Security,
+    // Funds arrived from, or were returned by, a peer wallet
+    ExternalTransfer,
+    // Another user sent funds to the account, or an incoming transfer
+    // waiting for acceptance progressed
+    IncomingTransfer,
+    // A monthly statement was issued
+    StatementReady,
+    // Time-locked or vesting funds became spendable
+    Unlock,
+    // A fundraiser the user organized or contributed to was settled
+    Fundraiser,
+    // An administrator corrected the balance
+    Adjustment,
+    // An autosave run was skipped for lack of funds
+    Autosave,
+    // A cashback payout was credited
+    Cashback,
+    // A handle is about to expire or lapsed
+    Handle,
+}
+
+impl NotificationKind {
+    // Kinds that preferences cannot mute or delay
+    fn is_mandatory(self) -> bool {
+        matches!(
+            self,
+            NotificationKind::AccountRecovery | NotificationKind::Security
+        )
+    }
+}
+
+/// A message addressed to the owner of an account, read back through
+/// `get_notifications`.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Notification {
+    id: u64,
+    user_id: u64,
+    kind: NotificationKind,
+    message: String,
+    created_at: u64,
+    read: bool,
+    // Hidden until then when raised during quiet hours
+    deliver_after: Option<u64>,
+}
+
+impl Notification {
+    fn is_delivered(&self, now: u64) -> bool {
+        self.deliver_after.is_none_or(|at| now >= at)
+    }
+}
+
+/// Hours of the day, in the user's local time, when notifications are held
+/// back. `start_hour` may be after `end_hour` to span midnight.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct QuietHours {
+    start_hour: u8,
+    end_hour: u8,
+    utc_offset_minutes: i32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct NotificationPreferences {
+    muted: Vec<NotificationKind>,
+    quiet_hours: Option<QuietHours>,
+}
+
+/// Operational message for the canister controllers.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AdminNotice {
+    id: u64,
+    message: String,
+    created_at: u64,
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for NotificationPreferences {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AdminNotice {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static NOTIFICATION_STORAGE: RefCell<StableBTreeMap<u64, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    static ADMIN_NOTICE_STORAGE: RefCell<StableBTreeMap<u64, AdminNotice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    static PREFERENCE_STORAGE: RefCell<StableBTreeMap<u64, NotificationPreferences, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map(
+            "notifications.notification_storage",
+            13,
+            &NOTIFICATION_STORAGE,
+        ),
+        manifest::map(
+            "notifications.admin_notice_storage",
+            26,
+            &ADMIN_NOTICE_STORAGE,
+        ),
+        manifest::map("notifications.preference_storage", 60, &PREFERENCE_STORAGE),
+    ]
+}
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+fn preferences_of(user_id: u64) -> NotificationPreferences {
+    PREFERENCE_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .unwrap_or_default()
+}
+
+// When the current quiet hours end, or `None` outside of them
+fn quiet_until(quiet_hours: &QuietHours, now: u64) -> Option<u64> {
+    let minute = ((now / NANOS_PER_MINUTE) as i64 + quiet_hours.utc_offset_minutes as i64)
+        .rem_euclid(MINUTES_PER_DAY);
+    let start = quiet_hours.start_hour as i64 * 60;
+    let end = quiet_hours.end_hour as i64 * 60;
+    let quiet = if start < end {
+        (start..end).contains(&minute)
+    } else {
+        minute >= start || minute < end
+    };
+    if !quiet {
+        return None;
+    }
+    let remaining = (end - minute).rem_euclid(MINUTES_PER_DAY) as u64;
+    Some(now - now % NANOS_PER_MINUTE + remaining * NANOS_PER_MINUTE)
+}
+
+pub(crate) fn notify(user_id: u64, kind: NotificationKind, message: String) {
+    let now = current_time();
+    let mut deliver_after = None;
+    if !kind.is_mandatory() {
+        let preferences = preferences_of(user_id);
+        if preferences.muted.contains(&kind) {
+            return;
+        }
+        deliver_after = preferences
+            .quiet_hours
+            .as_ref()
+            .and_then(|quiet_hours| quiet_until(quiet_hours, now));
+    }
+    let id = next_id();
+    let notification = Notification {
+        id,
+        user_id,
+        kind,
+        message,
+        created_at: now,
+        read: false,
+        deliver_after,
+    };
+    NOTIFICATION_STORAGE.with(|storage| storage.borrow_mut().insert(id, notification));
+}
+
+pub(crate) fn notify_admins(message: String) {
+    let id = next_id();
+    let notice = AdminNotice {
+        id,
+        message,
+        created_at: current_time(),
+    };
+    ADMIN_NOTICE_STORAGE.with(|storage| storage.borrow_mut().insert(id, notice));
+}
+
+pub(crate) fn unread_count(user_id: u64) -> u64 {
+    let now = current_time();
+    NOTIFICATION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, notification)| {
+                notification.user_id == user_id
+                    && !notification.read
+                    && notification.is_delivered(now)
+            })
+            .count() as u64
+    })
+}
+
+/// Removes the notifications delivered before `cutoff`, read or not;
+/// returns how many were removed.
+pub(crate) fn prune(cutoff: u64) -> u64 {
+    NOTIFICATION_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let stale: Vec<u64> = storage
+            .iter()
+            .filter(|(_, notification)| {
+                notification
+                    .deliver_after
+                    .unwrap_or(notification.created_at)
+                    < cutoff
+            })
+            .map(|(id, _)| id)
+            .collect();
+        for id in &stale {
+            storage.remove(id);
+        }
+        stale.len() as u64
+    })
+}
+
+#[ic_cdk::query]
+fn get_notifications() -> Result<Vec<Notification>, WalletError> {
+    perf::instrument("get_notifications", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        let now = current_time();
+        Ok(NOTIFICATION_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, notification)| {
+                    notification.user_id == user_id && notification.is_delivered(now)
+                })
+                .map(|(_, notification)| notification)
+                .collect()
+        }))
+    })
+}
+
+#[ic_cdk::update]
+fn mark_notification_read(id: u64) -> Result<(), WalletError> {
+    perf::instrument("mark_notification_read", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        NOTIFICATION_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let mut notification = storage
+                .get(&id)
+                .filter(|notification| notification.user_id == user_id)
+                .ok_or(WalletError::not_found("notification", id))?;
+            notification.read = true;
+            storage.insert(id, notification);
+            Ok(())
+        })
+    })
+}
+
+/// Replaces which notifications `user_id` receives and when.
+#[ic_cdk::update]
+fn set_notification_preferences(
+    user_id: u64,
+    preferences: NotificationPreferences,
+) -> Result<(), WalletError> {
+    perf::instrument("set_notification_preferences", || {
+        ensure_writable()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        if let Some(kind) = preferences.muted.iter().find(|kind| kind.is_mandatory()) {
+            return Err(WalletError::invalid(
+                "muted",
+                &format!("{:?} notifications cannot be muted", kind),
+            ));
+        }
+        if let Some(quiet_hours) = &preferences.quiet_hours {
+            if quiet_hours.start_hour > 23 || quiet_hours.end_hour > 23 {
+                return Err(WalletError::invalid(
+                    "quiet_hours",
+                    "hours must be between 0 and 23",
+                ));
+            }
+            if quiet_hours.start_hour == quiet_hours.end_hour {
+                return Err(WalletError::invalid(
+                    "quiet_hours",
+                    "must start and end at different hours",
+                ));
+            }
+            if quiet_hours.utc_offset_minutes.abs() > 14 * 60 {
+                return Err(WalletError::invalid(
+                    "utc_offset_minutes",
+                    "must be within 14 hours of UTC",
+                ));
+            }
+        }
+        PREFERENCE_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, preferences));
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_notification_preferences(user_id: u64) -> Result<NotificationPreferences, WalletError> {
+    perf::instrument("get_notification_preferences", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(preferences_of(user_id))
+    })
+}
+
+#[ic_cdk::query]
+fn get_admin_notices() -> Result<Vec<AdminNotice>, WalletError> {
+    perf::instrument("get_admin_notices", || {
+        ensure_admin()?;
+
+        Ok(ADMIN_NOTICE_STORAGE
+            .with(|storage| storage.borrow().iter().map(|(_, notice)| notice).collect()))
+    })
+}
@@ -0,0 +1,67 @@
+use crate::alerts::{self, Alert};
+use crate::auth::ensure_owner;
+use crate::backup::ensure_not_restoring;
+use crate::disputes::{self, Dispute};
+use crate::perf;
+use crate::subscriptions::{self, Subscription};
+use crate::{holds, notifications, Transaction, WalletError, TRANSACTION_STORAGE, USER_STORAGE};
+
+const RECENT_TRANSACTIONS: usize = 10;
+
+/// Everything the wallet home screen shows, in a single call.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct WalletOverview {
+    user_id: u64,
+    username: String,
+    balance: u64,
+    // `balance` minus the funds under active holds
+    available_balance: u64,
+    points: u64,
+    // Newest first
+    recent_transactions: Vec<Transaction>,
+    upcoming_charges: Vec<Subscription>,
+    pending_disputes: Vec<Dispute>,
+    unacknowledged_alerts: Vec<Alert>,
+    unread_notifications: u64,
+}
+
+fn recent_transactions(user_id: u64) -> Vec<Transaction> {
+    let mut transactions: Vec<Transaction> = TRANSACTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, transaction)| transaction)
+            .filter(|transaction| transaction.involves(user_id))
+            .collect()
+    });
+    // Ids grow over time, so the last ones are the most recent
+    let start = transactions.len().saturating_sub(RECENT_TRANSACTIONS);
+    let mut recent = transactions.split_off(start);
+    recent.reverse();
+    recent
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_wallet_overview(user_id: u64) -> Result<WalletOverview, WalletError> {
+    perf::instrument("get_wallet_overview", || {
+        ensure_not_restoring()?;
+
+        let user = USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("user", user_id))?;
+        ensure_owner(user_id)?;
+
+        Ok(WalletOverview {
+            user_id,
+            username: user.username,
+            balance: user.balance,
+            available_balance: holds::available_balance(user_id, user.balance),
+            points: user.points,
+            recent_transactions: recent_transactions(user_id),
+            upcoming_charges: subscriptions::upcoming_charges(user_id),
+            pending_disputes: disputes::pending_disputes(user_id),
+            unacknowledged_alerts: alerts::unacknowledged_alerts(user_id),
+            unread_notifications: notifications::unread_count(user_id),
+        })
+    })
+}
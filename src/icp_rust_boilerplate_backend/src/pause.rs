@@ -0,0 +1,174 @@
+//! Emergency pause. Controllers can halt money movement while everything
+//! else keeps working, make the canister read-only, or halt it entirely.
+//! Reconciliation can also trip a transfer pause on its own.
+//!
+//! Read-only mode supports a controlled cutover to another canister or
+//! schema: queries keep working, every update and timer job that would
+//! change state is rejected with `MaintenanceMode`, and
+//! `export_state_manifest` describes the state to compare after the move.
+
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{current_time, ensure_admin, reconciliation, Memory, WalletError, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum PauseLevel {
+    // Deposits, transfers and gift cards stop; queries and other updates go on
+    Transfers,
+    // Queries go on but every update is rejected apart from the pause
+    // controls and backups
+    ReadOnly,
+    // Every call is rejected apart from the pause controls
+    Full,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct PauseStatus {
+    level: Option<PauseLevel>,
+    reason: Option<String>,
+    since: Option<u64>,
+    // Set when reconciliation paused the canister rather than an admin
+    automatic: bool,
+}
+
+impl Storable for PauseStatus {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static PAUSE_STATE: RefCell<Cell<PauseStatus, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+            PauseStatus::default(),
+        )
+        .expect("Cannot create the pause state cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell("pause.pause_state", 34, &PAUSE_STATE)]
+}
+
+fn pause_level() -> Option<PauseLevel> {
+    PAUSE_STATE.with(|state| state.borrow().get().level)
+}
+
+fn set_pause_state(state: PauseStatus) {
+    PAUSE_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("Cannot update the pause state");
+}
+
+fn paused_error() -> WalletError {
+    let reason = PAUSE_STATE
+        .with(|state| state.borrow().get().reason.clone())
+        .unwrap_or_default();
+    if is_read_only() {
+        WalletError::MaintenanceMode { reason }
+    } else {
+        WalletError::Paused { reason }
+    }
+}
+
+pub(crate) fn is_fully_paused() -> bool {
+    pause_level() == Some(PauseLevel::Full)
+}
+
+pub(crate) fn is_read_only() -> bool {
+    pause_level() == Some(PauseLevel::ReadOnly)
+}
+
+/// Rejects calls that change state while the canister is read-only.
+pub(crate) fn ensure_not_read_only() -> Result<(), WalletError> {
+    if is_read_only() {
+        return Err(paused_error());
+    }
+    Ok(())
+}
+
+/// Rejects every call while the canister is fully paused.
+pub(crate) fn ensure_not_fully_paused() -> Result<(), WalletError> {
+    if is_fully_paused() {
+        return Err(paused_error());
+    }
+    Ok(())
+}
+
+/// Rejects anything that moves funds while the canister is paused at any
+/// level.
+pub(crate) fn ensure_transfers_allowed() -> Result<(), WalletError> {
+    if pause_level().is_some() {
+        return Err(paused_error());
+    }
+    Ok(())
+}
+
+/// Pauses transfers on behalf of an internal check that found the state
+/// inconsistent.
+pub(crate) fn trip(reason: String) {
+    set_pause_state(PauseStatus {
+        level: Some(PauseLevel::Transfers),
+        reason: Some(reason),
+        since: Some(current_time()),
+        automatic: true,
+    });
+}
+
+#[ic_cdk::update]
+fn pause(level: PauseLevel, reason: String) -> Result<PauseStatus, WalletError> {
+    perf::instrument("pause", || {
+        ensure_admin()?;
+
+        hardening::check_args(|fields| fields.required_text("reason", &reason, TextKind::Reason))?;
+        let status = PauseStatus {
+            level: Some(level),
+            reason: Some(reason.trim().to_string()),
+            since: Some(current_time()),
+            automatic: false,
+        };
+        set_pause_state(status.clone());
+        Ok(status)
+    })
+}
+
+#[ic_cdk::update]
+fn resume() -> Result<(), WalletError> {
+    perf::instrument("resume", || {
+        ensure_admin()?;
+
+        let state = PAUSE_STATE.with(|state| state.borrow().get().clone());
+        if state.level.is_none() {
+            return Err(WalletError::InvalidState {
+                reason: "The canister is not paused".to_string(),
+            });
+        }
+        // Resuming after a failed reconciliation accepts the balances as they
+        // now are, otherwise the next run would pause again
+        if state.automatic {
+            reconciliation::reseed();
+        }
+        set_pause_state(PauseStatus::default());
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_pause_status() -> PauseStatus {
+    perf::measure("get_pause_status", || {
+        PAUSE_STATE.with(|state| state.borrow().get().clone())
+    })
+}
@@ -0,0 +1,540 @@
+//! Payment intents for e-commerce checkouts, modeled on Stripe's. A merchant
+//! creates an intent for an amount with its own metadata, typically the
+//! order it pays for, and hands the intent id to the checkout page. The
+//! payer confirms it, which executes the transfer and marks the intent
+//! `Succeeded`. Merchants poll `get_payment_intent`, or register a webhook:
+//! a canister method that is called with the intent once it succeeds. Each
+//! call is logged as a delivery operators can replay.
+//!
+//! Creation is idempotent per merchant and `idempotency_key`, so a checkout
+//! that retries after a lost reply gets the intent it already created.
+
+use crate::auth::caller_user_id;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::deliveries;
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::merchants::caller_merchant_id;
+use crate::perf;
+use crate::{
+    current_time, next_id, send_transfer, token, Memory, Screening, TransactionPayload,
+    WalletError, MEMORY_MANAGER,
+};
+use candid::utils::ArgumentEncoder;
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::{call_raw, notify};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const DEFAULT_INTENT_TTL_SECONDS: u64 = 60 * 60;
+const MAX_INTENT_TTL_SECONDS: u64 = 7 * 24 * 60 * 60;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+// Limits follow Stripe's metadata limits
+const MAX_METADATA_ENTRIES: usize = 50;
+const MAX_METADATA_KEY_LEN: usize = 40;
+const MAX_METADATA_VALUE_LEN: usize = 500;
+const MAX_WEBHOOK_METHOD_LEN: usize = 64;
+// Largest encoded `create_payment_intent` argument: full metadata plus
+// framing and the other fields
+pub(crate) const MAX_INTENT_PAYLOAD_BYTES: usize =
+    MAX_METADATA_ENTRIES * (MAX_METADATA_KEY_LEN + MAX_METADATA_VALUE_LEN + 16) + 1024;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum PaymentIntentStatus {
+    RequiresConfirmation,
+    Succeeded { tx_id: u64, payer_user_id: u64 },
+    Canceled,
+    Expired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PaymentIntent {
+    id: u64,
+    merchant_id: u64,
+    amount: u64,
+    // Token symbol the amount is denominated in
+    currency: String,
+    metadata: Vec<(String, String)>,
+    idempotency_key: String,
+    status: PaymentIntentStatus,
+    created_at: u64,
+    expires_at: u64,
+    updated_at: u64,
+    // Whether a delivery of the outcome to the merchant's webhook was
+    // started; the delivery log has its result
+    webhook_notified: bool,
+}
+
+impl PaymentIntent {
+    // Intents past their expiry report `Expired` before anything stores it
+    fn refreshed(mut self, now: u64) -> Self {
+        if self.status == PaymentIntentStatus::RequiresConfirmation && now >= self.expires_at {
+            self.status = PaymentIntentStatus::Expired;
+            self.updated_at = self.expires_at;
+        }
+        self
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct PaymentIntentPayload {
+    amount: u64,
+    currency: String,
+    metadata: Vec<(String, String)>,
+    expires_in_seconds: Option<u64>,
+    // Chosen by the merchant; creating again with the same key returns the
+    // same intent
+    idempotency_key: String,
+}
+
+impl Harden for PaymentIntentPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.text("currency", &self.currency, TextKind::Alias);
+        fields.list("metadata", &self.metadata, MAX_METADATA_ENTRIES);
+        for (index, (key, value)) in self.metadata.iter().enumerate() {
+            fields.item("metadata", index, |entry| {
+                entry.text("key", key, TextKind::Alias);
+                entry.text("value", value, TextKind::Reason);
+            });
+        }
+        fields.text("idempotency_key", &self.idempotency_key, TextKind::Alias);
+    }
+}
+
+/// Canister method called when an intent succeeds, with the
+/// `PaymentIntent` as its only argument. Its reply is ignored.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct MerchantWebhook {
+    canister: Principal,
+    method: String,
+}
+
+impl Storable for PaymentIntent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl MerchantWebhook {
+    pub(crate) fn validate(&self) -> Result<(), WalletError> {
+        if self.canister == Principal::anonymous() {
+            return Err(WalletError::invalid(
+                "canister",
+                "must not be the anonymous principal",
+            ));
+        }
+        if self.method.is_empty() || self.method.len() > MAX_WEBHOOK_METHOD_LEN {
+            return Err(WalletError::invalid(
+                "method",
+                &format!("must be 1 to {} bytes", MAX_WEBHOOK_METHOD_LEN),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Calls the webhook one-way; true if the call was sent.
+    pub(crate) fn deliver<T: ArgumentEncoder>(&self, args: T) -> bool {
+        notify(self.canister, &self.method, args).is_ok()
+    }
+
+    /// Name of the webhook in delivery metrics.
+    pub(crate) fn endpoint(&self) -> String {
+        format!("{}.{}", self.canister, self.method)
+    }
+
+    /// Calls the webhook with encoded `args` and waits for its reply.
+    pub(crate) async fn call(&self, args: Vec<u8>) -> Result<(), String> {
+        call_raw(self.canister, &self.method, args, 0)
+            .await
+            .map(|_| ())
+            .map_err(|(code, message)| format!("{:?}: {}", code, message))
+    }
+}
+
+impl Storable for MerchantWebhook {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static INTENT_STORAGE: RefCell<StableBTreeMap<u64, PaymentIntent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+
+    // "<merchant id>/<idempotency key>" to intent id
+    static IDEMPOTENCY_INDEX: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+
+    static WEBHOOK_STORAGE: RefCell<StableBTreeMap<u64, MerchantWebhook, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("payment_intents.intent_storage", 64, &INTENT_STORAGE),
+        manifest::map("payment_intents.idempotency_index", 65, &IDEMPOTENCY_INDEX),
+        manifest::map("payment_intents.webhook_storage", 66, &WEBHOOK_STORAGE),
+    ]
+}
+
+fn get_intent_record(intent_id: u64) -> Result<PaymentIntent, WalletError> {
+    INTENT_STORAGE
+        .with(|storage| storage.borrow().get(&intent_id))
+        .map(|intent| intent.refreshed(current_time()))
+        .ok_or(WalletError::not_found("payment intent", intent_id))
+}
+
+fn save_intent(intent: &PaymentIntent) {
+    INTENT_STORAGE.with(|storage| storage.borrow_mut().insert(intent.id, intent.clone()));
+}
+
+fn idempotency_index_key(merchant_id: u64, idempotency_key: &str) -> String {
+    format!("{}/{}", merchant_id, idempotency_key)
+}
+
+/// Removes the intents that succeeded, were canceled or expired before
+/// `cutoff`, with their idempotency keys; returns how many were removed.
+pub(crate) fn prune_intents(cutoff: u64) -> u64 {
+    let now = current_time();
+    let stale: Vec<PaymentIntent> = INTENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, intent)| intent.refreshed(now))
+            .filter(|intent| {
+                intent.status != PaymentIntentStatus::RequiresConfirmation
+                    && intent.updated_at < cutoff
+            })
+            .collect()
+    });
+    for intent in &stale {
+        IDEMPOTENCY_INDEX.with(|index| {
+            index.borrow_mut().remove(&idempotency_index_key(
+                intent.merchant_id,
+                &intent.idempotency_key,
+            ))
+        });
+        INTENT_STORAGE.with(|storage| storage.borrow_mut().remove(&intent.id));
+    }
+    stale.len() as u64
+}
+
+/// Frees the idempotency keys of intents created before `cutoff` that no
+/// longer wait for confirmation, so the merchant can use them for new
+/// intents; returns how many were freed. The intents stay.
+pub(crate) fn prune_idempotency_keys(cutoff: u64) -> u64 {
+    let now = current_time();
+    let stale: Vec<String> = IDEMPOTENCY_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .filter(|(_, intent_id)| {
+                INTENT_STORAGE
+                    .with(|storage| storage.borrow().get(intent_id))
+                    .map(|intent| intent.refreshed(now))
+                    .is_none_or(|intent| {
+                        intent.status != PaymentIntentStatus::RequiresConfirmation
+                            && intent.created_at < cutoff
+                    })
+            })
+            .map(|(key, _)| key)
+            .collect()
+    });
+    IDEMPOTENCY_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in &stale {
+            index.remove(key);
+        }
+    });
+    stale.len() as u64
+}
+
+fn validate_intent(payload: &PaymentIntentPayload) -> Result<u64, WalletError> {
+    if payload.amount == 0 {
+        return Err(WalletError::invalid("amount", "must be greater than 0"));
+    }
+    token::validate_amount("amount", payload.amount)?;
+    if payload.currency != token::symbol() {
+        return Err(WalletError::invalid(
+            "currency",
+            &format!("must be {}", token::symbol()),
+        ));
+    }
+    let key = &payload.idempotency_key;
+    if key.is_empty()
+        || key.len() > MAX_IDEMPOTENCY_KEY_LEN
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(WalletError::invalid(
+            "idempotency_key",
+            "must be 1 to 64 letters, digits, '-' or '_'",
+        ));
+    }
+    for (index, (key, value)) in payload.metadata.iter().enumerate() {
+        if key.is_empty() || key.len() > MAX_METADATA_KEY_LEN {
+            return Err(WalletError::invalid(
+                "metadata",
+                &format!("keys must be 1 to {} bytes", MAX_METADATA_KEY_LEN),
+            ));
+        }
+        if value.len() > MAX_METADATA_VALUE_LEN {
+            return Err(WalletError::invalid(
+                "metadata",
+                &format!("values must be at most {} bytes", MAX_METADATA_VALUE_LEN),
+            ));
+        }
+        if payload.metadata[..index]
+            .iter()
+            .any(|(other, _)| other == key)
+        {
+            return Err(WalletError::invalid(
+                "metadata",
+                &format!("key '{}' is given more than once", key),
+            ));
+        }
+    }
+    let ttl = payload
+        .expires_in_seconds
+        .unwrap_or(DEFAULT_INTENT_TTL_SECONDS);
+    if ttl == 0 || ttl > MAX_INTENT_TTL_SECONDS {
+        return Err(WalletError::invalid(
+            "expires_in_seconds",
+            &format!("must be between 1 and {}", MAX_INTENT_TTL_SECONDS),
+        ));
+    }
+    Ok(ttl)
+}
+
+// Delivers the intent to the merchant's webhook, if one is registered. A
+// failed delivery stays in the log for operators to replay; merchants can
+// still poll.
+fn notify_webhook(intent: &mut PaymentIntent) {
+    let Some(webhook) = WEBHOOK_STORAGE.with(|storage| storage.borrow().get(&intent.merchant_id))
+    else {
+        return;
+    };
+    intent.webhook_notified = true;
+    deliveries::enqueue(
+        intent.merchant_id,
+        intent.id,
+        webhook,
+        Encode!(&*intent).unwrap(),
+    );
+}
+
+/// Creates an intent, or returns the one created earlier with the same
+/// idempotency key. Reusing a key with different parameters is rejected;
+/// keys are freed by the retention job once their intent settles.
+#[ic_cdk::update]
+fn create_payment_intent(payload: PaymentIntentPayload) -> Result<PaymentIntent, WalletError> {
+    perf::instrument("create_payment_intent", || {
+        ensure_writable()?;
+
+        hardening::check(&payload)?;
+        let merchant_id = caller_merchant_id()?;
+        let ttl = validate_intent(&payload)?;
+
+        let index_key = idempotency_index_key(merchant_id, &payload.idempotency_key);
+        if let Some(intent_id) = IDEMPOTENCY_INDEX.with(|index| index.borrow().get(&index_key)) {
+            let intent = get_intent_record(intent_id)?;
+            if intent.amount != payload.amount
+                || intent.currency != payload.currency
+                || intent.metadata != payload.metadata
+            {
+                return Err(WalletError::invalid(
+                    "idempotency_key",
+                    "was already used for an intent with different parameters",
+                ));
+            }
+            return Ok(intent);
+        }
+
+        let now = current_time();
+        let intent = PaymentIntent {
+            id: next_id(),
+            merchant_id,
+            amount: payload.amount,
+            currency: payload.currency,
+            metadata: payload.metadata,
+            idempotency_key: payload.idempotency_key,
+            status: PaymentIntentStatus::RequiresConfirmation,
+            created_at: now,
+            expires_at: now + ttl * NANOS_PER_SECOND,
+            updated_at: now,
+            webhook_notified: false,
+        };
+        save_intent(&intent);
+        IDEMPOTENCY_INDEX.with(|index| index.borrow_mut().insert(index_key, intent.id));
+        Ok(intent)
+    })
+}
+
+/// Pays an intent from the caller's account.
+#[ic_cdk::update]
+fn confirm_payment_intent(intent_id: u64) -> Result<PaymentIntent, WalletError> {
+    perf::instrument("confirm_payment_intent", || {
+        ensure_writable()?;
+
+        let payer_user_id = caller_user_id()?;
+        let mut intent = get_intent_record(intent_id)?;
+        match intent.status {
+            PaymentIntentStatus::RequiresConfirmation => {}
+            PaymentIntentStatus::Expired => {
+                save_intent(&intent);
+                return Err(WalletError::InvalidState {
+                    reason: format!("Payment intent {} has expired", intent_id),
+                });
+            }
+            _ => {
+                return Err(WalletError::InvalidState {
+                    reason: format!("Payment intent {} can no longer be confirmed", intent_id),
+                });
+            }
+        }
+        // The token may have been renamed since the intent was created
+        if intent.currency != token::symbol() {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "Payment intent {} is in {}, which is no longer the wallet's currency",
+                    intent_id, intent.currency
+                ),
+            });
+        }
+
+        // Rejected rather than held for review, for the same reason as
+        // payment links
+        let transaction = send_transfer(
+            TransactionPayload {
+                from_user_id: payer_user_id,
+                to_user_id: intent.merchant_id,
+                amount: intent.amount,
+                category: None,
+                memo: Some(format!("Payment intent {}", intent_id)),
+                to_handle: None,
+            },
+            Screening::Reject,
+        )?;
+        intent.status = PaymentIntentStatus::Succeeded {
+            tx_id: transaction.id.0,
+            payer_user_id,
+        };
+        intent.updated_at = transaction.created_at;
+        notify_webhook(&mut intent);
+        save_intent(&intent);
+        Ok(intent)
+    })
+}
+
+#[ic_cdk::update]
+fn cancel_payment_intent(intent_id: u64) -> Result<PaymentIntent, WalletError> {
+    perf::instrument("cancel_payment_intent", || {
+        ensure_writable()?;
+
+        let merchant_id = caller_merchant_id()?;
+        let mut intent = get_intent_record(intent_id)?;
+        if intent.merchant_id != merchant_id {
+            return Err(WalletError::not_found("payment intent", intent_id));
+        }
+        if intent.status != PaymentIntentStatus::RequiresConfirmation {
+            return Err(WalletError::InvalidState {
+                reason: format!("Payment intent {} can no longer be canceled", intent_id),
+            });
+        }
+        intent.status = PaymentIntentStatus::Canceled;
+        intent.updated_at = current_time();
+        save_intent(&intent);
+        Ok(intent)
+    })
+}
+
+/// The merchant sees its intents in every state. Anyone else sees an intent
+/// while it awaits confirmation, and the payer once they paid it.
+#[ic_cdk::query]
+fn get_payment_intent(intent_id: u64) -> Result<PaymentIntent, WalletError> {
+    perf::instrument("get_payment_intent", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        let intent = get_intent_record(intent_id)?;
+        let visible = intent.merchant_id == user_id
+            || match intent.status {
+                PaymentIntentStatus::RequiresConfirmation => true,
+                PaymentIntentStatus::Succeeded { payer_user_id, .. } => payer_user_id == user_id,
+                _ => false,
+            };
+        if !visible {
+            return Err(WalletError::not_found("payment intent", intent_id));
+        }
+        Ok(intent)
+    })
+}
+
+/// The caller's intents carrying metadata `key`, and `value` when one is
+/// given, oldest first.
+#[ic_cdk::query]
+fn find_payment_intents(
+    key: String,
+    value: Option<String>,
+) -> Result<Vec<PaymentIntent>, WalletError> {
+    perf::instrument("find_payment_intents", || {
+        ensure_not_restoring()?;
+
+        let merchant_id = caller_merchant_id()?;
+        let now = current_time();
+        Ok(INTENT_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, intent)| intent)
+                .filter(|intent| intent.merchant_id == merchant_id)
+                .filter(|intent| {
+                    intent.metadata.iter().any(|(entry_key, entry_value)| {
+                        *entry_key == key && value.as_ref().is_none_or(|value| entry_value == value)
+                    })
+                })
+                .map(|intent| intent.refreshed(now))
+                .collect()
+        }))
+    })
+}
+
+/// Registers the caller's webhook, replacing any earlier one, or removes it
+/// when `webhook` is `None`.
+#[ic_cdk::update]
+fn set_merchant_webhook(webhook: Option<MerchantWebhook>) -> Result<(), WalletError> {
+    perf::instrument("set_merchant_webhook", || {
+        ensure_writable()?;
+
+        let merchant_id = caller_merchant_id()?;
+        let Some(webhook) = webhook else {
+            WEBHOOK_STORAGE.with(|storage| storage.borrow_mut().remove(&merchant_id));
+            return Ok(());
+        };
+        webhook.validate()?;
+        WEBHOOK_STORAGE.with(|storage| storage.borrow_mut().insert(merchant_id, webhook));
+        Ok(())
+    })
+}
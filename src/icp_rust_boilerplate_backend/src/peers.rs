@@ -0,0 +1,793 @@
+//! Transfers between wallet instances. Controllers register the canisters of
+//! trusted peer wallets; users can then send funds to an account on a peer
+//! with `send_external`. A transfer is settled in two phases: the sender is
+//! debited and asks the peer to reserve the credit, then tells it to commit
+//! the reservation. Both phases are idempotent per transfer id, so a phase
+//! whose reply was lost is simply retried by the recovery job, as is a
+//! commit the peer cannot take right now, for instance while it is in
+//! maintenance. A transfer the peer rejects, or that cannot be reserved after
+//! several attempts, is aborted on the peer and refunded to the sender.
+//!
+//! Every instance implements both sides of the protocol. The peers settle
+//! the net amounts moved between them outside the wallet.
+
+use crate::auth::{self, caller_user_id, ensure_owner, StorablePrincipal};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::events::{self, EventKind};
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::ledger::{self, EntryKind, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, notify_admins, NotificationKind};
+use crate::perf;
+use crate::{
+    alerts, current_time, devices, dust, ensure_admin, handles, holds, lockdown, next_id, pause,
+    token, username, verification, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::{call, CallResult};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Reservations the sender never committed are released after an hour
+const RESERVATION_TTL_SECONDS: u64 = 60 * 60;
+// Reserve calls without a reply before the transfer is aborted
+const MAX_RESERVE_ATTEMPTS: u32 = 5;
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(2 * 60);
+// Transfers driven by one run of the recovery job
+const RECOVERY_BATCH_SIZE: usize = 20;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Peer {
+    canister: Principal,
+    name: String,
+    registered_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ExternalTransferStatus {
+    // Debited, but the peer has not confirmed the reservation yet
+    Pending,
+    // Reserved on the peer, but the commit has not been confirmed yet
+    Reserved,
+    // Given up on; waiting for the peer to confirm the abort
+    Aborting,
+    Committed,
+    // Refunded to the sender
+    RolledBack { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ExternalTransfer {
+    id: u64,
+    from_user_id: u64,
+    peer_canister: Principal,
+    recipient_ref: String,
+    amount: u64,
+    status: ExternalTransferStatus,
+    reserve_attempts: u32,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl ExternalTransfer {
+    fn is_settled(&self) -> bool {
+        matches!(
+            self.status,
+            ExternalTransferStatus::Committed | ExternalTransferStatus::RolledBack { .. }
+        )
+    }
+}
+
+/// Arguments of `peer_reserve`, sent by the sending instance.
+#[derive(candid::CandidType, Clone, Deserialize, Serialize)]
+pub(crate) struct PeerReserveArgs {
+    // The sender's id for the transfer; unique per sending instance
+    transfer_id: u64,
+    // Username of the recipient, or their user id
+    recipient_ref: String,
+    amount: u64,
+}
+
+impl Harden for PeerReserveArgs {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.text("recipient_ref", &self.recipient_ref, TextKind::Alias);
+    }
+}
+
+/// State of a transfer received from a peer.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum InboundStatus {
+    Reserved,
+    Committed,
+    Aborted,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InboundTransfer {
+    to_user_id: u64,
+    amount: u64,
+    status: InboundStatus,
+    reserved_at: u64,
+    expires_at: u64,
+}
+
+// Inbound transfers are identified by the sending peer and its transfer id
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct InboundKey {
+    transfer_id: u64,
+    peer: Principal,
+}
+
+impl Storable for InboundKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.transfer_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.peer.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (transfer_id, peer) = bytes.split_at(8);
+        InboundKey {
+            transfer_id: u64::from_be_bytes(transfer_id.try_into().unwrap()),
+            peer: Principal::from_slice(peer),
+        }
+    }
+
+    // An 8 byte transfer id followed by a principal of at most 29 bytes
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 37,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Peer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ExternalTransfer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for InboundTransfer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static PEER_STORAGE: RefCell<StableBTreeMap<StorablePrincipal, Peer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+
+    static OUTBOUND_TRANSFERS: RefCell<StableBTreeMap<u64, ExternalTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    static INBOUND_TRANSFERS: RefCell<StableBTreeMap<InboundKey, InboundTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+
+    // Outbound transfers with a call in progress, so the recovery job never
+    // drives a transfer twice at once
+    static IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("peers.peer_storage", 52, &PEER_STORAGE),
+        manifest::map("peers.outbound_transfers", 53, &OUTBOUND_TRANSFERS),
+        manifest::map("peers.inbound_transfers", 54, &INBOUND_TRANSFERS),
+    ]
+}
+
+fn is_peer(canister: Principal) -> bool {
+    PEER_STORAGE.with(|peers| peers.borrow().contains_key(&StorablePrincipal(canister)))
+}
+
+fn ensure_peer_caller() -> Result<Principal, WalletError> {
+    let caller = auth::caller();
+    if !is_peer(caller) {
+        return Err(WalletError::Unauthorized {
+            reason: "caller is not a registered peer wallet".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+fn get_outbound(transfer_id: u64) -> Result<ExternalTransfer, WalletError> {
+    OUTBOUND_TRANSFERS
+        .with(|transfers| transfers.borrow().get(&transfer_id))
+        .ok_or(WalletError::not_found("external transfer", transfer_id))
+}
+
+fn save_outbound(mut transfer: ExternalTransfer) -> ExternalTransfer {
+    transfer.updated_at = current_time();
+    OUTBOUND_TRANSFERS
+        .with(|transfers| transfers.borrow_mut().insert(transfer.id, transfer.clone()));
+    transfer
+}
+
+/// Funds debited for outgoing transfers that have not settled.
+pub(crate) fn escrowed_amount() -> u128 {
+    OUTBOUND_TRANSFERS.with(|transfers| {
+        transfers
+            .borrow()
+            .iter()
+            .filter(|(_, transfer)| !transfer.is_settled())
+            .map(|(_, transfer)| transfer.amount as u128)
+            .sum()
+    })
+}
+
+// Moves the amount of outgoing transfer `transfer_id` into escrow until
+// the peer settles it
+fn debit_sender(user_id: u64, amount: u64, transfer_id: u64) -> Result<(), WalletError> {
+    lockdown::ensure_not_locked(user_id)?;
+    let available = holds::available_balance(user_id, ledger::user_balance(user_id));
+    if available < amount {
+        return Err(WalletError::InsufficientBalance {
+            available,
+            required: amount,
+        });
+    }
+    ledger::transfer(
+        EntryKind::ExternalTransfer { transfer_id },
+        ledger::user(user_id),
+        LedgerAccount::Escrow,
+        amount,
+    )?;
+    alerts::check_balance(user_id, ledger::user_balance(user_id));
+    Ok(())
+}
+
+// Whether the peer refused to commit for good, rather than for now
+fn is_definitive(error: &WalletError) -> bool {
+    matches!(
+        error,
+        WalletError::InvalidState { .. } | WalletError::NotFound { .. }
+    )
+}
+
+// Records a transfer the peer committed. If the escrow cannot be settled,
+// the transfer keeps its status so the recovery job completes it again.
+fn complete(mut transfer: ExternalTransfer) {
+    // The funds now belong to the peer wallet
+    if let Err(error) = ledger::transfer(
+        EntryKind::ExternalTransfer {
+            transfer_id: transfer.id,
+        },
+        LedgerAccount::Escrow,
+        LedgerAccount::Treasury,
+        transfer.amount,
+    ) {
+        notify_admins(format!(
+            "Cannot settle external transfer {}: {}",
+            transfer.id, error
+        ));
+        return;
+    }
+    transfer.status = ExternalTransferStatus::Committed;
+    events::record(EventKind::ExternalTransferSent {
+        transfer_id: transfer.id,
+        user_id: transfer.from_user_id,
+        peer_canister: transfer.peer_canister,
+        amount: transfer.amount,
+    });
+    save_outbound(transfer);
+}
+
+fn roll_back(mut transfer: ExternalTransfer, reason: String) {
+    // The funds left the sender's balance when the transfer started
+    if let Err(error) = ledger::transfer(
+        EntryKind::ExternalTransfer {
+            transfer_id: transfer.id,
+        },
+        LedgerAccount::Escrow,
+        ledger::user(transfer.from_user_id),
+        transfer.amount,
+    ) {
+        notify_admins(format!(
+            "Cannot refund external transfer {}: {}",
+            transfer.id, error
+        ));
+    }
+    notify(
+        transfer.from_user_id,
+        NotificationKind::ExternalTransfer,
+        format!(
+            "Your transfer {} of {} to {} was returned: {}",
+            transfer.id, transfer.amount, transfer.recipient_ref, reason
+        ),
+    );
+    transfer.status = ExternalTransferStatus::RolledBack { reason };
+    save_outbound(transfer);
+}
+
+// Moves an unsettled transfer as far forward as the peer's replies allow.
+// A call without a reply leaves the transfer in its current state, to be
+// retried by the recovery job.
+async fn drive(transfer_id: u64) {
+    loop {
+        let Ok(mut transfer) = get_outbound(transfer_id) else {
+            return;
+        };
+        let peer = transfer.peer_canister;
+        match transfer.status {
+            ExternalTransferStatus::Pending => {
+                transfer.reserve_attempts += 1;
+                let transfer = save_outbound(transfer);
+                let args = PeerReserveArgs {
+                    transfer_id,
+                    recipient_ref: transfer.recipient_ref.clone(),
+                    amount: transfer.amount,
+                };
+                let result: CallResult<(Result<(), WalletError>,)> =
+                    call(peer, "peer_reserve", (args,)).await;
+                let mut transfer = match get_outbound(transfer_id) {
+                    Ok(transfer) => transfer,
+                    Err(_) => return,
+                };
+                match result {
+                    Ok((Ok(()),)) => {
+                        transfer.status = ExternalTransferStatus::Reserved;
+                        save_outbound(transfer);
+                    }
+                    Ok((Err(error),)) => {
+                        // The peer refused, so nothing was reserved
+                        return roll_back(transfer, error.to_string());
+                    }
+                    Err(_) => {
+                        if transfer.reserve_attempts >= MAX_RESERVE_ATTEMPTS {
+                            transfer.status = ExternalTransferStatus::Aborting;
+                            save_outbound(transfer);
+                            continue;
+                        }
+                        return;
+                    }
+                }
+            }
+            ExternalTransferStatus::Reserved => {
+                let result: CallResult<(Result<(), WalletError>,)> =
+                    call(peer, "peer_commit", (transfer_id,)).await;
+                let Ok(transfer) = get_outbound(transfer_id) else {
+                    return;
+                };
+                return match result {
+                    Ok((Ok(()),)) => complete(transfer),
+                    // The reservation expired or was aborted on the peer
+                    Ok((Err(error),)) if is_definitive(&error) => {
+                        roll_back(transfer, error.to_string())
+                    }
+                    // The reservation still stands, so the commit is retried
+                    Ok((Err(_),)) | Err(_) => (),
+                };
+            }
+            ExternalTransferStatus::Aborting => {
+                let result: CallResult<(Result<InboundStatus, WalletError>,)> =
+                    call(peer, "peer_abort", (transfer_id,)).await;
+                let Ok(transfer) = get_outbound(transfer_id) else {
+                    return;
+                };
+                return match result {
+                    Ok((Ok(InboundStatus::Committed),)) => complete(transfer),
+                    Ok((Ok(_),)) => {
+                        roll_back(transfer, "the peer wallet did not respond".to_string())
+                    }
+                    // Refunding without the peer's confirmation could pay
+                    // the funds out twice, so the abort is retried
+                    Ok((Err(_),)) | Err(_) => (),
+                };
+            }
+            ExternalTransferStatus::Committed | ExternalTransferStatus::RolledBack { .. } => return,
+        }
+    }
+}
+
+// Marks an outbound transfer as in flight until dropped. The future holding
+// it is dropped even when a callback traps, so the mark never outlives the
+// call.
+struct InFlight(u64);
+
+impl InFlight {
+    fn claim(transfer_id: u64) -> Option<Self> {
+        IN_FLIGHT
+            .with(|in_flight| in_flight.borrow_mut().insert(transfer_id))
+            .then_some(InFlight(transfer_id))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.0));
+    }
+}
+
+async fn drive_exclusively(transfer_id: u64) {
+    let Some(_in_flight) = InFlight::claim(transfer_id) else {
+        return;
+    };
+    drive(transfer_id).await;
+}
+
+pub(crate) fn start_recovery_job() {
+    ic_cdk_timers::set_timer_interval(RECOVERY_INTERVAL, recover_transfers);
+}
+
+// Retries unsettled outbound transfers and releases expired reservations
+fn recover_transfers() {
+    if ensure_writable().is_err() {
+        return;
+    }
+    let unsettled: Vec<u64> = OUTBOUND_TRANSFERS.with(|transfers| {
+        transfers
+            .borrow()
+            .iter()
+            .filter(|(id, transfer)| {
+                !transfer.is_settled()
+                    && !IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(id))
+            })
+            .take(RECOVERY_BATCH_SIZE)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for transfer_id in unsettled {
+        ic_cdk::spawn(drive_exclusively(transfer_id));
+    }
+
+    let now = current_time();
+    INBOUND_TRANSFERS.with(|transfers| {
+        let mut transfers = transfers.borrow_mut();
+        let expired: Vec<(InboundKey, InboundTransfer)> = transfers
+            .iter()
+            .filter(|(_, transfer)| {
+                transfer.status == InboundStatus::Reserved && now >= transfer.expires_at
+            })
+            .collect();
+        for (key, mut transfer) in expired {
+            transfer.status = InboundStatus::Aborted;
+            transfers.insert(key, transfer);
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn register_peer(canister: Principal, name: String) -> Result<Peer, WalletError> {
+    perf::instrument("register_peer", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if canister == ic_cdk::id() || canister == Principal::anonymous() {
+            return Err(WalletError::invalid(
+                "canister",
+                "must be another wallet canister",
+            ));
+        }
+        hardening::check_args(|fields| fields.required_text("name", &name, TextKind::Name))?;
+        let name = name.trim().to_string();
+
+        let peer = Peer {
+            canister,
+            name,
+            registered_at: current_time(),
+        };
+        PEER_STORAGE.with(|peers| {
+            peers
+                .borrow_mut()
+                .insert(StorablePrincipal(canister), peer.clone())
+        });
+        Ok(peer)
+    })
+}
+
+/// Stops trusting a peer. Transfers already under way with it are still
+/// settled; reservations it holds here expire unless it commits them.
+#[ic_cdk::update]
+fn remove_peer(canister: Principal) -> Result<(), WalletError> {
+    perf::instrument("remove_peer", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        PEER_STORAGE
+            .with(|peers| peers.borrow_mut().remove(&StorablePrincipal(canister)))
+            .map(|_| ())
+            .ok_or(WalletError::NotFoundByKey {
+                entity: "peer".to_string(),
+                key: canister.to_text(),
+            })
+    })
+}
+
+#[ic_cdk::query]
+fn list_peers() -> Vec<Peer> {
+    perf::measure("list_peers", || {
+        PEER_STORAGE.with(|peers| peers.borrow().iter().map(|(_, peer)| peer).collect())
+    })
+}
+
+/// Sends funds from the caller's account to `recipient_ref` (a handle,
+/// username or user id) on the peer wallet `peer_canister`. Returns the
+/// transfer in the state it reached; a transfer still `Pending` or
+/// `Reserved` is completed by the recovery job.
+#[ic_cdk::update]
+async fn send_external(
+    peer_canister: Principal,
+    recipient_ref: String,
+    amount: u64,
+) -> Result<ExternalTransfer, WalletError> {
+    perf::instrument_async("send_external", async move {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+        pause::ensure_transfers_allowed()?;
+
+        let user_id = caller_user_id()?;
+        devices::record_activity(user_id);
+        verification::ensure_verified(user_id)?;
+        if !is_peer(peer_canister) {
+            return Err(WalletError::NotFoundByKey {
+                entity: "peer".to_string(),
+                key: peer_canister.to_text(),
+            });
+        }
+        if amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", amount)?;
+        dust::ensure_above_minimum("amount", amount)?;
+        hardening::check_args(|fields| {
+            fields.required_text("recipient_ref", &recipient_ref, TextKind::Alias)
+        })?;
+        let recipient_ref = recipient_ref.trim().to_string();
+
+        // Debited up front, so the funds cannot be spent again while the peer
+        // is being called
+        let transfer_id = next_id();
+        debit_sender(user_id, amount, transfer_id)?;
+        let now = current_time();
+        let transfer = save_outbound(ExternalTransfer {
+            id: transfer_id,
+            from_user_id: user_id,
+            peer_canister,
+            recipient_ref,
+            amount,
+            status: ExternalTransferStatus::Pending,
+            reserve_attempts: 0,
+            created_at: now,
+            updated_at: now,
+        });
+
+        drive_exclusively(transfer.id).await;
+        get_outbound(transfer.id)
+    })
+    .await
+}
+
+#[ic_cdk::query]
+fn get_external_transfer(transfer_id: u64) -> Result<ExternalTransfer, WalletError> {
+    perf::instrument("get_external_transfer", || {
+        ensure_not_restoring()?;
+
+        let transfer = get_outbound(transfer_id)?;
+        ensure_owner(transfer.from_user_id)?;
+        Ok(transfer)
+    })
+}
+
+#[ic_cdk::query]
+fn list_external_transfers() -> Result<Vec<ExternalTransfer>, WalletError> {
+    perf::instrument("list_external_transfers", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        Ok(OUTBOUND_TRANSFERS.with(|transfers| {
+            transfers
+                .borrow()
+                .iter()
+                .map(|(_, transfer)| transfer)
+                .filter(|transfer| transfer.from_user_id == user_id)
+                .collect()
+        }))
+    })
+}
+
+fn resolve_recipient(recipient_ref: &str) -> Option<u64> {
+    let by_name = handles::lookup(recipient_ref).or_else(|| username::lookup(recipient_ref));
+    by_name.or_else(|| {
+        recipient_ref
+            .parse::<u64>()
+            .ok()
+            .filter(|user_id| USER_STORAGE.with(|storage| storage.borrow().contains_key(user_id)))
+    })
+}
+
+fn get_inbound(key: &InboundKey) -> Option<InboundTransfer> {
+    INBOUND_TRANSFERS.with(|transfers| transfers.borrow().get(key))
+}
+
+fn save_inbound(key: InboundKey, transfer: InboundTransfer) {
+    INBOUND_TRANSFERS.with(|transfers| transfers.borrow_mut().insert(key, transfer));
+}
+
+/// First phase, called by the sending peer: checks that the recipient can
+/// be credited and reserves the credit. Repeating the call for a transfer
+/// that is already reserved or committed succeeds without effect.
+#[ic_cdk::update]
+fn peer_reserve(args: PeerReserveArgs) -> Result<(), WalletError> {
+    perf::instrument("peer_reserve", || {
+        ensure_writable()?;
+        let peer = ensure_peer_caller()?;
+        hardening::check(&args)?;
+
+        let key = InboundKey {
+            transfer_id: args.transfer_id,
+            peer,
+        };
+        if let Some(existing) = get_inbound(&key) {
+            return match existing.status {
+                InboundStatus::Reserved | InboundStatus::Committed
+                    if existing.amount == args.amount =>
+                {
+                    Ok(())
+                }
+                _ => Err(WalletError::InvalidState {
+                    reason: format!("Transfer {} was already aborted", args.transfer_id),
+                }),
+            };
+        }
+
+        pause::ensure_transfers_allowed()?;
+        if args.amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", args.amount)?;
+        let to_user_id =
+            resolve_recipient(args.recipient_ref.trim()).ok_or(WalletError::NotFoundByKey {
+                entity: "recipient".to_string(),
+                key: args.recipient_ref.clone(),
+            })?;
+        let balance = USER_STORAGE
+            .with(|storage| storage.borrow().get(&to_user_id))
+            .map(|user| user.balance)
+            .unwrap_or_default();
+        if balance.checked_add(args.amount).is_none() {
+            return Err(WalletError::Overflow {
+                field: "balance".to_string(),
+            });
+        }
+
+        let now = current_time();
+        save_inbound(
+            key,
+            InboundTransfer {
+                to_user_id,
+                amount: args.amount,
+                status: InboundStatus::Reserved,
+                reserved_at: now,
+                expires_at: now + RESERVATION_TTL_SECONDS * NANOS_PER_SECOND,
+            },
+        );
+        Ok(())
+    })
+}
+
+/// Second phase, called by the sending peer: credits a reserved transfer to
+/// its recipient. Committing an already committed transfer succeeds without
+/// effect.
+#[ic_cdk::update]
+fn peer_commit(transfer_id: u64) -> Result<(), WalletError> {
+    perf::instrument("peer_commit", || {
+        ensure_writable()?;
+        let peer = ensure_peer_caller()?;
+
+        let key = InboundKey { transfer_id, peer };
+        let mut transfer =
+            get_inbound(&key).ok_or(WalletError::not_found("inbound transfer", transfer_id))?;
+        match transfer.status {
+            InboundStatus::Committed => return Ok(()),
+            InboundStatus::Aborted => {
+                return Err(WalletError::InvalidState {
+                    reason: format!("Transfer {} was aborted", transfer_id),
+                })
+            }
+            InboundStatus::Reserved => {}
+        }
+        if current_time() >= transfer.expires_at {
+            transfer.status = InboundStatus::Aborted;
+            save_inbound(key, transfer);
+            return Err(WalletError::InvalidState {
+                reason: format!("The reservation of transfer {} expired", transfer_id),
+            });
+        }
+
+        ledger::deposit(
+            EntryKind::InboundTransfer {
+                peer_canister: peer,
+                transfer_id,
+            },
+            transfer.to_user_id,
+            transfer.amount,
+        )?;
+        transfer.status = InboundStatus::Committed;
+        events::record(EventKind::ExternalTransferReceived {
+            transfer_id,
+            user_id: transfer.to_user_id,
+            peer_canister: peer,
+            amount: transfer.amount,
+        });
+        notify(
+            transfer.to_user_id,
+            NotificationKind::ExternalTransfer,
+            format!("You received {} from another wallet", transfer.amount),
+        );
+        save_inbound(key, transfer);
+        Ok(())
+    })
+}
+
+/// Called by a sending peer that gave up on a transfer. Returns the final
+/// state: `Aborted`, or `Committed` if the commit got through after all.
+/// An unknown transfer is recorded as aborted, so a reserve call still in
+/// flight is refused when it arrives.
+#[ic_cdk::update]
+fn peer_abort(transfer_id: u64) -> Result<InboundStatus, WalletError> {
+    perf::instrument("peer_abort", || {
+        ensure_writable()?;
+        let peer = ensure_peer_caller()?;
+
+        let key = InboundKey { transfer_id, peer };
+        let now = current_time();
+        let mut transfer = get_inbound(&key).unwrap_or(InboundTransfer {
+            to_user_id: 0,
+            amount: 0,
+            status: InboundStatus::Aborted,
+            reserved_at: now,
+            expires_at: now,
+        });
+        if transfer.status == InboundStatus::Reserved {
+            transfer.status = InboundStatus::Aborted;
+        }
+        let status = transfer.status;
+        save_inbound(key, transfer);
+        Ok(status)
+    })
+}
+
+/// State of a transfer the calling peer sent here, if it is known.
+#[ic_cdk::query]
+fn peer_transfer_status(transfer_id: u64) -> Result<Option<InboundStatus>, WalletError> {
+    perf::instrument("peer_transfer_status", || {
+        let peer = ensure_peer_caller()?;
+
+        Ok(get_inbound(&InboundKey { transfer_id, peer }).map(|transfer| transfer.status))
+    })
+}
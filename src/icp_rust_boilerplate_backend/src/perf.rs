@@ -0,0 +1,135 @@
+//! Per-endpoint instrumentation. Every update and query runs inside
+//! `instrument` (or `measure`, for endpoints that cannot fail), which counts
+//! its calls and errors and the instructions it executed. The v1 shims are
+//! the exception, counted by the v2 endpoint they forward to. The counters
+//! live on the heap, so they restart from zero after an upgrade.
+//!
+//! State changes made by query calls are discarded, so queries only show up
+//! when they run as replicated calls. Queries executed by a single replica
+//! are not charged cycles either.
+
+use crate::backup::ensure_writable;
+use crate::{ensure_admin, WalletError};
+use ic_cdk::api::performance_counter;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+
+// Instructions executed by the current message
+const MESSAGE_COUNTER: u32 = 0;
+// Instructions executed by all messages of the current call, across the
+// awaits of an async endpoint
+const CALL_CONTEXT_COUNTER: u32 = 1;
+
+#[derive(Default)]
+struct MethodStats {
+    calls: u64,
+    errors: u64,
+    total_instructions: u128,
+    max_instructions: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct EndpointStats {
+    method: String,
+    calls: u64,
+    errors: u64,
+    // Share of calls that returned an error, between 0 and 1
+    error_rate: f64,
+    average_instructions: u64,
+    max_instructions: u64,
+    total_instructions: u128,
+}
+
+thread_local! {
+    static METHOD_STATS: RefCell<BTreeMap<&'static str, MethodStats>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+// Unit tests run natively, where there is no instruction counter
+fn instructions(counter: u32) -> u64 {
+    if cfg!(test) {
+        0
+    } else {
+        performance_counter(counter)
+    }
+}
+
+fn record(method: &'static str, failed: bool, instructions: u64) {
+    METHOD_STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        let entry = stats.entry(method).or_default();
+        entry.calls += 1;
+        if failed {
+            entry.errors += 1;
+        }
+        entry.total_instructions += instructions as u128;
+        entry.max_instructions = entry.max_instructions.max(instructions);
+    });
+}
+
+/// Runs the body of endpoint `method`, counting it as an error when it
+/// returns `Err`.
+pub(crate) fn instrument<T, E>(
+    method: &'static str,
+    body: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    let result = body();
+    record(method, result.is_err(), instructions(MESSAGE_COUNTER));
+    result
+}
+
+/// `instrument` for async endpoints.
+pub(crate) async fn instrument_async<T, E>(
+    method: &'static str,
+    body: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let result = body.await;
+    record(method, result.is_err(), instructions(CALL_CONTEXT_COUNTER));
+    result
+}
+
+/// `instrument` for endpoints that cannot fail.
+pub(crate) fn measure<T>(method: &'static str, body: impl FnOnce() -> T) -> T {
+    let result = body();
+    record(method, false, instructions(MESSAGE_COUNTER));
+    result
+}
+
+/// Call counts, error rates and instruction counts of every endpoint called
+/// since the last upgrade, most expensive first.
+#[ic_cdk::query]
+pub(crate) fn get_performance_stats() -> Result<Vec<EndpointStats>, WalletError> {
+    instrument("get_performance_stats", || {
+        ensure_admin()?;
+
+        let mut stats: Vec<EndpointStats> = METHOD_STATS.with(|stats| {
+            stats
+                .borrow()
+                .iter()
+                .map(|(method, stats)| EndpointStats {
+                    method: method.to_string(),
+                    calls: stats.calls,
+                    errors: stats.errors,
+                    error_rate: stats.errors as f64 / stats.calls as f64,
+                    average_instructions: (stats.total_instructions / stats.calls as u128) as u64,
+                    max_instructions: stats.max_instructions,
+                    total_instructions: stats.total_instructions,
+                })
+                .collect()
+        });
+        stats.sort_by_key(|stats| std::cmp::Reverse(stats.total_instructions));
+        Ok(stats)
+    })
+}
+
+#[ic_cdk::update]
+fn reset_performance_stats() -> Result<(), WalletError> {
+    instrument("reset_performance_stats", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        METHOD_STATS.with(|stats| stats.borrow_mut().clear());
+        Ok(())
+    })
+}
@@ -0,0 +1,228 @@
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::events::{self, EventKind};
+use crate::manifest::{self, Storage};
+use crate::{
+    current_time, ensure_admin, ensure_not_restoring, ensure_writable, next_id, Memory,
+    WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use crate::{devices, leaderboard, lockdown, pause, perf};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct PointsTransfer {
+    id: u64,
+    from_user_id: u64,
+    to_user_id: u64,
+    points: u64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PointsConfig {
+    transfers_enabled: bool,
+}
+
+impl Default for PointsConfig {
+    fn default() -> Self {
+        PointsConfig {
+            transfers_enabled: true,
+        }
+    }
+}
+
+impl Storable for PointsTransfer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PointsConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static POINTS_TRANSFER_STORAGE: RefCell<StableBTreeMap<u64, PointsTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static POINTS_CONFIG: RefCell<Cell<PointsConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+            PointsConfig::default(),
+        )
+        .expect("Cannot create the points config cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map(
+            "points.points_transfer_storage",
+            6,
+            &POINTS_TRANSFER_STORAGE,
+        ),
+        manifest::cell("points.points_config", 7, &POINTS_CONFIG),
+    ]
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct PointsTransferPayload {
+    pub(crate) to_user_id: u64,
+    pub(crate) points: u64,
+}
+
+#[ic_cdk::update]
+pub(crate) fn transfer_points(
+    payload: PointsTransferPayload,
+) -> Result<PointsTransfer, WalletError> {
+    perf::instrument("transfer_points", || {
+        ensure_writable()?;
+
+        if !POINTS_CONFIG.with(|config| config.borrow().get().transfers_enabled) {
+            return Err(WalletError::InvalidState {
+                reason: "Points transfers are currently disabled".to_string(),
+            });
+        }
+
+        let from_user_id = caller_user_id()?;
+        lockdown::ensure_not_locked(from_user_id)?;
+        devices::record_activity(from_user_id);
+        if payload.points == 0 {
+            return Err(WalletError::invalid("points", "must be greater than 0"));
+        }
+        if from_user_id == payload.to_user_id {
+            return Err(WalletError::invalid(
+                "to_user_id",
+                "sender and recipient must be different users",
+            ));
+        }
+
+        USER_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let mut from_user = storage
+                .get(&from_user_id)
+                .ok_or(WalletError::not_found("sender", from_user_id))?;
+            let mut to_user = storage
+                .get(&payload.to_user_id)
+                .ok_or(WalletError::not_found("recipient", payload.to_user_id))?;
+
+            if from_user.points < payload.points {
+                return Err(WalletError::InsufficientPoints {
+                    available: from_user.points,
+                    required: payload.points,
+                });
+            }
+            to_user.points =
+                to_user
+                    .points
+                    .checked_add(payload.points)
+                    .ok_or(WalletError::Overflow {
+                        field: "points".to_string(),
+                    })?;
+            from_user.points -= payload.points;
+            leaderboard::index_points(from_user.id.0, from_user.points);
+            leaderboard::index_points(to_user.id.0, to_user.points);
+
+            storage.insert(from_user.id.0, from_user);
+            storage.insert(to_user.id.0, to_user);
+            Ok(())
+        })?;
+
+        let id = next_id();
+        let transfer = PointsTransfer {
+            id,
+            from_user_id,
+            to_user_id: payload.to_user_id,
+            points: payload.points,
+            created_at: current_time(),
+        };
+        POINTS_TRANSFER_STORAGE.with(|storage| storage.borrow_mut().insert(id, transfer.clone()));
+        events::record(EventKind::PointsTransferred {
+            transfer_id: id,
+            from_user_id,
+            to_user_id: payload.to_user_id,
+            points: payload.points,
+        });
+        Ok(transfer)
+    })
+}
+
+#[ic_cdk::query]
+fn get_points_transfer_history(user_id: u64) -> Result<Vec<PointsTransfer>, WalletError> {
+    perf::instrument("get_points_transfer_history", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(POINTS_TRANSFER_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, transfer)| {
+                    transfer.from_user_id == user_id || transfer.to_user_id == user_id
+                })
+                .map(|(_, transfer)| transfer)
+                .collect()
+        }))
+    })
+}
+
+// Lets admins switch off peer-to-peer gifting if the rewards economy needs tightening
+#[ic_cdk::update]
+fn set_points_transfers_enabled(enabled: bool) -> Result<(), WalletError> {
+    perf::instrument("set_points_transfers_enabled", || {
+        ensure_admin()?;
+        pause::ensure_not_read_only()?;
+
+        POINTS_CONFIG
+            .with(|config| {
+                config.borrow_mut().set(PointsConfig {
+                    transfers_enabled: enabled,
+                })
+            })
+            .expect("Cannot update the points config");
+        Ok(())
+    })
+}
+
+pub(crate) fn export_points_transfers() -> Vec<PointsTransfer> {
+    POINTS_TRANSFER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, transfer)| transfer)
+            .collect()
+    })
+}
+
+pub(crate) fn import_points_transfers(transfers: Vec<PointsTransfer>) {
+    POINTS_TRANSFER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        crate::clear_map(&mut storage);
+        for transfer in transfers {
+            storage.insert(transfer.id, transfer);
+        }
+    });
+}
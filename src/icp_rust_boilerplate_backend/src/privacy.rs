@@ -0,0 +1,154 @@
+//! Privacy settings. Each user chooses whether their username, display name
+//! and avatar are shown to others in directory search, in the transaction
+//! details of their counterparties and on the points leaderboard. Nothing is
+//! shown until the user allows it. Every read path that describes another
+//! user goes through `visible_profile`.
+//!
+//! Ids are always shown; they are what transfers are addressed to. A
+//! username or display name hidden from search cannot be searched by either.
+
+use crate::auth::ensure_owner;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::{directory, perf, profile, Memory, User, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+/// Where another user can come across a profile.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum Surface {
+    Search,
+    TransactionDetails,
+    Leaderboard,
+}
+
+/// The surfaces a profile field is shown on.
+#[derive(candid::CandidType, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct FieldVisibility {
+    search: bool,
+    transaction_details: bool,
+    leaderboard: bool,
+}
+
+impl FieldVisibility {
+    fn on(self, surface: Surface) -> bool {
+        match surface {
+            Surface::Search => self.search,
+            Surface::TransactionDetails => self.transaction_details,
+            Surface::Leaderboard => self.leaderboard,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct PrivacySettings {
+    username: FieldVisibility,
+    display_name: FieldVisibility,
+    avatar: FieldVisibility,
+}
+
+/// What others see of a user on one surface; hidden fields are `None`.
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct VisibleProfile {
+    pub(crate) username: Option<String>,
+    pub(crate) display_name: Option<String>,
+    pub(crate) avatar: Option<String>,
+}
+
+impl Storable for PrivacySettings {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Users without an entry show nothing
+    static PRIVACY_SETTINGS: RefCell<StableBTreeMap<u64, PrivacySettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map(
+        "privacy.privacy_settings",
+        105,
+        &PRIVACY_SETTINGS,
+    )]
+}
+
+fn settings_of(user_id: u64) -> PrivacySettings {
+    PRIVACY_SETTINGS
+        .with(|storage| storage.borrow().get(&user_id))
+        .unwrap_or_default()
+}
+
+/// Whether `user_id`'s username and display name can be searched.
+pub(crate) fn searchable_names(user_id: u64) -> (bool, bool) {
+    let settings = settings_of(user_id);
+    (
+        settings.username.on(Surface::Search),
+        settings.display_name.on(Surface::Search),
+    )
+}
+
+/// The parts of `user`'s profile they show on `surface`.
+pub(crate) fn visible_profile(user: &User, surface: Surface) -> VisibleProfile {
+    let settings = settings_of(user.id.0);
+    VisibleProfile {
+        username: settings.username.on(surface).then(|| user.username.clone()),
+        display_name: settings.display_name.on(surface).then(|| {
+            format!("{} {}", user.first_name, user.last_name)
+                .trim()
+                .to_string()
+        }),
+        avatar: if settings.avatar.on(surface) {
+            profile::avatar_of(user.id.0)
+        } else {
+            None
+        },
+    }
+}
+
+/// Replaces what `user_id` shows of their profile. Search and the
+/// leaderboard reflect the change at once; leaderboard snapshots already
+/// taken keep what was shown then.
+#[ic_cdk::update]
+fn set_privacy_settings(user_id: u64, settings: PrivacySettings) -> Result<(), WalletError> {
+    perf::instrument("set_privacy_settings", || {
+        ensure_writable()?;
+
+        let user = USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("user", user_id))?;
+        ensure_owner(user_id)?;
+
+        directory::unindex_user(&user);
+        PRIVACY_SETTINGS.with(|storage| storage.borrow_mut().insert(user_id, settings));
+        directory::index_user(&user);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_privacy_settings(user_id: u64) -> Result<PrivacySettings, WalletError> {
+    perf::instrument("get_privacy_settings", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(settings_of(user_id))
+    })
+}
@@ -0,0 +1,191 @@
+//! Read access to user records. Anyone can look a user up, but unless the
+//! caller owns the account or is a controller, the contact details are
+//! masked, the balance is left out, and the username, names and avatar are
+//! only shown if the user shows them in search.
+//!
+//! Users can also set an avatar, a reference such as a URL that clients
+//! resolve; others only see it where the user's privacy settings allow.
+
+use crate::auth::{self, caller_user_id, ensure_owner, owner_of, user_of};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::privacy::{self, Surface};
+use crate::{Memory, User, UserId, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct UserView {
+    id: UserId,
+    // The username and names are empty for others unless the user shows
+    // them in search
+    username: String,
+    first_name: String,
+    last_name: String,
+    email: String,
+    phone_number: String,
+    created_at: u64,
+    // Only shown to the owner and the controllers
+    balance: Option<u64>,
+    points: u64,
+    // Whether `email` and `phone_number` are masked
+    masked: bool,
+    // Shown to others only if the user shows it in search
+    avatar: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct WhoAmI {
+    principal: Principal,
+    user_id: Option<u64>,
+    is_admin: bool,
+}
+
+thread_local! {
+    static AVATARS: RefCell<StableBTreeMap<u64, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map("profile.avatars", 104, &AVATARS)]
+}
+
+pub(crate) fn avatar_of(user_id: u64) -> Option<String> {
+    AVATARS.with(|avatars| avatars.borrow().get(&user_id))
+}
+
+// Keeps the first character of the local part, e.g. "j***@example.com"
+fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            let first: String = local.chars().take(1).collect();
+            format!("{}***@{}", first, domain)
+        }
+        None => "***".to_string(),
+    }
+}
+
+// Keeps the last four digits, e.g. "*******7890"
+fn mask_phone(phone_number: &str) -> String {
+    let chars: Vec<char> = phone_number.chars().collect();
+    let visible = chars.len().saturating_sub(4);
+    chars
+        .iter()
+        .enumerate()
+        .map(|(index, c)| if index < visible { '*' } else { *c })
+        .collect()
+}
+
+fn user_view(user: User) -> UserView {
+    let caller = auth::caller();
+    let privileged = owner_of(user.id.0) == Some(caller) || ic_cdk::api::is_controller(&caller);
+    if privileged {
+        let avatar = avatar_of(user.id.0);
+        return UserView {
+            id: user.id,
+            username: user.username,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            phone_number: user.phone_number,
+            created_at: user.created_at,
+            balance: Some(user.balance),
+            points: user.points,
+            masked: false,
+            avatar,
+        };
+    }
+
+    // Looking a user up shows what finding them in search would
+    let shown = privacy::visible_profile(&user, Surface::Search);
+    let (first_name, last_name) = match shown.display_name {
+        Some(_) => (user.first_name, user.last_name),
+        None => (String::new(), String::new()),
+    };
+    UserView {
+        id: user.id,
+        username: shown.username.unwrap_or_default(),
+        first_name,
+        last_name,
+        email: mask_email(&user.email),
+        phone_number: mask_phone(&user.phone_number),
+        created_at: user.created_at,
+        balance: None,
+        points: user.points,
+        masked: true,
+        avatar: shown.avatar,
+    }
+}
+
+#[ic_cdk::query]
+fn get_user(user_id: u64) -> Result<UserView, WalletError> {
+    perf::instrument("get_user", || {
+        ensure_not_restoring()?;
+
+        USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .map(user_view)
+            .ok_or(WalletError::not_found("user", user_id))
+    })
+}
+
+#[ic_cdk::query]
+fn get_my_profile() -> Result<UserView, WalletError> {
+    perf::instrument("get_my_profile", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .map(user_view)
+            .ok_or(WalletError::not_found("user", user_id))
+    })
+}
+
+/// Sets the avatar reference of `user_id`, or removes it with `None`.
+#[ic_cdk::update]
+fn set_avatar(user_id: u64, avatar: Option<String>) -> Result<(), WalletError> {
+    perf::instrument("set_avatar", || {
+        ensure_writable()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        let Some(avatar) = avatar else {
+            AVATARS.with(|avatars| avatars.borrow_mut().remove(&user_id));
+            return Ok(());
+        };
+        hardening::check_args(|fields| {
+            fields.required_text("avatar", &avatar, TextKind::Link);
+            if avatar.chars().any(char::is_whitespace) {
+                fields.rule(Err(WalletError::invalid(
+                    "avatar",
+                    "must not contain spaces",
+                )));
+            }
+        })?;
+        AVATARS.with(|avatars| avatars.borrow_mut().insert(user_id, avatar));
+        Ok(())
+    })
+}
+
+/// The caller's principal and the account it owns, if any.
+#[ic_cdk::query]
+fn whoami() -> WhoAmI {
+    perf::measure("whoami", || {
+        let principal = auth::caller();
+        WhoAmI {
+            principal,
+            user_id: user_of(principal),
+            is_admin: ic_cdk::api::is_controller(&principal),
+        }
+    })
+}
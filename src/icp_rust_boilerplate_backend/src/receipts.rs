@@ -0,0 +1,234 @@
+//! Transaction receipts. Raw transactions only carry user ids; receipts add
+//! what a wallet needs to show them, from the point of view of one of the
+//! two participants. The counterparty is described by what its privacy
+//! settings show in transaction details.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::ensure_not_restoring;
+use crate::budgets::{self, Category};
+use crate::disputes::{self, DisputeStatus};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::privacy::{self, Surface};
+use crate::{
+    token, Memory, Transaction, TransactionId, WalletError, MEMORY_MANAGER, TRANSACTION_STORAGE,
+    USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// Transfers between wallet users are free
+pub(crate) const TRANSFER_FEE: u64 = 0;
+
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize)]
+pub(crate) enum TransactionStatus {
+    Completed,
+    // A dispute is open or under review
+    Disputed,
+    Refunded { refund_tx_id: u64 },
+    // This transaction is the refund of a disputed one
+    Refund { original_tx_id: u64 },
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct Counterparty {
+    user_id: u64,
+    // Hidden fields are absent
+    username: Option<String>,
+    display_name: Option<String>,
+    avatar: Option<String>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct TransactionDetail {
+    id: TransactionId,
+    direction: Direction,
+    counterparty: Counterparty,
+    amount: u64,
+    // `amount` rendered with the token's decimals and symbol
+    formatted_amount: String,
+    fee: u64,
+    memo: Option<String>,
+    category: Option<Category>,
+    status: TransactionStatus,
+    // Absent on transactions recorded before balances were tracked
+    balance_after: Option<u64>,
+    created_at: u64,
+}
+
+/// Both participants' balances right after a transaction executed.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BalancesAfter {
+    sender: u64,
+    recipient: u64,
+}
+
+impl Storable for BalancesAfter {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static BALANCES_AFTER: RefCell<StableBTreeMap<u64, BalancesAfter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map(
+        "receipts.balances_after",
+        38,
+        &BALANCES_AFTER,
+    )]
+}
+
+pub(crate) fn record_balances_after(tx_id: u64, sender: u64, recipient: u64) {
+    BALANCES_AFTER.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(tx_id, BalancesAfter { sender, recipient })
+    });
+}
+
+fn counterparty(user_id: u64) -> Counterparty {
+    let profile = USER_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .map(|user| privacy::visible_profile(&user, Surface::TransactionDetails))
+        .unwrap_or_default();
+    Counterparty {
+        user_id,
+        username: profile.username,
+        display_name: profile.display_name,
+        avatar: profile.avatar,
+    }
+}
+
+fn status_of(tx_id: u64, disputes: &BTreeMap<u64, DisputeStatus>) -> TransactionStatus {
+    match disputes.get(&tx_id) {
+        Some(DisputeStatus::Open) | Some(DisputeStatus::UnderReview) => {
+            return TransactionStatus::Disputed
+        }
+        Some(DisputeStatus::ResolvedRefund { refund_tx_id }) => {
+            return TransactionStatus::Refunded {
+                refund_tx_id: *refund_tx_id,
+            }
+        }
+        Some(DisputeStatus::ResolvedUpheld) | None => {}
+    }
+    let original = disputes
+        .iter()
+        .find_map(|(original_tx_id, status)| match status {
+            DisputeStatus::ResolvedRefund { refund_tx_id } if *refund_tx_id == tx_id => {
+                Some(*original_tx_id)
+            }
+            _ => None,
+        });
+    match original {
+        Some(original_tx_id) => TransactionStatus::Refund { original_tx_id },
+        None => TransactionStatus::Completed,
+    }
+}
+
+// `user_id` must be one of the participants of `tx`
+fn detail_for(
+    tx: Transaction,
+    user_id: u64,
+    disputes: &BTreeMap<u64, DisputeStatus>,
+) -> TransactionDetail {
+    let direction = if tx.from_user_id.0 == user_id {
+        Direction::Outgoing
+    } else {
+        Direction::Incoming
+    };
+    let (counterparty_id, fee) = match direction {
+        Direction::Outgoing => (tx.to_user_id.0, TRANSFER_FEE),
+        Direction::Incoming => (tx.from_user_id.0, 0),
+    };
+    let balance_after = BALANCES_AFTER
+        .with(|storage| storage.borrow().get(&tx.id.0))
+        .map(|balances| match direction {
+            Direction::Outgoing => balances.sender,
+            Direction::Incoming => balances.recipient,
+        });
+    TransactionDetail {
+        id: tx.id,
+        direction,
+        counterparty: counterparty(counterparty_id),
+        amount: tx.amount,
+        formatted_amount: token::format_amount(tx.amount),
+        fee,
+        category: budgets::category_of(tx.id.0),
+        status: status_of(tx.id.0, disputes),
+        memo: tx.memo,
+        balance_after,
+        created_at: tx.created_at,
+    }
+}
+
+/// Receipt of one transaction, as seen by the calling participant.
+#[ic_cdk::query]
+pub(crate) fn get_transaction_detail(tx_id: u64) -> Result<TransactionDetail, WalletError> {
+    perf::instrument("get_transaction_detail", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        let tx = TRANSACTION_STORAGE
+            .with(|storage| storage.borrow().get(&tx_id))
+            .ok_or(WalletError::not_found("transaction", tx_id))?;
+        if !tx.involves(user_id) {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller is not a participant of transaction {}", tx_id),
+            });
+        }
+        Ok(detail_for(
+            tx,
+            user_id,
+            &disputes::statuses_by_transaction(),
+        ))
+    })
+}
+
+/// Same transactions as `v2_get_transaction_history`, as receipts.
+#[ic_cdk::query]
+fn get_transaction_history_detailed(user_id: u64) -> Result<Vec<TransactionDetail>, WalletError> {
+    perf::instrument("get_transaction_history_detailed", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        let disputes = disputes::statuses_by_transaction();
+        let transactions: Vec<Transaction> = TRANSACTION_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, transaction)| transaction)
+                .filter(|transaction| transaction.involves(user_id))
+                .collect()
+        });
+        Ok(transactions
+            .into_iter()
+            .map(|transaction| detail_for(transaction, user_id, &disputes))
+            .collect())
+    })
+}
@@ -0,0 +1,214 @@
+//! Reconciliation of balances. Every change to the funds held in accounts
+//! also moves a running total; a timer recomputes the sum of all balances
+//! and reports a break when the two disagree, pausing transfers unless the
+//! controllers turned that off. Each cached balance is also compared with
+//! its account in the ledger.
+
+use crate::backup::ensure_writable;
+use crate::events::{self, EventKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::notify_admins;
+use crate::perf;
+use crate::{
+    current_time, ensure_admin, ledger, pause, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const RECONCILIATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ReconciliationReport {
+    ran_at: u64,
+    expected_total: u64,
+    actual_total: u64,
+    // Users whose cached balance differs from the ledger
+    ledger_mismatches: Option<u64>,
+    balanced: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReconciliationState {
+    // What the balances should add up to
+    expected_total: u64,
+    // False until seeded from the balances held when reconciliation started
+    seeded: bool,
+    auto_pause: bool,
+    last_report: Option<ReconciliationReport>,
+}
+
+impl Default for ReconciliationState {
+    fn default() -> Self {
+        ReconciliationState {
+            expected_total: 0,
+            seeded: false,
+            auto_pause: true,
+            last_report: None,
+        }
+    }
+}
+
+impl Storable for ReconciliationState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static RECONCILIATION_STATE: RefCell<Cell<ReconciliationState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+            ReconciliationState::default(),
+        )
+        .expect("Cannot create the reconciliation state cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell(
+        "reconciliation.reconciliation_state",
+        36,
+        &RECONCILIATION_STATE,
+    )]
+}
+
+fn reconciliation_state() -> ReconciliationState {
+    RECONCILIATION_STATE.with(|state| state.borrow().get().clone())
+}
+
+fn set_reconciliation_state(state: ReconciliationState) {
+    RECONCILIATION_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("Cannot update the reconciliation state");
+}
+
+fn total_balances() -> u64 {
+    USER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, user)| user.balance)
+            .fold(0u64, u64::saturating_add)
+    })
+}
+
+/// Records funds added to a user account; called by the ledger.
+pub(crate) fn record_credit(amount: u64) {
+    let mut state = reconciliation_state();
+    state.expected_total = state.expected_total.saturating_add(amount);
+    set_reconciliation_state(state);
+}
+
+/// Records funds taken out of a user account; called by the ledger.
+pub(crate) fn record_debit(amount: u64) {
+    let mut state = reconciliation_state();
+    state.expected_total = state.expected_total.saturating_sub(amount);
+    set_reconciliation_state(state);
+}
+
+/// Accepts the current balances as correct, for canisters that held funds
+/// before reconciliation existed, after a restore replaced the balances and
+/// when an admin resumes after a break.
+pub(crate) fn reseed() {
+    ledger::adopt_cached_balances();
+    let mut state = reconciliation_state();
+    state.expected_total = total_balances();
+    state.seeded = true;
+    set_reconciliation_state(state);
+}
+
+pub(crate) fn start_reconciliation_job() {
+    if !reconciliation_state().seeded {
+        reseed();
+    }
+    ic_cdk_timers::set_timer_interval(RECONCILIATION_INTERVAL, || {
+        // A break already paused transfers and needs an admin to look at it
+        if ensure_writable().is_ok() && pause::ensure_transfers_allowed().is_ok() {
+            reconcile();
+        }
+    });
+}
+
+fn reconcile() -> ReconciliationReport {
+    let mut state = reconciliation_state();
+    let actual_total = total_balances();
+    let ledger_mismatches = ledger::mismatched_accounts();
+    let report = ReconciliationReport {
+        ran_at: current_time(),
+        expected_total: state.expected_total,
+        actual_total,
+        ledger_mismatches: Some(ledger_mismatches),
+        balanced: actual_total == state.expected_total && ledger_mismatches == 0,
+    };
+    state.last_report = Some(report.clone());
+    let auto_pause = state.auto_pause;
+    set_reconciliation_state(state);
+    if report.balanced {
+        return report;
+    }
+
+    events::record(EventKind::ReconciliationBreak {
+        expected_total: report.expected_total,
+        actual_total,
+    });
+    let reason = if ledger_mismatches > 0 {
+        format!(
+            "Reconciliation break: {} balances differ from the ledger",
+            ledger_mismatches
+        )
+    } else {
+        format!(
+            "Reconciliation break: balances add up to {} instead of {}",
+            actual_total, report.expected_total
+        )
+    };
+    if auto_pause {
+        notify_admins(format!("{}; transfers were paused", reason));
+        pause::trip(reason);
+    } else {
+        notify_admins(reason);
+    }
+    report
+}
+
+#[ic_cdk::update]
+pub(crate) fn run_reconciliation_now() -> Result<ReconciliationReport, WalletError> {
+    perf::instrument("run_reconciliation_now", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        Ok(reconcile())
+    })
+}
+
+#[ic_cdk::update]
+fn set_reconciliation_auto_pause(enabled: bool) -> Result<(), WalletError> {
+    perf::instrument("set_reconciliation_auto_pause", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let mut state = reconciliation_state();
+        state.auto_pause = enabled;
+        set_reconciliation_state(state);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_last_reconciliation() -> Result<Option<ReconciliationReport>, WalletError> {
+    perf::instrument("get_last_reconciliation", || {
+        ensure_admin()?;
+
+        Ok(reconciliation_state().last_report)
+    })
+}
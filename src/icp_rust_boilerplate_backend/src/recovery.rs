@@ -0,0 +1,386 @@
+//! Social recovery: an owner designates guardian principals, and if they lose
+//! access a new principal can take the account over once enough guardians
+//! approve. Approved requests wait out a delay during which the current owner
+//! is notified and can veto.
+
+use crate::auth::{self, caller_user_id};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::hardening::{self, FieldChecker, Harden};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::perf;
+use crate::{current_time, lockdown, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+// Guardians have 72 hours to approve a request
+const APPROVAL_WINDOW: u64 = 72 * NANOS_PER_HOUR;
+// Approved requests only take effect after 48 hours so the owner can veto
+const RECOVERY_DELAY: u64 = 48 * NANOS_PER_HOUR;
+const RECOVERY_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const MAX_GUARDIANS: usize = 10;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct GuardianConfig {
+    guardians: Vec<Principal>,
+    threshold: u32,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum RecoveryStatus {
+    AwaitingApprovals,
+    Delayed,
+    Completed,
+    Vetoed,
+    Expired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecoveryRequest {
+    user_id: u64,
+    new_owner: Principal,
+    initiated_at: u64,
+    approvals: Vec<Principal>,
+    status: RecoveryStatus,
+    // Set once the guardian threshold is reached
+    executable_at: Option<u64>,
+}
+
+impl RecoveryRequest {
+    fn is_active(&self) -> bool {
+        matches!(
+            self.status,
+            RecoveryStatus::AwaitingApprovals | RecoveryStatus::Delayed
+        )
+    }
+}
+
+impl GuardianConfig {
+    pub(crate) fn is_guardian(&self, principal: Principal) -> bool {
+        self.guardians.contains(&principal)
+    }
+
+    pub(crate) fn threshold(&self) -> u32 {
+        self.threshold
+    }
+}
+
+impl Storable for GuardianConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RecoveryRequest {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static GUARDIAN_STORAGE: RefCell<StableBTreeMap<u64, GuardianConfig, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    // Latest recovery request per account
+    static RECOVERY_STORAGE: RefCell<StableBTreeMap<u64, RecoveryRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("recovery.guardian_storage", 14, &GUARDIAN_STORAGE),
+        manifest::map("recovery.recovery_storage", 15, &RECOVERY_STORAGE),
+    ]
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct GuardiansPayload {
+    guardians: Vec<Principal>,
+    threshold: u32,
+}
+
+impl Harden for GuardiansPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.list("guardians", &self.guardians, MAX_GUARDIANS);
+    }
+}
+
+pub(crate) fn guardian_config(user_id: u64) -> Option<GuardianConfig> {
+    GUARDIAN_STORAGE.with(|storage| storage.borrow().get(&user_id))
+}
+
+fn active_request(user_id: u64) -> Option<RecoveryRequest> {
+    RECOVERY_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .filter(RecoveryRequest::is_active)
+}
+
+fn store_request(request: RecoveryRequest) {
+    RECOVERY_STORAGE.with(|storage| storage.borrow_mut().insert(request.user_id, request));
+}
+
+fn no_active_request(user_id: u64) -> WalletError {
+    WalletError::InvalidState {
+        reason: format!("User {} has no recovery in progress", user_id),
+    }
+}
+
+pub(crate) fn start_recovery_monitor() {
+    ic_cdk_timers::set_timer_interval(RECOVERY_CHECK_INTERVAL, process_recoveries);
+}
+
+// Expires requests whose approval window closed and rotates the owner of
+// requests whose veto delay has passed
+fn process_recoveries() {
+    if ensure_writable().is_err() {
+        return;
+    }
+
+    let now = current_time();
+    let active: Vec<RecoveryRequest> = RECOVERY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(RecoveryRequest::is_active)
+            .collect()
+    });
+    for mut request in active {
+        match (request.status, request.executable_at) {
+            (RecoveryStatus::AwaitingApprovals, _)
+                if now >= request.initiated_at + APPROVAL_WINDOW =>
+            {
+                request.status = RecoveryStatus::Expired;
+                store_request(request);
+            }
+            (RecoveryStatus::Delayed, Some(executable_at)) if now >= executable_at => {
+                complete_recovery(request);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn complete_recovery(mut request: RecoveryRequest) {
+    // The new principal may have opened an account of its own in the meantime
+    if auth::ensure_can_own_account(request.new_owner).is_err() {
+        request.status = RecoveryStatus::Expired;
+        store_request(request);
+        return;
+    }
+
+    auth::rotate_owner(request.user_id, request.new_owner);
+    notify(
+        request.user_id,
+        NotificationKind::AccountRecovery,
+        format!("Account ownership was moved to {}", request.new_owner),
+    );
+    request.status = RecoveryStatus::Completed;
+    store_request(request);
+}
+
+#[ic_cdk::update]
+fn set_guardians(payload: GuardiansPayload) -> Result<GuardianConfig, WalletError> {
+    perf::instrument("set_guardians", || {
+        ensure_writable()?;
+
+        hardening::check(&payload)?;
+        let user_id = caller_user_id()?;
+        // Guardians can unlock a locked account, so a lock freezes them too
+        lockdown::ensure_not_locked(user_id)?;
+        if active_request(user_id).is_some() {
+            return Err(WalletError::InvalidState {
+                reason: "Guardians cannot be changed while a recovery is in progress".to_string(),
+            });
+        }
+        if payload.guardians.is_empty() || payload.guardians.len() > MAX_GUARDIANS {
+            return Err(WalletError::invalid(
+                "guardians",
+                "must list between 1 and 10 principals",
+            ));
+        }
+        let owner = auth::caller();
+        for (position, guardian) in payload.guardians.iter().enumerate() {
+            if *guardian == Principal::anonymous() || *guardian == owner {
+                return Err(WalletError::invalid(
+                    "guardians",
+                    "must not contain the anonymous principal or the owner",
+                ));
+            }
+            if payload.guardians[..position].contains(guardian) {
+                return Err(WalletError::invalid(
+                    "guardians",
+                    "must not contain duplicates",
+                ));
+            }
+        }
+        if payload.threshold == 0 || payload.threshold as usize > payload.guardians.len() {
+            return Err(WalletError::invalid(
+                "threshold",
+                "must be between 1 and the number of guardians",
+            ));
+        }
+
+        let config = GuardianConfig {
+            guardians: payload.guardians,
+            threshold: payload.threshold,
+        };
+        GUARDIAN_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, config.clone()));
+        Ok(config)
+    })
+}
+
+#[ic_cdk::query]
+fn get_guardians(user_id: u64) -> Result<GuardianConfig, WalletError> {
+    perf::instrument("get_guardians", || {
+        ensure_not_restoring()?;
+
+        GUARDIAN_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("guardian config", user_id))
+    })
+}
+
+// Called by the principal that wants to take over `user_id`
+#[ic_cdk::update]
+fn initiate_recovery(user_id: u64) -> Result<RecoveryRequest, WalletError> {
+    perf::instrument("initiate_recovery", || {
+        ensure_writable()?;
+
+        let new_owner = auth::caller();
+        auth::ensure_can_own_account(new_owner)?;
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        if !GUARDIAN_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::InvalidState {
+                reason: format!("User {} has no guardians", user_id),
+            });
+        }
+        if active_request(user_id).is_some() {
+            return Err(WalletError::AlreadyExists {
+                entity: "recovery request".to_string(),
+                field: "user_id".to_string(),
+            });
+        }
+
+        let request = RecoveryRequest {
+            user_id,
+            new_owner,
+            initiated_at: current_time(),
+            approvals: Vec::new(),
+            status: RecoveryStatus::AwaitingApprovals,
+            executable_at: None,
+        };
+        store_request(request.clone());
+        notify(
+            user_id,
+            NotificationKind::AccountRecovery,
+            format!(
+                "{} started recovering this account. Call veto_recovery if this was not you",
+                new_owner
+            ),
+        );
+        Ok(request)
+    })
+}
+
+// Called by a guardian of `user_id`
+#[ic_cdk::update]
+fn approve_recovery(user_id: u64) -> Result<RecoveryRequest, WalletError> {
+    perf::instrument("approve_recovery", || {
+        ensure_writable()?;
+
+        let guardian = auth::caller();
+        let config = GUARDIAN_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("guardian config", user_id))?;
+        if !config.guardians.contains(&guardian) {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller is not a guardian of user {}", user_id),
+            });
+        }
+
+        let mut request = active_request(user_id)
+            .filter(|request| request.status == RecoveryStatus::AwaitingApprovals)
+            .ok_or_else(|| no_active_request(user_id))?;
+        let now = current_time();
+        if now >= request.initiated_at + APPROVAL_WINDOW {
+            request.status = RecoveryStatus::Expired;
+            store_request(request);
+            return Err(WalletError::InvalidState {
+                reason: "The approval window for this recovery has closed".to_string(),
+            });
+        }
+        if !request.approvals.contains(&guardian) {
+            request.approvals.push(guardian);
+        }
+
+        if request.approvals.len() >= config.threshold as usize {
+            let executable_at = now + RECOVERY_DELAY;
+            request.status = RecoveryStatus::Delayed;
+            request.executable_at = Some(executable_at);
+            notify(
+                user_id,
+                NotificationKind::AccountRecovery,
+                format!(
+                    "Guardians approved the recovery. Ownership moves to {} at {} unless vetoed",
+                    request.new_owner, executable_at
+                ),
+            );
+        }
+        store_request(request.clone());
+        Ok(request)
+    })
+}
+
+// Lets the current owner cancel a recovery they did not start
+#[ic_cdk::update]
+fn veto_recovery() -> Result<RecoveryRequest, WalletError> {
+    perf::instrument("veto_recovery", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let mut request = active_request(user_id).ok_or_else(|| no_active_request(user_id))?;
+        request.status = RecoveryStatus::Vetoed;
+        store_request(request.clone());
+        notify(
+            user_id,
+            NotificationKind::AccountRecovery,
+            "The recovery request was vetoed".to_string(),
+        );
+        Ok(request)
+    })
+}
+
+#[ic_cdk::query]
+fn get_recovery_status(user_id: u64) -> Result<RecoveryRequest, WalletError> {
+    perf::instrument("get_recovery_status", || {
+        ensure_not_restoring()?;
+
+        RECOVERY_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("recovery request", user_id))
+    })
+}
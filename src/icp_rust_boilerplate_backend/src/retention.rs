@@ -0,0 +1,234 @@
+//! Retention of auxiliary data. An hourly timer prunes records that nothing
+//! needs once they are old enough: settled and expired payment intents, the
+//! idempotency keys of intents no longer pending, delivered notifications,
+//! journal events, and settled holds with the incoming transfers they
+//! reserved. Admins set how many days each kind is kept. Users,
+//! transactions and the ledger are never pruned; old transactions go to the
+//! archive instead.
+//!
+//! Each run's counts, and the totals since deployment, are kept with the
+//! policy and reported by `get_retention_status` and `get_metrics`.
+
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::{
+    current_time, ensure_admin, events, holds, incoming, notifications, payment_intents, perf,
+    Memory, WalletError, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PRUNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_RETENTION_DAYS: u32 = 3650;
+
+/// Days each kind of record is kept, counted from when it was settled,
+/// delivered or recorded.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RetentionPolicy {
+    payment_intent_days: u32,
+    idempotency_key_days: u32,
+    notification_days: u32,
+    event_days: u32,
+    hold_days: u32,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            payment_intent_days: 90,
+            // Long enough for any checkout to retry, as with Stripe's keys
+            idempotency_key_days: 1,
+            notification_days: 90,
+            event_days: 30,
+            hold_days: 30,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct PrunedCounts {
+    payment_intents: u64,
+    idempotency_keys: u64,
+    notifications: u64,
+    events: u64,
+    holds: u64,
+    incoming_transfers: u64,
+}
+
+impl PrunedCounts {
+    fn add(&mut self, other: &PrunedCounts) {
+        self.payment_intents = self.payment_intents.saturating_add(other.payment_intents);
+        self.idempotency_keys = self.idempotency_keys.saturating_add(other.idempotency_keys);
+        self.notifications = self.notifications.saturating_add(other.notifications);
+        self.events = self.events.saturating_add(other.events);
+        self.holds = self.holds.saturating_add(other.holds);
+        self.incoming_transfers = self
+            .incoming_transfers
+            .saturating_add(other.incoming_transfers);
+    }
+}
+
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct RetentionState {
+    policy: RetentionPolicy,
+    last_run_at: Option<u64>,
+    last_run: PrunedCounts,
+    // Everything pruned since deployment
+    total: PrunedCounts,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct RetentionStatus {
+    policy: RetentionPolicy,
+    last_run_at: Option<u64>,
+    last_run: PrunedCounts,
+    total: PrunedCounts,
+}
+
+impl Storable for RetentionState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static RETENTION_STATE: RefCell<Cell<RetentionState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103))),
+            RetentionState::default(),
+        )
+        .expect("Cannot create the retention state cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell(
+        "retention.retention_state",
+        103,
+        &RETENTION_STATE,
+    )]
+}
+
+fn state() -> RetentionState {
+    RETENTION_STATE.with(|cell| cell.borrow().get().clone())
+}
+
+fn save_state(state: RetentionState) {
+    RETENTION_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("Cannot update the retention state");
+}
+
+/// Everything pruned since deployment, for the metrics.
+pub(crate) fn total_pruned() -> PrunedCounts {
+    state().total
+}
+
+fn cutoff(now: u64, days: u32) -> u64 {
+    now.saturating_sub(days as u64 * NANOS_PER_DAY)
+}
+
+fn prune_stale_data() -> PrunedCounts {
+    let mut state = state();
+    let now = current_time();
+    let policy = &state.policy;
+
+    let pruned_holds = holds::prune(cutoff(now, policy.hold_days));
+    let counts = PrunedCounts {
+        payment_intents: payment_intents::prune_intents(cutoff(now, policy.payment_intent_days)),
+        idempotency_keys: payment_intents::prune_idempotency_keys(cutoff(
+            now,
+            policy.idempotency_key_days,
+        )),
+        notifications: notifications::prune(cutoff(now, policy.notification_days)),
+        events: events::prune(cutoff(now, policy.event_days)),
+        holds: pruned_holds.len() as u64,
+        incoming_transfers: pruned_holds
+            .into_iter()
+            .filter(|hold_id| incoming::forget(*hold_id))
+            .count() as u64,
+    };
+
+    state.last_run_at = Some(now);
+    state.total.add(&counts);
+    state.last_run = counts.clone();
+    save_state(state);
+    counts
+}
+
+pub(crate) fn start_pruning_job() {
+    ic_cdk_timers::set_timer_interval(PRUNING_INTERVAL, || {
+        // The manifest of a read-only canister must not change under it
+        if ensure_writable().is_ok() {
+            prune_stale_data();
+        }
+    });
+}
+
+#[ic_cdk::query]
+fn get_retention_status() -> Result<RetentionStatus, WalletError> {
+    perf::instrument("get_retention_status", || {
+        ensure_not_restoring()?;
+        ensure_admin()?;
+
+        let state = state();
+        Ok(RetentionStatus {
+            policy: state.policy,
+            last_run_at: state.last_run_at,
+            last_run: state.last_run,
+            total: state.total,
+        })
+    })
+}
+
+/// Replaces the retention periods. Records already past a shortened period
+/// are removed on the next run.
+#[ic_cdk::update]
+fn set_retention_policy(policy: RetentionPolicy) -> Result<(), WalletError> {
+    perf::instrument("set_retention_policy", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let periods = [
+            ("payment_intent_days", policy.payment_intent_days),
+            ("idempotency_key_days", policy.idempotency_key_days),
+            ("notification_days", policy.notification_days),
+            ("event_days", policy.event_days),
+            ("hold_days", policy.hold_days),
+        ];
+        for (field, days) in periods {
+            if days == 0 || days > MAX_RETENTION_DAYS {
+                return Err(WalletError::invalid(
+                    field,
+                    &format!("must be between 1 and {}", MAX_RETENTION_DAYS),
+                ));
+            }
+        }
+        let mut state = state();
+        state.policy = policy;
+        save_state(state);
+        Ok(())
+    })
+}
+
+/// Prunes at once instead of waiting for the timer.
+#[ic_cdk::update]
+fn run_pruning_now() -> Result<PrunedCounts, WalletError> {
+    perf::instrument("run_pruning_now", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        Ok(prune_stale_data())
+    })
+}
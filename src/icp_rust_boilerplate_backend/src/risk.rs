@@ -0,0 +1,626 @@
+//! Fraud heuristics on outgoing transfers. `send_transaction` scores every
+//! transfer against a few signals: a recipient the sender never paid before,
+//! an amount far above the sender's average, a burst of high-value sends and
+//! a recently created recipient. The weights of the signals that fire add up
+//! to the score, which is stored with the transaction. A transfer scoring at
+//! or above the review threshold is not executed; its amount is reserved and
+//! it waits for a controller to approve or reject it.
+//!
+//! What each sender sent, whom they paid and what they have waiting for
+//! review is kept as transfers execute and reviews are decided, so scoring a
+//! transfer does not walk the whole history.
+
+use crate::backup::ensure_writable;
+use crate::events::{self, EventKind};
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, notify_admins, NotificationKind};
+use crate::perf;
+use crate::{
+    check_transfer_with, clear_map, current_time, ensure_admin, execute_transfer, incoming,
+    next_id, spenders, token, Memory, Transaction, TransactionPayload, User, WalletError,
+    MEMORY_MANAGER, TRANSACTION_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Earlier sends needed before the sender's average is meaningful
+const MIN_SENDS_FOR_AVERAGE: u64 = 3;
+// Latest sends kept per sender, which bounds the burst a config can ask for
+const MAX_RECENT_SENDS: usize = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RiskConfig {
+    enabled: bool,
+    // Transfers scoring at least this much are held for review
+    review_threshold: u32,
+    new_counterparty_weight: u32,
+    large_amount_weight: u32,
+    // An amount is large above this multiple of the sender's average send
+    large_amount_factor: u64,
+    rapid_sends_weight: u32,
+    // Sends of at least this amount count as high-value; `None` turns the
+    // signal off
+    high_value_amount: Option<u64>,
+    // High-value sends, this one included, that make a burst
+    rapid_sends_count: u32,
+    rapid_sends_window_seconds: u64,
+    new_recipient_weight: u32,
+    new_recipient_age_seconds: u64,
+}
+
+impl Default for RiskConfig {
+    fn default() -> Self {
+        RiskConfig {
+            enabled: true,
+            review_threshold: 70,
+            new_counterparty_weight: 25,
+            large_amount_weight: 35,
+            large_amount_factor: 10,
+            rapid_sends_weight: 30,
+            high_value_amount: None,
+            rapid_sends_count: 3,
+            rapid_sends_window_seconds: 10 * 60,
+            new_recipient_weight: 20,
+            new_recipient_age_seconds: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum RiskSignal {
+    NewCounterparty,
+    LargeAmount { average: u64 },
+    RapidHighValueSends { count: u32 },
+    NewRecipient { age_seconds: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RiskAssessment {
+    score: u32,
+    signals: Vec<RiskSignal>,
+    assessed_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ReviewStatus {
+    Pending,
+    Approved { tx_id: u64 },
+    // Approved and handed to the recipient, who accepts incoming transfers
+    AwaitingAcceptance { incoming_id: u64 },
+    Rejected { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TransferReview {
+    id: u64,
+    payload: TransactionPayload,
+    assessment: RiskAssessment,
+    status: ReviewStatus,
+    created_at: u64,
+    decided_at: Option<u64>,
+    // The spender whose allowance the transfer was charged to, given back
+    // if it is rejected
+    spender: Option<Principal>,
+}
+
+// What a sender sent so far
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct SenderStats {
+    sends: u64,
+    sent_total: u64,
+    // (created_at, amount) of the latest sends, oldest first
+    recent_sends: Vec<(u64, u64)>,
+}
+
+impl Storable for SenderStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RiskConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RiskAssessment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TransferReview {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static RISK_CONFIG: RefCell<Cell<RiskConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))),
+            RiskConfig::default(),
+        )
+        .expect("Cannot create the risk config cell")
+    );
+
+    // Assessment of every scored transaction, by transaction id
+    static RISK_SCORES: RefCell<StableBTreeMap<u64, RiskAssessment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+
+    static REVIEW_STORAGE: RefCell<StableBTreeMap<u64, TransferReview, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+
+    static SENDER_STATS: RefCell<StableBTreeMap<u64, SenderStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(116)))
+    ));
+
+    // When each sender first paid each recipient, by (sender, recipient)
+    static PAID_PAIRS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(117)))
+    ));
+
+    // Total of each sender's transfers waiting for review
+    static PENDING_REVIEW_TOTALS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(118)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::cell("risk.risk_config", 61, &RISK_CONFIG),
+        manifest::map("risk.risk_scores", 62, &RISK_SCORES),
+        manifest::map("risk.review_storage", 63, &REVIEW_STORAGE),
+        manifest::map("risk.sender_stats", 116, &SENDER_STATS),
+        manifest::map("risk.paid_pairs", 117, &PAID_PAIRS),
+        manifest::map("risk.pending_review_totals", 118, &PENDING_REVIEW_TOTALS),
+    ]
+}
+
+fn risk_config() -> RiskConfig {
+    RISK_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+fn get_review_record(review_id: u64) -> Result<TransferReview, WalletError> {
+    REVIEW_STORAGE
+        .with(|storage| storage.borrow().get(&review_id))
+        .ok_or(WalletError::not_found("transfer review", review_id))
+}
+
+fn save_review(review: &TransferReview) {
+    let previous =
+        REVIEW_STORAGE.with(|storage| storage.borrow_mut().insert(review.id, review.clone()));
+    let user_id = review.payload.from_user_id;
+    if previous.is_some_and(|previous| previous.status == ReviewStatus::Pending) {
+        PENDING_REVIEW_TOTALS.with(|totals| {
+            let mut totals = totals.borrow_mut();
+            let total = totals
+                .get(&user_id)
+                .unwrap_or(0)
+                .saturating_sub(review.payload.amount);
+            if total == 0 {
+                totals.remove(&user_id);
+            } else {
+                totals.insert(user_id, total);
+            }
+        });
+    }
+    if review.status == ReviewStatus::Pending {
+        add_pending(user_id, review.payload.amount);
+    }
+}
+
+fn add_pending(user_id: u64, amount: u64) {
+    PENDING_REVIEW_TOTALS.with(|totals| {
+        let mut totals = totals.borrow_mut();
+        let total = totals.get(&user_id).unwrap_or(0).saturating_add(amount);
+        totals.insert(user_id, total);
+    });
+}
+
+/// Total of `user_id`'s transfers waiting for review, which cannot be spent
+/// until they are decided.
+pub(crate) fn amount_in_review(user_id: u64) -> u64 {
+    PENDING_REVIEW_TOTALS.with(|totals| totals.borrow().get(&user_id).unwrap_or(0))
+}
+
+/// Adds an executed transaction to its sender's aggregates.
+pub(crate) fn record_send(transaction: &Transaction) {
+    let from_user_id = transaction.from_user_id.0;
+    SENDER_STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        let mut sender = stats.get(&from_user_id).unwrap_or_default();
+        sender.sends += 1;
+        sender.sent_total = sender.sent_total.saturating_add(transaction.amount);
+        sender
+            .recent_sends
+            .push((transaction.created_at, transaction.amount));
+        if sender.recent_sends.len() > MAX_RECENT_SENDS {
+            sender.recent_sends.remove(0);
+        }
+        stats.insert(from_user_id, sender);
+    });
+    PAID_PAIRS.with(|pairs| {
+        let mut pairs = pairs.borrow_mut();
+        let key = (from_user_id, transaction.to_user_id.0);
+        if !pairs.contains_key(&key) {
+            pairs.insert(key, transaction.created_at);
+        }
+    });
+}
+
+/// Recomputes the sender aggregates and review totals from the stored
+/// transactions and reviews, used after a restore replaced them.
+pub(crate) fn rebuild_aggregates() {
+    SENDER_STATS.with(|stats| clear_map(&mut stats.borrow_mut()));
+    PAID_PAIRS.with(|pairs| clear_map(&mut pairs.borrow_mut()));
+    PENDING_REVIEW_TOTALS.with(|totals| clear_map(&mut totals.borrow_mut()));
+    let transactions: Vec<Transaction> = TRANSACTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, transaction)| transaction)
+            .collect()
+    });
+    for transaction in &transactions {
+        record_send(transaction);
+    }
+    let pending: Vec<(u64, u64)> = REVIEW_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, review)| review)
+            .filter(|review| review.status == ReviewStatus::Pending)
+            .map(|review| (review.payload.from_user_id, review.payload.amount))
+            .collect()
+    });
+    for (user_id, amount) in pending {
+        add_pending(user_id, amount);
+    }
+}
+
+/// Fills the aggregates of a canister that scored transfers before they
+/// were kept, from the transactions and reviews still in storage.
+pub(crate) fn seed_aggregates_if_needed() {
+    if SENDER_STATS.with(|stats| stats.borrow().is_empty()) {
+        rebuild_aggregates();
+    }
+}
+
+/// Scores a transfer that already passed `check_transfer`.
+pub(crate) fn assess(payload: &TransactionPayload, recipient: &User) -> RiskAssessment {
+    let config = risk_config();
+    let now = current_time();
+    if !config.enabled {
+        return RiskAssessment {
+            score: 0,
+            signals: Vec::new(),
+            assessed_at: now,
+        };
+    }
+
+    let window_start = now.saturating_sub(
+        config
+            .rapid_sends_window_seconds
+            .saturating_mul(NANOS_PER_SECOND),
+    );
+    let paid_before = PAID_PAIRS.with(|pairs| {
+        pairs
+            .borrow()
+            .contains_key(&(payload.from_user_id, payload.to_user_id))
+    });
+    let sender = SENDER_STATS
+        .with(|stats| stats.borrow().get(&payload.from_user_id))
+        .unwrap_or_default();
+    let (sends, sent_total) = (sender.sends, sender.sent_total);
+    // This send counts towards its own burst
+    let high_value_sends = 1 + sender
+        .recent_sends
+        .iter()
+        .filter(|(created_at, amount)| {
+            config.high_value_amount.is_some_and(|high| *amount >= high)
+                && *created_at >= window_start
+        })
+        .count() as u32;
+
+    let mut signals = Vec::new();
+    if !paid_before {
+        signals.push(RiskSignal::NewCounterparty);
+    }
+    if sends >= MIN_SENDS_FOR_AVERAGE {
+        let average = sent_total / sends;
+        if payload.amount > average.saturating_mul(config.large_amount_factor) {
+            signals.push(RiskSignal::LargeAmount { average });
+        }
+    }
+    if config
+        .high_value_amount
+        .is_some_and(|high| payload.amount >= high)
+        && high_value_sends >= config.rapid_sends_count
+    {
+        signals.push(RiskSignal::RapidHighValueSends {
+            count: high_value_sends,
+        });
+    }
+    let age_seconds = now.saturating_sub(recipient.created_at) / NANOS_PER_SECOND;
+    if age_seconds < config.new_recipient_age_seconds {
+        signals.push(RiskSignal::NewRecipient { age_seconds });
+    }
+
+    let score = signals
+        .iter()
+        .map(|signal| match signal {
+            RiskSignal::NewCounterparty => config.new_counterparty_weight,
+            RiskSignal::LargeAmount { .. } => config.large_amount_weight,
+            RiskSignal::RapidHighValueSends { .. } => config.rapid_sends_weight,
+            RiskSignal::NewRecipient { .. } => config.new_recipient_weight,
+        })
+        .fold(0u32, u32::saturating_add);
+    RiskAssessment {
+        score,
+        signals,
+        assessed_at: now,
+    }
+}
+
+pub(crate) fn needs_review(assessment: &RiskAssessment) -> bool {
+    let config = risk_config();
+    config.enabled && assessment.score >= config.review_threshold
+}
+
+pub(crate) fn record_score(tx_id: u64, assessment: RiskAssessment) {
+    RISK_SCORES.with(|scores| scores.borrow_mut().insert(tx_id, assessment));
+}
+
+/// Queues a transfer for review and returns the error `send_transaction`
+/// reports to the caller.
+pub(crate) fn hold_for_review(
+    payload: TransactionPayload,
+    assessment: RiskAssessment,
+    spender: Option<Principal>,
+) -> WalletError {
+    let review = TransferReview {
+        id: next_id(),
+        payload,
+        assessment,
+        status: ReviewStatus::Pending,
+        created_at: current_time(),
+        decided_at: None,
+        spender,
+    };
+    save_review(&review);
+    notify_admins(format!(
+        "Transfer review {}: {} from user {} to user {} scored {}",
+        review.id,
+        token::format_amount(review.payload.amount),
+        review.payload.from_user_id,
+        review.payload.to_user_id,
+        review.assessment.score
+    ));
+    notify(
+        review.payload.from_user_id,
+        NotificationKind::Security,
+        format!(
+            "Your transfer of {} to user {} is being reviewed",
+            token::format_amount(review.payload.amount),
+            review.payload.to_user_id
+        ),
+    );
+    WalletError::UnderReview {
+        review_id: review.id,
+    }
+}
+
+fn pending_review(review_id: u64) -> Result<TransferReview, WalletError> {
+    let review = get_review_record(review_id)?;
+    if review.status != ReviewStatus::Pending {
+        return Err(WalletError::InvalidState {
+            reason: format!("Transfer review {} was already decided", review_id),
+        });
+    }
+    Ok(review)
+}
+
+#[ic_cdk::query]
+fn get_risk_config() -> Result<RiskConfig, WalletError> {
+    perf::instrument("get_risk_config", || {
+        ensure_admin()?;
+
+        Ok(risk_config())
+    })
+}
+
+#[ic_cdk::update]
+fn set_risk_config(config: RiskConfig) -> Result<(), WalletError> {
+    perf::instrument("set_risk_config", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if config.review_threshold == 0 {
+            return Err(WalletError::invalid(
+                "review_threshold",
+                "must be greater than 0",
+            ));
+        }
+        if config.large_amount_factor == 0 {
+            return Err(WalletError::invalid(
+                "large_amount_factor",
+                "must be greater than 0",
+            ));
+        }
+        if config.high_value_amount == Some(0) {
+            return Err(WalletError::invalid(
+                "high_value_amount",
+                "must be greater than 0",
+            ));
+        }
+        if config.rapid_sends_count < 2 || config.rapid_sends_window_seconds == 0 {
+            return Err(WalletError::invalid(
+                "rapid_sends_count",
+                "a burst needs at least 2 sends within a non-empty window",
+            ));
+        }
+        if config.rapid_sends_count as usize > MAX_RECENT_SENDS + 1 {
+            return Err(WalletError::invalid(
+                "rapid_sends_count",
+                &format!("must be at most {}", MAX_RECENT_SENDS + 1),
+            ));
+        }
+        RISK_CONFIG
+            .with(|cell| cell.borrow_mut().set(config))
+            .map_err(|_| WalletError::Internal {
+                reason: "cannot update the risk config".to_string(),
+            })?;
+        Ok(())
+    })
+}
+
+/// The risk assessment of an executed transfer. Transfers made before
+/// scoring existed have none.
+#[ic_cdk::query]
+fn get_transaction_risk(tx_id: u64) -> Result<RiskAssessment, WalletError> {
+    perf::instrument("get_transaction_risk", || {
+        ensure_admin()?;
+
+        RISK_SCORES
+            .with(|scores| scores.borrow().get(&tx_id))
+            .ok_or(WalletError::not_found("risk assessment", tx_id))
+    })
+}
+
+#[ic_cdk::query]
+fn list_transfer_reviews(pending_only: bool) -> Result<Vec<TransferReview>, WalletError> {
+    perf::instrument("list_transfer_reviews", || {
+        ensure_admin()?;
+
+        Ok(REVIEW_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, review)| review)
+                .filter(|review| !pending_only || review.status == ReviewStatus::Pending)
+                .collect()
+        }))
+    })
+}
+
+/// Executes a held transfer, after running every transfer check again:
+/// the reserved amount is only protected from other spending, not from
+/// refunds or disputes taking funds back, and limits, blocks and lockdowns
+/// may have changed while it waited. A transfer to a recipient who accepts
+/// incoming transfers then waits for their acceptance.
+#[ic_cdk::update]
+fn approve_transfer_review(review_id: u64) -> Result<Transaction, WalletError> {
+    perf::instrument("approve_transfer_review", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let mut review = pending_review(review_id)?;
+        let payload = review.payload.clone();
+        // The review's own amount must stop counting as reserved while the
+        // transfer is checked
+        let pending = review.clone();
+        review.status = ReviewStatus::Approved { tx_id: 0 };
+        save_review(&review);
+        // The admin's approval stands in for the owner's authorization
+        if let Err(error) = check_transfer_with(&payload, || Ok(())) {
+            save_review(&pending);
+            return Err(error);
+        }
+        review.decided_at = Some(current_time());
+
+        if incoming::requires_acceptance(payload.to_user_id) {
+            let error = incoming::await_acceptance(payload, review.spender);
+            if let WalletError::AwaitingAcceptance { incoming_id } = error {
+                review.status = ReviewStatus::AwaitingAcceptance { incoming_id };
+            }
+            save_review(&review);
+            return Err(error);
+        }
+
+        let transaction = execute_transfer(payload);
+        record_score(transaction.id.0, review.assessment.clone());
+        review.status = ReviewStatus::Approved {
+            tx_id: transaction.id.0,
+        };
+        save_review(&review);
+        Ok(transaction)
+    })
+}
+
+#[ic_cdk::update]
+fn reject_transfer_review(review_id: u64, reason: String) -> Result<TransferReview, WalletError> {
+    perf::instrument("reject_transfer_review", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        hardening::check_args(|fields| fields.required_text("reason", &reason, TextKind::Reason))?;
+        let mut review = pending_review(review_id)?;
+        let reason = reason.trim().to_string();
+        review.status = ReviewStatus::Rejected {
+            reason: reason.clone(),
+        };
+        review.decided_at = Some(current_time());
+        save_review(&review);
+        if let Some(spender) = review.spender {
+            spenders::restore_allowance(
+                review.payload.from_user_id,
+                spender,
+                review.payload.amount,
+                review.created_at,
+            );
+        }
+        events::record(EventKind::TransferReviewRejected {
+            review_id,
+            user_id: review.payload.from_user_id,
+        });
+        notify(
+            review.payload.from_user_id,
+            NotificationKind::Security,
+            format!(
+                "Your transfer of {} to user {} was rejected: {}",
+                token::format_amount(review.payload.amount),
+                review.payload.to_user_id,
+                reason
+            ),
+        );
+        Ok(review)
+    })
+}
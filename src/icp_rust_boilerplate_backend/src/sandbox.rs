@@ -0,0 +1,659 @@
+//! Developer sandbox. Principals an admin designates as testers get accounts
+//! of their own in stable maps kept apart from the production ones, fund
+//! them from a faucet with `mint_test_funds`, and move test funds between
+//! them or request them from each other, so integrators can exercise
+//! transfers, payment requests and webhooks without touching real balances.
+//! Sandbox transfers follow the production amount and memo rules but never
+//! reach the ledger, the event log or the supply counters; `wipe_sandbox`
+//! clears the sandbox again.
+//!
+//! A tester may register one webhook, notified one-way with every transfer
+//! into their accounts. Unlike merchant webhook calls, these are not logged
+//! as deliveries.
+
+use crate::auth::{self, StorablePrincipal};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::payment_intents::MerchantWebhook;
+use crate::{
+    clear_map, current_time, dust, ensure_admin, perf, token, IdCell, Memory, WalletError,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_ACCOUNTS_PER_TESTER: usize = 20;
+// Most the faucet mints in one call, and the most an account can hold
+const MAX_MINT_AMOUNT: u64 = 1_000_000_000_000;
+const MAX_SANDBOX_BALANCE: u64 = 1_000_000_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SandboxAccount {
+    id: u64,
+    owner: Principal,
+    label: String,
+    balance: u64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SandboxTransfer {
+    id: u64,
+    // `None` for funds minted by the faucet
+    from_account_id: Option<u64>,
+    to_account_id: u64,
+    amount: u64,
+    memo: Option<String>,
+    created_at: u64,
+    webhook_notified: bool,
+    // The payment request the transfer paid
+    request_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum SandboxRequestStatus {
+    Open,
+    Paid { transfer_id: u64 },
+    Canceled,
+}
+
+/// A request for test funds into one of a tester's accounts, which any
+/// tester may pay from one of theirs.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SandboxPaymentRequest {
+    id: u64,
+    to_account_id: u64,
+    amount: u64,
+    memo: Option<String>,
+    status: SandboxRequestStatus,
+    created_at: u64,
+    updated_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SandboxTransferPayload {
+    from_account_id: u64,
+    to_account_id: u64,
+    amount: u64,
+    memo: Option<String>,
+}
+
+impl Harden for SandboxTransferPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.optional_text("memo", &self.memo, TextKind::Memo);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SandboxRequestPayload {
+    to_account_id: u64,
+    amount: u64,
+    memo: Option<String>,
+}
+
+impl Harden for SandboxRequestPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.optional_text("memo", &self.memo, TextKind::Memo);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SandboxWipeSummary {
+    accounts_removed: u64,
+    transfers_removed: u64,
+    requests_removed: u64,
+}
+
+impl Storable for SandboxAccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for SandboxTransfer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for SandboxPaymentRequest {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Testers to when they were designated
+    static SANDBOX_TESTERS: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98)))
+    ));
+
+    static SANDBOX_ACCOUNTS: RefCell<StableBTreeMap<u64, SandboxAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99)))
+    ));
+
+    static SANDBOX_TRANSFERS: RefCell<StableBTreeMap<u64, SandboxTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100)))
+    ));
+
+    static SANDBOX_WEBHOOKS: RefCell<StableBTreeMap<StorablePrincipal, MerchantWebhook, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101)))
+    ));
+
+    // Sandbox ids never use up production ones
+    static SANDBOX_ID: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102))), 1)
+            .expect("Cannot create the sandbox id counter")
+    );
+
+    static SANDBOX_REQUESTS: RefCell<StableBTreeMap<u64, SandboxPaymentRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("sandbox.sandbox_testers", 98, &SANDBOX_TESTERS),
+        manifest::map("sandbox.sandbox_accounts", 99, &SANDBOX_ACCOUNTS),
+        manifest::map("sandbox.sandbox_transfers", 100, &SANDBOX_TRANSFERS),
+        manifest::map("sandbox.sandbox_webhooks", 101, &SANDBOX_WEBHOOKS),
+        manifest::cell("sandbox.sandbox_id", 102, &SANDBOX_ID),
+        manifest::map("sandbox.sandbox_requests", 114, &SANDBOX_REQUESTS),
+    ]
+}
+
+fn next_sandbox_id() -> u64 {
+    SANDBOX_ID.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let id = *cell.get();
+        cell.set(id + 1)
+            .expect("Cannot increment the sandbox id counter");
+        id
+    })
+}
+
+fn ensure_tester() -> Result<Principal, WalletError> {
+    let caller = auth::caller();
+    if !SANDBOX_TESTERS.with(|testers| testers.borrow().contains_key(&StorablePrincipal(caller))) {
+        return Err(WalletError::Unauthorized {
+            reason: "caller is not a sandbox tester".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+fn get_account_record(account_id: u64) -> Result<SandboxAccount, WalletError> {
+    SANDBOX_ACCOUNTS
+        .with(|storage| storage.borrow().get(&account_id))
+        .ok_or(WalletError::not_found("sandbox account", account_id))
+}
+
+fn own_account(account_id: u64, tester: Principal) -> Result<SandboxAccount, WalletError> {
+    let account = get_account_record(account_id)?;
+    if account.owner != tester {
+        return Err(WalletError::Unauthorized {
+            reason: format!("caller does not own sandbox account {}", account_id),
+        });
+    }
+    Ok(account)
+}
+
+fn save_account(account: &SandboxAccount) {
+    SANDBOX_ACCOUNTS.with(|storage| storage.borrow_mut().insert(account.id, account.clone()));
+}
+
+fn get_request_record(request_id: u64) -> Result<SandboxPaymentRequest, WalletError> {
+    SANDBOX_REQUESTS
+        .with(|storage| storage.borrow().get(&request_id))
+        .ok_or(WalletError::not_found(
+            "sandbox payment request",
+            request_id,
+        ))
+}
+
+fn save_request(request: &SandboxPaymentRequest) {
+    SANDBOX_REQUESTS.with(|storage| storage.borrow_mut().insert(request.id, request.clone()));
+}
+
+fn ensure_open(request: &SandboxPaymentRequest) -> Result<(), WalletError> {
+    if request.status != SandboxRequestStatus::Open {
+        return Err(WalletError::InvalidState {
+            reason: format!("Sandbox payment request {} is not open", request.id),
+        });
+    }
+    Ok(())
+}
+
+// The production rules for a transfer amount
+fn validate_amount(amount: u64) -> Result<(), WalletError> {
+    if amount == 0 {
+        return Err(WalletError::invalid("amount", "must be greater than 0"));
+    }
+    token::validate_amount("amount", amount)?;
+    dust::ensure_above_minimum("amount", amount)
+}
+
+// Records a transfer into `to` and notifies the webhook of its owner
+fn record_transfer(
+    from_account_id: Option<u64>,
+    to: &SandboxAccount,
+    amount: u64,
+    memo: Option<String>,
+    request_id: Option<u64>,
+) -> SandboxTransfer {
+    let mut transfer = SandboxTransfer {
+        id: next_sandbox_id(),
+        from_account_id,
+        to_account_id: to.id,
+        amount,
+        memo,
+        created_at: current_time(),
+        webhook_notified: false,
+        request_id,
+    };
+    if let Some(webhook) =
+        SANDBOX_WEBHOOKS.with(|storage| storage.borrow().get(&StorablePrincipal(to.owner)))
+    {
+        transfer.webhook_notified = webhook.deliver((transfer.clone(),));
+    }
+    SANDBOX_TRANSFERS.with(|storage| storage.borrow_mut().insert(transfer.id, transfer.clone()));
+    transfer
+}
+
+// Moves test funds from one of the tester's accounts to any other account
+fn move_funds(
+    tester: Principal,
+    from_account_id: u64,
+    to_account_id: u64,
+    amount: u64,
+    memo: Option<String>,
+    request_id: Option<u64>,
+) -> Result<SandboxTransfer, WalletError> {
+    if from_account_id == to_account_id {
+        return Err(WalletError::invalid(
+            "to_account_id",
+            "sender and recipient must be different accounts",
+        ));
+    }
+    let mut from = own_account(from_account_id, tester)?;
+    let mut to = get_account_record(to_account_id)?;
+    if from.balance < amount {
+        return Err(WalletError::InsufficientBalance {
+            available: from.balance,
+            required: amount,
+        });
+    }
+    to.balance = to
+        .balance
+        .checked_add(amount)
+        .ok_or(WalletError::Overflow {
+            field: "balance".to_string(),
+        })?;
+    from.balance -= amount;
+    save_account(&from);
+    save_account(&to);
+    Ok(record_transfer(
+        Some(from.id),
+        &to,
+        amount,
+        memo,
+        request_id,
+    ))
+}
+
+/// Designates `principal` as a sandbox tester, or withdraws it with
+/// `enabled` false. The accounts of a former tester stay until the next
+/// wipe.
+#[ic_cdk::update]
+fn set_sandbox_tester(principal: Principal, enabled: bool) -> Result<(), WalletError> {
+    perf::instrument("set_sandbox_tester", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if principal == Principal::anonymous() {
+            return Err(WalletError::invalid(
+                "principal",
+                "must not be the anonymous principal",
+            ));
+        }
+        SANDBOX_TESTERS.with(|testers| {
+            let mut testers = testers.borrow_mut();
+            if enabled {
+                testers.insert(StorablePrincipal(principal), current_time());
+            } else {
+                testers.remove(&StorablePrincipal(principal));
+            }
+        });
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn list_sandbox_testers() -> Result<Vec<Principal>, WalletError> {
+    perf::instrument("list_sandbox_testers", || {
+        ensure_admin()?;
+
+        Ok(SANDBOX_TESTERS.with(|testers| {
+            testers
+                .borrow()
+                .iter()
+                .map(|(principal, _)| principal.0)
+                .collect()
+        }))
+    })
+}
+
+/// Removes every sandbox account, transfer and payment request. Testers and
+/// their webhooks stay.
+#[ic_cdk::update]
+fn wipe_sandbox() -> Result<SandboxWipeSummary, WalletError> {
+    perf::instrument("wipe_sandbox", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let accounts_removed = SANDBOX_ACCOUNTS.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let removed = storage.len();
+            clear_map(&mut storage);
+            removed
+        });
+        let transfers_removed = SANDBOX_TRANSFERS.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let removed = storage.len();
+            clear_map(&mut storage);
+            removed
+        });
+        let requests_removed = SANDBOX_REQUESTS.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let removed = storage.len();
+            clear_map(&mut storage);
+            removed
+        });
+        Ok(SandboxWipeSummary {
+            accounts_removed,
+            transfers_removed,
+            requests_removed,
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn sandbox_create_account(label: String) -> Result<SandboxAccount, WalletError> {
+    perf::instrument("sandbox_create_account", || {
+        ensure_writable()?;
+        let tester = ensure_tester()?;
+
+        hardening::check_args(|fields| fields.required_text("label", &label, TextKind::Alias))?;
+        let label = label.trim().to_string();
+        let owned = SANDBOX_ACCOUNTS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, account)| account.owner == tester)
+                .count()
+        });
+        if owned >= MAX_ACCOUNTS_PER_TESTER {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "A tester can have at most {} sandbox accounts",
+                    MAX_ACCOUNTS_PER_TESTER
+                ),
+            });
+        }
+
+        let account = SandboxAccount {
+            id: next_sandbox_id(),
+            owner: tester,
+            label,
+            balance: 0,
+            created_at: current_time(),
+        };
+        save_account(&account);
+        Ok(account)
+    })
+}
+
+/// Credits one of the caller's sandbox accounts with test funds.
+#[ic_cdk::update]
+fn mint_test_funds(account_id: u64, amount: u64) -> Result<SandboxAccount, WalletError> {
+    perf::instrument("mint_test_funds", || {
+        ensure_writable()?;
+        let tester = ensure_tester()?;
+
+        let mut account = own_account(account_id, tester)?;
+        if amount == 0 || amount > MAX_MINT_AMOUNT {
+            return Err(WalletError::invalid(
+                "amount",
+                &format!("must be between 1 and {}", MAX_MINT_AMOUNT),
+            ));
+        }
+        token::validate_amount("amount", amount)?;
+        account.balance = account
+            .balance
+            .checked_add(amount)
+            .filter(|balance| *balance <= MAX_SANDBOX_BALANCE)
+            .ok_or(WalletError::Overflow {
+                field: "balance".to_string(),
+            })?;
+        save_account(&account);
+        record_transfer(None, &account, amount, None, None);
+        Ok(account)
+    })
+}
+
+/// Moves test funds from one of the caller's sandbox accounts to any other
+/// sandbox account, under the production amount and memo rules.
+#[ic_cdk::update]
+fn sandbox_send(payload: SandboxTransferPayload) -> Result<SandboxTransfer, WalletError> {
+    perf::instrument("sandbox_send", || {
+        ensure_writable()?;
+        let tester = ensure_tester()?;
+
+        hardening::check(&payload)?;
+        validate_amount(payload.amount)?;
+        move_funds(
+            tester,
+            payload.from_account_id,
+            payload.to_account_id,
+            payload.amount,
+            payload.memo,
+            None,
+        )
+    })
+}
+
+/// Requests test funds into one of the caller's sandbox accounts. Any
+/// tester can pay the request with `sandbox_pay_request`.
+#[ic_cdk::update]
+fn sandbox_create_request(
+    payload: SandboxRequestPayload,
+) -> Result<SandboxPaymentRequest, WalletError> {
+    perf::instrument("sandbox_create_request", || {
+        ensure_writable()?;
+        let tester = ensure_tester()?;
+
+        hardening::check(&payload)?;
+        validate_amount(payload.amount)?;
+        own_account(payload.to_account_id, tester)?;
+        let now = current_time();
+        let request = SandboxPaymentRequest {
+            id: next_sandbox_id(),
+            to_account_id: payload.to_account_id,
+            amount: payload.amount,
+            memo: payload.memo,
+            status: SandboxRequestStatus::Open,
+            created_at: now,
+            updated_at: now,
+        };
+        save_request(&request);
+        Ok(request)
+    })
+}
+
+/// Pays an open request from one of the caller's sandbox accounts. The
+/// transfer notifies the requester's webhook like any other.
+#[ic_cdk::update]
+fn sandbox_pay_request(
+    request_id: u64,
+    from_account_id: u64,
+) -> Result<SandboxPaymentRequest, WalletError> {
+    perf::instrument("sandbox_pay_request", || {
+        ensure_writable()?;
+        let tester = ensure_tester()?;
+
+        let mut request = get_request_record(request_id)?;
+        ensure_open(&request)?;
+        let transfer = move_funds(
+            tester,
+            from_account_id,
+            request.to_account_id,
+            request.amount,
+            request.memo.clone(),
+            Some(request.id),
+        )?;
+        request.status = SandboxRequestStatus::Paid {
+            transfer_id: transfer.id,
+        };
+        request.updated_at = transfer.created_at;
+        save_request(&request);
+        Ok(request)
+    })
+}
+
+/// Cancels an open request into one of the caller's sandbox accounts.
+#[ic_cdk::update]
+fn sandbox_cancel_request(request_id: u64) -> Result<SandboxPaymentRequest, WalletError> {
+    perf::instrument("sandbox_cancel_request", || {
+        ensure_writable()?;
+        let tester = ensure_tester()?;
+
+        let mut request = get_request_record(request_id)?;
+        own_account(request.to_account_id, tester)?;
+        ensure_open(&request)?;
+        request.status = SandboxRequestStatus::Canceled;
+        request.updated_at = current_time();
+        save_request(&request);
+        Ok(request)
+    })
+}
+
+/// Any tester may look a request up, as a payer does before paying it.
+#[ic_cdk::query]
+fn sandbox_get_request(request_id: u64) -> Result<SandboxPaymentRequest, WalletError> {
+    perf::instrument("sandbox_get_request", || {
+        ensure_not_restoring()?;
+        ensure_tester()?;
+
+        get_request_record(request_id)
+    })
+}
+
+/// The requests into the caller's sandbox accounts, newest first.
+#[ic_cdk::query]
+fn sandbox_list_requests() -> Result<Vec<SandboxPaymentRequest>, WalletError> {
+    perf::instrument("sandbox_list_requests", || {
+        ensure_not_restoring()?;
+        let tester = ensure_tester()?;
+
+        let mut requests: Vec<SandboxPaymentRequest> = SANDBOX_REQUESTS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, request)| request)
+                .filter(|request| {
+                    get_account_record(request.to_account_id)
+                        .is_ok_and(|account| account.owner == tester)
+                })
+                .collect()
+        });
+        requests.reverse();
+        Ok(requests)
+    })
+}
+
+#[ic_cdk::query]
+fn sandbox_list_accounts() -> Result<Vec<SandboxAccount>, WalletError> {
+    perf::instrument("sandbox_list_accounts", || {
+        ensure_not_restoring()?;
+        let tester = ensure_tester()?;
+
+        Ok(SANDBOX_ACCOUNTS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, account)| account)
+                .filter(|account| account.owner == tester)
+                .collect()
+        }))
+    })
+}
+
+/// The transfers into and out of one of the caller's sandbox accounts,
+/// newest first.
+#[ic_cdk::query]
+fn sandbox_get_history(account_id: u64) -> Result<Vec<SandboxTransfer>, WalletError> {
+    perf::instrument("sandbox_get_history", || {
+        ensure_not_restoring()?;
+        let tester = ensure_tester()?;
+        own_account(account_id, tester)?;
+
+        let mut transfers: Vec<SandboxTransfer> = SANDBOX_TRANSFERS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, transfer)| transfer)
+                .filter(|transfer| {
+                    transfer.to_account_id == account_id
+                        || transfer.from_account_id == Some(account_id)
+                })
+                .collect()
+        });
+        transfers.reverse();
+        Ok(transfers)
+    })
+}
+
+/// Registers the webhook notified with every transfer into the caller's
+/// sandbox accounts, or removes it with `None`.
+#[ic_cdk::update]
+fn set_sandbox_webhook(webhook: Option<MerchantWebhook>) -> Result<(), WalletError> {
+    perf::instrument("set_sandbox_webhook", || {
+        ensure_writable()?;
+        let tester = StorablePrincipal(ensure_tester()?);
+
+        let Some(webhook) = webhook else {
+            SANDBOX_WEBHOOKS.with(|storage| storage.borrow_mut().remove(&tester));
+            return Ok(());
+        };
+        webhook.validate()?;
+        SANDBOX_WEBHOOKS.with(|storage| storage.borrow_mut().insert(tester, webhook));
+        Ok(())
+    })
+}
@@ -0,0 +1,267 @@
+use crate::auth::{self, caller_user_id};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{current_time, devices, verification, Memory, WalletError, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Grants are keyed by account first so one account's spenders are adjacent
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct SpenderKey {
+    user_id: u64,
+    spender: Principal,
+}
+
+impl Storable for SpenderKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.user_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.spender.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (user_id, spender) = bytes.split_at(8);
+        SpenderKey {
+            user_id: u64::from_be_bytes(user_id.try_into().unwrap()),
+            spender: Principal::from_slice(spender),
+        }
+    }
+
+    // An 8 byte user id followed by a principal of at most 29 bytes
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 37,
+        is_fixed_size: false,
+    };
+}
+
+/// Permission for `spender` to send up to `daily_cap` per day out of an
+/// account until `expires_at`.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SpenderGrant {
+    spender: Principal,
+    daily_cap: u64,
+    expires_at: u64,
+    spent_today: u64,
+    // Day (since the epoch) that `spent_today` refers to
+    day: u64,
+    created_at: u64,
+}
+
+impl SpenderGrant {
+    fn spent_on(&self, day: u64) -> u64 {
+        if self.day == day {
+            self.spent_today
+        } else {
+            0
+        }
+    }
+}
+
+impl Storable for SpenderGrant {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static SPENDER_STORAGE: RefCell<StableBTreeMap<SpenderKey, SpenderGrant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map(
+        "spenders.spender_storage",
+        16,
+        &SPENDER_STORAGE,
+    )]
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SpenderPayload {
+    spender: Principal,
+    daily_cap: u64,
+    expires_at: u64,
+}
+
+fn today() -> u64 {
+    current_time() / NANOS_PER_DAY
+}
+
+/// Allows the debit if the caller owns `user_id`, or is a spender of it with
+/// enough of today's allowance left.
+pub(crate) fn ensure_can_spend(user_id: u64, amount: u64) -> Result<(), WalletError> {
+    let caller = auth::caller();
+    if auth::owner_of(user_id) == Some(caller) {
+        return Ok(());
+    }
+
+    let key = SpenderKey {
+        user_id,
+        spender: caller,
+    };
+    let grant = SPENDER_STORAGE
+        .with(|storage| storage.borrow().get(&key))
+        .filter(|grant| current_time() < grant.expires_at)
+        .ok_or(WalletError::Unauthorized {
+            reason: format!("caller does not own or spend for user {}", user_id),
+        })?;
+
+    let spent = grant.spent_on(today());
+    if spent.saturating_add(amount) > grant.daily_cap {
+        return Err(WalletError::InvalidState {
+            reason: format!(
+                "Daily spending cap reached: {} of {} left today",
+                grant.daily_cap - spent,
+                grant.daily_cap
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Whether `principal` holds an unexpired spending grant on `user_id`.
+pub(crate) fn is_spender(user_id: u64, principal: Principal) -> bool {
+    let key = SpenderKey {
+        user_id,
+        spender: principal,
+    };
+    SPENDER_STORAGE
+        .with(|storage| storage.borrow().get(&key))
+        .is_some_and(|grant| current_time() < grant.expires_at)
+}
+
+pub(crate) fn remove_grant(user_id: u64, spender: Principal) -> Option<SpenderGrant> {
+    SPENDER_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .remove(&SpenderKey { user_id, spender })
+    })
+}
+
+/// Records a debit made by a spender against its allowance and returns the
+/// spender charged. Debits made by the owner are not tracked.
+pub(crate) fn consume_allowance(user_id: u64, amount: u64) -> Option<Principal> {
+    let key = SpenderKey {
+        user_id,
+        spender: auth::caller(),
+    };
+    SPENDER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut grant = storage.get(&key)?;
+        let day = today();
+        grant.spent_today = grant.spent_on(day).saturating_add(amount);
+        grant.day = day;
+        storage.insert(key, grant);
+        Some(key.spender)
+    })
+}
+
+/// Gives back what `consume_allowance` charged `spender` at `charged_at` for
+/// a transfer that never executed. Allowance charged on an earlier day has
+/// been reset already.
+pub(crate) fn restore_allowance(user_id: u64, spender: Principal, amount: u64, charged_at: u64) {
+    let key = SpenderKey { user_id, spender };
+    SPENDER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut grant) = storage.get(&key) {
+            if grant.day == charged_at / NANOS_PER_DAY {
+                grant.spent_today = grant.spent_today.saturating_sub(amount);
+                storage.insert(key, grant);
+            }
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn authorize_spender(payload: SpenderPayload) -> Result<SpenderGrant, WalletError> {
+    perf::instrument("authorize_spender", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        verification::ensure_verified(user_id)?;
+        if payload.spender == Principal::anonymous() || payload.spender == auth::caller() {
+            return Err(WalletError::invalid(
+                "spender",
+                "must not be the anonymous principal or the owner",
+            ));
+        }
+        if payload.daily_cap == 0 {
+            return Err(WalletError::invalid("daily_cap", "must be greater than 0"));
+        }
+        let now = current_time();
+        if payload.expires_at <= now {
+            return Err(WalletError::invalid("expires_at", "must be in the future"));
+        }
+
+        let key = SpenderKey {
+            user_id,
+            spender: payload.spender,
+        };
+        // Re-authorizing keeps what was already spent today
+        let existing = SPENDER_STORAGE.with(|storage| storage.borrow().get(&key));
+        let grant = SpenderGrant {
+            spender: payload.spender,
+            daily_cap: payload.daily_cap,
+            expires_at: payload.expires_at,
+            spent_today: existing
+                .as_ref()
+                .map_or(0, |grant| grant.spent_on(now / NANOS_PER_DAY)),
+            day: now / NANOS_PER_DAY,
+            created_at: existing.map_or(now, |grant| grant.created_at),
+        };
+        SPENDER_STORAGE.with(|storage| storage.borrow_mut().insert(key, grant.clone()));
+        devices::record_activity(user_id);
+        devices::reinstate(user_id, payload.spender);
+        Ok(grant)
+    })
+}
+
+#[ic_cdk::update]
+fn revoke_spender(spender: Principal) -> Result<(), WalletError> {
+    perf::instrument("revoke_spender", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        remove_grant(user_id, spender)
+            .map(|_| ())
+            .ok_or(WalletError::NotFoundByKey {
+                entity: "spender".to_string(),
+                key: spender.to_text(),
+            })
+    })
+}
+
+#[ic_cdk::query]
+fn list_spenders() -> Result<Vec<SpenderGrant>, WalletError> {
+    perf::instrument("list_spenders", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        let start = SpenderKey {
+            user_id,
+            spender: Principal::from_slice(&[]),
+        };
+        Ok(SPENDER_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .range(start..)
+                .take_while(|(key, _)| key.user_id == user_id)
+                .map(|(_, grant)| grant)
+                .collect()
+        }))
+    })
+}
@@ -0,0 +1,355 @@
+//! Monthly statements. An hourly timer closes every calendar month once it
+//! is over and stores an immutable `Statement` per user: the transfers in
+//! and out, fees, points earned and the balance at the close. Controllers
+//! can also set a retention window, after which local transactions from
+//! closed months are pruned; their statements remain.
+
+use crate::auth::ensure_owner;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::budgets::{month_of, month_range};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::perf;
+use crate::{
+    current_time, earning, ensure_admin, receipts, Memory, Transaction, WalletError,
+    MEMORY_MANAGER, TRANSACTION_STORAGE, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Well past the 30 day dispute window, so disputed transactions are kept
+const MIN_RETENTION_DAYS: u64 = 90;
+// Transactions pruned by one run of the job
+const PRUNE_BATCH_SIZE: usize = 1_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Statement {
+    user_id: u64,
+    // "YYYY-MM"
+    month: String,
+    period_start: u64,
+    period_end: u64,
+    // Closing balance of the previous statement; absent on the first one
+    opening_balance: Option<u64>,
+    closing_balance: u64,
+    total_in: u64,
+    total_out: u64,
+    transaction_count: u64,
+    fees_paid: u64,
+    points_earned: u64,
+    // Deposits, promo bonuses and other changes that are not transfers
+    other_changes: Option<i64>,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct StatementConfig {
+    // `None` keeps every transaction
+    retention_days: Option<u64>,
+    // Last month statements were generated for
+    closed_through: Option<String>,
+    pruned_transactions: u64,
+}
+
+impl Storable for Statement {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StatementConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Keyed by (user id, period start), so a user's statements are adjacent
+    // and in chronological order
+    static STATEMENTS: RefCell<StableBTreeMap<(u64, u64), Statement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    static STATEMENT_CONFIG: RefCell<Cell<StatementConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59))),
+            StatementConfig::default(),
+        )
+        .expect("Cannot create the statement config cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("statements.statements", 58, &STATEMENTS),
+        manifest::cell("statements.statement_config", 59, &STATEMENT_CONFIG),
+    ]
+}
+
+#[derive(Default)]
+struct MonthActivity {
+    total_in: u64,
+    total_out: u64,
+    transaction_count: u64,
+    outgoing_count: u64,
+    points_earned: u64,
+    // Net transfers after the month ended, to rewind today's balance
+    net_after: i128,
+}
+
+fn statement_config() -> StatementConfig {
+    STATEMENT_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+fn set_statement_config(config: StatementConfig) {
+    STATEMENT_CONFIG
+        .with(|cell| cell.borrow_mut().set(config))
+        .expect("Cannot update the statement config");
+}
+
+fn next_month(month: &str) -> Option<String> {
+    month_range(month).ok().map(|(_, end)| month_of(end))
+}
+
+fn previous_month(now: u64) -> Option<String> {
+    let (start, _) = month_range(&month_of(now)).ok()?;
+    Some(month_of(start.checked_sub(1)?))
+}
+
+fn last_statement_before(user_id: u64, period_start: u64) -> Option<Statement> {
+    STATEMENTS.with(|statements| {
+        statements
+            .borrow()
+            .range((user_id, 0)..(user_id, period_start))
+            .last()
+            .map(|(_, statement)| statement)
+    })
+}
+
+fn record_activity(
+    activity: &mut BTreeMap<u64, MonthActivity>,
+    transaction: &Transaction,
+    start: u64,
+    end: u64,
+) {
+    let amount = transaction.amount;
+    let sender = transaction.from_user_id.0;
+    let recipient = transaction.to_user_id.0;
+    if transaction.created_at >= end {
+        activity.entry(sender).or_default().net_after -= amount as i128;
+        activity.entry(recipient).or_default().net_after += amount as i128;
+        return;
+    }
+    if transaction.created_at < start {
+        return;
+    }
+    let points = earning::awarded_for(transaction.id.0, amount);
+    let sent = activity.entry(sender).or_default();
+    sent.total_out = sent.total_out.saturating_add(amount);
+    sent.transaction_count += 1;
+    sent.outgoing_count += 1;
+    sent.points_earned = sent.points_earned.saturating_add(points);
+    let received = activity.entry(recipient).or_default();
+    received.total_in = received.total_in.saturating_add(amount);
+    received.transaction_count += 1;
+}
+
+// Stores the statement of `month` for every user that existed before it
+// ended
+fn close_month(month: &str, now: u64) {
+    let Ok((start, end)) = month_range(month) else {
+        return;
+    };
+    let mut activity: BTreeMap<u64, MonthActivity> = BTreeMap::new();
+    TRANSACTION_STORAGE.with(|storage| {
+        for (_, transaction) in storage.borrow().iter() {
+            record_activity(&mut activity, &transaction, start, end);
+        }
+    });
+
+    let users: Vec<(u64, u64)> = USER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, user)| user.created_at < end)
+            .map(|(id, user)| (id, user.balance))
+            .collect()
+    });
+    for (user_id, balance) in users {
+        let month_activity = activity.remove(&user_id).unwrap_or_default();
+        let closing_balance =
+            (balance as i128 - month_activity.net_after).clamp(0, u64::MAX as i128) as u64;
+        let opening_balance =
+            last_statement_before(user_id, start).map(|statement| statement.closing_balance);
+        let net_transfers = month_activity.total_in as i128 - month_activity.total_out as i128;
+        let other_changes = opening_balance.map(|opening| {
+            (closing_balance as i128 - opening as i128 - net_transfers)
+                .clamp(i64::MIN as i128, i64::MAX as i128) as i64
+        });
+        let statement = Statement {
+            user_id,
+            month: month.to_string(),
+            period_start: start,
+            period_end: end,
+            opening_balance,
+            closing_balance,
+            total_in: month_activity.total_in,
+            total_out: month_activity.total_out,
+            transaction_count: month_activity.transaction_count,
+            fees_paid: month_activity
+                .outgoing_count
+                .saturating_mul(receipts::TRANSFER_FEE),
+            points_earned: month_activity.points_earned,
+            other_changes,
+            created_at: now,
+        };
+        // Statements are immutable once issued
+        let issued = STATEMENTS.with(|statements| {
+            let mut statements = statements.borrow_mut();
+            if statements.contains_key(&(user_id, start)) {
+                return false;
+            }
+            statements.insert((user_id, start), statement);
+            true
+        });
+        if issued {
+            notify(
+                user_id,
+                NotificationKind::StatementReady,
+                format!("Your statement for {} is ready", month),
+            );
+        }
+    }
+}
+
+// Removes local transactions that are past the retention window and belong
+// to a month that already has statements
+fn prune_transactions(config: &mut StatementConfig, now: u64) {
+    let (Some(retention_days), Some(closed_through)) =
+        (config.retention_days, config.closed_through.as_deref())
+    else {
+        return;
+    };
+    let Ok((_, closed_until)) = month_range(closed_through) else {
+        return;
+    };
+    let cutoff = now
+        .saturating_sub(retention_days.saturating_mul(NANOS_PER_DAY))
+        .min(closed_until);
+    let stale: Vec<u64> = TRANSACTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, transaction)| transaction.created_at < cutoff)
+            .take(PRUNE_BATCH_SIZE)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    TRANSACTION_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for id in &stale {
+            storage.remove(id);
+        }
+    });
+    config.pruned_transactions += stale.len() as u64;
+}
+
+pub(crate) fn start_statement_job() {
+    ic_cdk_timers::set_timer_interval(CLOSE_CHECK_INTERVAL, close_due_months);
+}
+
+// Runs hourly. The first run only closes the month that just ended; later
+// runs catch up on every month missed since the last close.
+fn close_due_months() {
+    if ensure_writable().is_err() {
+        return;
+    }
+    let now = current_time();
+    let Some(due) = previous_month(now) else {
+        return;
+    };
+    let mut config = statement_config();
+    let mut month = match config.closed_through.as_deref() {
+        Some(closed) if closed >= due.as_str() => None,
+        Some(closed) => next_month(closed),
+        None => Some(due.clone()),
+    };
+    while let Some(current) = month {
+        close_month(&current, now);
+        config.closed_through = Some(current.clone());
+        month = (current < due).then(|| next_month(&current)).flatten();
+    }
+    prune_transactions(&mut config, now);
+    set_statement_config(config);
+}
+
+/// The user's statements, oldest first.
+#[ic_cdk::query]
+fn list_statements(user_id: u64) -> Result<Vec<Statement>, WalletError> {
+    perf::instrument("list_statements", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(STATEMENTS.with(|statements| {
+            statements
+                .borrow()
+                .range((user_id, 0)..=(user_id, u64::MAX))
+                .map(|(_, statement)| statement)
+                .collect()
+        }))
+    })
+}
+
+#[ic_cdk::query]
+fn get_statement_config() -> Result<StatementConfig, WalletError> {
+    perf::instrument("get_statement_config", || {
+        ensure_admin()?;
+
+        Ok(statement_config())
+    })
+}
+
+/// Sets how many days local transactions are kept once their month has
+/// statements, or `None` to keep them all.
+#[ic_cdk::update]
+fn set_transaction_retention(retention_days: Option<u64>) -> Result<(), WalletError> {
+    perf::instrument("set_transaction_retention", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if retention_days.is_some_and(|days| days < MIN_RETENTION_DAYS) {
+            return Err(WalletError::invalid(
+                "retention_days",
+                &format!("must be at least {}", MIN_RETENTION_DAYS),
+            ));
+        }
+        let mut config = statement_config();
+        config.retention_days = retention_days;
+        set_statement_config(config);
+        Ok(())
+    })
+}
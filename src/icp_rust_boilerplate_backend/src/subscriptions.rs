@@ -0,0 +1,522 @@
+//! Pull-based recurring billing. A merchant publishes a plan, payers
+//! subscribe to it, and a timer charges every due subscription. Failed
+//! charges are retried with exponential backoff and the subscription is
+//! cancelled after `MAX_FAILED_ATTEMPTS` consecutive failures.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::pause;
+use crate::perf;
+use crate::{
+    check_transfer_with, current_time, execute_transfer, next_id, Memory, TransactionPayload,
+    WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const BILLING_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Plans bill at most once an hour
+const MIN_PLAN_INTERVAL_SECONDS: u64 = 60 * 60;
+const MAX_FAILED_ATTEMPTS: u32 = 3;
+// First retry after an hour, doubling with every further failure
+const RETRY_BACKOFF: u64 = 60 * 60 * NANOS_PER_SECOND;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Plan {
+    id: u64,
+    merchant_user_id: u64,
+    name: String,
+    amount: u64,
+    interval_seconds: u64,
+    active: bool,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum SubscriptionStatus {
+    Active,
+    // The last charge failed and is waiting to be retried
+    PastDue,
+    Cancelled,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    id: u64,
+    plan_id: u64,
+    payer_user_id: u64,
+    status: SubscriptionStatus,
+    // Total the payer allows this subscription to charge, if capped
+    max_total: Option<u64>,
+    total_charged: u64,
+    failed_attempts: u32,
+    next_charge_at: u64,
+    created_at: u64,
+    cancelled_reason: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SubscriptionCharge {
+    id: u64,
+    subscription_id: u64,
+    amount: u64,
+    attempted_at: u64,
+    transaction_id: Option<u64>,
+    failure: Option<String>,
+}
+
+impl Plan {
+    fn next_charge_after(&self, time: u64) -> u64 {
+        time.saturating_add(self.interval_seconds.saturating_mul(NANOS_PER_SECOND))
+    }
+}
+
+impl Storable for Plan {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for SubscriptionCharge {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static PLAN_STORAGE: RefCell<StableBTreeMap<u64, Plan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    static SUBSCRIPTION_STORAGE: RefCell<StableBTreeMap<u64, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    static CHARGE_STORAGE: RefCell<StableBTreeMap<u64, SubscriptionCharge, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("subscriptions.plan_storage", 18, &PLAN_STORAGE),
+        manifest::map(
+            "subscriptions.subscription_storage",
+            19,
+            &SUBSCRIPTION_STORAGE,
+        ),
+        manifest::map("subscriptions.charge_storage", 20, &CHARGE_STORAGE),
+    ]
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct PlanPayload {
+    name: String,
+    amount: u64,
+    interval_seconds: u64,
+}
+
+impl Harden for PlanPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.text("name", &self.name, TextKind::Name);
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SubscribePayload {
+    plan_id: u64,
+    max_total: Option<u64>,
+}
+
+fn get_plan(plan_id: u64) -> Result<Plan, WalletError> {
+    PLAN_STORAGE
+        .with(|storage| storage.borrow().get(&plan_id))
+        .ok_or(WalletError::not_found("plan", plan_id))
+}
+
+fn store_subscription(subscription: &Subscription) {
+    SUBSCRIPTION_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(subscription.id, subscription.clone())
+    });
+}
+
+fn cancel(subscription: &mut Subscription, reason: &str) {
+    subscription.status = SubscriptionStatus::Cancelled;
+    subscription.cancelled_reason = Some(reason.to_string());
+}
+
+fn exceeds_limit(plan: &Plan, subscription: &Subscription) -> bool {
+    subscription
+        .max_total
+        .is_some_and(|max_total| subscription.total_charged.saturating_add(plan.amount) > max_total)
+}
+
+// Pulls one period's payment for `subscription` from its payer. The
+// subscription itself is the payer's authorization for the debit.
+fn charge(plan: &Plan, subscription: &Subscription) -> Result<u64, WalletError> {
+    if exceeds_limit(plan, subscription) {
+        return Err(WalletError::InvalidState {
+            reason: "Charge would exceed the subscription's spending limit".to_string(),
+        });
+    }
+
+    let payload = TransactionPayload {
+        from_user_id: subscription.payer_user_id,
+        to_user_id: plan.merchant_user_id,
+        amount: plan.amount,
+        category: None,
+        memo: Some(format!("Subscription: {}", plan.name)),
+        to_handle: None,
+    };
+    check_transfer_with(&payload, || Ok(()))?;
+    Ok(execute_transfer(payload).id.0)
+}
+
+fn record_charge(subscription: &Subscription, amount: u64, outcome: &Result<u64, WalletError>) {
+    let id = next_id();
+    let record = SubscriptionCharge {
+        id,
+        subscription_id: subscription.id,
+        amount,
+        attempted_at: current_time(),
+        transaction_id: outcome.as_ref().ok().copied(),
+        failure: outcome.as_ref().err().map(|error| error.to_string()),
+    };
+    CHARGE_STORAGE.with(|storage| storage.borrow_mut().insert(id, record));
+}
+
+fn bill(mut subscription: Subscription, now: u64) {
+    let plan = match get_plan(subscription.plan_id) {
+        Ok(plan) if plan.active => plan,
+        _ => {
+            cancel(&mut subscription, "The plan is no longer available");
+            store_subscription(&subscription);
+            return;
+        }
+    };
+    // Retrying cannot help once the payer's limit is used up
+    if exceeds_limit(&plan, &subscription) {
+        cancel(&mut subscription, "The spending limit was reached");
+        store_subscription(&subscription);
+        return;
+    }
+
+    let outcome = charge(&plan, &subscription);
+    record_charge(&subscription, plan.amount, &outcome);
+    match outcome {
+        Ok(_) => {
+            subscription.status = SubscriptionStatus::Active;
+            subscription.failed_attempts = 0;
+            subscription.total_charged += plan.amount;
+            subscription.next_charge_at = plan.next_charge_after(now);
+        }
+        Err(error) => {
+            subscription.failed_attempts += 1;
+            if subscription.failed_attempts >= MAX_FAILED_ATTEMPTS {
+                cancel(&mut subscription, "Too many failed payments");
+                notify(
+                    subscription.payer_user_id,
+                    NotificationKind::SubscriptionBilling,
+                    format!(
+                        "Subscription to {} was cancelled after {} failed payments",
+                        plan.name, MAX_FAILED_ATTEMPTS
+                    ),
+                );
+            } else {
+                let backoff = RETRY_BACKOFF << (subscription.failed_attempts - 1);
+                subscription.status = SubscriptionStatus::PastDue;
+                subscription.next_charge_at = now + backoff;
+                notify(
+                    subscription.payer_user_id,
+                    NotificationKind::SubscriptionBilling,
+                    format!(
+                        "Payment of {} for {} failed: {}. It will be retried at {}",
+                        plan.amount, plan.name, error, subscription.next_charge_at
+                    ),
+                );
+            }
+        }
+    }
+    store_subscription(&subscription);
+}
+
+pub(crate) fn start_billing_job() {
+    ic_cdk_timers::set_timer_interval(BILLING_INTERVAL, run_billing);
+}
+
+fn run_billing() {
+    // Charges would fail and count towards dunning while transfers are paused
+    if ensure_writable().is_err() || pause::ensure_transfers_allowed().is_err() {
+        return;
+    }
+
+    let now = current_time();
+    let due: Vec<Subscription> = SUBSCRIPTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| {
+                subscription.status != SubscriptionStatus::Cancelled
+                    && subscription.next_charge_at <= now
+            })
+            .collect()
+    });
+    for subscription in due {
+        bill(subscription, now);
+    }
+}
+
+#[ic_cdk::update]
+fn create_plan(payload: PlanPayload) -> Result<Plan, WalletError> {
+    perf::instrument("create_plan", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+
+        hardening::check(&payload)?;
+        let merchant_user_id = caller_user_id()?;
+        if payload.name.trim().is_empty() {
+            return Err(WalletError::invalid("name", "must not be empty"));
+        }
+        if payload.amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        if payload.interval_seconds < MIN_PLAN_INTERVAL_SECONDS {
+            return Err(WalletError::invalid(
+                "interval_seconds",
+                "must be at least one hour",
+            ));
+        }
+
+        let id = next_id();
+        let plan = Plan {
+            id,
+            merchant_user_id,
+            name: payload.name,
+            amount: payload.amount,
+            interval_seconds: payload.interval_seconds,
+            active: true,
+            created_at: current_time(),
+        };
+        PLAN_STORAGE.with(|storage| storage.borrow_mut().insert(id, plan.clone()));
+        Ok(plan)
+    })
+}
+
+// Stops new subscriptions; existing ones are cancelled at their next charge
+#[ic_cdk::update]
+fn deactivate_plan(plan_id: u64) -> Result<Plan, WalletError> {
+    perf::instrument("deactivate_plan", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let mut plan = get_plan(plan_id)?;
+        if plan.merchant_user_id != user_id {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller does not own plan {}", plan_id),
+            });
+        }
+        plan.active = false;
+        PLAN_STORAGE.with(|storage| storage.borrow_mut().insert(plan_id, plan.clone()));
+        Ok(plan)
+    })
+}
+
+#[ic_cdk::query]
+fn get_plan_details(plan_id: u64) -> Result<Plan, WalletError> {
+    perf::instrument("get_plan_details", || {
+        ensure_not_restoring()?;
+
+        get_plan(plan_id)
+    })
+}
+
+// Charges the first period right away, so a payer that cannot afford the
+// plan is never subscribed
+#[ic_cdk::update]
+fn subscribe(payload: SubscribePayload) -> Result<Subscription, WalletError> {
+    perf::instrument("subscribe", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+
+        let payer_user_id = caller_user_id()?;
+        let plan = get_plan(payload.plan_id)?;
+        if !plan.active {
+            return Err(WalletError::InvalidState {
+                reason: format!("Plan {} is no longer available", plan.id),
+            });
+        }
+        let already_subscribed = SUBSCRIPTION_STORAGE.with(|storage| {
+            storage.borrow().iter().any(|(_, subscription)| {
+                subscription.plan_id == plan.id
+                    && subscription.payer_user_id == payer_user_id
+                    && subscription.status != SubscriptionStatus::Cancelled
+            })
+        });
+        if already_subscribed {
+            return Err(WalletError::AlreadyExists {
+                entity: "subscription".to_string(),
+                field: "plan_id".to_string(),
+            });
+        }
+
+        let now = current_time();
+        let mut subscription = Subscription {
+            id: next_id(),
+            plan_id: plan.id,
+            payer_user_id,
+            status: SubscriptionStatus::Active,
+            max_total: payload.max_total,
+            total_charged: 0,
+            failed_attempts: 0,
+            next_charge_at: plan.next_charge_after(now),
+            created_at: now,
+            cancelled_reason: None,
+        };
+        let transaction_id = charge(&plan, &subscription)?;
+        record_charge(&subscription, plan.amount, &Ok(transaction_id));
+
+        subscription.total_charged = plan.amount;
+        store_subscription(&subscription);
+        Ok(subscription)
+    })
+}
+
+// Either the payer or the plan's merchant may cancel
+#[ic_cdk::update]
+fn cancel_subscription(subscription_id: u64) -> Result<Subscription, WalletError> {
+    perf::instrument("cancel_subscription", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let mut subscription = SUBSCRIPTION_STORAGE
+            .with(|storage| storage.borrow().get(&subscription_id))
+            .ok_or(WalletError::not_found("subscription", subscription_id))?;
+        let merchant_user_id = get_plan(subscription.plan_id)?.merchant_user_id;
+        if user_id != subscription.payer_user_id && user_id != merchant_user_id {
+            return Err(WalletError::Unauthorized {
+                reason: format!("caller is not a party to subscription {}", subscription_id),
+            });
+        }
+        if subscription.status == SubscriptionStatus::Cancelled {
+            return Err(WalletError::InvalidState {
+                reason: format!("Subscription {} is already cancelled", subscription_id),
+            });
+        }
+
+        let reason = if user_id == subscription.payer_user_id {
+            "Cancelled by the payer"
+        } else {
+            "Cancelled by the merchant"
+        };
+        cancel(&mut subscription, reason);
+        store_subscription(&subscription);
+        Ok(subscription)
+    })
+}
+
+// Subscriptions the user pays for or receives as a merchant
+/// Subscriptions that will still charge `user_id`, soonest first.
+pub(crate) fn upcoming_charges(user_id: u64) -> Vec<Subscription> {
+    let mut subscriptions: Vec<Subscription> = SUBSCRIPTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| {
+                subscription.payer_user_id == user_id
+                    && subscription.status != SubscriptionStatus::Cancelled
+            })
+            .collect()
+    });
+    subscriptions.sort_by_key(|subscription| subscription.next_charge_at);
+    subscriptions
+}
+
+#[ic_cdk::query]
+fn get_subscriptions(user_id: u64) -> Result<Vec<Subscription>, WalletError> {
+    perf::instrument("get_subscriptions", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+        let merchant_plans: Vec<u64> = PLAN_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, plan)| plan.merchant_user_id == user_id)
+                .map(|(id, _)| id)
+                .collect()
+        });
+        Ok(SUBSCRIPTION_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, subscription)| subscription)
+                .filter(|subscription| {
+                    subscription.payer_user_id == user_id
+                        || merchant_plans.contains(&subscription.plan_id)
+                })
+                .collect()
+        }))
+    })
+}
+
+#[ic_cdk::query]
+fn get_subscription_charges(subscription_id: u64) -> Result<Vec<SubscriptionCharge>, WalletError> {
+    perf::instrument("get_subscription_charges", || {
+        ensure_not_restoring()?;
+
+        if !SUBSCRIPTION_STORAGE.with(|storage| storage.borrow().contains_key(&subscription_id)) {
+            return Err(WalletError::not_found("subscription", subscription_id));
+        }
+        Ok(CHARGE_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, charge)| charge)
+                .filter(|charge| charge.subscription_id == subscription_id)
+                .collect()
+        }))
+    })
+}
@@ -0,0 +1,232 @@
+//! Supply accounting. Running counters of what was minted into the wallet,
+//! burned out of it and collected as fees, per asset, so the macro-level
+//! numbers can be read without iterating the users. The ledger updates the
+//! token counters on every posting that touches the treasury or the fee
+//! account; points are counted where they are awarded and redeemed.
+//!
+//! Token supply enters through the treasury (deposits, imports, promo
+//! bonuses, inbound peer transfers and adjustments) and leaves through it
+//! (outgoing peer transfers and adjustments). Points transfers between users
+//! do not change the points supply.
+
+use crate::ledger::{self, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::{ensure_admin, perf, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum Asset {
+    Token,
+    Points,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct AssetCounters {
+    minted: u128,
+    burned: u128,
+    fees_collected: u128,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SupplyCounters {
+    // False until the counters were initialized from the existing balances
+    seeded: bool,
+    token: AssetCounters,
+    points: AssetCounters,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct TotalSupply {
+    asset: Asset,
+    minted: u128,
+    burned: u128,
+    // Minted minus burned: what users, escrow and fees hold together
+    total_supply: u128,
+}
+
+/// Balances of the ledger's system accounts. The treasury balance is minus
+/// the token supply.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct TreasuryBalances {
+    treasury: i128,
+    escrow: i128,
+    fees: i128,
+    cashback: i128,
+    // The supply minus what escrow, fees and the cashback pool hold
+    user_funds: i128,
+}
+
+impl Storable for SupplyCounters {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static SUPPLY_COUNTERS: RefCell<Cell<SupplyCounters, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83))),
+            SupplyCounters::default(),
+        )
+        .expect("Cannot create the supply counters cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell(
+        "supply.supply_counters",
+        83,
+        &SUPPLY_COUNTERS,
+    )]
+}
+
+fn counters() -> SupplyCounters {
+    SUPPLY_COUNTERS.with(|cell| cell.borrow().get().clone())
+}
+
+fn update(asset: Asset, change: impl FnOnce(&mut AssetCounters)) {
+    let mut counters = counters();
+    match asset {
+        Asset::Token => change(&mut counters.token),
+        Asset::Points => change(&mut counters.points),
+    }
+    SUPPLY_COUNTERS
+        .with(|cell| cell.borrow_mut().set(counters))
+        .expect("Cannot update the supply counters");
+}
+
+fn counters_of(asset: Asset) -> AssetCounters {
+    let counters = counters();
+    match asset {
+        Asset::Token => counters.token,
+        Asset::Points => counters.points,
+    }
+}
+
+/// Minted minus burned.
+pub(crate) fn outstanding(asset: Asset) -> u128 {
+    let counters = counters_of(asset);
+    counters.minted.saturating_sub(counters.burned)
+}
+
+pub(crate) fn total_points() -> u128 {
+    USER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, user)| user.points as u128)
+            .sum()
+    })
+}
+
+pub(crate) fn record_minted(asset: Asset, amount: u128) {
+    if amount > 0 {
+        update(asset, |counters| {
+            counters.minted = counters.minted.saturating_add(amount)
+        });
+    }
+}
+
+pub(crate) fn record_burned(asset: Asset, amount: u128) {
+    if amount > 0 {
+        update(asset, |counters| {
+            counters.burned = counters.burned.saturating_add(amount)
+        });
+    }
+}
+
+pub(crate) fn record_fee(asset: Asset, amount: u128) {
+    if amount > 0 {
+        update(asset, |counters| {
+            counters.fees_collected = counters.fees_collected.saturating_add(amount)
+        });
+    }
+}
+
+pub(crate) fn fees_collected(asset: Asset) -> u128 {
+    counters_of(asset).fees_collected
+}
+
+/// Initializes the counters of a canister that held funds and points before
+/// supply accounting existed. Runs once.
+pub(crate) fn seed_if_needed() {
+    let mut counters = counters();
+    if counters.seeded {
+        return;
+    }
+    counters.token.minted = (-ledger::balance_of(LedgerAccount::Treasury)).max(0) as u128;
+    counters.token.fees_collected = ledger::balance_of(LedgerAccount::Fees).max(0) as u128;
+    counters.points.minted = total_points();
+    counters.seeded = true;
+    SUPPLY_COUNTERS
+        .with(|cell| cell.borrow_mut().set(counters))
+        .expect("Cannot seed the supply counters");
+}
+
+/// Accounts for points a restore replaced as minted or burned. Token supply
+/// changes are recorded by `ledger::adopt_cached_balances`, which posts the
+/// restored balances.
+pub(crate) fn reseed_points() {
+    let counters = counters_of(Asset::Points);
+    let supply = counters.minted.saturating_sub(counters.burned);
+    let restored = total_points();
+    if restored > supply {
+        record_minted(Asset::Points, restored - supply);
+    } else {
+        record_burned(Asset::Points, supply - restored);
+    }
+}
+
+#[ic_cdk::query]
+fn get_total_supply(asset: Asset) -> Result<TotalSupply, WalletError> {
+    perf::instrument("get_total_supply", || {
+        ensure_admin()?;
+
+        let counters = counters_of(asset);
+        Ok(TotalSupply {
+            asset,
+            minted: counters.minted,
+            burned: counters.burned,
+            total_supply: outstanding(asset),
+        })
+    })
+}
+
+/// Fees collected since supply accounting started; points carry no fees.
+#[ic_cdk::query]
+fn get_total_fees_collected(asset: Asset) -> Result<u128, WalletError> {
+    perf::instrument("get_total_fees_collected", || {
+        ensure_admin()?;
+
+        Ok(counters_of(asset).fees_collected)
+    })
+}
+
+#[ic_cdk::query]
+fn get_treasury_balances() -> Result<TreasuryBalances, WalletError> {
+    perf::instrument("get_treasury_balances", || {
+        ensure_admin()?;
+
+        let treasury = ledger::balance_of(LedgerAccount::Treasury);
+        let escrow = ledger::balance_of(LedgerAccount::Escrow);
+        let fees = ledger::balance_of(LedgerAccount::Fees);
+        let cashback = ledger::balance_of(LedgerAccount::Cashback);
+        Ok(TreasuryBalances {
+            treasury,
+            escrow,
+            fees,
+            cashback,
+            user_funds: -treasury - escrow - fees - cashback,
+        })
+    })
+}
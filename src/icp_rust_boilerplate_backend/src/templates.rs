@@ -0,0 +1,246 @@
+use crate::auth::caller_user_id;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{
+    current_time, username, v2_send_transaction, Memory, Transaction, TransactionPayload,
+    WalletError, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TEMPLATE_NAME_LEN: usize = 32;
+const MAX_TEMPLATES_PER_USER: usize = 50;
+
+/// A saved transfer the owner can replay with `send_from_template`. The
+/// recipient is kept as a username and resolved again at every send.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TransferTemplate {
+    name: String,
+    recipient: String,
+    amount: u64,
+    memo: Option<String>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UserTemplates {
+    templates: Vec<TransferTemplate>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct TransferTemplatePayload {
+    name: String,
+    recipient: String,
+    amount: u64,
+    memo: Option<String>,
+}
+
+impl Harden for TransferTemplatePayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.text("name", &self.name, TextKind::Name);
+        fields.text("recipient", &self.recipient, TextKind::Alias);
+        fields.optional_text("memo", &self.memo, TextKind::Memo);
+    }
+}
+
+impl Storable for UserTemplates {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static TEMPLATE_STORAGE: RefCell<StableBTreeMap<u64, UserTemplates, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::map(
+        "templates.template_storage",
+        12,
+        &TEMPLATE_STORAGE,
+    )]
+}
+
+fn template_not_found(name: &str) -> WalletError {
+    WalletError::NotFoundByKey {
+        entity: "template".to_string(),
+        key: name.to_string(),
+    }
+}
+
+// Resolves the template recipient against the current username index
+fn resolve_recipient(recipient: &str) -> Result<u64, WalletError> {
+    username::lookup(recipient).ok_or(WalletError::NotFoundByKey {
+        entity: "username".to_string(),
+        key: recipient.to_string(),
+    })
+}
+
+fn validate_template(user_id: u64, payload: &TransferTemplatePayload) -> Result<(), WalletError> {
+    hardening::check(payload)?;
+    if payload.name.trim().is_empty() || payload.name.chars().count() > MAX_TEMPLATE_NAME_LEN {
+        return Err(WalletError::invalid("name", "must be 1-32 characters long"));
+    }
+    if payload.amount == 0 {
+        return Err(WalletError::invalid("amount", "must be greater than 0"));
+    }
+    if resolve_recipient(&payload.recipient)? == user_id {
+        return Err(WalletError::invalid(
+            "recipient",
+            "sender and recipient must be different users",
+        ));
+    }
+    Ok(())
+}
+
+fn user_templates(user_id: u64) -> UserTemplates {
+    TEMPLATE_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .unwrap_or_default()
+}
+
+fn store_templates(user_id: u64, templates: UserTemplates) {
+    TEMPLATE_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, templates));
+}
+
+#[ic_cdk::update]
+fn save_transfer_template(
+    payload: TransferTemplatePayload,
+) -> Result<TransferTemplate, WalletError> {
+    perf::instrument("save_transfer_template", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+
+        let user_id = caller_user_id()?;
+        validate_template(user_id, &payload)?;
+
+        let mut templates = user_templates(user_id);
+        if templates
+            .templates
+            .iter()
+            .any(|template| template.name == payload.name)
+        {
+            return Err(WalletError::AlreadyExists {
+                entity: "template".to_string(),
+                field: "name".to_string(),
+            });
+        }
+        if templates.templates.len() >= MAX_TEMPLATES_PER_USER {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "A user can save at most {} templates",
+                    MAX_TEMPLATES_PER_USER
+                ),
+            });
+        }
+
+        let now = current_time();
+        let template = TransferTemplate {
+            name: payload.name,
+            recipient: payload.recipient,
+            amount: payload.amount,
+            memo: payload.memo,
+            created_at: now,
+            updated_at: now,
+        };
+        templates.templates.push(template.clone());
+        store_templates(user_id, templates);
+        Ok(template)
+    })
+}
+
+#[ic_cdk::update]
+fn update_transfer_template(
+    payload: TransferTemplatePayload,
+) -> Result<TransferTemplate, WalletError> {
+    perf::instrument("update_transfer_template", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+
+        let user_id = caller_user_id()?;
+        validate_template(user_id, &payload)?;
+
+        let mut templates = user_templates(user_id);
+        let template = templates
+            .templates
+            .iter_mut()
+            .find(|template| template.name == payload.name)
+            .ok_or_else(|| template_not_found(&payload.name))?;
+        template.recipient = payload.recipient;
+        template.amount = payload.amount;
+        template.memo = payload.memo;
+        template.updated_at = current_time();
+
+        let template = template.clone();
+        store_templates(user_id, templates);
+        Ok(template)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_transfer_template(name: String) -> Result<(), WalletError> {
+    perf::instrument("delete_transfer_template", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let mut templates = user_templates(user_id);
+        let before = templates.templates.len();
+        templates.templates.retain(|template| template.name != name);
+        if templates.templates.len() == before {
+            return Err(template_not_found(&name));
+        }
+        store_templates(user_id, templates);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn list_transfer_templates() -> Result<Vec<TransferTemplate>, WalletError> {
+    perf::instrument("list_transfer_templates", || {
+        ensure_not_restoring()?;
+
+        let user_id = caller_user_id()?;
+        Ok(user_templates(user_id).templates)
+    })
+}
+
+#[ic_cdk::update]
+fn send_from_template(name: String) -> Result<Transaction, WalletError> {
+    perf::instrument("send_from_template", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let template = user_templates(user_id)
+            .templates
+            .into_iter()
+            .find(|template| template.name == name)
+            .ok_or_else(|| template_not_found(&name))?;
+
+        // The recipient may have renamed or left since the template was saved
+        let to_user_id = resolve_recipient(&template.recipient)?;
+        v2_send_transaction(TransactionPayload {
+            from_user_id: user_id,
+            to_user_id,
+            amount: template.amount,
+            category: None,
+            memo: template.memo,
+            to_handle: None,
+        })
+    })
+}
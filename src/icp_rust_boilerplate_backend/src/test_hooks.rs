@@ -0,0 +1,411 @@
+//! Hooks for deterministic tests, compiled only with the `test-hooks`
+//! feature. PocketIC and state-machine tests pin the time `current_time`
+//! returns and the principal the ownership and admin checks see, take
+//! snapshots of the state to rewind to between cases, and ask the canister
+//! whether the money-movement invariants still hold after every step, which
+//! is what property tests of the transfer, points and limit logic assert.
+//!
+//! Snapshots copy every stable storage the state manifest lists, so
+//! restoring one rewinds holds, limits, allowances, escrow and the ledger
+//! along with users and transactions. Heap caches of stored state are
+//! dropped on restore. The hooks answer only to the real controllers,
+//! whichever principal is injected.
+
+use crate::ledger::{self, LedgerAccount};
+use crate::manifest::{self, Record};
+use crate::supply::{self, Asset};
+use crate::{perf, validation, WalletError};
+use candid::Principal;
+use std::cell::{Cell, RefCell};
+
+thread_local! {
+    static MOCK_TIME: Cell<Option<u64>> = const { Cell::new(None) };
+    static MOCK_CALLER: Cell<Option<Principal>> = const { Cell::new(None) };
+    // Indexed by the id `snapshot_state` returned
+    static SNAPSHOTS: RefCell<Vec<Snapshot>> = const { RefCell::new(Vec::new()) };
+}
+
+// The records of every storage, in memory id order
+type Snapshot = Vec<Vec<Record>>;
+
+fn take_snapshot() -> Snapshot {
+    manifest::all_storages()
+        .iter()
+        .map(|storage| storage.export())
+        .collect()
+}
+
+fn restore(snapshot: &Snapshot) {
+    for (storage, records) in manifest::all_storages().iter().zip(snapshot) {
+        storage.import(records);
+    }
+    validation::forget_compiled_rules();
+}
+
+pub(crate) fn mock_time() -> Option<u64> {
+    MOCK_TIME.with(|time| time.get())
+}
+
+pub(crate) fn mock_caller() -> Option<Principal> {
+    MOCK_CALLER.with(|caller| caller.get())
+}
+
+// Injected callers must not be able to reach the hooks themselves
+fn ensure_controller() -> Result<(), WalletError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(WalletError::Unauthorized {
+            reason: "only canister controllers can use the test hooks".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// The violated invariants, described one per entry.
+fn violations() -> Vec<String> {
+    let mut violations = Vec::new();
+    let balances = ledger::balances();
+
+    let total: i128 = balances.iter().map(|(_, balance)| balance).sum();
+    if total != 0 {
+        violations.push(format!("ledger accounts sum to {} instead of 0", total));
+    }
+    for (account, balance) in &balances {
+        if *account != LedgerAccount::Treasury && *balance < 0 {
+            violations.push(format!(
+                "{:?} has a negative balance of {}",
+                account, balance
+            ));
+        }
+    }
+    let mismatched = ledger::mismatched_accounts();
+    if mismatched > 0 {
+        violations.push(format!(
+            "{} cached user balances differ from the ledger",
+            mismatched
+        ));
+    }
+    let escrow = ledger::balance_of(LedgerAccount::Escrow);
+    let expected_escrow = ledger::expected_escrow() as i128;
+    if escrow != expected_escrow {
+        violations.push(format!(
+            "escrow holds {} but its records add up to {}",
+            escrow, expected_escrow
+        ));
+    }
+    let token_supply = supply::outstanding(Asset::Token) as i128;
+    let treasury = ledger::balance_of(LedgerAccount::Treasury);
+    if token_supply != -treasury {
+        violations.push(format!(
+            "token supply is {} but the treasury issued {}",
+            token_supply, -treasury
+        ));
+    }
+    let points_supply = supply::outstanding(Asset::Points);
+    let points = supply::total_points();
+    if points_supply != points {
+        violations.push(format!(
+            "points supply is {} but users hold {}",
+            points_supply, points
+        ));
+    }
+    violations
+}
+
+/// Pins the time the canister reads in nanoseconds since the epoch, or
+/// returns to the system time with `None`.
+#[ic_cdk::update]
+fn set_mock_time(time: Option<u64>) -> Result<(), WalletError> {
+    perf::instrument("set_mock_time", || {
+        ensure_controller()?;
+
+        MOCK_TIME.with(|mock| mock.set(time));
+        Ok(())
+    })
+}
+
+/// Makes every check see `principal` as the caller, or the real caller
+/// again with `None`.
+#[ic_cdk::update]
+fn set_mock_caller(principal: Option<Principal>) -> Result<(), WalletError> {
+    perf::instrument("set_mock_caller", || {
+        ensure_controller()?;
+
+        MOCK_CALLER.with(|mock| mock.set(principal));
+        Ok(())
+    })
+}
+
+/// Snapshots the state and returns the id to restore it by.
+#[ic_cdk::update]
+fn snapshot_state() -> Result<u64, WalletError> {
+    perf::instrument("snapshot_state", || {
+        ensure_controller()?;
+
+        let snapshot = take_snapshot();
+        Ok(SNAPSHOTS.with(|snapshots| {
+            let mut snapshots = snapshots.borrow_mut();
+            snapshots.push(snapshot);
+            snapshots.len() as u64 - 1
+        }))
+    })
+}
+
+/// Puts every storage back the way it was when the snapshot was taken.
+#[ic_cdk::update]
+fn restore_state(snapshot_id: u64) -> Result<(), WalletError> {
+    perf::instrument("restore_state", || {
+        ensure_controller()?;
+
+        SNAPSHOTS.with(|snapshots| {
+            let snapshots = snapshots.borrow();
+            let snapshot = snapshots
+                .get(snapshot_id as usize)
+                .ok_or(WalletError::not_found("snapshot", snapshot_id))?;
+            restore(snapshot);
+            Ok(())
+        })
+    })
+}
+
+/// Checks that no balance is negative, that the ledger still balances and
+/// matches the cached balances and escrow records, and that the supply
+/// counters match what was issued. Returns the violations; empty means all
+/// hold.
+#[ic_cdk::query]
+fn check_invariants() -> Result<Vec<String>, WalletError> {
+    perf::instrument("check_invariants", || {
+        ensure_controller()?;
+
+        Ok(violations())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counterparties::{set_counterparty_limit, CounterpartyLimitPayload};
+    use crate::points::{transfer_points, PointsTransferPayload};
+    use crate::{
+        v2_create_user, v2_deposit_funds, v2_send_transaction, DepositPayload, TransactionPayload,
+        UserPayload, USER_STORAGE,
+    };
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    const USERS: usize = 4;
+    const FUNDING: u64 = 1_000_000;
+    const START: u64 = 1_700_000_000_000_000_000;
+    const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+    const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+
+    // Users are named by their index into the accounts a case opens
+    #[derive(Clone, Debug)]
+    enum Step {
+        Transfer {
+            from: usize,
+            to: usize,
+            amount: u64,
+        },
+        PointsTransfer {
+            from: usize,
+            to: usize,
+            points: u64,
+        },
+        SetLimit {
+            user: usize,
+            counterparty: usize,
+            // Maximum amount and period in days, or `None` to remove it
+            limit: Option<(u64, u32)>,
+        },
+        Wait {
+            hours: u64,
+        },
+    }
+
+    fn step() -> impl Strategy<Value = Step> {
+        prop_oneof![
+            (0..USERS, 0..USERS, 1..=FUNDING / 2).prop_map(|(from, to, amount)| Step::Transfer {
+                from,
+                to,
+                amount
+            }),
+            (0..USERS, 0..USERS, 1..=50u64).prop_map(|(from, to, points)| Step::PointsTransfer {
+                from,
+                to,
+                points
+            }),
+            (0..USERS, 0..USERS, 1..=FUNDING, 1..=30u32).prop_map(
+                |(user, counterparty, max_amount, period_days)| Step::SetLimit {
+                    user,
+                    counterparty,
+                    limit: Some((max_amount, period_days)),
+                }
+            ),
+            (0..USERS, 0..USERS).prop_map(|(user, counterparty)| Step::SetLimit {
+                user,
+                counterparty,
+                limit: None,
+            }),
+            (1..=72u64).prop_map(|hours| Step::Wait { hours }),
+        ]
+    }
+
+    thread_local! {
+        // The state before any case ran, which every case starts from
+        static PRISTINE: Snapshot = take_snapshot();
+    }
+
+    fn owner(index: usize) -> Principal {
+        Principal::from_slice(&[index as u8 + 1])
+    }
+
+    fn as_user<T>(index: usize, f: impl FnOnce() -> T) -> T {
+        MOCK_CALLER.with(|mock| mock.set(Some(owner(index))));
+        let result = f();
+        MOCK_CALLER.with(|mock| mock.set(None));
+        result
+    }
+
+    // Rewinds to the pristine state and opens `USERS` funded accounts
+    fn open_accounts() -> Vec<u64> {
+        PRISTINE.with(restore);
+        MOCK_TIME.with(|time| time.set(Some(START)));
+        (0..USERS)
+            .map(|index| {
+                let user = as_user(index, || {
+                    v2_create_user(UserPayload {
+                        first_name: "Test".to_string(),
+                        last_name: "User".to_string(),
+                        email: format!("user{}@example.com", index),
+                        phone_number: format!("+1555000000{}", index),
+                        username: Some(format!("user_{}", index)),
+                    })
+                })
+                .expect("Cannot open a test account");
+                v2_deposit_funds(DepositPayload {
+                    user_id: user.id.0,
+                    amount: FUNDING,
+                })
+                .expect("Cannot fund a test account");
+                user.id.0
+            })
+            .collect()
+    }
+
+    fn user_totals(ids: &[u64]) -> (u64, u64) {
+        ids.iter().fold((0, 0), |(balance, points), id| {
+            let user = USER_STORAGE
+                .with(|storage| storage.borrow().get(id))
+                .expect("Test account disappeared");
+            (balance + user.balance, points + user.points)
+        })
+    }
+
+    // What `from` sent `to` on the days a limit of `period_days` covers
+    fn sent_in_period(
+        sent: &[(u64, usize, usize, u64)],
+        from: usize,
+        to: usize,
+        period_days: u32,
+    ) -> u64 {
+        let first_day = (current_day() + 1).saturating_sub(period_days as u64);
+        sent.iter()
+            .filter(|(day, sender, recipient, _)| {
+                *day >= first_day && *sender == from && *recipient == to
+            })
+            .map(|(_, _, _, amount)| amount)
+            .sum()
+    }
+
+    fn current_day() -> u64 {
+        crate::current_time() / NANOS_PER_DAY
+    }
+
+    fn run(steps: Vec<Step>) -> Result<(), TestCaseError> {
+        let ids = open_accounts();
+        prop_assert!(violations().is_empty(), "{:?}", violations());
+        let mut limits: BTreeMap<(usize, usize), (u64, u32)> = BTreeMap::new();
+        // Executed transfers as (day, sender, recipient, amount)
+        let mut sent: Vec<(u64, usize, usize, u64)> = Vec::new();
+
+        for step in steps {
+            let (balances_before, points_before) = user_totals(&ids);
+            match step.clone() {
+                Step::Transfer { from, to, amount } => {
+                    let result = as_user(from, || {
+                        v2_send_transaction(TransactionPayload {
+                            from_user_id: ids[from],
+                            to_user_id: ids[to],
+                            amount,
+                            category: None,
+                            memo: None,
+                            to_handle: None,
+                        })
+                    });
+                    if result.is_ok() {
+                        sent.push((current_day(), from, to, amount));
+                        if let Some((max_amount, period_days)) = limits.get(&(from, to)) {
+                            let total = sent_in_period(&sent, from, to, *period_days);
+                            prop_assert!(
+                                total <= *max_amount,
+                                "{} sent over a limit of {}",
+                                total,
+                                max_amount
+                            );
+                        }
+                    }
+                }
+                Step::PointsTransfer { from, to, points } => {
+                    let _ = as_user(from, || {
+                        transfer_points(PointsTransferPayload {
+                            to_user_id: ids[to],
+                            points,
+                        })
+                    });
+                    prop_assert_eq!(user_totals(&ids).1, points_before);
+                }
+                Step::SetLimit {
+                    user,
+                    counterparty,
+                    limit,
+                } => {
+                    let payload = limit.map(|(max_amount, period_days)| CounterpartyLimitPayload {
+                        max_amount,
+                        period_days,
+                    });
+                    let result = as_user(user, || {
+                        set_counterparty_limit(ids[user], ids[counterparty], payload)
+                    });
+                    if result.is_ok() {
+                        match limit {
+                            Some(limit) => limits.insert((user, counterparty), limit),
+                            None => limits.remove(&(user, counterparty)),
+                        };
+                    }
+                }
+                Step::Wait { hours } => {
+                    MOCK_TIME
+                        .with(|time| time.set(time.get().map(|now| now + hours * NANOS_PER_HOUR)));
+                }
+            }
+
+            let violations = violations();
+            prop_assert!(violations.is_empty(), "after {:?}: {:?}", step, violations);
+            // Transfers between users are free, so they only move funds
+            // around; points are minted by earning and never vanish
+            let (balances, points) = user_totals(&ids);
+            prop_assert_eq!(balances, balances_before, "after {:?}", step);
+            prop_assert!(points >= points_before, "points vanished after {:?}", step);
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn money_movement_preserves_invariants(steps in prop::collection::vec(step(), 1..40)) {
+            run(steps)?;
+        }
+    }
+}
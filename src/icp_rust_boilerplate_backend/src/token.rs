@@ -0,0 +1,181 @@
+//! Display metadata of the wallet's currency. Balances and amounts are
+//! integers in the smallest unit; `decimals` says where the decimal point
+//! goes, so with 8 decimals an amount of 1500 reads as 0.00001500.
+
+use crate::backup::ensure_writable;
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{ensure_admin, Memory, WalletError, MEMORY_MANAGER, USER_STORAGE};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// u64 holds at most 20 digits
+const MAX_DECIMALS: u8 = 18;
+const MAX_SYMBOL_LEN: usize = 8;
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TokenMetadata {
+    name: String,
+    symbol: String,
+    decimals: u8,
+    // Amounts must be a multiple of this many units; 1 accepts any amount
+    min_unit: u64,
+}
+
+impl Default for TokenMetadata {
+    fn default() -> Self {
+        TokenMetadata {
+            name: "Wallet Token".to_string(),
+            symbol: "WLT".to_string(),
+            decimals: 8,
+            min_unit: 1,
+        }
+    }
+}
+
+impl Storable for TokenMetadata {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static TOKEN_METADATA: RefCell<Cell<TokenMetadata, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))),
+            TokenMetadata::default(),
+        )
+        .expect("Cannot create the token metadata cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell("token.token_metadata", 39, &TOKEN_METADATA)]
+}
+
+fn token_metadata() -> TokenMetadata {
+    TOKEN_METADATA.with(|metadata| metadata.borrow().get().clone())
+}
+
+/// Rejects amounts that are not a whole number of the smallest unit.
+pub(crate) fn validate_amount(field: &str, amount: u64) -> Result<(), WalletError> {
+    let min_unit = token_metadata().min_unit;
+    if !amount.is_multiple_of(min_unit) {
+        return Err(WalletError::invalid(
+            field,
+            &format!("must be a multiple of {} units", min_unit),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn min_unit() -> u64 {
+    token_metadata().min_unit
+}
+
+pub(crate) fn symbol() -> String {
+    token_metadata().symbol
+}
+
+/// Renders an amount with its decimal point and symbol, e.g. "0.00001500 WLT".
+#[ic_cdk::query]
+pub(crate) fn format_amount(amount: u64) -> String {
+    perf::measure("format_amount", || {
+        let metadata = token_metadata();
+        let scale = 10u64.pow(metadata.decimals as u32);
+        if metadata.decimals == 0 {
+            return format!("{} {}", amount, metadata.symbol);
+        }
+        format!(
+            "{}.{:0width$} {}",
+            amount / scale,
+            amount % scale,
+            metadata.symbol,
+            width = metadata.decimals as usize
+        )
+    })
+}
+
+#[ic_cdk::query]
+fn get_token_metadata() -> TokenMetadata {
+    perf::measure("get_token_metadata", token_metadata)
+}
+
+#[ic_cdk::update]
+fn set_token_metadata(metadata: TokenMetadata) -> Result<(), WalletError> {
+    perf::instrument("set_token_metadata", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        let name = metadata.name.trim();
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(WalletError::invalid(
+                "name",
+                &format!("must be between 1 and {} characters", MAX_NAME_LEN),
+            ));
+        }
+        let symbol = metadata.symbol.trim();
+        if symbol.is_empty()
+            || symbol.len() > MAX_SYMBOL_LEN
+            || !symbol.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(WalletError::invalid(
+                "symbol",
+                &format!("must be 1 to {} letters or digits", MAX_SYMBOL_LEN),
+            ));
+        }
+        if metadata.decimals > MAX_DECIMALS {
+            return Err(WalletError::invalid(
+                "decimals",
+                &format!("must be at most {}", MAX_DECIMALS),
+            ));
+        }
+        if metadata.min_unit == 0 {
+            return Err(WalletError::invalid("min_unit", "must be greater than 0"));
+        }
+        // Changing the precision would silently rescale every existing balance
+        let current = token_metadata();
+        let funds_exist =
+            USER_STORAGE.with(|storage| storage.borrow().iter().any(|(_, user)| user.balance > 0));
+        if metadata.decimals != current.decimals && funds_exist {
+            return Err(WalletError::InvalidState {
+                reason: "Decimals cannot change once accounts hold funds".to_string(),
+            });
+        }
+
+        let metadata = TokenMetadata {
+            name: name.to_string(),
+            symbol: symbol.to_string(),
+            ..metadata
+        };
+        TOKEN_METADATA
+            .with(|cell| cell.borrow_mut().set(metadata))
+            .expect("Cannot update the token metadata");
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn icrc1_name() -> String {
+    perf::measure("icrc1_name", || token_metadata().name)
+}
+
+#[ic_cdk::query]
+fn icrc1_symbol() -> String {
+    perf::measure("icrc1_symbol", || token_metadata().symbol)
+}
+
+#[ic_cdk::query]
+fn icrc1_decimals() -> u8 {
+    perf::measure("icrc1_decimals", || token_metadata().decimals)
+}
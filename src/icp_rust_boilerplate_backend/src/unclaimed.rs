@@ -0,0 +1,431 @@
+//! Sends to people without an account. The funds go into escrow under the
+//! SHA-256 of the recipient's email address or phone number; whoever signs
+//! up with that contact detail and verifies it receives them. Sends nobody
+//! claimed within the configured period are refunded to the sender by a
+//! timer, and the sender can cancel them before that.
+
+use crate::alerts;
+use crate::auth::caller_user_id;
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::cycles::ensure_not_frozen;
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::ledger::{self, EntryKind, LedgerAccount};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::verification::{self, ContactChannel};
+use crate::{counterparties, devices, dust, holds, lockdown, pause, perf, token, validation};
+use crate::{
+    current_time, ensure_admin, next_id, sha256_hex, Memory, User, WalletError, MEMORY_MANAGER,
+    USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const DEFAULT_EXPIRY_DAYS: u32 = 14;
+const MAX_EXPIRY_DAYS: u32 = 90;
+const MAX_PENDING_PER_SENDER: usize = 50;
+const REFUND_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum UnclaimedSendStatus {
+    Pending,
+    Claimed { by_user_id: u64, at: u64 },
+    // Returned to the sender at expiry
+    Refunded { at: u64 },
+    Cancelled { at: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct UnclaimedSend {
+    id: u64,
+    sender_user_id: u64,
+    channel: ContactChannel,
+    // Hex SHA-256 of the normalized contact detail
+    contact_hash: String,
+    amount: u64,
+    created_at: u64,
+    expires_at: u64,
+    status: UnclaimedSendStatus,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct UnclaimedSendPayload {
+    channel: ContactChannel,
+    contact: String,
+    amount: u64,
+}
+
+impl Harden for UnclaimedSendPayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.text("contact", &self.contact, TextKind::Email);
+        fields.rule(match self.channel {
+            ContactChannel::Email => validation::validate_email(self.contact.trim()),
+            ContactChannel::Phone => validation::validate_phone(self.contact.trim()),
+        });
+    }
+}
+
+impl Storable for UnclaimedSend {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static UNCLAIMED_SENDS: RefCell<StableBTreeMap<u64, UnclaimedSend, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81)))
+    ));
+
+    // Days a send waits to be claimed before it is refunded
+    static EXPIRY_DAYS: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82))),
+            DEFAULT_EXPIRY_DAYS,
+        )
+        .expect("Cannot create the unclaimed send expiry cell")
+    );
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("unclaimed.unclaimed_sends", 81, &UNCLAIMED_SENDS),
+        manifest::cell("unclaimed.expiry_days", 82, &EXPIRY_DAYS),
+    ]
+}
+
+// Emails match case-insensitively, phone numbers without separators
+fn normalize(channel: ContactChannel, contact: &str) -> String {
+    let contact = contact.trim();
+    match channel {
+        ContactChannel::Email => contact.to_lowercase(),
+        ContactChannel::Phone => contact
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == '+')
+            .collect(),
+    }
+}
+
+fn contact_hash(channel: ContactChannel, contact: &str) -> String {
+    sha256_hex(normalize(channel, contact).as_bytes())
+}
+
+fn contact_of(user: &User, channel: ContactChannel) -> &str {
+    match channel {
+        ContactChannel::Email => &user.email,
+        ContactChannel::Phone => &user.phone_number,
+    }
+}
+
+fn save_send(send: &UnclaimedSend) {
+    UNCLAIMED_SENDS.with(|storage| storage.borrow_mut().insert(send.id, send.clone()));
+}
+
+fn pending_for(hash: &str, now: u64) -> Vec<UnclaimedSend> {
+    UNCLAIMED_SENDS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, send)| send)
+            .filter(|send| {
+                send.status == UnclaimedSendStatus::Pending
+                    && send.contact_hash == hash
+                    && now < send.expires_at
+            })
+            .collect()
+    })
+}
+
+/// Funds waiting in escrow for a recipient to claim them.
+pub(crate) fn escrowed_amount() -> u128 {
+    UNCLAIMED_SENDS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, send)| send.status == UnclaimedSendStatus::Pending)
+            .map(|(_, send)| send.amount as u128)
+            .sum()
+    })
+}
+
+// Releases the amount of `send` from escrow to `user_id`
+fn credit(user_id: u64, send: &UnclaimedSend) -> Result<(), WalletError> {
+    ledger::transfer(
+        EntryKind::UnclaimedSendSettled { send_id: send.id },
+        LedgerAccount::Escrow,
+        ledger::user(user_id),
+        send.amount,
+    )?;
+    Ok(())
+}
+
+/// Tells a newly created user about the sends waiting for their contact
+/// details; they are claimed once those are verified.
+pub(crate) fn announce_pending(user: &User) {
+    let now = current_time();
+    let waiting: u64 = [ContactChannel::Email, ContactChannel::Phone]
+        .into_iter()
+        .flat_map(|channel| pending_for(&contact_hash(channel, contact_of(user, channel)), now))
+        .map(|send| send.amount)
+        .fold(0u64, u64::saturating_add);
+    if waiting > 0 {
+        notify(
+            user.id.0,
+            NotificationKind::IncomingTransfer,
+            format!(
+                "{} is waiting for you; verify your contact details to claim it",
+                token::format_amount(waiting)
+            ),
+        );
+    }
+}
+
+/// Credits `user` with the pending sends addressed to the contact detail of
+/// `channel`, which must have just been verified. Sends from a sender the
+/// rules of either user forbid are left to be refunded.
+pub(crate) fn claim_verified(user: &User, channel: ContactChannel) {
+    if pause::ensure_transfers_allowed().is_err() {
+        return;
+    }
+    let user_id = user.id.0;
+    let now = current_time();
+    for mut send in pending_for(&contact_hash(channel, contact_of(user, channel)), now) {
+        if counterparties::ensure_transfer_permitted(send.sender_user_id, user_id, "contact")
+            .is_err()
+            || credit(user_id, &send).is_err()
+        {
+            continue;
+        }
+        send.status = UnclaimedSendStatus::Claimed {
+            by_user_id: user_id,
+            at: now,
+        };
+        save_send(&send);
+        notify(
+            user_id,
+            NotificationKind::IncomingTransfer,
+            format!(
+                "You claimed {} sent by user {}",
+                token::format_amount(send.amount),
+                send.sender_user_id
+            ),
+        );
+        notify(
+            send.sender_user_id,
+            NotificationKind::IncomingTransfer,
+            format!(
+                "Your send {} of {} was claimed by user {}",
+                send.id,
+                token::format_amount(send.amount),
+                user_id
+            ),
+        );
+    }
+}
+
+pub(crate) fn start_refund_job() {
+    ic_cdk_timers::set_timer_interval(REFUND_CHECK_INTERVAL, refund_expired);
+}
+
+fn refund_expired() {
+    if ensure_writable().is_err() || pause::ensure_transfers_allowed().is_err() {
+        return;
+    }
+
+    let now = current_time();
+    let expired: Vec<UnclaimedSend> = UNCLAIMED_SENDS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, send)| send)
+            .filter(|send| send.status == UnclaimedSendStatus::Pending && send.expires_at <= now)
+            .collect()
+    });
+    for mut send in expired {
+        // Left pending to be retried if the sender cannot be credited
+        if credit(send.sender_user_id, &send).is_err() {
+            continue;
+        }
+        send.status = UnclaimedSendStatus::Refunded { at: now };
+        save_send(&send);
+        notify(
+            send.sender_user_id,
+            NotificationKind::IncomingTransfer,
+            format!(
+                "Nobody claimed your send {} and {} was refunded",
+                send.id,
+                token::format_amount(send.amount)
+            ),
+        );
+    }
+}
+
+/// Sends `amount` from the caller to the owner of an email address or phone
+/// number that has no account yet.
+#[ic_cdk::update]
+fn send_to_contact(payload: UnclaimedSendPayload) -> Result<UnclaimedSend, WalletError> {
+    perf::instrument("send_to_contact", || {
+        ensure_writable()?;
+        ensure_not_frozen()?;
+        pause::ensure_transfers_allowed()?;
+
+        hardening::check(&payload)?;
+        let sender_user_id = caller_user_id()?;
+        verification::ensure_verified(sender_user_id)?;
+        devices::record_activity(sender_user_id);
+        if payload.amount == 0 {
+            return Err(WalletError::invalid("amount", "must be greater than 0"));
+        }
+        token::validate_amount("amount", payload.amount)?;
+        dust::ensure_above_minimum("amount", payload.amount)?;
+
+        let normalized = normalize(payload.channel, &payload.contact);
+        let registered = USER_STORAGE.with(|storage| {
+            storage.borrow().iter().any(|(_, user)| {
+                normalize(payload.channel, contact_of(&user, payload.channel)) == normalized
+            })
+        });
+        if registered {
+            return Err(WalletError::invalid(
+                "contact",
+                "belongs to a registered user; send to their account instead",
+            ));
+        }
+        let pending = UNCLAIMED_SENDS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, send)| {
+                    send.sender_user_id == sender_user_id
+                        && send.status == UnclaimedSendStatus::Pending
+                })
+                .count()
+        });
+        if pending >= MAX_PENDING_PER_SENDER {
+            return Err(WalletError::InvalidState {
+                reason: format!(
+                    "At most {} unclaimed sends can be pending at a time",
+                    MAX_PENDING_PER_SENDER
+                ),
+            });
+        }
+        lockdown::ensure_not_locked(sender_user_id)?;
+        let available =
+            holds::available_balance(sender_user_id, ledger::user_balance(sender_user_id));
+        if available < payload.amount {
+            return Err(WalletError::InsufficientBalance {
+                available,
+                required: payload.amount,
+            });
+        }
+
+        let now = current_time();
+        let expiry_days = EXPIRY_DAYS.with(|cell| *cell.borrow().get());
+        let send = UnclaimedSend {
+            id: next_id(),
+            sender_user_id,
+            channel: payload.channel,
+            contact_hash: sha256_hex(normalized.as_bytes()),
+            amount: payload.amount,
+            created_at: now,
+            expires_at: now + expiry_days as u64 * NANOS_PER_DAY,
+            status: UnclaimedSendStatus::Pending,
+        };
+        ledger::transfer(
+            EntryKind::UnclaimedSend { send_id: send.id },
+            ledger::user(sender_user_id),
+            LedgerAccount::Escrow,
+            send.amount,
+        )?;
+        alerts::check_balance(sender_user_id, ledger::user_balance(sender_user_id));
+        save_send(&send);
+        Ok(send)
+    })
+}
+
+/// Returns a pending send to the caller who made it.
+#[ic_cdk::update]
+fn cancel_unclaimed_send(send_id: u64) -> Result<UnclaimedSend, WalletError> {
+    perf::instrument("cancel_unclaimed_send", || {
+        ensure_writable()?;
+        pause::ensure_transfers_allowed()?;
+
+        let sender_user_id = caller_user_id()?;
+        let mut send = UNCLAIMED_SENDS
+            .with(|storage| storage.borrow().get(&send_id))
+            .filter(|send| send.sender_user_id == sender_user_id)
+            .ok_or(WalletError::not_found("unclaimed send", send_id))?;
+        if send.status != UnclaimedSendStatus::Pending {
+            return Err(WalletError::InvalidState {
+                reason: format!("Send {} is no longer pending", send_id),
+            });
+        }
+        credit(sender_user_id, &send)?;
+        send.status = UnclaimedSendStatus::Cancelled { at: current_time() };
+        save_send(&send);
+        Ok(send)
+    })
+}
+
+/// Sends the caller made to contacts without an account, newest first.
+#[ic_cdk::query]
+fn list_my_unclaimed_sends() -> Result<Vec<UnclaimedSend>, WalletError> {
+    perf::instrument("list_my_unclaimed_sends", || {
+        ensure_not_restoring()?;
+
+        let sender_user_id = caller_user_id()?;
+        let mut sends: Vec<UnclaimedSend> = UNCLAIMED_SENDS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, send)| send)
+                .filter(|send| send.sender_user_id == sender_user_id)
+                .collect()
+        });
+        sends.reverse();
+        Ok(sends)
+    })
+}
+
+#[ic_cdk::query]
+fn get_unclaimed_send_expiry_days() -> Result<u32, WalletError> {
+    perf::instrument("get_unclaimed_send_expiry_days", || {
+        ensure_admin()?;
+
+        Ok(EXPIRY_DAYS.with(|cell| *cell.borrow().get()))
+    })
+}
+
+/// Sets how long new sends wait to be claimed; pending sends keep their
+/// expiry.
+#[ic_cdk::update]
+fn set_unclaimed_send_expiry_days(days: u32) -> Result<(), WalletError> {
+    perf::instrument("set_unclaimed_send_expiry_days", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if days == 0 || days > MAX_EXPIRY_DAYS {
+            return Err(WalletError::invalid(
+                "days",
+                &format!("must be between 1 and {}", MAX_EXPIRY_DAYS),
+            ));
+        }
+        EXPIRY_DAYS
+            .with(|cell| cell.borrow_mut().set(days))
+            .map_err(|_| WalletError::Internal {
+                reason: "cannot update the unclaimed send expiry".to_string(),
+            })?;
+        Ok(())
+    })
+}
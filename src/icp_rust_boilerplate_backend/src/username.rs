@@ -0,0 +1,177 @@
+use crate::auth::caller_user_id;
+use crate::hardening::{self, TextKind};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::validation::{is_reserved_username, validate_username};
+use crate::{
+    clear_map, current_time, directory, ensure_not_restoring, ensure_writable, Memory, User,
+    WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// A user may change their username once every 30 days
+const USERNAME_CHANGE_COOLDOWN: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+// Generated usernames keep the historical 10 character prefix of first+last name
+const GENERATED_PREFIX_LEN: usize = 10;
+
+thread_local! {
+    static USERNAME_INDEX: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static USERNAME_CHANGED_AT: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("username.username_index", 8, &USERNAME_INDEX),
+        manifest::map("username.username_changed_at", 9, &USERNAME_CHANGED_AT),
+    ]
+}
+
+fn is_taken(username: &str) -> bool {
+    USERNAME_INDEX.with(|index| index.borrow().contains_key(&username.to_string()))
+}
+
+// Generated usernames also steer clear of reserved ones
+fn is_unavailable(username: &str) -> bool {
+    is_taken(username) || is_reserved_username(username)
+}
+
+/// Validates the username a user asked for, or derives one from their name
+/// when none was given, appending a number only if the derived one is taken.
+pub(crate) fn claim_or_generate(
+    desired: Option<String>,
+    first_name: &str,
+    last_name: &str,
+) -> Result<String, WalletError> {
+    if let Some(username) = desired {
+        hardening::check_args(|fields| {
+            fields.required_text("username", &username, TextKind::Alias);
+            fields.rule(validate_username(&username));
+        })?;
+        if is_taken(&username) {
+            return Err(WalletError::AlreadyExists {
+                entity: "user".to_string(),
+                field: "username".to_string(),
+            });
+        }
+        return Ok(username);
+    }
+
+    let mut base = format!("{}{}", first_name, last_name)
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(GENERATED_PREFIX_LEN)
+        .collect::<String>();
+    if base.len() < 3 {
+        base = format!("user{}", base);
+    }
+    if !is_unavailable(&base) {
+        return Ok(base);
+    }
+    let suffix = (2u64..)
+        .find(|n| !is_unavailable(&format!("{}{}", base, n)))
+        .expect("Cannot find a free username");
+    Ok(format!("{}{}", base, suffix))
+}
+
+pub(crate) fn lookup(username: &str) -> Option<u64> {
+    USERNAME_INDEX.with(|index| index.borrow().get(&username.to_string()))
+}
+
+pub(crate) fn index_username(username: &str, user_id: u64) {
+    USERNAME_INDEX.with(|index| index.borrow_mut().insert(username.to_string(), user_id));
+}
+
+/// Re-indexes every stored user, used after upgrades and restores. Legacy
+/// duplicates keep pointing at the oldest account.
+pub(crate) fn rebuild_index() {
+    let users: Vec<(u64, String)> = USER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(id, user)| (id, user.username))
+            .collect()
+    });
+    USERNAME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        clear_map(&mut index);
+        for (id, username) in users {
+            if !index.contains_key(&username) {
+                index.insert(username, id);
+            }
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn change_username(username: String) -> Result<User, WalletError> {
+    perf::instrument("change_username", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let mut user = USER_STORAGE
+            .with(|storage| storage.borrow().get(&user_id))
+            .ok_or(WalletError::not_found("user", user_id))?;
+        if user.username == username {
+            return Ok(user);
+        }
+
+        let now = current_time();
+        if let Some(changed_at) = USERNAME_CHANGED_AT.with(|changes| changes.borrow().get(&user_id))
+        {
+            if now < changed_at + USERNAME_CHANGE_COOLDOWN {
+                return Err(WalletError::InvalidState {
+                    reason: format!(
+                        "Username can be changed again at {}",
+                        changed_at + USERNAME_CHANGE_COOLDOWN
+                    ),
+                });
+            }
+        }
+
+        validate_username(&username)?;
+        if is_taken(&username) {
+            return Err(WalletError::AlreadyExists {
+                entity: "user".to_string(),
+                field: "username".to_string(),
+            });
+        }
+
+        USERNAME_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            // Only release the old name if it actually belongs to this user
+            if index.get(&user.username) == Some(user_id) {
+                index.remove(&user.username);
+            }
+            index.insert(username.clone(), user_id);
+        });
+        USERNAME_CHANGED_AT.with(|changes| changes.borrow_mut().insert(user_id, now));
+
+        directory::unindex_user(&user);
+        user.username = username;
+        directory::index_user(&user);
+        USER_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, user.clone()));
+        Ok(user)
+    })
+}
+
+#[ic_cdk::query]
+fn get_user_id_by_username(username: String) -> Result<u64, WalletError> {
+    perf::instrument("get_user_id_by_username", || {
+        ensure_not_restoring()?;
+
+        lookup(&username).ok_or(WalletError::NotFoundByKey {
+            entity: "username".to_string(),
+            key: username.clone(),
+        })
+    })
+}
@@ -0,0 +1,168 @@
+//! Deprecated v1 interface, kept for one release so existing frontends keep
+//! working while they move to the `v2_` endpoints. Every method here is a thin
+//! shim over its v2 counterpart that folds `WalletError` back into `Message`,
+//! with the text in the caller's locale. The shims are not instrumented
+//! themselves: a v1 call is counted once, under its v2 counterpart.
+//! `get_api_version` tells clients which interface versions are served and
+//! what replaces each deprecated method.
+
+use crate::{localization, perf};
+use crate::{
+    v2_create_user, v2_deposit_funds, v2_get_transaction_history, v2_get_user_balance,
+    v2_get_user_points, v2_redeem_points, v2_send_transaction, v2_validate_transfer,
+    DepositPayload, PointsPayload, Transaction, TransactionPayload, TransferPreview, User,
+    UserPayload, WalletError,
+};
+
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
+pub(crate) enum Message {
+    Success(String),
+    Error(String),
+    NotFound(String),
+    InvalidPayload(String),
+    Unauthorized(String),
+}
+
+// The interface served by the unprefixed methods below
+const LEGACY_API_VERSION: u32 = 1;
+const CURRENT_API_VERSION: u32 = 2;
+
+// Deprecated methods and their replacements
+const DEPRECATED_METHODS: &[(&str, &str)] = &[
+    ("create_user", "v2_create_user"),
+    ("deposit_funds", "v2_deposit_funds"),
+    ("validate_transfer", "v2_validate_transfer"),
+    ("send_transaction", "v2_send_transaction"),
+    ("redeem_points", "v2_redeem_points"),
+    ("get_transaction_history", "v2_get_transaction_history"),
+    ("get_user_balance", "v2_get_user_balance"),
+    ("get_user_points", "v2_get_user_points"),
+];
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct DeprecatedMethod {
+    method: String,
+    replacement: String,
+    // Last interface version that serves the method
+    removed_after: u32,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ApiVersion {
+    current: u32,
+    // Oldest version whose methods are still served
+    minimum_supported: u32,
+    deprecated_methods: Vec<DeprecatedMethod>,
+}
+
+impl From<WalletError> for Message {
+    fn from(error: WalletError) -> Self {
+        let text = localization::localize_error(&error).into_message();
+        match error {
+            WalletError::InvalidPayload { .. }
+            | WalletError::InvalidFields { .. }
+            | WalletError::AlreadyExists { .. } => Message::InvalidPayload(text),
+            WalletError::NotFound { .. } | WalletError::NotFoundByKey { .. } => {
+                Message::NotFound(text)
+            }
+            WalletError::Unauthorized { .. } => Message::Unauthorized(text),
+            _ => Message::Error(text),
+        }
+    }
+}
+
+#[ic_cdk::update]
+fn create_user(payload: UserPayload) -> Result<User, Message> {
+    v2_create_user(payload).map_err(Message::from)
+}
+
+#[ic_cdk::update]
+fn deposit_funds(payload: DepositPayload) -> Result<Message, Message> {
+    v2_deposit_funds(payload)
+        .map(|receipt| {
+            Message::Success(
+                localization::localize(
+                    "DEPOSIT_SUCCEEDED",
+                    &[
+                        ("amount", receipt.amount.to_string()),
+                        ("user_id", receipt.user_id.to_string()),
+                    ],
+                    format!(
+                        "Deposited {} units of currency to user {}",
+                        receipt.amount, receipt.user_id
+                    ),
+                )
+                .into_message(),
+            )
+        })
+        .map_err(Message::from)
+}
+
+#[ic_cdk::query]
+fn validate_transfer(payload: TransactionPayload) -> Result<TransferPreview, Message> {
+    v2_validate_transfer(payload).map_err(Message::from)
+}
+
+#[ic_cdk::update]
+fn send_transaction(payload: TransactionPayload) -> Result<Transaction, Message> {
+    v2_send_transaction(payload).map_err(Message::from)
+}
+
+#[ic_cdk::update]
+fn redeem_points(payload: PointsPayload) -> Result<Message, Message> {
+    v2_redeem_points(payload)
+        .map(|receipt| {
+            Message::Success(
+                localization::localize(
+                    "POINTS_REDEEMED",
+                    &[
+                        ("points", receipt.points_redeemed.to_string()),
+                        ("user_id", receipt.user_id.to_string()),
+                    ],
+                    format!(
+                        "Redeemed {} points from user {}",
+                        receipt.points_redeemed, receipt.user_id
+                    ),
+                )
+                .into_message(),
+            )
+        })
+        .map_err(Message::from)
+}
+
+#[ic_cdk::query]
+fn get_transaction_history(user_id: u64) -> Result<Vec<Transaction>, Message> {
+    let transactions = v2_get_transaction_history(user_id).map_err(Message::from)?;
+    // v1 reported an empty history as an error
+    if transactions.is_empty() {
+        Err(Message::NotFound("No transactions found".to_string()))
+    } else {
+        Ok(transactions)
+    }
+}
+
+#[ic_cdk::query]
+fn get_user_balance(user_id: u64) -> Result<u64, Message> {
+    v2_get_user_balance(user_id).map_err(Message::from)
+}
+
+#[ic_cdk::query]
+fn get_user_points(user_id: u64) -> Result<u64, Message> {
+    v2_get_user_points(user_id).map_err(Message::from)
+}
+
+#[ic_cdk::query]
+fn get_api_version() -> ApiVersion {
+    perf::measure("get_api_version", || ApiVersion {
+        current: CURRENT_API_VERSION,
+        minimum_supported: LEGACY_API_VERSION,
+        deprecated_methods: DEPRECATED_METHODS
+            .iter()
+            .map(|(method, replacement)| DeprecatedMethod {
+                method: method.to_string(),
+                replacement: replacement.to_string(),
+                removed_after: LEGACY_API_VERSION,
+            })
+            .collect(),
+    })
+}
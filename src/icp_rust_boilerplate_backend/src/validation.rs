@@ -0,0 +1,276 @@
+//! Validation of account fields. The rules live in stable memory so admins
+//! can change them at runtime with `set_validation_rules`; the regexes are
+//! compiled once and cached until the rules change or the canister upgrades.
+
+use crate::backup::ensure_writable;
+use crate::hardening::{self, FieldChecker, TextKind};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{ensure_admin, Memory, WalletError, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use regex::Regex;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_PATTERN_LEN: usize = 256;
+const MAX_LIST_LEN: usize = 500;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ValidationRules {
+    email_pattern: String,
+    phone_pattern: String,
+    username_pattern: String,
+    // Shown when a username does not match `username_pattern`
+    username_hint: String,
+    name_min_len: u32,
+    name_max_len: u32,
+    // Compared case-insensitively against the part after '@'
+    blocked_email_domains: Vec<String>,
+    reserved_usernames: Vec<String>,
+}
+
+impl Default for ValidationRules {
+    fn default() -> Self {
+        ValidationRules {
+            email_pattern: r"^[^\s@]+@[^\s@]+\.[^\s@]+$".to_string(),
+            // Basic regex for international phone numbers
+            phone_pattern: r"^\+?[1-9]\d{1,14}$".to_string(),
+            username_pattern: r"^[a-z0-9_]{3,20}$".to_string(),
+            username_hint: "must be 3-20 lowercase letters, digits or underscores".to_string(),
+            name_min_len: 1,
+            name_max_len: 100,
+            blocked_email_domains: Vec::new(),
+            reserved_usernames: Vec::new(),
+        }
+    }
+}
+
+impl Storable for ValidationRules {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+struct CompiledRules {
+    rules: ValidationRules,
+    email: Regex,
+    phone: Regex,
+    username: Regex,
+}
+
+thread_local! {
+    static VALIDATION_RULES: RefCell<Cell<ValidationRules, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))),
+            ValidationRules::default(),
+        )
+        .expect("Cannot create the validation rules cell")
+    );
+
+    // Filled on first use and cleared whenever the rules change
+    static COMPILED_RULES: RefCell<Option<CompiledRules>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![manifest::cell(
+        "validation.validation_rules",
+        37,
+        &VALIDATION_RULES,
+    )]
+}
+
+fn compile(rules: ValidationRules) -> Result<CompiledRules, WalletError> {
+    let compile_pattern = |field: &str, pattern: &str| {
+        if pattern.len() > MAX_PATTERN_LEN {
+            return Err(WalletError::invalid(
+                field,
+                &format!("must be at most {} characters", MAX_PATTERN_LEN),
+            ));
+        }
+        Regex::new(pattern)
+            .map_err(|e| WalletError::invalid(field, &format!("is not a valid regex: {}", e)))
+    };
+    Ok(CompiledRules {
+        email: compile_pattern("email_pattern", &rules.email_pattern)?,
+        phone: compile_pattern("phone_pattern", &rules.phone_pattern)?,
+        username: compile_pattern("username_pattern", &rules.username_pattern)?,
+        rules,
+    })
+}
+
+/// Drops the compiled rules, which are compiled again from the stored ones
+/// on next use.
+#[cfg(feature = "test-hooks")]
+pub(crate) fn forget_compiled_rules() {
+    COMPILED_RULES.with(|cache| *cache.borrow_mut() = None);
+}
+
+fn with_rules<R>(f: impl FnOnce(&CompiledRules) -> R) -> R {
+    COMPILED_RULES.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let compiled = cache.get_or_insert_with(|| {
+            let rules = VALIDATION_RULES.with(|rules| rules.borrow().get().clone());
+            // Stored rules were compiled successfully before being saved
+            compile(rules).expect("Stored validation rules do not compile")
+        });
+        f(compiled)
+    })
+}
+
+pub(crate) fn validate_name(field: &str, value: &str) -> Result<(), WalletError> {
+    with_rules(|compiled| {
+        let len = value.chars().count() as u32;
+        if len < compiled.rules.name_min_len || len > compiled.rules.name_max_len {
+            return Err(WalletError::invalid(
+                field,
+                &format!(
+                    "must be between {} and {} characters",
+                    compiled.rules.name_min_len, compiled.rules.name_max_len
+                ),
+            ));
+        }
+        Ok(())
+    })
+}
+
+pub(crate) fn validate_email(email: &str) -> Result<(), WalletError> {
+    with_rules(|compiled| {
+        if !compiled.email.is_match(email) {
+            return Err(WalletError::invalid(
+                "email",
+                "invalid email address format",
+            ));
+        }
+        let domain = email.rsplit('@').next().unwrap_or_default();
+        if compiled
+            .rules
+            .blocked_email_domains
+            .iter()
+            .any(|blocked| blocked.eq_ignore_ascii_case(domain))
+        {
+            return Err(WalletError::invalid(
+                "email",
+                "addresses from this domain are not accepted",
+            ));
+        }
+        Ok(())
+    })
+}
+
+pub(crate) fn validate_phone(phone_number: &str) -> Result<(), WalletError> {
+    with_rules(|compiled| {
+        if !compiled.phone.is_match(phone_number) {
+            return Err(WalletError::invalid(
+                "phone_number",
+                "invalid phone number format",
+            ));
+        }
+        Ok(())
+    })
+}
+
+pub(crate) fn is_reserved_username(username: &str) -> bool {
+    with_rules(|compiled| {
+        compiled
+            .rules
+            .reserved_usernames
+            .iter()
+            .any(|reserved| reserved == username)
+    })
+}
+
+pub(crate) fn validate_username(username: &str) -> Result<(), WalletError> {
+    with_rules(|compiled| {
+        if !compiled.username.is_match(username) {
+            return Err(WalletError::invalid(
+                "username",
+                &compiled.rules.username_hint,
+            ));
+        }
+        Ok(())
+    })?;
+    if is_reserved_username(username) {
+        return Err(WalletError::invalid("username", "is reserved"));
+    }
+    Ok(())
+}
+
+/// Checks the fields every account is created with: present, within the
+/// hardening ceilings and matching the rules.
+pub(crate) fn check_account_fields(
+    fields: &mut FieldChecker,
+    first_name: &str,
+    last_name: &str,
+    email: &str,
+    phone_number: &str,
+) {
+    fields.text("first_name", first_name, TextKind::Name);
+    fields.text("last_name", last_name, TextKind::Name);
+    fields.text("email", email, TextKind::Email);
+    fields.text("phone_number", phone_number, TextKind::Phone);
+    for (field, value) in [
+        ("first_name", first_name),
+        ("last_name", last_name),
+        ("email", email),
+        ("phone_number", phone_number),
+    ] {
+        if value.is_empty() {
+            fields.reject(field, "must be provided");
+        }
+    }
+    fields.rule(validate_name("first_name", first_name));
+    fields.rule(validate_name("last_name", last_name));
+    fields.rule(validate_email(email));
+    fields.rule(validate_phone(phone_number));
+}
+
+#[ic_cdk::query]
+fn get_validation_rules() -> ValidationRules {
+    perf::measure("get_validation_rules", || {
+        VALIDATION_RULES.with(|rules| rules.borrow().get().clone())
+    })
+}
+
+#[ic_cdk::update]
+fn set_validation_rules(rules: ValidationRules) -> Result<(), WalletError> {
+    perf::instrument("set_validation_rules", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if rules.name_min_len == 0 || rules.name_min_len > rules.name_max_len {
+            return Err(WalletError::invalid(
+                "name_min_len",
+                "must be at least 1 and at most name_max_len",
+            ));
+        }
+        if rules.name_max_len as usize > hardening::MAX_NAME_LEN {
+            return Err(WalletError::invalid(
+                "name_max_len",
+                &format!("must be at most {}", hardening::MAX_NAME_LEN),
+            ));
+        }
+        if rules.blocked_email_domains.len() > MAX_LIST_LEN
+            || rules.reserved_usernames.len() > MAX_LIST_LEN
+        {
+            return Err(WalletError::invalid(
+                "rules",
+                &format!("lists can hold at most {} entries", MAX_LIST_LEN),
+            ));
+        }
+        let compiled = compile(rules.clone())?;
+
+        VALIDATION_RULES
+            .with(|cell| cell.borrow_mut().set(rules))
+            .expect("Cannot update the validation rules");
+        COMPILED_RULES.with(|cache| *cache.borrow_mut() = Some(compiled));
+        Ok(())
+    })
+}
@@ -0,0 +1,368 @@
+//! Verification of an account's email address and phone number. Accounts
+//! start unverified, and changing a contact detail unverifies it again. A
+//! verifier (a controller, or a principal the controllers registered, such as
+//! an SMS or email provider integration) delivers a code to the user out of
+//! band and submits its SHA-256 hash here; the user then confirms the code
+//! with `verify_contact`. Moving funds out of the wallet and granting others
+//! spending rights require a verified account.
+
+use crate::auth::{self, caller_user_id, ensure_owner, StorablePrincipal};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::hardening::{self, FieldChecker, Harden, TextKind};
+use crate::manifest::{self, Storage};
+use crate::perf;
+use crate::{
+    current_time, ensure_admin, sha256_hex, unclaimed, validation, Memory, User, WalletError,
+    MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const CHALLENGE_TTL_SECONDS: u64 = 15 * 60;
+// Wrong codes accepted before a challenge is discarded
+const MAX_CODE_ATTEMPTS: u32 = 5;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ContactChannel {
+    Email,
+    Phone,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Challenge {
+    code_hash: String,
+    // The contact the code was sent to; changing it voids the challenge
+    contact: String,
+    expires_at: u64,
+    failed_attempts: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PendingChallenges {
+    email: Option<Challenge>,
+    phone: Option<Challenge>,
+}
+
+impl PendingChallenges {
+    fn slot(&mut self, channel: ContactChannel) -> &mut Option<Challenge> {
+        match channel {
+            ContactChannel::Email => &mut self.email,
+            ContactChannel::Phone => &mut self.phone,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct VerificationStatus {
+    user_id: u64,
+    email_verified_at: Option<u64>,
+    phone_verified_at: Option<u64>,
+    verified: bool,
+    // Channels with a code waiting to be confirmed
+    pending: Vec<ContactChannel>,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ContactUpdatePayload {
+    email: Option<String>,
+    phone_number: Option<String>,
+}
+
+impl Harden for ContactUpdatePayload {
+    fn harden(&self, fields: &mut FieldChecker) {
+        fields.optional_text("email", &self.email, TextKind::Email);
+        fields.optional_text("phone_number", &self.phone_number, TextKind::Phone);
+        if let Some(email) = &self.email {
+            fields.rule(validation::validate_email(email));
+        }
+        if let Some(phone_number) = &self.phone_number {
+            fields.rule(validation::validate_phone(phone_number));
+        }
+    }
+}
+
+impl Storable for PendingChallenges {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static CHALLENGES: RefCell<StableBTreeMap<u64, PendingChallenges, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+
+    // Registered verifiers and when they were added
+    static VERIFIERS: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("verification.challenges", 56, &CHALLENGES),
+        manifest::map("verification.verifiers", 57, &VERIFIERS),
+    ]
+}
+
+fn get_user_record(user_id: u64) -> Result<User, WalletError> {
+    USER_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or(WalletError::not_found("user", user_id))
+}
+
+fn contact_of(user: &User, channel: ContactChannel) -> &str {
+    match channel {
+        ContactChannel::Email => &user.email,
+        ContactChannel::Phone => &user.phone_number,
+    }
+}
+
+fn is_verified(user: &User) -> bool {
+    user.email_verified_at.is_some() && user.phone_verified_at.is_some()
+}
+
+/// Fails unless both contact details of `user_id` are verified.
+pub(crate) fn ensure_verified(user_id: u64) -> Result<(), WalletError> {
+    if !is_verified(&get_user_record(user_id)?) {
+        return Err(WalletError::Unauthorized {
+            reason: format!(
+                "user {} must verify their email and phone number first",
+                user_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn ensure_verifier() -> Result<(), WalletError> {
+    let caller = auth::caller();
+    if ic_cdk::api::is_controller(&caller)
+        || VERIFIERS.with(|verifiers| verifiers.borrow().contains_key(&StorablePrincipal(caller)))
+    {
+        return Ok(());
+    }
+    Err(WalletError::Unauthorized {
+        reason: "only verifiers can submit verification codes".to_string(),
+    })
+}
+
+#[ic_cdk::update]
+fn add_verifier(verifier: Principal) -> Result<(), WalletError> {
+    perf::instrument("add_verifier", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        if verifier == Principal::anonymous() {
+            return Err(WalletError::invalid(
+                "verifier",
+                "must not be the anonymous principal",
+            ));
+        }
+        VERIFIERS.with(|verifiers| {
+            verifiers
+                .borrow_mut()
+                .insert(StorablePrincipal(verifier), current_time())
+        });
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn remove_verifier(verifier: Principal) -> Result<(), WalletError> {
+    perf::instrument("remove_verifier", || {
+        ensure_writable()?;
+        ensure_admin()?;
+
+        VERIFIERS
+            .with(|verifiers| verifiers.borrow_mut().remove(&StorablePrincipal(verifier)))
+            .map(|_| ())
+            .ok_or(WalletError::NotFoundByKey {
+                entity: "verifier".to_string(),
+                key: verifier.to_text(),
+            })
+    })
+}
+
+/// Opens a challenge for one contact detail of `user_id`, replacing any
+/// earlier one. `code_hash` is the hex SHA-256 hash of the code that was
+/// delivered to the user.
+#[ic_cdk::update]
+fn submit_verification_code(
+    user_id: u64,
+    channel: ContactChannel,
+    code_hash: String,
+) -> Result<(), WalletError> {
+    perf::instrument("submit_verification_code", || {
+        ensure_writable()?;
+        ensure_verifier()?;
+
+        let user = get_user_record(user_id)?;
+        let code_hash = code_hash.trim().to_lowercase();
+        if code_hash.len() != 64 || !code_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(WalletError::invalid(
+                "code_hash",
+                "must be a hex encoded SHA-256 hash",
+            ));
+        }
+
+        let mut challenges = CHALLENGES
+            .with(|challenges| challenges.borrow().get(&user_id))
+            .unwrap_or_default();
+        *challenges.slot(channel) = Some(Challenge {
+            code_hash,
+            contact: contact_of(&user, channel).to_string(),
+            expires_at: current_time() + CHALLENGE_TTL_SECONDS * NANOS_PER_SECOND,
+            failed_attempts: 0,
+        });
+        CHALLENGES.with(|storage| storage.borrow_mut().insert(user_id, challenges));
+        Ok(())
+    })
+}
+
+/// Confirms a code delivered to the caller. The code is matched against
+/// every open challenge of the account, and the channel it verified is
+/// returned.
+#[ic_cdk::update]
+fn verify_contact(code: String) -> Result<ContactChannel, WalletError> {
+    perf::instrument("verify_contact", || {
+        ensure_writable()?;
+
+        let user_id = caller_user_id()?;
+        let mut user = get_user_record(user_id)?;
+        let mut challenges = CHALLENGES
+            .with(|challenges| challenges.borrow().get(&user_id))
+            .unwrap_or_default();
+        let now = current_time();
+        let code_hash = sha256_hex(code.trim().as_bytes());
+
+        let channels = [ContactChannel::Email, ContactChannel::Phone];
+        // Expired challenges and those for a contact that changed are dropped
+        for channel in channels {
+            let contact = contact_of(&user, channel).to_string();
+            let slot = challenges.slot(channel);
+            if slot.as_ref().is_some_and(|challenge| {
+                now >= challenge.expires_at || challenge.contact != contact
+            }) {
+                *slot = None;
+            }
+        }
+        let verified = channels.into_iter().find(|&channel| {
+            challenges
+                .slot(channel)
+                .as_ref()
+                .is_some_and(|challenge| challenge.code_hash == code_hash)
+        });
+        match verified {
+            Some(channel) => *challenges.slot(channel) = None,
+            // A wrong code counts against every open challenge
+            None => {
+                for channel in channels {
+                    let slot = challenges.slot(channel);
+                    if let Some(challenge) = slot.as_mut() {
+                        challenge.failed_attempts += 1;
+                        if challenge.failed_attempts >= MAX_CODE_ATTEMPTS {
+                            *slot = None;
+                        }
+                    }
+                }
+            }
+        }
+        CHALLENGES.with(|storage| storage.borrow_mut().insert(user_id, challenges));
+
+        let channel = verified.ok_or(WalletError::invalid(
+            "code",
+            "does not match an open verification challenge",
+        ))?;
+        match channel {
+            ContactChannel::Email => user.email_verified_at = Some(now),
+            ContactChannel::Phone => user.phone_verified_at = Some(now),
+        }
+        USER_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, user.clone()));
+        // Only after the user is saved, since claims change the balance
+        unclaimed::claim_verified(&user, channel);
+        Ok(channel)
+    })
+}
+
+/// Changes the caller's email address or phone number. A changed detail is
+/// unverified until it is confirmed again.
+#[ic_cdk::update]
+fn update_contact_details(payload: ContactUpdatePayload) -> Result<User, WalletError> {
+    perf::instrument("update_contact_details", || {
+        ensure_writable()?;
+
+        hardening::check(&payload)?;
+        let user_id = caller_user_id()?;
+        let mut user = get_user_record(user_id)?;
+        if let Some(email) = payload.email {
+            if email != user.email {
+                let taken = USER_STORAGE.with(|storage| {
+                    storage
+                        .borrow()
+                        .iter()
+                        .any(|(id, other)| id != user_id && other.email == email)
+                });
+                if taken {
+                    return Err(WalletError::AlreadyExists {
+                        entity: "user".to_string(),
+                        field: "email".to_string(),
+                    });
+                }
+                user.email = email;
+                user.email_verified_at = None;
+            }
+        }
+        if let Some(phone_number) = payload.phone_number {
+            if phone_number != user.phone_number {
+                user.phone_number = phone_number;
+                user.phone_verified_at = None;
+            }
+        }
+        USER_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, user.clone()));
+        Ok(user)
+    })
+}
+
+#[ic_cdk::query]
+fn get_verification_status(user_id: u64) -> Result<VerificationStatus, WalletError> {
+    perf::instrument("get_verification_status", || {
+        ensure_not_restoring()?;
+
+        let user = get_user_record(user_id)?;
+        ensure_owner(user_id)?;
+
+        let now = current_time();
+        let mut challenges = CHALLENGES
+            .with(|challenges| challenges.borrow().get(&user_id))
+            .unwrap_or_default();
+        let pending = [ContactChannel::Email, ContactChannel::Phone]
+            .into_iter()
+            .filter(|&channel| {
+                let contact = contact_of(&user, channel).to_string();
+                challenges.slot(channel).as_ref().is_some_and(|challenge| {
+                    now < challenge.expires_at && challenge.contact == contact
+                })
+            })
+            .collect();
+        Ok(VerificationStatus {
+            user_id,
+            email_verified_at: user.email_verified_at,
+            phone_verified_at: user.phone_verified_at,
+            verified: is_verified(&user),
+            pending,
+        })
+    })
+}
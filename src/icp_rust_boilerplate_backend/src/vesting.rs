@@ -0,0 +1,375 @@
+//! Time-locked transfers and vesting. The funds move to the recipient at
+//! once, but stay locked in the recipient's balance, like a hold, until the
+//! schedule releases them: a time lock on a single date, a vesting grant
+//! linearly over its duration, with nothing released before the cliff. A
+//! timer moves the released amounts forward. What is still locked for each
+//! recipient is kept as a running total.
+
+use crate::auth::{caller_user_id, ensure_owner};
+use crate::backup::{ensure_not_restoring, ensure_writable};
+use crate::manifest::{self, Storage};
+use crate::notifications::{notify, NotificationKind};
+use crate::{
+    current_time, next_id, perf, send_transfer, token, Memory, Screening, TransactionPayload,
+    WalletError, MEMORY_MANAGER, USER_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const RELEASE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_LOCK_SECONDS: u64 = 10 * 365 * 24 * 60 * 60;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum LockSchedule {
+    // Everything is released at `unlock_at`
+    TimeLock { unlock_at: u64 },
+    // Released linearly from `start` to `end`, nothing before `cliff_at`
+    Vesting { start: u64, cliff_at: u64, end: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LockedTransfer {
+    id: u64,
+    tx_id: u64,
+    sender_user_id: u64,
+    recipient_user_id: u64,
+    total: u64,
+    // Part of `total` the recipient can spend
+    released: u64,
+    schedule: LockSchedule,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct UpcomingUnlock {
+    lock_id: u64,
+    sender_user_id: u64,
+    // Still locked now
+    locked: u64,
+    // Released between now and the requested date
+    unlocking: u64,
+    fully_unlocked_at: u64,
+}
+
+impl Storable for LockedTransfer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static LOCK_STORAGE: RefCell<StableBTreeMap<u64, LockedTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78)))
+    ));
+
+    // Amount still locked for each recipient
+    static LOCKED_TOTALS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(120)))
+    ));
+}
+
+pub(crate) fn storages() -> Vec<Box<dyn Storage>> {
+    vec![
+        manifest::map("vesting.lock_storage", 78, &LOCK_STORAGE),
+        manifest::map("vesting.locked_totals", 120, &LOCKED_TOTALS),
+    ]
+}
+
+fn save_lock(lock: &LockedTransfer) {
+    let previous = LOCK_STORAGE.with(|storage| storage.borrow_mut().insert(lock.id, lock.clone()));
+    let was_locked = previous.map_or(0, |previous| previous.total - previous.released);
+    let locked = lock.total - lock.released;
+    if was_locked == locked {
+        return;
+    }
+    LOCKED_TOTALS.with(|totals| {
+        let mut totals = totals.borrow_mut();
+        let total = totals
+            .get(&lock.recipient_user_id)
+            .unwrap_or(0)
+            .saturating_sub(was_locked)
+            .saturating_add(locked);
+        if total == 0 {
+            totals.remove(&lock.recipient_user_id);
+        } else {
+            totals.insert(lock.recipient_user_id, total);
+        }
+    });
+}
+
+/// Totals the locks of a canister that kept them before the running totals
+/// existed.
+pub(crate) fn seed_totals_if_needed() {
+    if !LOCKED_TOTALS.with(|totals| totals.borrow().is_empty()) {
+        return;
+    }
+    let locks: Vec<LockedTransfer> = LOCK_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, lock)| lock)
+            .filter(|lock| lock.is_active())
+            .collect()
+    });
+    for lock in locks {
+        let total = LOCKED_TOTALS
+            .with(|totals| totals.borrow().get(&lock.recipient_user_id))
+            .unwrap_or(0)
+            .saturating_add(lock.total - lock.released);
+        LOCKED_TOTALS.with(|totals| totals.borrow_mut().insert(lock.recipient_user_id, total));
+    }
+}
+
+impl LockSchedule {
+    fn fully_unlocked_at(&self) -> u64 {
+        match *self {
+            LockSchedule::TimeLock { unlock_at } => unlock_at,
+            LockSchedule::Vesting { end, .. } => end,
+        }
+    }
+
+    // Part of `total` the schedule releases by `at`
+    fn released_by(&self, total: u64, at: u64) -> u64 {
+        match *self {
+            LockSchedule::TimeLock { unlock_at } if at >= unlock_at => total,
+            LockSchedule::TimeLock { .. } => 0,
+            LockSchedule::Vesting { cliff_at, .. } if at < cliff_at => 0,
+            LockSchedule::Vesting { end, .. } if at >= end => total,
+            LockSchedule::Vesting { start, end, .. } => {
+                (total as u128 * (at - start) as u128 / (end - start) as u128) as u64
+            }
+        }
+    }
+}
+
+impl LockedTransfer {
+    fn is_active(&self) -> bool {
+        self.released < self.total
+    }
+}
+
+/// Funds received through time locks and vesting that are not released yet.
+pub(crate) fn locked_amount(user_id: u64) -> u64 {
+    LOCKED_TOTALS.with(|totals| totals.borrow().get(&user_id).unwrap_or(0))
+}
+
+pub(crate) fn start_release_job() {
+    ic_cdk_timers::set_timer_interval(RELEASE_INTERVAL, release_due);
+}
+
+fn release_due() {
+    if ensure_writable().is_err() {
+        return;
+    }
+    let now = current_time();
+    let due: Vec<(LockedTransfer, u64)> = LOCK_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, lock)| lock)
+            .filter(|lock| lock.is_active())
+            .filter_map(|lock| {
+                let released = lock.schedule.released_by(lock.total, now);
+                (released > lock.released).then_some((lock, released))
+            })
+            .collect()
+    });
+    for (mut lock, released) in due {
+        let first_release = lock.released == 0;
+        lock.released = released;
+        if !lock.is_active() {
+            notify(
+                lock.recipient_user_id,
+                NotificationKind::Unlock,
+                format!(
+                    "All {} from user {} is now unlocked",
+                    token::format_amount(lock.total),
+                    lock.sender_user_id
+                ),
+            );
+        } else if first_release {
+            notify(
+                lock.recipient_user_id,
+                NotificationKind::Unlock,
+                format!(
+                    "Your vesting grant {} passed its cliff; {} is unlocked",
+                    lock.id,
+                    token::format_amount(released)
+                ),
+            );
+        }
+        save_lock(&lock);
+    }
+}
+
+// Sends `amount` from the caller's account and locks it for the recipient
+fn send_locked(
+    recipient_user_id: u64,
+    amount: u64,
+    schedule: LockSchedule,
+) -> Result<LockedTransfer, WalletError> {
+    let sender_user_id = caller_user_id()?;
+    let id = next_id();
+    let memo = match schedule {
+        LockSchedule::TimeLock { .. } => format!("Time-locked transfer {}", id),
+        LockSchedule::Vesting { .. } => format!("Vesting grant {}", id),
+    };
+    // Rejected rather than held for review, since a held transfer would
+    // execute without its lock
+    let transaction = send_transfer(
+        TransactionPayload {
+            from_user_id: sender_user_id,
+            to_user_id: recipient_user_id,
+            amount,
+            category: None,
+            memo: Some(memo),
+            to_handle: None,
+        },
+        Screening::Reject,
+    )?;
+    let lock = LockedTransfer {
+        id,
+        tx_id: transaction.id.0,
+        sender_user_id,
+        recipient_user_id,
+        total: amount,
+        released: schedule.released_by(amount, current_time()),
+        schedule,
+        created_at: current_time(),
+    };
+    save_lock(&lock);
+    Ok(lock)
+}
+
+fn validate_horizon(field: &str, seconds: u64) -> Result<(), WalletError> {
+    if seconds == 0 || seconds > MAX_LOCK_SECONDS {
+        return Err(WalletError::invalid(
+            field,
+            &format!("must be between 1 and {} seconds away", MAX_LOCK_SECONDS),
+        ));
+    }
+    Ok(())
+}
+
+/// Sends `amount` that the recipient can only spend from `unlock_at`, in
+/// nanoseconds since the epoch.
+#[ic_cdk::update]
+fn send_timelocked(
+    recipient_user_id: u64,
+    amount: u64,
+    unlock_at: u64,
+) -> Result<LockedTransfer, WalletError> {
+    perf::instrument("send_timelocked", || {
+        ensure_writable()?;
+
+        let now = current_time();
+        validate_horizon(
+            "unlock_at",
+            unlock_at.saturating_sub(now) / NANOS_PER_SECOND,
+        )?;
+        send_locked(
+            recipient_user_id,
+            amount,
+            LockSchedule::TimeLock { unlock_at },
+        )
+    })
+}
+
+/// Sends `total` that vests linearly over `duration_seconds`; nothing is
+/// released until `cliff_seconds` have passed.
+#[ic_cdk::update]
+fn create_vesting(
+    recipient_user_id: u64,
+    total: u64,
+    cliff_seconds: u64,
+    duration_seconds: u64,
+) -> Result<LockedTransfer, WalletError> {
+    perf::instrument("create_vesting", || {
+        ensure_writable()?;
+
+        validate_horizon("duration_seconds", duration_seconds)?;
+        if cliff_seconds > duration_seconds {
+            return Err(WalletError::invalid(
+                "cliff_seconds",
+                "must not be longer than the duration",
+            ));
+        }
+        let start = current_time();
+        send_locked(
+            recipient_user_id,
+            total,
+            LockSchedule::Vesting {
+                start,
+                cliff_at: start + cliff_seconds * NANOS_PER_SECOND,
+                end: start + duration_seconds * NANOS_PER_SECOND,
+            },
+        )
+    })
+}
+
+/// Time locks and vesting grants the user sent or received.
+#[ic_cdk::query]
+fn list_locked_transfers(user_id: u64) -> Result<Vec<LockedTransfer>, WalletError> {
+    perf::instrument("list_locked_transfers", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(LOCK_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, lock)| lock)
+                .filter(|lock| lock.sender_user_id == user_id || lock.recipient_user_id == user_id)
+                .collect()
+        }))
+    })
+}
+
+/// What each of the user's locked funds releases between now and `until`.
+#[ic_cdk::query]
+fn get_upcoming_unlocks(user_id: u64, until: u64) -> Result<Vec<UpcomingUnlock>, WalletError> {
+    perf::instrument("get_upcoming_unlocks", || {
+        ensure_not_restoring()?;
+
+        if !USER_STORAGE.with(|storage| storage.borrow().contains_key(&user_id)) {
+            return Err(WalletError::not_found("user", user_id));
+        }
+        ensure_owner(user_id)?;
+
+        Ok(LOCK_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, lock)| lock)
+                .filter(|lock| lock.recipient_user_id == user_id && lock.is_active())
+                .map(|lock| UpcomingUnlock {
+                    lock_id: lock.id,
+                    sender_user_id: lock.sender_user_id,
+                    locked: lock.total - lock.released,
+                    unlocking: lock
+                        .schedule
+                        .released_by(lock.total, until)
+                        .saturating_sub(lock.released),
+                    fully_unlocked_at: lock.schedule.fully_unlocked_at(),
+                })
+                .collect()
+        }))
+    })
+}